@@ -0,0 +1,83 @@
+/*
+ * Device side of `nus_terminal --flow-control credits`
+ *
+ * Received UART data goes into a ring buffer the application drains at its own pace. The
+ * terminal may only send as many bytes as granted, so the buffer never overflows: the free
+ * room is granted on connecting, and every byte taken out of the buffer is granted again,
+ * collected into grants of at least GRANT_BATCH bytes to keep the overhead low.
+ *
+ * nus_send() stands for whatever sends data to the terminal, e.g. bt_nus_send() of the
+ * nRF Connect SDK; call the on_*() hooks from the matching callbacks.
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RX_BUFFER_SIZE 1024
+#define GRANT_BATCH 128
+#define GRANT 0x1d
+
+void nus_send(const uint8_t *data, size_t len);
+
+static uint8_t rx_buffer[RX_BUFFER_SIZE];
+static size_t rx_head, rx_tail, rx_used;
+/* Bytes consumed but not granted back yet */
+static size_t consumed;
+
+static void grant(uint16_t bytes)
+{
+	const uint8_t frame[3] = { GRANT, bytes & 0xff, bytes >> 8 };
+
+	nus_send(frame, sizeof(frame));
+}
+
+/* The terminal connected, it starts without credits */
+void on_connected(void)
+{
+	rx_head = rx_tail = rx_used = 0;
+	consumed = 0;
+	grant(RX_BUFFER_SIZE);
+}
+
+/* Data written by the terminal, never more than granted */
+void on_received(const uint8_t *data, size_t len)
+{
+	for (size_t i = 0; i < len && rx_used < RX_BUFFER_SIZE; i++) {
+		rx_buffer[rx_head] = data[i];
+		rx_head = (rx_head + 1) % RX_BUFFER_SIZE;
+		rx_used++;
+	}
+}
+
+/* Takes a byte for the application, returns -1 if there is none */
+int rx_read(void)
+{
+	if (rx_used == 0) {
+		return -1;
+	}
+	uint8_t byte = rx_buffer[rx_tail];
+
+	rx_tail = (rx_tail + 1) % RX_BUFFER_SIZE;
+	rx_used--;
+	if (++consumed >= GRANT_BATCH || rx_used == 0) {
+		grant(consumed);
+		consumed = 0;
+	}
+	return byte;
+}
+
+/* Output to the terminal, where a 0x1D byte has to be sent as a grant of zero */
+void tx_write(const uint8_t *data, size_t len)
+{
+	static const uint8_t escaped[3] = { GRANT, 0, 0 };
+	size_t start = 0;
+
+	for (size_t i = 0; i < len; i++) {
+		if (data[i] == GRANT) {
+			nus_send(data + start, i - start);
+			nus_send(escaped, sizeof(escaped));
+			start = i + 1;
+		}
+	}
+	nus_send(data + start, len - start);
+}
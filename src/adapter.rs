@@ -0,0 +1,105 @@
+//! Choosing between the Bluetooth adapters of the system
+
+use crate::error::Error;
+use anyhow::Result;
+use btleplug::api::{Central, CentralState, Manager as _};
+use btleplug::platform::{Adapter, Manager};
+
+/// A Bluetooth adapter along with what is shown about it to the user
+#[derive(Debug, Clone)]
+pub struct AdapterInfo {
+    pub adapter: Adapter,
+    /// Position in the list of adapters, usable with `--adapter`
+    pub index: usize,
+    /// Platform description, e.g. `hci0 (usb:v1D6Bp0246d0537)` on Linux
+    pub description: String,
+    /// Bluetooth address, where the platform exposes it
+    pub address: Option<String>,
+    pub state: CentralState,
+}
+
+impl AdapterInfo {
+    /// Short platform name of the adapter, like `hci0`
+    pub fn name(&self) -> &str {
+        self.description
+            .split_whitespace()
+            .next()
+            .unwrap_or(&self.description)
+    }
+
+    /// Whether the adapter is known to be turned off
+    pub fn is_powered_off(&self) -> bool {
+        self.state == CentralState::PoweredOff
+    }
+
+    /// Whether `selector` (an index, address or name) refers to this adapter
+    fn matches(&self, selector: &str) -> bool {
+        match selector.parse::<usize>() {
+            Ok(index) => index == self.index,
+            Err(_) => {
+                self.name() == selector
+                    || self
+                        .address
+                        .as_deref()
+                        .is_some_and(|a| a.eq_ignore_ascii_case(selector))
+            }
+        }
+    }
+}
+
+/// Returns all Bluetooth adapters of the system
+pub async fn list() -> Result<Vec<AdapterInfo>> {
+    let manager = Manager::new().await?;
+    let mut adapters = Vec::new();
+    for (index, adapter) in manager.adapters().await?.into_iter().enumerate() {
+        let description = adapter.adapter_info().await?;
+        let state = adapter.adapter_state().await?;
+        let address = address(&description).await;
+        adapters.push(AdapterInfo {
+            adapter,
+            index,
+            description,
+            address,
+            state,
+        });
+    }
+    Ok(adapters)
+}
+
+/// Returns the adapter given by `selector`, or the first one, making sure it is turned on
+pub async fn select(selector: Option<&str>) -> Result<Adapter> {
+    let adapters = list().await?;
+    let info = match selector {
+        Some(selector) => adapters
+            .into_iter()
+            .find(|a| a.matches(selector))
+            .ok_or(Error::AdapterNotFound(Some(selector.to_string())))?,
+        None => adapters
+            .into_iter()
+            .next()
+            .ok_or(Error::AdapterNotFound(None))?,
+    };
+    if info.is_powered_off() {
+        return Err(Error::AdapterPoweredOff(info.name().to_string()).into());
+    }
+    Ok(info.adapter)
+}
+
+/// Looks up the address of the adapter described by `description` in BlueZ
+#[cfg(target_os = "linux")]
+async fn address(description: &str) -> Option<String> {
+    let id = description.split_whitespace().next()?;
+    let session = crate::bluez::session().await?;
+    session
+        .get_adapters()
+        .await
+        .ok()?
+        .into_iter()
+        .find(|a| a.id.to_string() == id)
+        .map(|a| a.mac_address.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn address(_description: &str) -> Option<String> {
+    None
+}
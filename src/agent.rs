@@ -0,0 +1,74 @@
+//! Answering the requests of a pairing, on the console or in the terminal UI
+
+use crate::pairing::{Agent, Request};
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+
+/// What the user is told about a request
+pub fn notice(request: &Request) -> String {
+    match request {
+        Request::Passkey(_) => "Pairing: enter the passkey shown by the device".to_string(),
+        Request::PinCode(_) => "Pairing: enter the PIN code of the device".to_string(),
+        Request::Confirm(passkey, _) => format!("Pairing: does the device show {passkey:06}?"),
+        Request::DisplayPasskey(passkey) => {
+            format!("Pairing: enter passkey {passkey:06} on the device")
+        }
+        Request::DisplayPinCode(pin) => format!("Pairing: enter PIN code {pin} on the device"),
+    }
+}
+
+/// Label of the input answering a request, `None` if there is nothing to answer
+pub fn label(request: &Request) -> Option<&'static str> {
+    match request {
+        Request::Passkey(_) => Some("Passkey"),
+        Request::PinCode(_) => Some("PIN code"),
+        Request::Confirm(..) => Some("Same passkey (y/n)"),
+        Request::DisplayPasskey(_) | Request::DisplayPinCode(_) => None,
+    }
+}
+
+/// Answers a request with what the user entered, rejecting it if that is not valid
+pub fn answer(request: Request, input: &str) {
+    let input = input.trim();
+    match request {
+        Request::Passkey(reply) => {
+            if let Ok(passkey) = input.parse() {
+                let _ = reply.send(passkey);
+            }
+        }
+        Request::PinCode(reply) => {
+            let _ = reply.send(input.to_string());
+        }
+        Request::Confirm(_, reply) => {
+            let _ =
+                reply.send(input.eq_ignore_ascii_case("y") || input.eq_ignore_ascii_case("yes"));
+        }
+        Request::DisplayPasskey(_) | Request::DisplayPinCode(_) => {}
+    }
+}
+
+/// An agent asking on the console, for pairing before the terminal UI is up or without one
+pub fn console() -> Agent {
+    static AGENT: OnceLock<Agent> = OnceLock::new();
+    AGENT
+        .get_or_init(|| {
+            let (agent, mut requests) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(request) = requests.recv().await {
+                    eprintln!("{}", notice(&request));
+                    let Some(label) = label(&request) else {
+                        continue;
+                    };
+                    eprint!("{label}: ");
+                    let line = tokio::task::spawn_blocking(|| {
+                        let mut line = String::new();
+                        std::io::stdin().read_line(&mut line).map(|_| line)
+                    })
+                    .await;
+                    answer(request, &line.ok().and_then(Result::ok).unwrap_or_default());
+                }
+            });
+            agent
+        })
+        .clone()
+}
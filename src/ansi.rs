@@ -0,0 +1,162 @@
+//! Interpretation of the control characters and escape sequences found in device output
+//!
+//! Only what makes sense within a single line is supported: carriage return, backspace,
+//! tabs, colors and attributes (SGR), horizontal cursor movement and erasing the line.
+//! Everything else is dropped.
+
+use ratatui::style::{Color, Modifier, Style};
+
+/// A line of output as displayed
+#[derive(Debug, Default)]
+pub struct RenderedLine {
+    pub cells: Vec<(char, Style)>,
+    /// Column the cursor is left at after the line
+    pub cursor: usize,
+}
+
+pub fn render(line: &str) -> RenderedLine {
+    let mut rendered = RenderedLine::default();
+    let mut style = Style::default();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => rendered.cursor = 0,
+            '\x08' => rendered.cursor = rendered.cursor.saturating_sub(1),
+            '\t' => {
+                let next_stop = (rendered.cursor / 8 + 1) * 8;
+                while rendered.cursor < next_stop {
+                    rendered.put(' ', style);
+                }
+            }
+            '\x1b' => {
+                if chars.next_if_eq(&'[').is_none() {
+                    // Two-character sequence, e.g. charset selection
+                    chars.next();
+                    continue;
+                }
+                let mut params = String::new();
+                let mut command = None;
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        command = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                match command {
+                    Some('m') => style = sgr(style, &params),
+                    Some('K') => rendered.erase(&params),
+                    Some('C') => rendered.cursor += count(&params),
+                    Some('D') => rendered.cursor = rendered.cursor.saturating_sub(count(&params)),
+                    Some('G') => rendered.cursor = count(&params) - 1,
+                    _ => {}
+                }
+            }
+            c if c.is_control() => {}
+            c => rendered.put(c, style),
+        }
+    }
+    rendered
+}
+
+impl RenderedLine {
+    fn put(&mut self, c: char, style: Style) {
+        if self.cursor < self.cells.len() {
+            self.cells[self.cursor] = (c, style);
+        } else {
+            self.cells.resize(self.cursor, (' ', Style::default()));
+            self.cells.push((c, style));
+        }
+        self.cursor += 1;
+    }
+
+    fn erase(&mut self, params: &str) {
+        match params {
+            "" | "0" => self.cells.truncate(self.cursor),
+            "1" => {
+                let end = self.cursor.min(self.cells.len());
+                self.cells[..end].fill((' ', Style::default()));
+            }
+            "2" => self.cells.clear(),
+            _ => {}
+        }
+    }
+}
+
+/// Parses the count argument of cursor movement sequences, which defaults to 1
+fn count(params: &str) -> usize {
+    params.parse().unwrap_or(1).max(1)
+}
+
+/// Applies the attributes of a "Select Graphic Rendition" sequence to `style`
+pub fn sgr(mut style: Style, params: &str) -> Style {
+    let mut params = params.split(';').map(|p| p.parse::<u16>().unwrap_or(0));
+
+    while let Some(p) = params.next() {
+        style = match p {
+            0 => Style::default(),
+            1 => style.add_modifier(Modifier::BOLD),
+            2 => style.add_modifier(Modifier::DIM),
+            3 => style.add_modifier(Modifier::ITALIC),
+            4 => style.add_modifier(Modifier::UNDERLINED),
+            5 => style.add_modifier(Modifier::SLOW_BLINK),
+            7 => style.add_modifier(Modifier::REVERSED),
+            22 => style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+            23 => style.remove_modifier(Modifier::ITALIC),
+            24 => style.remove_modifier(Modifier::UNDERLINED),
+            25 => style.remove_modifier(Modifier::SLOW_BLINK),
+            27 => style.remove_modifier(Modifier::REVERSED),
+            30..=37 => style.fg(ansi_color(p - 30)),
+            38 => match extended_color(&mut params) {
+                Some(color) => style.fg(color),
+                None => style,
+            },
+            39 => style.fg(Color::Reset),
+            40..=47 => style.bg(ansi_color(p - 40)),
+            48 => match extended_color(&mut params) {
+                Some(color) => style.bg(color),
+                None => style,
+            },
+            49 => style.bg(Color::Reset),
+            90..=97 => style.fg(ansi_color(p - 90 + 8)),
+            100..=107 => style.bg(ansi_color(p - 100 + 8)),
+            _ => style,
+        };
+    }
+    style
+}
+
+/// Parses the arguments of the 256-color (`5;n`) and truecolor (`2;r;g;b`) SGR forms
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::Indexed(params.next()? as u8)),
+        2 => Some(Color::Rgb(
+            params.next()? as u8,
+            params.next()? as u8,
+            params.next()? as u8,
+        )),
+        _ => None,
+    }
+}
+
+fn ansi_color(index: u16) -> Color {
+    match index {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        7 => Color::Gray,
+        8 => Color::DarkGray,
+        9 => Color::LightRed,
+        10 => Color::LightGreen,
+        11 => Color::LightYellow,
+        12 => Color::LightBlue,
+        13 => Color::LightMagenta,
+        14 => Color::LightCyan,
+        _ => Color::White,
+    }
+}
@@ -0,0 +1,358 @@
+//! HTTP API controlling a running bridge with `--api`, beside the data of its clients
+//!
+//! | Request            | Does                                                 |
+//! |--------------------|------------------------------------------------------|
+//! | `GET /status`      | The device, its connection and the running log       |
+//! | `GET /stats`       | Bytes and notifications received and sent            |
+//! | `POST /send`       | Writes the request body to the device                |
+//! | `POST /log/start`  | Appends received data to the file named by the body  |
+//! | `POST /log/stop`   | Stops the log                                        |
+//! | `POST /reconnect`  | Drops the link and connects to the device again      |
+//!
+//! Every request has to carry the token as `Authorization: Bearer <token>`. Requests naming
+//! another host than an IP address or `localhost`, or coming from a web page not served by
+//! this machine, are refused, so a browser cannot be tricked into sending them.
+
+use crate::bridge::Hub;
+use crate::http::{self, Request};
+use crate::json;
+use anyhow::{Context, Result};
+use log::warn;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Who may use the API and where it may write
+#[derive(Debug)]
+pub struct Access {
+    pub token: String,
+    /// Directory of the logs started with `POST /log/start`, which is refused without one
+    pub log_dir: Option<PathBuf>,
+}
+
+/// A random token for `--api` when none is given
+pub fn random_token() -> Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::fill(&mut bytes).context("Could not generate an API token")?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Answers requests on `listener` until the bridge exits
+pub async fn serve(listener: TcpListener, hub: Arc<Hub>, access: Arc<Access>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Could not accept an API connection: {e}");
+                continue;
+            }
+        };
+        let hub = hub.clone();
+        let access = access.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &hub, &access).await {
+                warn!("API request of {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, hub: &Hub, access: &Access) -> Result<()> {
+    let Some(request) = http::read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let (status, body) = handle(request, hub, access).await;
+    let content_type = if body.is_empty() {
+        "text/plain"
+    } else {
+        "application/json"
+    };
+    http::respond(&mut stream, status, content_type, &body).await
+}
+
+async fn handle(request: Request, hub: &Hub, access: &Access) -> (&'static str, String) {
+    if let Err(refused) = check(&request, access) {
+        return refused;
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => ("200 OK", status(hub)),
+        ("GET", "/stats") => ("200 OK", hub.metrics().stats_json()),
+        ("POST", "/send") => {
+            if !request.body.is_empty() {
+                hub.send(request.body).await;
+            }
+            ("204 No Content", String::new())
+        }
+        ("POST", "/log/start") => {
+            let Some(dir) = &access.log_dir else {
+                return error("403 Forbidden", "Logging needs --api-log-dir");
+            };
+            let name = String::from_utf8_lossy(&request.body);
+            let name = Path::new(name.trim());
+            // Only a file of the directory, a request cannot write anywhere else
+            if !matches!(
+                name.components().collect::<Vec<_>>()[..],
+                [Component::Normal(_)]
+            ) {
+                return error(
+                    "400 Bad Request",
+                    "The body must name a file in --api-log-dir",
+                );
+            }
+            match hub.start_log(&dir.join(name)) {
+                Ok(()) => ("204 No Content", String::new()),
+                Err(e) => error("500 Internal Server Error", &format!("{e:#}")),
+            }
+        }
+        ("POST", "/log/stop") => {
+            if hub.stop_log() {
+                ("204 No Content", String::new())
+            } else {
+                error("409 Conflict", "Not logging")
+            }
+        }
+        ("POST", "/reconnect") => {
+            hub.request_reconnect();
+            ("202 Accepted", String::new())
+        }
+        (_, "/status" | "/stats" | "/send" | "/log/start" | "/log/stop" | "/reconnect") => {
+            error("405 Method Not Allowed", "Method not allowed")
+        }
+        _ => error("404 Not Found", "Not found"),
+    }
+}
+
+/// Refuses requests without the token and those a web page may have made
+fn check(request: &Request, access: &Access) -> Result<(), (&'static str, String)> {
+    // Against DNS rebinding, where a page's own host name resolves to this machine
+    if !request
+        .header("Host")
+        .is_some_and(|host| is_ip_or_local(authority_host(host)))
+    {
+        return Err(error("403 Forbidden", "Host not allowed"));
+    }
+    if let Some(origin) = request.header("Origin") {
+        let host = origin
+            .strip_prefix("http://")
+            .or(origin.strip_prefix("https://"))
+            .map(authority_host);
+        if !host.is_some_and(is_local) {
+            return Err(error("403 Forbidden", "Origin not allowed"));
+        }
+    }
+    let token = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| same(token.trim().as_bytes(), access.token.as_bytes())) {
+        return Err(error("401 Unauthorized", "Missing or wrong token"));
+    }
+    Ok(())
+}
+
+/// The host of `host:port`, with IPv6 addresses in brackets
+fn authority_host(authority: &str) -> &str {
+    match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    }
+}
+
+fn is_local(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn is_ip_or_local(host: &str) -> bool {
+    is_local(host) || host.parse::<IpAddr>().is_ok()
+}
+
+/// Compares in a time independent of where the tokens differ
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn status(hub: &Hub) -> String {
+    let metrics = hub.metrics();
+    let (address, name, rssi) = metrics.device();
+    let log = hub.log_path();
+    format!(
+        "{{\"connected\":{},\"address\":{},\"name\":{},\"rssi\":{},\"log\":{}}}",
+        metrics.connected.load(Ordering::Relaxed),
+        json::string(&address),
+        json::opt_string(name.as_deref()),
+        json::opt_number(rssi),
+        json::opt_string(log.as_ref().and_then(|path| path.to_str()))
+    )
+}
+
+fn error(status: &'static str, message: &str) -> (&'static str, String) {
+    (status, format!("{{\"error\":{}}}", json::string(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn access(log_dir: Option<PathBuf>) -> Access {
+        Access {
+            token: TOKEN.to_string(),
+            log_dir,
+        }
+    }
+
+    /// A request as curl on this machine sends it
+    fn request(method: &str, path: &str, body: &[u8]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: vec![
+                ("Host".to_string(), "127.0.0.1:8080".to_string()),
+                ("Authorization".to_string(), format!("Bearer {TOKEN}")),
+            ],
+            body: body.to_vec(),
+        }
+    }
+
+    fn with_header(mut request: Request, name: &str, value: &str) -> Request {
+        request
+            .headers
+            .retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        request.headers.push((name.to_string(), value.to_string()));
+        request
+    }
+
+    async fn status_of(request: Request, access: &Access) -> &'static str {
+        handle(request, &Hub::new(false, false), access).await.0
+    }
+
+    #[tokio::test]
+    async fn routes_requests() {
+        let access = access(None);
+        let hub = Hub::new(false, false);
+        let (status, body) = handle(request("GET", "/status", b""), &hub, &access).await;
+        assert_eq!(status, "200 OK");
+        assert!(body.starts_with("{\"connected\":false,"), "{body}");
+        assert!(body.ends_with(",\"log\":null}"), "{body}");
+        let (status, _) = handle(request("GET", "/stats", b""), &hub, &access).await;
+        assert_eq!(status, "200 OK");
+        let (status, body) = handle(request("POST", "/send", b"reboot\r"), &hub, &access).await;
+        assert_eq!((status, body.as_str()), ("204 No Content", ""));
+        let (status, _) = handle(request("POST", "/reconnect", b""), &hub, &access).await;
+        assert_eq!(status, "202 Accepted");
+        let (status, body) = handle(request("POST", "/log/stop", b""), &hub, &access).await;
+        assert_eq!(
+            (status, body.as_str()),
+            ("409 Conflict", r#"{"error":"Not logging"}"#)
+        );
+        let (status, _) = handle(request("GET", "/send", b""), &hub, &access).await;
+        assert_eq!(status, "405 Method Not Allowed");
+        let (status, _) = handle(request("GET", "/", b""), &hub, &access).await;
+        assert_eq!(status, "404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn requires_the_token() {
+        let access = access(None);
+        let mut missing = request("POST", "/send", b"x");
+        missing.headers.retain(|(name, _)| name != "Authorization");
+        assert_eq!(status_of(missing, &access).await, "401 Unauthorized");
+        for value in [
+            "Bearer wrong",
+            "Bearer 0123456789abcde",
+            TOKEN,
+            "Basic 0123456789abcdef",
+        ] {
+            let request = with_header(request("POST", "/send", b"x"), "Authorization", value);
+            assert_eq!(
+                status_of(request, &access).await,
+                "401 Unauthorized",
+                "{value}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_hosts_and_origins() {
+        let access = access(None);
+        for host in [
+            "localhost:8080",
+            "[::1]:8080",
+            "192.168.1.20:8080",
+            "127.0.0.1",
+        ] {
+            let request = with_header(request("GET", "/status", b""), "Host", host);
+            assert_eq!(status_of(request, &access).await, "200 OK", "{host}");
+        }
+        for host in ["evil.example:8080", "localhost.evil.example", ""] {
+            let request = with_header(request("GET", "/status", b""), "Host", host);
+            assert_eq!(status_of(request, &access).await, "403 Forbidden", "{host}");
+        }
+        let mut request_without_host = request("GET", "/status", b"");
+        request_without_host
+            .headers
+            .retain(|(name, _)| name != "Host");
+        assert_eq!(
+            status_of(request_without_host, &access).await,
+            "403 Forbidden"
+        );
+
+        for origin in [
+            "http://localhost:3000",
+            "https://127.0.0.1",
+            "http://[::1]:8080",
+        ] {
+            let request = with_header(request("POST", "/send", b"x"), "Origin", origin);
+            assert_eq!(
+                status_of(request, &access).await,
+                "204 No Content",
+                "{origin}"
+            );
+        }
+        for origin in ["https://evil.example", "http://192.168.1.20:8080", "null"] {
+            let request = with_header(request("POST", "/send", b"x"), "Origin", origin);
+            assert_eq!(
+                status_of(request, &access).await,
+                "403 Forbidden",
+                "{origin}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_only_into_the_log_directory() {
+        let start = |name: &str| request("POST", "/log/start", name.as_bytes());
+        assert_eq!(
+            status_of(start("a.log"), &access(None)).await,
+            "403 Forbidden"
+        );
+
+        let dir = std::env::temp_dir().join(format!("nus-api-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let access = access(Some(dir.clone()));
+        for name in ["", "../a.log", "/tmp/a.log", "sub/a.log", ".."] {
+            assert_eq!(
+                status_of(start(name), &access).await,
+                "400 Bad Request",
+                "{name}"
+            );
+        }
+        let hub = Hub::new(false, false);
+        let (status, _) = handle(start("a.log\n"), &hub, &access).await;
+        assert_eq!(status, "204 No Content");
+        assert_eq!(hub.log_path(), Some(dir.join("a.log")));
+        assert!(dir.join("a.log").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn random_tokens_differ() {
+        let token = random_token().unwrap();
+        assert_eq!(token.len(), 32);
+        assert!(token.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(token, random_token().unwrap());
+    }
+}
@@ -0,0 +1,65 @@
+//! The standard Battery Service, reporting the charge of the peripheral's battery
+
+use anyhow::{Result, anyhow};
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{CharPropFlags, Characteristic, Peripheral as _};
+use btleplug::platform::Peripheral;
+use futures::stream::{self, Stream, StreamExt};
+use std::pin::Pin;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const BATTERY_SERVICE_UUID: Uuid = uuid_from_u16(0x180f);
+pub const BATTERY_LEVEL_UUID: Uuid = uuid_from_u16(0x2a19);
+
+/// How often the level is read from devices that do not notify its changes
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Battery levels in percent, as they change
+pub type Levels = Pin<Box<dyn Stream<Item = u8> + Send>>;
+
+/// Reads the battery level and follows its changes, `None` without a Battery Service
+///
+/// Has to be called after service discovery.
+pub async fn watch(peripheral: &Peripheral) -> Result<Option<(u8, Levels)>> {
+    let Some(level_char) = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.service_uuid == BATTERY_SERVICE_UUID && c.uuid == BATTERY_LEVEL_UUID)
+    else {
+        return Ok(None);
+    };
+    let level = read(peripheral, &level_char).await?;
+
+    let levels: Levels = if level_char.properties.contains(CharPropFlags::NOTIFY) {
+        let notifications = peripheral.notifications().await?;
+        peripheral.subscribe(&level_char).await?;
+        Box::pin(notifications.filter_map(|notification| async move {
+            match notification.uuid {
+                BATTERY_LEVEL_UUID => notification.value.first().map(|&level| level.min(100)),
+                _ => None,
+            }
+        }))
+    } else {
+        let peripheral = peripheral.clone();
+        Box::pin(stream::unfold((), move |()| {
+            let (peripheral, level_char) = (peripheral.clone(), level_char.clone());
+            async move {
+                loop {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    // A failed read is retried, the link supervisor notices a lost device
+                    if let Ok(level) = read(&peripheral, &level_char).await {
+                        return Some((level, ()));
+                    }
+                }
+            }
+        }))
+    };
+    Ok(Some((level, levels)))
+}
+
+async fn read(peripheral: &Peripheral, level_char: &Characteristic) -> Result<u8> {
+    let value = peripheral.read(level_char).await?;
+    let level = value.first().ok_or(anyhow!("Empty battery level"))?;
+    Ok((*level).min(100))
+}
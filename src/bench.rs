@@ -0,0 +1,316 @@
+//! Measuring the performance of the link
+
+use crate::cli::{BenchArgs, BenchCommand, PingArgs, ThroughputArgs};
+use crate::device;
+use crate::error::Error;
+use crate::link::{Link, LinkOptions, Notifications};
+use anyhow::{Result, bail};
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Adapter;
+use futures::stream::StreamExt;
+use log::{debug, info, warn};
+use regex::bytes::Regex;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Failed writes in a row after which sending is given up
+const MAX_FAILED_WRITES: usize = 10;
+
+/// Device output kept while looking for the answers to probes
+const MAX_PING_BUFFER: usize = 4096;
+
+/// Which way the throughput test moves data, seen from this side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Direction {
+    /// Write the test pattern to the device
+    Send,
+    /// Count what the device sends
+    Receive,
+    /// Send and receive at the same time, e.g. through a firmware echoing its input
+    Both,
+}
+
+pub async fn run(central: &Adapter, args: &BenchArgs) -> Result<()> {
+    match &args.command {
+        BenchCommand::Throughput(args) => throughput(central, args).await,
+        BenchCommand::Ping(args) => ping(central, args).await,
+    }
+}
+
+/// Byte `i` of the test data, cycling through the printable ASCII characters
+fn pattern(i: usize) -> u8 {
+    b'!' + (i % 94) as u8
+}
+
+/// What one direction of a test got across
+#[derive(Debug, Default)]
+pub struct Counts {
+    pub bytes: usize,
+    pub packets: usize,
+    /// From the start of the test to the last packet
+    pub elapsed: Duration,
+}
+
+impl Counts {
+    /// One line of the summary, like `65536 bytes in 2.41 s, 217.5 kbit/s, ...`
+    pub fn summary(&self, conn_interval: Option<f64>) -> String {
+        let secs = self.elapsed.as_secs_f64();
+        if self.packets == 0 || secs == 0.0 {
+            return format!("{} bytes", self.bytes);
+        }
+        let packets_per_sec = self.packets as f64 / secs;
+        let mut summary = format!(
+            "{} bytes in {secs:.2} s, {:.1} kbit/s, {} packets, {packets_per_sec:.1} packets/s",
+            self.bytes,
+            self.bytes as f64 * 8.0 / secs / 1000.0,
+            self.packets,
+        );
+        if let Some(interval) = conn_interval {
+            let per_interval = packets_per_sec * interval / 1000.0;
+            summary.push_str(&format!(" ({per_interval:.2} per {interval} ms interval)"));
+        }
+        summary
+    }
+}
+
+async fn throughput(central: &Adapter, args: &ThroughputArgs) -> Result<()> {
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, mut notifications) =
+        Link::open(device.peripheral.clone(), &LinkOptions::from(&args.device)).await?;
+    let payload = match args.payload.map(usize::from) {
+        Some(payload) if payload > link.max_payload() => bail!(
+            "At most {} bytes fit into one write with an MTU of {}",
+            link.max_payload(),
+            link.mtu
+        ),
+        Some(payload) => payload,
+        None => link.max_payload(),
+    };
+    info!(
+        "Connected to {}, MTU {}, writing {payload} bytes at a time ({:?})",
+        device.display_name(),
+        link.mtu,
+        link.write_type
+    );
+
+    let started = Instant::now();
+    if let Some(line) = &args.start {
+        let mut line = line.as_bytes().to_vec();
+        line.extend_from_slice(args.send_newline.bytes());
+        link.write(&line).await?;
+    }
+    let sending = args.direction != Direction::Receive;
+    let receiving = args.direction != Direction::Send;
+    let idle = Duration::from_millis(args.timeout);
+    let ((sent, failed), (received, corrupted)) = tokio::join!(
+        async {
+            if !sending {
+                return Default::default();
+            }
+            send(&link, args.bytes, payload, started).await
+        },
+        async {
+            if !receiving {
+                return Default::default();
+            }
+            receive(&mut notifications, args.bytes, idle, started, args.verify).await
+        },
+    );
+    let _ = link.peripheral.disconnect().await;
+
+    if sending {
+        println!("Sent:     {}", sent.summary(args.conn_interval));
+    }
+    if receiving {
+        println!("Received: {}", received.summary(args.conn_interval));
+    }
+    let mut errors = vec![format!("{failed} failed writes")];
+    if receiving {
+        let missing = args.bytes.saturating_sub(received.bytes);
+        errors.push(format!("{missing} missing bytes"));
+    }
+    if args.verify {
+        errors.push(format!("{corrupted} corrupted bytes"));
+    }
+    println!("Errors:   {}", errors.join(", "));
+    Ok(())
+}
+
+/// Writes `total` bytes of the test pattern, returns what got across and the failed writes
+async fn send(link: &Link, total: usize, payload: usize, started: Instant) -> (Counts, usize) {
+    let data: Vec<u8> = (0..total).map(pattern).collect();
+    let mut counts = Counts::default();
+    let mut failed = 0;
+    let mut failed_in_row = 0;
+    for chunk in data.chunks(payload) {
+        match link.write(chunk).await {
+            Ok(()) => {
+                counts.bytes += chunk.len();
+                counts.packets += 1;
+                failed_in_row = 0;
+            }
+            Err(e) => {
+                debug!("Write failed: {e}");
+                failed += 1;
+                failed_in_row += 1;
+                if failed_in_row == MAX_FAILED_WRITES {
+                    warn!("Giving up after {MAX_FAILED_WRITES} failed writes in a row");
+                    break;
+                }
+            }
+        }
+    }
+    counts.elapsed = started.elapsed();
+    (counts, failed)
+}
+
+/// Counts the data from the device until `expected` bytes arrived or it went quiet for
+/// `idle`, returns it with the number of bytes differing from the test pattern if `verify`
+async fn receive(
+    notifications: &mut Notifications,
+    expected: usize,
+    idle: Duration,
+    started: Instant,
+    verify: bool,
+) -> (Counts, usize) {
+    let mut counts = Counts::default();
+    let mut corrupted = 0;
+    while counts.bytes < expected {
+        let data = match tokio::time::timeout(idle, notifications.next()).await {
+            Ok(Some(notification)) => notification.value,
+            Ok(None) => {
+                warn!("Connection lost");
+                break;
+            }
+            Err(_) => break,
+        };
+        if verify {
+            corrupted += data
+                .iter()
+                .enumerate()
+                .filter(|&(i, &b)| b != pattern(counts.bytes + i))
+                .count();
+        }
+        counts.bytes += data.len();
+        counts.packets += 1;
+        counts.elapsed = started.elapsed();
+    }
+    (counts, corrupted)
+}
+
+/// A probe like `@3.1520374;`, its number and the microseconds since the start when it
+/// was sent
+fn probe(seq: u32, micros: u128, size: Option<usize>) -> String {
+    let mut probe = format!("@{seq}.{micros}");
+    // Padding goes before the end marker, so only complete answers are found
+    let padding = size.unwrap_or(0).saturating_sub(probe.len() + 1);
+    probe.push_str(&"-".repeat(padding));
+    probe.push(';');
+    probe
+}
+
+fn parse<T: FromStr>(bytes: &[u8]) -> Option<T> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+async fn ping(central: &Adapter, args: &PingArgs) -> Result<()> {
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, mut notifications) =
+        Link::open(device.peripheral.clone(), &LinkOptions::from(&args.device)).await?;
+    info!("Connected to {}", device.display_name());
+
+    let answer = Regex::new(r"@(\d+)\.(\d+)-*;").unwrap();
+    let started = Instant::now();
+    let timeout = Duration::from_millis(args.timeout);
+    let mut ticker = tokio::time::interval(Duration::from_millis(args.interval.max(1)));
+    let mut sent = 0;
+    // Send times of the probes waiting for an answer, by number
+    let mut pending: BTreeMap<u32, Instant> = BTreeMap::new();
+    let mut rtts = Vec::new();
+    let mut buffer = Vec::new();
+    let result = loop {
+        let now = Instant::now();
+        pending.retain(|seq, sent| {
+            let waiting = now.duration_since(*sent) < timeout;
+            if !waiting {
+                println!("seq={seq} timed out");
+            }
+            waiting
+        });
+        if sent == args.count && pending.is_empty() {
+            break Ok(());
+        }
+        let deadline = pending.values().min().map(|sent| *sent + timeout);
+
+        tokio::select! {
+            _ = ticker.tick(), if sent < args.count => {
+                let probe = probe(sent, started.elapsed().as_micros(), args.size);
+                let mut line = args.probe.replace("{probe}", &probe).into_bytes();
+                if !args.loopback {
+                    line.extend_from_slice(args.send_newline.bytes());
+                }
+                pending.insert(sent, Instant::now());
+                sent += 1;
+                if let Err(e) = link.write(&line).await {
+                    break Err(e);
+                }
+            }
+            notification = notifications.next() => {
+                let Some(notification) = notification else {
+                    break Err(Error::ConnectionLost.into());
+                };
+                buffer.extend_from_slice(&notification.value);
+                let mut end = 0;
+                for captures in answer.captures_iter(&buffer) {
+                    end = captures.get(0).unwrap().end();
+                    let (Some(seq), Some(micros)) = (parse(&captures[1]), parse(&captures[2]))
+                    else {
+                        continue;
+                    };
+                    // Only the first copy counts, a shell echoing its input sends the line back too
+                    if pending.remove(&seq).is_none() {
+                        continue;
+                    }
+                    // The time in the probe, so answers out of order are timed right
+                    let rtt = started.elapsed().saturating_sub(Duration::from_micros(micros));
+                    println!("seq={seq} time={:.2} ms", millis(rtt));
+                    rtts.push(rtt);
+                }
+                buffer.drain(..end);
+                if buffer.len() > MAX_PING_BUFFER {
+                    buffer.drain(..buffer.len() - MAX_PING_BUFFER);
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or(now).into()), if deadline.is_some() => {}
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        }
+    };
+    let _ = link.peripheral.disconnect().await;
+    result?;
+
+    let lost = sent as usize - rtts.len();
+    println!("--- {} ping statistics ---", device.display_name());
+    println!(
+        "{sent} probes sent, {} answered, {lost} lost ({:.1}%)",
+        rtts.len(),
+        lost as f64 * 100.0 / f64::from(sent.max(1))
+    );
+    if !rtts.is_empty() {
+        rtts.sort();
+        let average = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        let p99 = rtts[(rtts.len() * 99).div_ceil(100) - 1];
+        println!(
+            "rtt min/avg/max/p99 = {:.2}/{:.2}/{:.2}/{:.2} ms",
+            millis(rtts[0]),
+            millis(average),
+            millis(rtts[rtts.len() - 1]),
+            millis(p99)
+        );
+    }
+    Ok(())
+}
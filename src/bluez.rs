@@ -0,0 +1,15 @@
+//! Direct access to BlueZ for what btleplug does not expose
+
+use bluez_async::BluetoothSession;
+use tokio::sync::OnceCell;
+
+/// Returns the shared D-Bus session, `None` if BlueZ is unreachable
+///
+/// The connection task lives for the rest of the process, so only one is created.
+pub async fn session() -> Option<&'static BluetoothSession> {
+    static SESSION: OnceCell<BluetoothSession> = OnceCell::const_new();
+    SESSION
+        .get_or_try_init(|| async { BluetoothSession::new().await.map(|(_, session)| session) })
+        .await
+        .ok()
+}
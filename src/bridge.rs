@@ -0,0 +1,950 @@
+//! Headless modes exposing the UART link to other programs
+
+use crate::api;
+use crate::cli::{
+    BridgeArgs, BridgeKind, DeviceArgs, MqttBridgeArgs, PtyBridgeArgs, TcpBridgeArgs,
+    TunBridgeArgs, UnixBridgeArgs, WsBridgeArgs,
+};
+use crate::connect;
+use crate::decode;
+use crate::device::{self, DeviceInfo};
+use crate::error::Error;
+use crate::http;
+use crate::json;
+use crate::link::{Link, LinkOptions};
+use crate::metrics::{self, Metrics};
+use crate::mqtt;
+use crate::pty;
+use crate::session_log::SessionLog;
+use crate::slip;
+use crate::systemd;
+use crate::transport::ByteStream;
+use crate::tun;
+use crate::websocket;
+use anyhow::{Context, Result, bail};
+use btleplug::api::Peripheral as _;
+use btleplug::platform::{Adapter, Peripheral};
+use futures::stream::StreamExt;
+use log::{debug, info, warn};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, broadcast, mpsc};
+
+/// Number of notifications buffered for a client before it misses data
+const RECEIVE_QUEUE_LEN: usize = 256;
+
+/// Number of pending client writes before reading from clients stalls
+const WRITE_QUEUE_LEN: usize = 64;
+
+/// Idle time after which a partial line is published to the MQTT broker, e.g. a prompt
+const MQTT_LINE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Longest line published as a whole, longer lines are split
+const MQTT_MAX_LINE_LEN: usize = 4096;
+
+/// Largest IP packet read from a TUN interface
+const TUN_MAX_PACKET_LEN: usize = 65535;
+
+/// How often the signal strength is read for `--metrics` and `--api`
+const RSSI_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pause before the first attempt to reconnect with `--daemon`, doubled after each failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest pause between attempts to reconnect
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+const TELNET_IAC: u8 = 255;
+const TELNET_WILL: u8 = 251;
+const TELNET_WONT: u8 = 252;
+const TELNET_DO: u8 = 253;
+const TELNET_DONT: u8 = 254;
+const TELNET_SB: u8 = 250;
+const TELNET_SE: u8 = 240;
+const TELNET_ECHO: u8 = 1;
+const TELNET_SUPPRESS_GO_AHEAD: u8 = 3;
+
+pub async fn run(central: &Adapter, args: &BridgeArgs) -> Result<()> {
+    // Bound before connecting, a busy port should not cost a connection attempt
+    let metrics = match args.metrics {
+        Some(addr) => Some(http::bind(addr, "--metrics").await?),
+        None => None,
+    };
+    let api = match args.api {
+        Some(addr) => Some(http::bind(addr, "--api").await?),
+        None => None,
+    };
+    if args.daemon {
+        systemd::log_to_journal();
+    }
+    let hub = Arc::new(Hub::new(args.daemon, metrics.is_some() || api.is_some()));
+    if let Some(listener) = metrics {
+        info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        tokio::spawn(metrics::serve(listener, hub.metrics.clone()));
+    }
+    if let Some(listener) = api {
+        let token = match &args.api_token {
+            Some(token) => token.clone(),
+            None => {
+                let token = api::random_token()?;
+                info!("Control API token: {token}");
+                token
+            }
+        };
+        let access = api::Access {
+            token,
+            log_dir: args.api_log_dir.clone(),
+        };
+        info!(
+            "Serving the control API on http://{}",
+            listener.local_addr()?
+        );
+        tokio::spawn(api::serve(listener, hub.clone(), Arc::new(access)));
+    }
+    match &args.kind {
+        BridgeKind::Tcp(args) => tcp(central, args, hub).await,
+        BridgeKind::Pty(args) => pty(central, args, hub).await,
+        BridgeKind::Unix(args) => unix(central, args, hub).await,
+        BridgeKind::Mqtt(args) => mqtt(central, args, hub).await,
+        BridgeKind::Ws(args) => ws(central, args, hub).await,
+        BridgeKind::Tun(args) => tun(central, args, hub).await,
+    }
+}
+
+/// Resolves on Ctrl+C, or when a service manager stops the bridge with SIGTERM
+async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Shutting down");
+    systemd::notify("STOPPING=1");
+}
+
+/// Connects to the device selected by `args`
+async fn open(central: &Adapter, args: &DeviceArgs) -> Result<(Link, ByteStream)> {
+    let device = device::select(central, &args.search()).await?;
+    connect::open_link(central, device.peripheral, &LinkOptions::from(args)).await
+}
+
+/// Fans data received from the device out to all clients and serializes their writes
+pub struct Hub {
+    received: broadcast::Sender<Vec<u8>>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    /// Writes of the clients, passed to whichever link is open
+    queued: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+    /// The device of the current link, disconnected when the bridge exits
+    peripheral: std::sync::Mutex<Option<Peripheral>>,
+    metrics: Arc<Metrics>,
+    /// Whether the signal strength is wanted, for `--metrics` or `--api`
+    poll_rssi: bool,
+    /// Log of received data started through the API
+    log: std::sync::Mutex<Option<SessionLog>>,
+    /// Signalled by the API to drop the link and connect again
+    reconnect_requested: Notify,
+    /// Whether to reconnect forever after losing the device
+    daemon: bool,
+}
+
+/// Why a link ended
+enum LinkEnd {
+    Lost,
+    Requested,
+}
+
+impl Hub {
+    pub fn new(daemon: bool, poll_rssi: bool) -> Hub {
+        let (received, _) = broadcast::channel(RECEIVE_QUEUE_LEN);
+        let (write_queue, queued) = mpsc::channel(WRITE_QUEUE_LEN);
+        Hub {
+            received,
+            write_queue,
+            queued: tokio::sync::Mutex::new(queued),
+            peripheral: std::sync::Mutex::new(None),
+            metrics: Arc::default(),
+            poll_rssi,
+            log: std::sync::Mutex::new(None),
+            reconnect_requested: Notify::new(),
+            daemon,
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Queues `data` to be written to the device, like the data of a client
+    pub async fn send(&self, data: Vec<u8>) {
+        let _ = self.write_queue.send(data).await;
+    }
+
+    /// Appends received data to the file at `path` from now on, replacing a running log
+    pub fn start_log(&self, path: &Path) -> Result<()> {
+        let log = SessionLog::open(path, None)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        info!("Logging received data to {}", path.display());
+        *self.log.lock().unwrap() = Some(log);
+        Ok(())
+    }
+
+    /// Stops the log, returns whether one was running
+    pub fn stop_log(&self) -> bool {
+        let Some(log) = self.log.lock().unwrap().take() else {
+            return false;
+        };
+        info!("Stopped logging to {}", log.path().display());
+        true
+    }
+
+    /// The file received data is logged to, if any
+    pub fn log_path(&self) -> Option<PathBuf> {
+        let log = self.log.lock().unwrap();
+        log.as_ref().map(|log| log.path().to_path_buf())
+    }
+
+    /// Drops the link and connects to the device again, also without `--daemon`
+    pub fn request_reconnect(&self) {
+        self.reconnect_requested.notify_one();
+    }
+
+    /// Passes data between the clients and `link` until the device disconnects
+    ///
+    /// With `--daemon` the device is connected again, as often as it takes.
+    async fn run(
+        &self,
+        central: &Adapter,
+        device: &DeviceArgs,
+        mut link: Link,
+        mut notifications: ByteStream,
+    ) -> Result<()> {
+        systemd::notify(&format!(
+            "READY=1\nSTATUS=Connected to {}",
+            link.peripheral.address()
+        ));
+        loop {
+            let end = self.forward(&link, &mut notifications).await;
+            self.metrics.connected.store(false, Ordering::Relaxed);
+            let reason = match end {
+                LinkEnd::Requested => {
+                    let _ = link.peripheral.disconnect().await;
+                    "Reconnect requested"
+                }
+                LinkEnd::Lost if self.daemon => "Connection lost",
+                LinkEnd::Lost => return Err(Error::ConnectionLost.into()),
+            };
+            (link, notifications) = self.reconnect(central, device, reason).await;
+            self.metrics.reconnects.fetch_add(1, Ordering::Relaxed);
+            systemd::notify(&format!(
+                "STATUS=Connected to {}",
+                link.peripheral.address()
+            ));
+        }
+    }
+
+    /// Opens the link again with exponential backoff, until it works
+    async fn reconnect(
+        &self,
+        central: &Adapter,
+        device: &DeviceArgs,
+        reason: &str,
+    ) -> (Link, ByteStream) {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            warn!("{reason}, reconnecting in {}s", delay.as_secs());
+            systemd::notify("STATUS=Reconnecting");
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            match open(central, device).await {
+                Ok(opened) => {
+                    info!("Reconnected to {}", opened.0.peripheral.address());
+                    return opened;
+                }
+                Err(e) => warn!("Reconnect failed: {e:#}"),
+            }
+        }
+    }
+
+    /// Passes data between the clients and `link` until the device disconnects
+    async fn forward(&self, link: &Link, notifications: &mut ByteStream) -> LinkEnd {
+        *self.peripheral.lock().unwrap() = Some(link.peripheral.clone());
+        self.metrics
+            .set_device(link.peripheral.address().to_string());
+        let mut queued = self.queued.lock().await;
+        let mut rssi_poll = tokio::time::interval(RSSI_POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                notification = notifications.next() => {
+                    let Some(notification) = notification else {
+                        return LinkEnd::Lost;
+                    };
+                    self.receive(&notification);
+                    // Sending only fails while no client is connected
+                    let _ = self.received.send(notification);
+                }
+                Some(data) = queued.recv() => self.write(link, &data).await,
+                _ = rssi_poll.tick(), if self.poll_rssi => {
+                    if let Ok(Some(props)) = link.peripheral.properties().await {
+                        self.metrics.set_properties(props.local_name, props.rssi);
+                    }
+                }
+                _ = self.reconnect_requested.notified() => return LinkEnd::Requested,
+            }
+        }
+    }
+
+    /// Counts and logs data received from the device
+    fn receive(&self, data: &[u8]) {
+        let metrics = &self.metrics;
+        metrics.rx_notifications.fetch_add(1, Ordering::Relaxed);
+        metrics
+            .rx_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        let mut log = self.log.lock().unwrap();
+        if let Some(session_log) = log.as_mut()
+            && let Err(e) = session_log.write(data)
+        {
+            warn!(
+                "Could not write to {}, logging stopped: {e}",
+                session_log.path().display()
+            );
+            *log = None;
+        }
+    }
+
+    async fn write(&self, link: &Link, data: &[u8]) {
+        let metrics = &self.metrics;
+        match link.write(data).await {
+            Ok(()) => {
+                metrics.tx_writes.fetch_add(1, Ordering::Relaxed);
+                metrics
+                    .tx_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                metrics.write_errors.fetch_add(1, Ordering::Relaxed);
+                warn!("Write failed: {e}");
+            }
+        }
+    }
+
+    /// Disconnects the device of the current link, when the bridge exits
+    async fn disconnect(&self) {
+        let peripheral = self.peripheral.lock().unwrap().take();
+        if let Some(peripheral) = peripheral {
+            let _ = peripheral.disconnect().await;
+        }
+    }
+}
+
+async fn tcp(central: &Adapter, args: &TcpBridgeArgs, hub: Arc<Hub>) -> Result<()> {
+    // Bind first, a busy port should not cost a connection attempt
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Could not listen on {}", args.listen))?;
+    let (link, notifications) = open(central, &args.device).await?;
+    info!("Listening on {}", listener.local_addr()?);
+
+    let accept = async {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            info!("Client {peer} connected");
+            let client = StreamClient {
+                peer: peer.to_string(),
+                telnet: args.telnet,
+                received: hub.received.subscribe(),
+                write_queue: hub.write_queue.clone(),
+            };
+            tokio::spawn(client.run(stream));
+        }
+    };
+
+    let result = tokio::select! {
+        result = hub.run(central, &args.device, link, notifications) => result,
+        result = accept => result,
+        _ = shutdown() => Ok(()),
+    };
+    hub.disconnect().await;
+    result
+}
+
+async fn pty(central: &Adapter, args: &PtyBridgeArgs, hub: Arc<Hub>) -> Result<()> {
+    let mut pty = pty::open()?;
+    if let Some(link) = &args.link {
+        pty.link(link)?;
+    }
+    let (link, notifications) = open(central, &args.device).await?;
+    match &args.link {
+        Some(link) => info!(
+            "Device available at {} ({})",
+            link.display(),
+            pty.path.display()
+        ),
+        None => info!("Device available at {}", pty.path.display()),
+    }
+
+    // The pty is a plain blocking file, so it gets threads of its own
+    let mut master = pty.master.try_clone()?;
+    let write_queue = hub.write_queue.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok(n @ 1..) = master.read(&mut buf) {
+            if write_queue.blocking_send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut master = pty.master.try_clone()?;
+    let mut received = hub.received.subscribe();
+    std::thread::spawn(move || {
+        loop {
+            match received.blocking_recv() {
+                Ok(data) => {
+                    if master.write_all(&data).is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Terminal not read, dropped {missed} notifications");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let result = tokio::select! {
+        result = hub.run(central, &args.device, link, notifications) => result,
+        _ = shutdown() => Ok(()),
+    };
+    hub.disconnect().await;
+    result
+}
+
+async fn tun(central: &Adapter, args: &TunBridgeArgs, hub: Arc<Hub>) -> Result<()> {
+    let tun = tun::open(&args.interface)?;
+    let (link, notifications) = open(central, &args.device).await?;
+    info!("Forwarding IP packets as SLIP frames through {}", tun.name);
+
+    // The interface is a plain blocking file, so it gets threads of its own
+    let mut interface = tun.file.try_clone()?;
+    let write_queue = hub.write_queue.clone();
+    std::thread::spawn(move || {
+        let mut packet = vec![0; TUN_MAX_PACKET_LEN];
+        while let Ok(n @ 1..) = interface.read(&mut packet) {
+            if write_queue
+                .blocking_send(slip::encode(&packet[..n]))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let mut interface = tun.file.try_clone()?;
+    let mut received = hub.received.subscribe();
+    std::thread::spawn(move || {
+        let mut decoder = slip::Decoder::default();
+        loop {
+            match received.blocking_recv() {
+                Ok(data) => {
+                    for frame in decoder.push(&data) {
+                        let Some(packet) = frame else {
+                            warn!("Dropped an invalid SLIP frame");
+                            continue;
+                        };
+                        // The kernel refuses what is not an IP packet, which ends nothing
+                        if let Err(e) = interface.write(&packet) {
+                            debug!("Interface dropped a packet of {} bytes: {e}", packet.len());
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Interface not read, dropped {missed} notifications");
+                    // The frame being received lost its middle
+                    decoder = slip::Decoder::default();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let result = tokio::select! {
+        result = hub.run(central, &args.device, link, notifications) => result,
+        _ = shutdown() => Ok(()),
+    };
+    hub.disconnect().await;
+    result
+}
+
+#[cfg(unix)]
+async fn unix(central: &Adapter, args: &UnixBridgeArgs, hub: Arc<Hub>) -> Result<()> {
+    use tokio::net::UnixListener;
+
+    let path = &args.path;
+    if path
+        .symlink_metadata()
+        .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type()))
+    {
+        // A socket left behind by a bridge that did not exit cleanly is reused
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("{} is in use by another program", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Could not remove {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Could not listen on {}", path.display()))?;
+    let _socket = SocketFile(path);
+    let (link, notifications) = open(central, &args.device).await?;
+    info!("Listening on {}", path.display());
+
+    let accept = async {
+        for n in 1.. {
+            let (stream, _) = listener.accept().await?;
+            info!("Client {n} connected");
+            let client = StreamClient {
+                peer: n.to_string(),
+                telnet: false,
+                received: hub.received.subscribe(),
+                write_queue: hub.write_queue.clone(),
+            };
+            if args.shared {
+                tokio::spawn(client.run(stream));
+            } else {
+                // Later clients wait in the backlog until this one is done
+                client.run(stream).await;
+            }
+        }
+        Ok(())
+    };
+
+    let result = tokio::select! {
+        result = hub.run(central, &args.device, link, notifications) => result,
+        result = accept => result,
+        _ = shutdown() => Ok(()),
+    };
+    hub.disconnect().await;
+    result
+}
+
+#[cfg(not(unix))]
+async fn unix(_central: &Adapter, _args: &UnixBridgeArgs, _hub: Arc<Hub>) -> Result<()> {
+    bail!("Unix domain sockets are only available on Linux and macOS")
+}
+
+/// Removes a socket file when the bridge exits
+#[cfg(unix)]
+struct SocketFile<'a>(&'a std::path::Path);
+
+#[cfg(unix)]
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0);
+    }
+}
+
+async fn mqtt(central: &Adapter, args: &MqttBridgeArgs, hub: Arc<Hub>) -> Result<()> {
+    let broker: mqtt::Broker = args.broker.parse().context("Invalid --broker")?;
+    if !broker.tls && (args.ca_file.is_some() || args.client_cert.is_some()) {
+        bail!("--ca-file and --client-cert need an mqtts:// broker");
+    }
+    let device = device::select(central, &args.device.search()).await?;
+    let publish_topic = expand_topic(&args.publish_topic, &device);
+    if publish_topic.contains(['+', '#']) {
+        bail!("The topic to publish to cannot contain wildcards: {publish_topic}");
+    }
+    let subscribe_topic = expand_topic(&args.subscribe_topic, &device);
+    let client_id = match &args.client_id {
+        Some(template) => expand_topic(template, &device),
+        None => {
+            let address: String = device
+                .address
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect();
+            format!("nus-{}", address.to_ascii_lowercase())
+        }
+    };
+
+    // The broker goes first, an unreachable one should not cost a connection attempt
+    let options = mqtt::ConnectOptions {
+        client_id,
+        username: args.username.clone(),
+        password: args.password.clone(),
+        keep_alive: args.keep_alive,
+        tls: mqtt::TlsOptions {
+            ca_file: args.ca_file.clone(),
+            client_cert: args.client_cert.clone().zip(args.client_key.clone()),
+        },
+    };
+    let mut broker = mqtt::Connection::open(&broker, &options).await?;
+    broker.subscribe(&subscribe_topic, args.qos).await?;
+    let (link, notifications) =
+        connect::open_link(central, device.peripheral, &LinkOptions::from(&args.device)).await?;
+    info!("Publishing received lines to {publish_topic}, writing messages from {subscribe_topic}");
+
+    let session = MqttSession {
+        args,
+        publish_topic,
+        received: hub.received.subscribe(),
+        write_queue: hub.write_queue.clone(),
+    };
+    let result = tokio::select! {
+        result = hub.run(central, &args.device, link, notifications) => result,
+        result = session.run(&mut broker) => result,
+        _ = shutdown() => Ok(()),
+    };
+    broker.disconnect().await;
+    hub.disconnect().await;
+    result
+}
+
+/// Fills the name and address of the device into a topic template
+fn expand_topic(template: &str, device: &DeviceInfo) -> String {
+    // Characters with a meaning in topics would change its structure
+    let clean = |s: &str| s.replace(['/', '+', '#'], "_");
+    let name = device.name.as_deref().unwrap_or(&device.address);
+    template
+        .replace("{name}", &clean(name))
+        .replace("{address}", &clean(&device.address))
+}
+
+struct MqttSession<'a> {
+    args: &'a MqttBridgeArgs,
+    publish_topic: String,
+    received: broadcast::Receiver<Vec<u8>>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+}
+
+impl MqttSession<'_> {
+    async fn run(mut self, broker: &mut mqtt::Connection) -> Result<()> {
+        let period = Duration::from_secs(u64::from(self.args.keep_alive.max(1)));
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut awaiting_response = false;
+        // Received data not published yet
+        let mut line = Vec::new();
+        loop {
+            tokio::select! {
+                packet = broker.recv() => {
+                    awaiting_response = false;
+                    if let Some(mut data) = broker.handle(packet?).await? {
+                        if let Some(newline) = self.args.send_newline {
+                            data.extend_from_slice(newline.bytes());
+                        }
+                        if self.write_queue.send(data).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                received = self.received.recv() => match received {
+                    Ok(data) => {
+                        line.extend_from_slice(&data);
+                        while let Some(end) = line.iter().position(|&b| b == b'\n') {
+                            let complete: Vec<u8> = line.drain(..=end).collect();
+                            self.publish(broker, &complete).await?;
+                        }
+                        if line.len() >= MQTT_MAX_LINE_LEN {
+                            self.publish(broker, &std::mem::take(&mut line)).await?;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Broker too slow, dropped {missed} notifications");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = tokio::time::sleep(MQTT_LINE_TIMEOUT), if !line.is_empty() => {
+                    self.publish(broker, &std::mem::take(&mut line)).await?;
+                }
+                _ = ping.tick(), if self.args.keep_alive > 0 => {
+                    if awaiting_response {
+                        bail!("Broker stopped responding");
+                    }
+                    awaiting_response = true;
+                    broker.ping().await?;
+                }
+            }
+        }
+    }
+
+    /// Publishes a line without its line ending, empty lines are skipped
+    async fn publish(&self, broker: &mut mqtt::Connection, line: &[u8]) -> Result<()> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Ok(());
+        }
+        broker
+            .publish(&self.publish_topic, line, self.args.qos)
+            .await
+    }
+}
+
+/// A client of the TCP or Unix socket bridge
+struct StreamClient {
+    peer: String,
+    telnet: bool,
+    received: broadcast::Receiver<Vec<u8>>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+}
+
+impl StreamClient {
+    async fn run(mut self, stream: impl AsyncRead + AsyncWrite) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut telnet = TelnetFilter::default();
+        if self.telnet {
+            // Character at a time mode, with the device echoing
+            let negotiation = [
+                TELNET_IAC,
+                TELNET_WILL,
+                TELNET_ECHO,
+                TELNET_IAC,
+                TELNET_WILL,
+                TELNET_SUPPRESS_GO_AHEAD,
+            ];
+            if writer.write_all(&negotiation).await.is_err() {
+                return;
+            }
+        }
+
+        let mut buf = [0; 1024];
+        loop {
+            tokio::select! {
+                read = reader.read(&mut buf) => {
+                    let data = match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) if self.telnet => telnet.input(&buf[..n]),
+                        Ok(n) => buf[..n].to_vec(),
+                    };
+                    if !data.is_empty() && self.write_queue.send(data).await.is_err() {
+                        break;
+                    }
+                }
+                received = self.received.recv() => {
+                    let data = match received {
+                        Ok(data) if self.telnet => telnet_escape(&data),
+                        Ok(data) => data,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!("Client {} too slow, dropped {missed} notifications", self.peer);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    if writer.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        info!("Client {} disconnected", self.peer);
+    }
+}
+
+async fn ws(central: &Adapter, args: &WsBridgeArgs, hub: Arc<Hub>) -> Result<()> {
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Could not listen on {}", args.listen))?;
+    let (link, notifications) = open(central, &args.device).await?;
+    info!(
+        "Accepting WebSocket connections on ws://{}",
+        listener.local_addr()?
+    );
+
+    let accept = async {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let client = WsClient {
+                peer,
+                origins: args.origins.clone(),
+                json: args.json,
+                received: hub.received.subscribe(),
+                write_queue: hub.write_queue.clone(),
+            };
+            tokio::spawn(client.run(stream));
+        }
+    };
+
+    let result = tokio::select! {
+        result = hub.run(central, &args.device, link, notifications) => result,
+        result = accept => result,
+        _ = shutdown() => Ok(()),
+    };
+    hub.disconnect().await;
+    result
+}
+
+struct WsClient {
+    peer: SocketAddr,
+    origins: Vec<String>,
+    json: bool,
+    received: broadcast::Receiver<Vec<u8>>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+}
+
+impl WsClient {
+    async fn run(mut self, mut stream: TcpStream) {
+        let mut messages = websocket::Decoder::default();
+        match websocket::accept(&mut stream, &self.origins).await {
+            Ok(rest) => messages.push(&rest),
+            Err(e) => {
+                warn!("Client {}: {e}", self.peer);
+                return;
+            }
+        }
+        info!("Client {} connected", self.peer);
+        if let Err(e) = self.serve(&mut stream, messages).await {
+            warn!("Client {}: {e}", self.peer);
+        }
+        info!("Client {} disconnected", self.peer);
+    }
+
+    async fn serve(
+        &mut self,
+        stream: &mut TcpStream,
+        mut messages: websocket::Decoder,
+    ) -> Result<()> {
+        let (mut reader, mut writer) = stream.split();
+        // Keeps UTF-8 sequences split across notifications together in JSON messages
+        let mut text = decode::Decoder::new(decode::Mode::Text);
+        let mut buf = [0; 4096];
+        loop {
+            while let Some(message) = messages.next()? {
+                let data = match message {
+                    websocket::Message::Close => {
+                        writer.write_all(&websocket::close()).await?;
+                        return Ok(());
+                    }
+                    websocket::Message::Ping(data) => {
+                        writer.write_all(&websocket::pong(&data)).await?;
+                        continue;
+                    }
+                    websocket::Message::Text(message) if self.json => {
+                        match json::parse_string_object(&message)
+                            .filter(|object| object.get("type").is_some_and(|t| t == "tx"))
+                            .and_then(|mut object| object.remove("data"))
+                        {
+                            Some(data) => data.into_bytes(),
+                            None => {
+                                let error = r#"{"type":"error","message":"expected {\"type\":\"tx\",\"data\":\"...\"}"}"#;
+                                writer.write_all(&websocket::text(error)).await?;
+                                continue;
+                            }
+                        }
+                    }
+                    websocket::Message::Text(message) => message.into_bytes(),
+                    websocket::Message::Binary(data) => data,
+                };
+                if !data.is_empty() && self.write_queue.send(data).await.is_err() {
+                    return Ok(());
+                }
+            }
+
+            tokio::select! {
+                read = reader.read(&mut buf) => match read? {
+                    0 => return Ok(()),
+                    n => messages.push(&buf[..n]),
+                },
+                received = self.received.recv() => {
+                    let frame = match received {
+                        Ok(data) if self.json => {
+                            let data = text.decode(&data);
+                            if data.is_empty() {
+                                continue;
+                            }
+                            websocket::text(&format!(r#"{{"type":"rx","data":{}}}"#, json::string(&data)))
+                        }
+                        Ok(data) => websocket::binary(&data),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!("Client {} too slow, dropped {missed} notifications", self.peer);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    };
+                    writer.write_all(&frame).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Removes telnet commands from client input
+#[derive(Debug, Default)]
+struct TelnetFilter {
+    state: TelnetState,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    /// After IAC
+    Command,
+    /// After WILL, WONT, DO or DONT, waiting for the option
+    Option,
+    /// Inside a subnegotiation
+    Subnegotiation,
+    /// IAC inside a subnegotiation
+    SubnegotiationCommand,
+    /// After CR, which telnet follows with LF or NUL
+    Return,
+}
+
+impl TelnetFilter {
+    fn input(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match (self.state, b) {
+                (TelnetState::Data | TelnetState::Return, TELNET_IAC) => TelnetState::Command,
+                // Enter is sent as CR NUL or CR LF, the device gets a plain CR
+                (TelnetState::Return, 0 | b'\n') => TelnetState::Data,
+                (TelnetState::Data | TelnetState::Return, b'\r') => {
+                    out.push(b);
+                    TelnetState::Return
+                }
+                (TelnetState::Data | TelnetState::Return, _) => {
+                    out.push(b);
+                    TelnetState::Data
+                }
+                (TelnetState::Command, TELNET_IAC) => {
+                    out.push(b);
+                    TelnetState::Data
+                }
+                (TelnetState::Command, TELNET_WILL | TELNET_WONT | TELNET_DO | TELNET_DONT) => {
+                    TelnetState::Option
+                }
+                (TelnetState::Command, TELNET_SB) => TelnetState::Subnegotiation,
+                (TelnetState::Command | TelnetState::Option, _) => TelnetState::Data,
+                (TelnetState::Subnegotiation, TELNET_IAC) => TelnetState::SubnegotiationCommand,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationCommand, TELNET_SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationCommand, _) => TelnetState::Subnegotiation,
+            };
+        }
+        out
+    }
+}
+
+/// Doubles IAC bytes in data sent to a telnet client
+fn telnet_escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        out.push(b);
+        if b == TELNET_IAC {
+            out.push(TELNET_IAC);
+        }
+    }
+    out
+}
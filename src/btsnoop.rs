@@ -0,0 +1,193 @@
+//! Captures of the ATT traffic with `--pcap`, in the btsnoop format Wireshark opens
+//!
+//! Every write and notification becomes an HCI ACL packet carrying the ATT PDU, as a capture
+//! of the Bluetooth stack would show it, so Wireshark dissects it like an `hcidump` or
+//! sniffer trace. The Bluetooth stack does not tell the attribute handles, so the RX and TX
+//! characteristics get the fixed handles [`RX_HANDLE`] and [`TX_HANDLE`].
+
+use crate::link::{Notices, Trace};
+use anyhow::Result;
+use btleplug::api::WriteType;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Handle the value of the RX characteristic is given in captures
+pub const RX_HANDLE: u16 = 0x0010;
+
+/// Handle the value of the TX characteristic is given in captures
+pub const TX_HANDLE: u16 = 0x0012;
+
+/// Datalink type of HCI packets prefixed with their H4 packet indicator
+const DATALINK_H4: u32 = 1002;
+
+/// Microseconds from the year 0, where btsnoop timestamps start, to the Unix epoch
+const EPOCH_OFFSET: i64 = 0x00dc_ddb3_0f2f_8000;
+
+/// Connection handle of the link in captures
+const CONNECTION_HANDLE: u16 = 0x0001;
+
+const H4_ACL: u8 = 0x02;
+/// First packet of an L2CAP PDU, which all of them are here
+const ACL_START: u16 = 0x2000;
+const ATT_CID: u16 = 0x0004;
+
+const ATT_WRITE_REQUEST: u8 = 0x12;
+const ATT_WRITE_RESPONSE: u8 = 0x13;
+const ATT_NOTIFICATION: u8 = 0x1b;
+const ATT_WRITE_COMMAND: u8 = 0x52;
+
+/// Records the packets of one link to a btsnoop file
+#[derive(Debug)]
+pub struct Btsnoop {
+    file: Mutex<File>,
+    /// Told about a failed write instead of the log, see [`Btsnoop::set_notices`]
+    notices: OnceLock<Arc<dyn Notices>>,
+    /// Whether a write failed already, only the first failure is reported
+    failed: AtomicBool,
+}
+
+impl Btsnoop {
+    /// Creates the capture at `path`, replacing an existing file
+    pub fn create(path: &Path) -> Result<Btsnoop> {
+        let mut file = File::create(path)?;
+        let mut header = b"btsnoop\0".to_vec();
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(&DATALINK_H4.to_be_bytes());
+        file.write_all(&header)?;
+        Ok(Btsnoop {
+            file: Mutex::new(file),
+            notices: OnceLock::new(),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Reports a failed write to `notices`, e.g. the screen of a tab the log would write over
+    pub fn set_notices(&self, notices: Arc<dyn Notices>) {
+        let _ = self.notices.set(notices);
+    }
+
+    /// Appends an ATT PDU sent to the device, or received from it
+    fn record(&self, received: bool, pdu: &[u8]) {
+        let l2cap_len = pdu.len() as u16;
+        let mut packet = vec![H4_ACL];
+        packet.extend_from_slice(&(CONNECTION_HANDLE | ACL_START).to_le_bytes());
+        packet.extend_from_slice(&(l2cap_len + 4).to_le_bytes());
+        packet.extend_from_slice(&l2cap_len.to_le_bytes());
+        packet.extend_from_slice(&ATT_CID.to_le_bytes());
+        packet.extend_from_slice(pdu);
+
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as i64);
+        let mut record = Vec::with_capacity(24 + packet.len());
+        record.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        record.extend_from_slice(&u32::from(received).to_be_bytes());
+        // Dropped packets
+        record.extend_from_slice(&0u32.to_be_bytes());
+        record.extend_from_slice(&(micros + EPOCH_OFFSET).to_be_bytes());
+        record.extend_from_slice(&packet);
+        // In one write, so Wireshark following the file never reads a torn record
+        if let Err(e) = self.file.lock().unwrap().write_all(&record)
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            let msg = format!("Could not write to the capture: {e}");
+            match self.notices.get() {
+                Some(notices) => notices.notice(&msg),
+                None => log::warn!("{msg}"),
+            }
+        }
+    }
+}
+
+impl Trace for Btsnoop {
+    fn write(&self, data: &[u8], write_type: WriteType) {
+        let opcode = match write_type {
+            WriteType::WithResponse => ATT_WRITE_REQUEST,
+            WriteType::WithoutResponse => ATT_WRITE_COMMAND,
+        };
+        self.record(false, &attribute_pdu(opcode, RX_HANDLE, data));
+    }
+
+    fn write_response(&self) {
+        self.record(true, &[ATT_WRITE_RESPONSE]);
+    }
+
+    fn notification(&self, data: &[u8]) {
+        self.record(true, &attribute_pdu(ATT_NOTIFICATION, TX_HANDLE, data));
+    }
+}
+
+fn attribute_pdu(opcode: u8, handle: u16, value: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![opcode];
+    pdu.extend_from_slice(&handle.to_le_bytes());
+    pdu.extend_from_slice(value);
+    pdu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits the records after the file header into their flags, timestamp and packet
+    fn records(mut data: &[u8]) -> Vec<(u32, i64, Vec<u8>)> {
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            let field = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+            let (original_len, included_len) = (field(0), field(4));
+            assert_eq!(original_len, included_len);
+            // No packets dropped
+            assert_eq!(field(12), 0);
+            let timestamp = i64::from_be_bytes(data[16..24].try_into().unwrap());
+            let end = 24 + included_len as usize;
+            records.push((field(8), timestamp, data[24..end].to_vec()));
+            data = &data[end..];
+        }
+        records
+    }
+
+    #[test]
+    fn records_att_pdus_in_acl_packets() {
+        let path = std::env::temp_dir().join(format!("nus-btsnoop-{}.btsnoop", std::process::id()));
+        let capture = Btsnoop::create(&path).unwrap();
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        capture.write(b"hi", WriteType::WithoutResponse);
+        capture.write(b"!", WriteType::WithResponse);
+        capture.write_response();
+        capture.notification(b"ok");
+        let end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        drop(capture);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Identification pattern, version 1 and the H4 datalink
+        assert_eq!(data[..16], *b"btsnoop\0\0\0\0\x01\0\0\x03\xea");
+        let records = records(&data[16..]);
+        let packets: Vec<(u32, &[u8])> = records
+            .iter()
+            .map(|(flags, _, packet)| (*flags, &packet[..]))
+            .collect();
+        assert_eq!(
+            packets,
+            [
+                // Sent: write command to handle 0x0010, on connection 1 and the ATT channel
+                (
+                    0,
+                    &b"\x02\x01\x20\x09\x00\x05\x00\x04\x00\x52\x10\x00hi"[..]
+                ),
+                (0, b"\x02\x01\x20\x08\x00\x04\x00\x04\x00\x12\x10\x00!"),
+                // Received: write response and notification from handle 0x0012
+                (1, b"\x02\x01\x20\x05\x00\x01\x00\x04\x00\x13"),
+                (1, b"\x02\x01\x20\x09\x00\x05\x00\x04\x00\x1b\x12\x00ok"),
+            ]
+        );
+        let micros = |since: std::time::Duration| since.as_micros() as i64 + EPOCH_OFFSET;
+        for (_, timestamp, _) in &records {
+            assert!((micros(start)..=micros(end)).contains(timestamp));
+        }
+    }
+}
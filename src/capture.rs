@@ -0,0 +1,43 @@
+//! Exact copies of the bytes received from the device, for traces of binary protocols
+//!
+//! Unlike the log nothing is decoded or stamped. With `--capture-framing` every notification
+//! becomes a record of its own: the microseconds since the Unix epoch as a little-endian
+//! u64, the length of the data as a little-endian u32, then the data.
+
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub struct RawCapture {
+    file: File,
+    framed: bool,
+}
+
+impl RawCapture {
+    /// Opens the capture, appending to a file at `path`
+    pub fn open(path: &Path, framed: bool) -> Result<RawCapture> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RawCapture { file, framed })
+    }
+
+    /// Writes the data of one notification
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if !self.framed {
+            self.file.write_all(data)?;
+            return Ok(());
+        }
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+        let mut record = Vec::with_capacity(12 + data.len());
+        record.extend_from_slice(&micros.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        // In one write, so a record is never torn by another process appending
+        self.file.write_all(&record)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! Recordings of the session in the asciinema v2 format, for replaying it with `asciinema play`
+//!
+//! The file starts with a header giving the terminal size, followed by a line for every
+//! chunk of output, and with `--record-input` of input: `[seconds, "o", "text"]`.
+
+use crate::decode::{self, Decoder};
+use crate::json;
+use anyhow::Result;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub struct Recording {
+    file: File,
+    start: Instant,
+    /// Whether input is recorded as well
+    input: bool,
+    output_decoder: Decoder,
+    input_decoder: Decoder,
+    /// Whether the last output character was a CR
+    after_cr: bool,
+}
+
+impl Recording {
+    /// Creates the recording, replacing a file at `path`
+    pub fn create(path: &Path, input: bool) -> Result<Recording> {
+        let mut file = File::create(path)?;
+        let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string());
+        writeln!(
+            file,
+            "{{\"version\": 2, \"width\": {width}, \"height\": {height}, \"timestamp\": {timestamp}, \"env\": {{\"TERM\": {}}}}}",
+            json::string(&term)
+        )?;
+        Ok(Recording {
+            file,
+            start: Instant::now(),
+            input,
+            output_decoder: Decoder::new(decode::Mode::Text),
+            input_decoder: Decoder::new(decode::Mode::Text),
+            after_cr: false,
+        })
+    }
+
+    /// Records data received from the device
+    pub fn output(&mut self, data: &[u8]) -> Result<()> {
+        let text = self.output_decoder.decode(data);
+        // Lines ended by LF alone would run down the replaying terminal like stairs
+        let mut translated = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c == '\n' && !self.after_cr {
+                translated.push('\r');
+            }
+            translated.push(c);
+            self.after_cr = c == '\r';
+        }
+        self.event("o", &translated)
+    }
+
+    /// Records data sent to the device, if input is recorded
+    pub fn input(&mut self, data: &[u8]) -> Result<()> {
+        if !self.input {
+            return Ok(());
+        }
+        let text = self.input_decoder.decode(data);
+        self.event("i", &text)
+    }
+
+    fn event(&mut self, kind: &str, text: &str) -> Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            self.file,
+            "[{:.6}, {}, {}]",
+            self.start.elapsed().as_secs_f64(),
+            json::string(kind),
+            json::string(text)
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_header_and_events() {
+        let path = std::env::temp_dir().join(format!("nus-cast-{}.cast", std::process::id()));
+        let mut recording = Recording::create(&path, true).unwrap();
+        recording.output(b"uart:~$ ").unwrap();
+        recording.input(b"help\r").unwrap();
+        // Nothing to record until the character is complete
+        recording.output(b"\xc3").unwrap();
+        recording.output(b"\xa9\n\r\nline\r").unwrap();
+        recording.output(b"\n\"quoted\"\x1b[0m").unwrap();
+        drop(recording);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut lines = text.lines();
+        let header = lines.next().unwrap();
+        assert!(
+            header.starts_with(r#"{"version": 2, "width": "#),
+            "{header}"
+        );
+        assert!(header.contains(r#", "timestamp": "#), "{header}");
+        assert!(header.ends_with("}}"), "{header}");
+        assert!(header.contains(r#", "env": {"TERM": ""#), "{header}");
+
+        let events: Vec<(f64, Vec<String>)> =
+            lines.map(|line| json::parse_event(line).unwrap()).collect();
+        let strings: Vec<Vec<&str>> = events
+            .iter()
+            .map(|(_, strings)| strings.iter().map(String::as_str).collect())
+            .collect();
+        assert_eq!(
+            strings,
+            [
+                vec!["o", "uart:~$ "],
+                vec!["i", "help\r"],
+                // Lone LFs get a CR
+                vec!["o", "é\r\n\r\nline\r"],
+                // But not after one ending the previous chunk
+                vec!["o", "\n\"quoted\"\x1b[0m"],
+            ]
+        );
+        assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        // Six decimals
+        for line in text.lines().skip(1) {
+            let (time, _) = line[1..].split_once(',').unwrap();
+            assert_eq!(time.split_once('.').unwrap().1.len(), 6, "{line}");
+        }
+    }
+
+    #[test]
+    fn skips_input_unless_asked() {
+        let path =
+            std::env::temp_dir().join(format!("nus-cast-output-{}.cast", std::process::id()));
+        let mut recording = Recording::create(&path, false).unwrap();
+        recording.input(b"help\r").unwrap();
+        recording.output(b"ok").unwrap();
+        drop(recording);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<&str> = text.lines().skip(1).collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].ends_with(r#", "o", "ok"]"#), "{}", events[0]);
+    }
+}
@@ -0,0 +1,556 @@
+//! Encoding and decoding of CBOR data items, for SMP and for showing received data
+
+use crate::json;
+use anyhow::{Result, bail};
+use std::fmt;
+
+/// Longest data item a [`Sequence`] waits for, longer ones are dropped as invalid
+const MAX_ITEM_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Unsigned(u64),
+    /// A negative integer, always below zero
+    Negative(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Tag(u64, Box<Value>),
+    Bool(bool),
+    Null,
+    Undefined,
+    Float(f64),
+}
+
+impl Value {
+    /// Looks up the value of a text key in a map
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| matches!(k, Value::Text(k) if k == key))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Unsigned(v) => i64::try_from(*v).ok(),
+            Value::Negative(v) => i64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Builds a map with text keys
+pub fn map<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (Value::Text(key.to_string()), value))
+            .collect(),
+    )
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write(&mut out, value);
+    out
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn write(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Unsigned(v) => write_head(out, 0, *v),
+        Value::Negative(v) => write_head(out, 1, (-1 - v) as u64),
+        Value::Bytes(bytes) => {
+            write_head(out, 2, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+        Value::Text(text) => {
+            write_head(out, 3, text.len() as u64);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(out, 4, items.len() as u64);
+            for item in items {
+                write(out, item);
+            }
+        }
+        Value::Map(entries) => {
+            write_head(out, 5, entries.len() as u64);
+            for (key, value) in entries {
+                write(out, key);
+                write(out, value);
+            }
+        }
+        Value::Tag(tag, value) => {
+            write_head(out, 6, *tag);
+            write(out, value);
+        }
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Null => out.push(0xf6),
+        Value::Undefined => out.push(0xf7),
+        Value::Float(v) => {
+            out.push(0xfb);
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+}
+
+/// Decodes the data item at the start of `data`, returns it with the number of bytes used
+pub fn decode(data: &[u8]) -> Result<(Value, usize)> {
+    let mut reader = Reader { data, pos: 0 };
+    let value = reader.value(0)?;
+    Ok((value, reader.pos))
+}
+
+/// Nesting allowed before data is rejected, keeping the recursion bounded
+const MAX_DEPTH: usize = 32;
+
+/// Error for data ending inside a data item, which more data may complete
+#[derive(Debug)]
+pub struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CBOR data ends unexpectedly")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// Splits a stream of data items following each other (RFC 8742) into the items
+#[derive(Debug, Default)]
+pub struct Sequence {
+    /// Start of an item that was not all received yet
+    buffer: Vec<u8>,
+}
+
+impl Sequence {
+    /// Returns the items completed by `data`, an error for invalid data, which is dropped
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<Value>> {
+        self.buffer.extend_from_slice(data);
+        let mut items = Vec::new();
+        while !self.buffer.is_empty() {
+            match decode(&self.buffer) {
+                Ok((value, len)) => {
+                    items.push(Ok(value));
+                    self.buffer.drain(..len);
+                }
+                Err(e) if e.is::<Truncated>() && self.buffer.len() <= MAX_ITEM_LEN => break,
+                Err(e) => {
+                    // Where the next item starts cannot be told
+                    items.push(Err(e));
+                    self.buffer.clear();
+                }
+            }
+        }
+        items
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if len > self.data.len() - self.pos {
+            return Err(Truncated.into());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads the argument of a head, `None` for the indefinite length marker
+    fn argument(&mut self, info: u8) -> Result<Option<u64>> {
+        Ok(Some(match info {
+            0..=23 => u64::from(info),
+            24 => u64::from(self.take(1)?[0]),
+            25 => u64::from(u16::from_be_bytes(self.take(2)?.try_into()?)),
+            26 => u64::from(u32::from_be_bytes(self.take(4)?.try_into()?)),
+            27 => u64::from_be_bytes(self.take(8)?.try_into()?),
+            31 => return Ok(None),
+            _ => bail!("invalid CBOR head"),
+        }))
+    }
+
+    /// Whether the break ending an indefinite length item is next, consuming it
+    fn at_break(&mut self) -> Result<bool> {
+        if self.data.get(self.pos) == Some(&0xff) {
+            self.pos += 1;
+            return Ok(true);
+        }
+        if self.pos >= self.data.len() {
+            return Err(Truncated.into());
+        }
+        Ok(false)
+    }
+
+    fn length(&mut self, argument: u64) -> Result<usize> {
+        let len = usize::try_from(argument)?;
+        if len > self.data.len() - self.pos {
+            return Err(Truncated.into());
+        }
+        Ok(len)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("CBOR data is nested too deeply");
+        }
+        let head = self.take(1)?[0];
+        let (major, info) = (head >> 5, head & 0x1f);
+        let argument = self.argument(info)?;
+        Ok(match (major, argument) {
+            (0, Some(v)) => Value::Unsigned(v),
+            (1, Some(v)) => Value::Negative(-1 - i128::from(v)),
+            (2 | 3, Some(len)) => {
+                let len = self.length(len)?;
+                let bytes = self.take(len)?.to_vec();
+                string(major, bytes)?
+            }
+            (2 | 3, None) => {
+                let mut bytes = Vec::new();
+                while !self.at_break()? {
+                    match self.value(depth + 1)? {
+                        Value::Bytes(chunk) if major == 2 => bytes.extend(chunk),
+                        Value::Text(chunk) if major == 3 => bytes.extend(chunk.into_bytes()),
+                        _ => bail!("invalid chunk of an indefinite length string"),
+                    }
+                }
+                string(major, bytes)?
+            }
+            (4, len) => {
+                let mut items = Vec::new();
+                match len {
+                    Some(len) => {
+                        for _ in 0..self.length(len)? {
+                            items.push(self.value(depth + 1)?);
+                        }
+                    }
+                    None => {
+                        while !self.at_break()? {
+                            items.push(self.value(depth + 1)?);
+                        }
+                    }
+                }
+                Value::Array(items)
+            }
+            (5, len) => {
+                let mut entries = Vec::new();
+                match len {
+                    Some(len) => {
+                        for _ in 0..self.length(len)? {
+                            entries.push((self.value(depth + 1)?, self.value(depth + 1)?));
+                        }
+                    }
+                    None => {
+                        while !self.at_break()? {
+                            entries.push((self.value(depth + 1)?, self.value(depth + 1)?));
+                        }
+                    }
+                }
+                Value::Map(entries)
+            }
+            (6, Some(tag)) => Value::Tag(tag, Box::new(self.value(depth + 1)?)),
+            (7, _) => match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                22 => Value::Null,
+                23 => Value::Undefined,
+                25 => Value::Float(half(argument.unwrap_or(0) as u16)),
+                26 => Value::Float(f64::from(f32::from_bits(argument.unwrap_or(0) as u32))),
+                27 => Value::Float(f64::from_bits(argument.unwrap_or(0))),
+                _ => bail!("unsupported CBOR simple value {info}"),
+            },
+            _ => bail!("invalid CBOR head"),
+        })
+    }
+}
+
+fn string(major: u8, bytes: Vec<u8>) -> Result<Value> {
+    Ok(match major {
+        2 => Value::Bytes(bytes),
+        _ => Value::Text(String::from_utf8(bytes)?),
+    })
+}
+
+/// Converts a half precision float
+fn half(bits: u16) -> f64 {
+    let exponent = i32::from((bits >> 10) & 0x1f);
+    let fraction = f64::from(bits & 0x3ff);
+    let magnitude = match exponent {
+        0 => fraction * 2f64.powi(-24),
+        31 if fraction == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + fraction / 1024.0) * 2f64.powi(exponent - 15),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Diagnostic notation, e.g. `{"rc": 0, "data": h'01ff'}`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unsigned(v) => write!(f, "{v}"),
+            Value::Negative(v) => write!(f, "{v}"),
+            Value::Bytes(bytes) => {
+                f.write_str("h'")?;
+                for b in bytes {
+                    write!(f, "{b:02x}")?;
+                }
+                f.write_str("'")
+            }
+            Value::Text(text) => f.write_str(&json::string(text)),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Map(entries) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{key}: {value}")?;
+                }
+                f.write_str("}")
+            }
+            Value::Tag(tag, value) => write!(f, "{tag}({value})"),
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::Null => f.write_str("null"),
+            Value::Undefined => f.write_str("undefined"),
+            Value::Float(v) if v.is_nan() => f.write_str("NaN"),
+            Value::Float(v) if v.is_infinite() && *v > 0.0 => f.write_str("Infinity"),
+            Value::Float(v) if v.is_infinite() => f.write_str("-Infinity"),
+            Value::Float(v) => write!(f, "{v:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Examples of RFC 8949 appendix A, decoded and encoded
+    fn vectors() -> Vec<(Value, Vec<u8>)> {
+        vec![
+            (Value::Unsigned(0), vec![0x00]),
+            (Value::Unsigned(23), vec![0x17]),
+            (Value::Unsigned(24), vec![0x18, 0x18]),
+            (Value::Unsigned(1000), vec![0x19, 0x03, 0xe8]),
+            (
+                Value::Unsigned(1_000_000),
+                vec![0x1a, 0x00, 0x0f, 0x42, 0x40],
+            ),
+            (
+                Value::Unsigned(u64::MAX),
+                vec![0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (Value::Negative(-1), vec![0x20]),
+            (Value::Negative(-1000), vec![0x39, 0x03, 0xe7]),
+            (
+                Value::Negative(-18_446_744_073_709_551_616),
+                vec![0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (Value::Bytes(vec![1, 2, 3, 4]), vec![0x44, 1, 2, 3, 4]),
+            (Value::Text(String::new()), vec![0x60]),
+            (Value::Text("\u{fc}".into()), vec![0x62, 0xc3, 0xbc]),
+            (
+                Value::Array(vec![
+                    Value::Unsigned(1),
+                    Value::Array(vec![Value::Unsigned(2), Value::Unsigned(3)]),
+                ]),
+                vec![0x82, 0x01, 0x82, 0x02, 0x03],
+            ),
+            (
+                map([("a", Value::Unsigned(1)), ("b", Value::Array(vec![]))]),
+                vec![0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x80],
+            ),
+            (
+                Value::Tag(1, Box::new(Value::Unsigned(1_363_896_240))),
+                vec![0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0],
+            ),
+            (Value::Bool(false), vec![0xf4]),
+            (Value::Bool(true), vec![0xf5]),
+            (Value::Null, vec![0xf6]),
+            (Value::Undefined, vec![0xf7]),
+            (
+                Value::Float(1.1),
+                vec![0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a],
+            ),
+        ]
+    }
+
+    #[test]
+    fn round_trips_the_rfc_examples() {
+        for (value, data) in vectors() {
+            assert_eq!(encode(&value), data, "{value}");
+            assert_eq!(decode(&data).unwrap(), (value, data.len()));
+        }
+    }
+
+    #[test]
+    fn decodes_short_floats_and_indefinite_lengths() {
+        let decoded = |data: &[u8]| decode(data).unwrap().0;
+        assert_eq!(decoded(&[0xf9, 0x3c, 0x00]), Value::Float(1.0));
+        assert_eq!(decoded(&[0xf9, 0xc4, 0x00]), Value::Float(-4.0));
+        assert_eq!(decoded(&[0xf9, 0x7c, 0x00]), Value::Float(f64::INFINITY));
+        assert_eq!(
+            decoded(&[0xfa, 0x47, 0xc3, 0x50, 0x00]),
+            Value::Float(100000.0)
+        );
+        assert_eq!(
+            decoded(&[0x5f, 0x42, 0x01, 0x02, 0x43, 0x03, 0x04, 0x05, 0xff]),
+            Value::Bytes(vec![1, 2, 3, 4, 5])
+        );
+        assert_eq!(
+            decoded(&[
+                0x7f, 0x65, b's', b't', b'r', b'e', b'a', 0x64, b'm', b'i', b'n', b'g', 0xff
+            ]),
+            Value::Text("streaming".into())
+        );
+        assert_eq!(
+            decoded(&[0x9f, 0x01, 0x9f, 0x02, 0xff, 0xff]),
+            Value::Array(vec![
+                Value::Unsigned(1),
+                Value::Array(vec![Value::Unsigned(2)])
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_data_is_rejected() {
+        let truncated = |data: &[u8]| decode(data).unwrap_err().is::<Truncated>();
+        assert!(truncated(&[]));
+        assert!(truncated(&[0x19, 0x03]));
+        assert!(truncated(&[0x44, 1, 2]));
+        assert!(truncated(&[0x82, 0x01]));
+        assert!(truncated(&[0x9f, 0x01]));
+        let invalid = |data: &[u8]| decode(data).is_err_and(|e| !e.is::<Truncated>());
+        // Reserved additional information, a break outside an indefinite length item, invalid
+        // UTF-8, a number as chunk of a byte string and excessive nesting
+        assert!(invalid(&[0x1c]));
+        assert!(invalid(&[0xff]));
+        assert!(invalid(&[0x62, 0xc3, 0x28]));
+        assert!(invalid(&[0x5f, 0x01, 0xff]));
+        assert!(invalid(&[0x81; MAX_DEPTH + 2]));
+    }
+
+    #[test]
+    fn sequence_joins_items_split_across_pushes() {
+        let stream: Vec<u8> = vectors().into_iter().flat_map(|(_, data)| data).collect();
+        for split in [1, 2, 3, 7, stream.len()] {
+            let mut sequence = Sequence::default();
+            let items: Vec<_> = stream
+                .chunks(split)
+                .flat_map(|chunk| sequence.push(chunk))
+                .map(Result::unwrap)
+                .collect();
+            let expected: Vec<_> = vectors().into_iter().map(|(value, _)| value).collect();
+            assert_eq!(items, expected, "split every {split} bytes");
+        }
+    }
+
+    #[test]
+    fn sequence_drops_invalid_data() {
+        let mut sequence = Sequence::default();
+        let items = sequence.push(&[0x01, 0x1c, 0x02]);
+        assert!(matches!(items[..], [Ok(Value::Unsigned(1)), Err(_)]));
+        assert!(matches!(
+            sequence.push(&[0x03])[..],
+            [Ok(Value::Unsigned(3))]
+        ));
+    }
+
+    #[test]
+    fn display_uses_diagnostic_notation() {
+        let value = map([
+            ("rc", Value::Unsigned(0)),
+            ("data", Value::Bytes(vec![0x01, 0xff])),
+            (
+                "list",
+                Value::Array(vec![Value::Negative(-2), Value::Float(1.5)]),
+            ),
+            ("tag", Value::Tag(32, Box::new(Value::Null))),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"rc": 0, "data": h'01ff', "list": [-2, 1.5], "tag": 32(null)}"#
+        );
+        assert_eq!(Value::Float(f64::NEG_INFINITY).to_string(), "-Infinity");
+    }
+
+    #[test]
+    fn display_escapes_text_like_json() {
+        let text = Value::Text("a\"b\\c\nd\te\u{1}f\u{7f}\u{e9}".into());
+        assert_eq!(
+            text.to_string(),
+            r#""a\"b\\c\nd\te\u0001f"#.to_owned() + "\u{7f}\u{e9}\""
+        );
+    }
+}
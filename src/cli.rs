@@ -0,0 +1,1470 @@
+use crate::agent;
+use crate::bench::Direction;
+use crate::config::{self, Profile};
+use crate::device::{DeviceFilter, SCAN_DURATION, Search};
+use crate::emulate::Behavior;
+use crate::flow::FlowControl;
+use crate::gatt;
+use crate::highlight::Highlight;
+use crate::init::{self, InitCommand};
+use crate::length_prefix::LengthPrefix;
+use crate::link::{Fallback, LinkOptions, WriteMode};
+use crate::macros::Macro;
+use crate::menu::EscapeKey;
+use crate::nus::{NusUuids, Protocol};
+use crate::plot;
+use crate::screen::Newline;
+use crate::self_test;
+use crate::timestamp::{self, Kind as TimestampKind};
+use crate::trigger::{self, Trigger};
+use anyhow::{Result, anyhow};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgGroup, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use regex::Regex;
+use std::io::{self, IsTerminal};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Nordic UART Service Client app
+#[derive(Parser, Debug)]
+#[command(version, about, long_about=None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Connection options when no subcommand is given
+    #[command(flatten)]
+    pub connect: ConnectArgs,
+}
+
+impl Cli {
+    /// Parses the command line and fills in the settings of the selected profile
+    pub fn parse_with_profile() -> Result<Cli> {
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let subcommand = |name| matches.subcommand_matches(name).unwrap();
+        match &mut cli.command {
+            None => resolve(&mut cli.connect, &matches)?,
+            Some(Command::Connect(args)) => resolve(args.as_mut(), subcommand("connect"))?,
+            Some(Command::SendFile(args)) => resolve(args, subcommand("send-file"))?,
+            Some(Command::Info(args)) => resolve(args, subcommand("info"))?,
+            Some(Command::Gatt(args)) => resolve(args, subcommand("gatt"))?,
+            Some(Command::Script(ScriptArgs {
+                command: ScriptCommand::Run(args),
+            })) => {
+                let (_, matches) = subcommand("script").subcommand().unwrap();
+                resolve(args.as_mut(), matches)?;
+            }
+            Some(Command::Test(args)) => resolve(args, subcommand("test"))?,
+            Some(Command::Dfu(args)) => resolve(args, subcommand("dfu"))?,
+            Some(Command::SelfTest(args)) => {
+                if args.emulate.is_some() && args.device.name.is_empty() {
+                    args.device.name.push(self_test::EMULATOR_NAME.to_string());
+                }
+                resolve(args, subcommand("self-test"))?
+            }
+            Some(Command::Bridge(args)) => {
+                let (_, matches) = subcommand("bridge").subcommand().unwrap();
+                match &mut args.kind {
+                    BridgeKind::Tcp(args) => resolve(args, matches)?,
+                    BridgeKind::Pty(args) => resolve(args, matches)?,
+                    BridgeKind::Mqtt(args) => resolve(args.as_mut(), matches)?,
+                    BridgeKind::Ws(args) => resolve(args, matches)?,
+                    BridgeKind::Unix(args) => resolve(args, matches)?,
+                    BridgeKind::Tun(args) => resolve(args, matches)?,
+                }
+            }
+            Some(Command::Bench(args)) => {
+                let (_, matches) = subcommand("bench").subcommand().unwrap();
+                match &mut args.command {
+                    BenchCommand::Throughput(args) => resolve(args, matches)?,
+                    BenchCommand::Ping(args) => resolve(args, matches)?,
+                }
+            }
+            Some(
+                Command::Scan(_)
+                | Command::Emulate(_)
+                | Command::ListAdapters
+                | Command::Completions { .. }
+                | Command::Replay(_)
+                | Command::Script(_),
+            ) => {}
+        }
+        Ok(cli)
+    }
+}
+
+impl Cli {
+    /// The Bluetooth adapter selected for the command
+    pub fn adapter(&self) -> Option<&str> {
+        let device = match &self.command {
+            None => &self.connect.device,
+            Some(Command::Connect(args)) => &args.device,
+            Some(Command::SendFile(args)) => &args.device,
+            Some(Command::Info(args)) => &args.device,
+            Some(Command::Gatt(args)) => &args.device,
+            Some(Command::Bridge(BridgeArgs {
+                kind: BridgeKind::Tcp(args),
+                ..
+            })) => &args.device,
+            Some(Command::Bridge(BridgeArgs {
+                kind: BridgeKind::Pty(args),
+                ..
+            })) => &args.device,
+            Some(Command::Bridge(BridgeArgs {
+                kind: BridgeKind::Mqtt(args),
+                ..
+            })) => &args.device,
+            Some(Command::Bridge(BridgeArgs {
+                kind: BridgeKind::Ws(args),
+                ..
+            })) => &args.device,
+            Some(Command::Bridge(BridgeArgs {
+                kind: BridgeKind::Unix(args),
+                ..
+            })) => &args.device,
+            Some(Command::Bridge(BridgeArgs {
+                kind: BridgeKind::Tun(args),
+                ..
+            })) => &args.device,
+            Some(Command::Script(ScriptArgs {
+                command: ScriptCommand::Run(args),
+            })) => &args.device,
+            Some(Command::Test(args)) => &args.device,
+            Some(Command::Dfu(args)) => &args.device,
+            Some(Command::SelfTest(args)) => &args.device,
+            Some(Command::Bench(BenchArgs {
+                command: BenchCommand::Throughput(args),
+            })) => &args.device,
+            Some(Command::Bench(BenchArgs {
+                command: BenchCommand::Ping(args),
+            })) => &args.device,
+            Some(Command::Scan(args)) => return args.adapter.as_deref(),
+            Some(Command::Emulate(args)) => return args.adapter.as_deref(),
+            Some(
+                Command::ListAdapters
+                | Command::Completions { .. }
+                | Command::Replay(_)
+                | Command::Script(_),
+            ) => return None,
+        };
+        device.adapter.as_deref()
+    }
+}
+
+/// Options of a command that can be filled in from a profile
+trait ProfileArgs {
+    fn device(&self) -> &DeviceArgs;
+
+    /// Whether the command can connect to several devices at once
+    fn multiple_devices(&self) -> bool {
+        false
+    }
+
+    /// Takes over the settings of `profile` for which `given` is false
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool);
+}
+
+/// Applies the selected profile to `args` and checks that a device was selected
+fn resolve(args: &mut impl ProfileArgs, matches: &ArgMatches) -> Result<()> {
+    let device = args.device();
+    if let Some(name) = &device.profile {
+        let path = match &device.config {
+            Some(path) => path.clone(),
+            None => config::default_path().ok_or(anyhow!("Could not locate config file"))?,
+        };
+        let profile = config::load_profile(&path, name)?;
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        args.apply_profile(&profile, &given);
+    }
+
+    let device = args.device();
+    if device.name.is_empty() && device.address.is_empty() && !device.any {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "either --name, --address, --any or a --profile providing one of them is required",
+            )
+            .exit();
+    }
+    if device.name.len() + device.address.len() > 1 && !args.multiple_devices() {
+        Cli::command()
+            .error(
+                ErrorKind::TooManyValues,
+                "only the terminal connects to several devices at once",
+            )
+            .exit();
+    }
+    Ok(())
+}
+
+/// Copies a profile setting to the options unless it was given on the command line
+macro_rules! apply {
+    ($args:ident, $profile:ident, $given:ident: $field:ident) => {
+        apply!($args, $profile, $given: $field => $field)
+    };
+    ($args:ident, $profile:ident, $given:ident: $field:ident => $($target:ident).+) => {
+        if let Some(value) = &$profile.$field
+            && !$given(stringify!($field))
+        {
+            $args.$($target).+ = value.clone().into();
+        }
+    };
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Connect to a device and open an interactive terminal (default)
+    Connect(Box<ConnectArgs>),
+    /// List nearby devices advertising the Nordic UART Service
+    Scan(ScanArgs),
+    /// List the Bluetooth adapters of this machine
+    ListAdapters,
+    /// Print a completion script for a shell
+    Completions { shell: Shell },
+    /// Play back a session recorded with --record or logged with --log-timestamps
+    Replay(ReplayArgs),
+    /// Show the Device Information Service fields, battery level and MTU of a device
+    Info(InfoArgs),
+    /// List the GATT services of a device, or read and subscribe to its characteristics
+    Gatt(GattArgs),
+    /// Send the contents of a file to a device and exit
+    SendFile(SendFileArgs),
+    /// Make the device available to other programs
+    Bridge(BridgeArgs),
+    /// Drive the device shell with an expect-style script
+    Script(ScriptArgs),
+    /// Run the tests of a test file against a device and print a JUnit report
+    Test(TestArgs),
+    /// Update the firmware over the MCUmgr SMP service, or list its images
+    Dfu(DfuArgs),
+    /// Measure the performance of the link
+    Bench(BenchArgs),
+    /// Advertise a UART service of this machine, to test without a device (Linux)
+    Emulate(EmulateArgs),
+    /// Check that data sent to an echoing device comes back intact, and how fast
+    SelfTest(SelfTestArgs),
+}
+
+/// Selection of the device and of its UART service
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("target").args(["name", "address", "any"]).multiple(true)))]
+pub struct DeviceArgs {
+    /// Apply the settings of a profile from the configuration file
+    #[arg(short, long)]
+    pub profile: Option<String>,
+
+    /// Configuration file [default: ~/.config/nus-terminal/config.toml]
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// BLE device name filter, repeat it to open a tab for each of several devices
+    #[arg(short, long)]
+    pub name: Vec<String>,
+
+    /// BLE device address (the peripheral identifier on macOS), can be repeated as well
+    #[arg(short, long)]
+    pub address: Vec<String>,
+
+    /// Connect to the closest device advertising the UART service, whatever its name
+    #[arg(long, conflicts_with_all = ["name", "address"])]
+    pub any: bool,
+
+    /// Bluetooth adapter to use, by index, address or name (like hci0) [default: the first]
+    #[arg(long)]
+    pub adapter: Option<String>,
+
+    /// Seconds to look for the device before giving up
+    #[arg(long, value_name = "SECONDS", default_value_t = SCAN_DURATION.as_secs())]
+    pub scan_timeout: u64,
+
+    /// Keep looking for the device until it shows up
+    #[arg(long, conflicts_with = "scan_timeout")]
+    pub wait: bool,
+
+    #[command(flatten)]
+    pub uuids: UuidArgs,
+
+    /// ATT MTU to assume instead of the one negotiated by the Bluetooth stack
+    #[arg(long, value_parser = clap::value_parser!(u16).range(23..))]
+    pub mtu: Option<u16>,
+
+    /// How data is written to the device [default: auto, without-response for hm10 and ti]
+    #[arg(long, value_enum)]
+    pub write_mode: Option<WriteMode>,
+
+    /// Pair with (bond to) the device after connecting if it is not paired yet
+    #[arg(long)]
+    pub pair: bool,
+
+    /// Seconds a connection attempt may take, 0 waits as long as the Bluetooth stack does
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub connect_timeout: u64,
+
+    /// Seconds the service discovery may take, 0 for no limit
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub discover_timeout: u64,
+
+    /// How often connecting and service discovery are tried again after failing or timing out
+    #[arg(long, default_value_t = 2)]
+    pub connect_retries: u32,
+
+    /// Use a UART-like service without asking if the device lacks the one of --protocol
+    #[arg(long)]
+    pub auto_detect: bool,
+}
+
+impl DeviceArgs {
+    /// How to find the selected device, the first one if several were given
+    pub fn search(&self) -> Search {
+        self.searches().swap_remove(0)
+    }
+
+    /// How to find each of the selected devices, those given by name first
+    pub fn searches(&self) -> Vec<Search> {
+        let mut filters: Vec<DeviceFilter> = self
+            .name
+            .iter()
+            .map(|name| DeviceFilter::Name(name.clone()))
+            .chain(
+                self.address
+                    .iter()
+                    .map(|address| DeviceFilter::Address(address.clone())),
+            )
+            .collect();
+        // Devices picked by name or address need not advertise the service, many only
+        // reveal it once connected
+        let any = filters.is_empty();
+        if any {
+            assert!(self.any, "either name, address or any is required");
+            filters.push(DeviceFilter::Service(self.uuids.service()));
+        }
+        filters
+            .into_iter()
+            .map(|filter| Search {
+                filter,
+                service: any.then(|| self.uuids.service()),
+                timeout: (!self.wait).then(|| Duration::from_secs(self.scan_timeout)),
+            })
+            .collect()
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        // The device is selected either way, so one given on the command line replaces all
+        if !given("name") && !given("address") && !given("any") {
+            self.address = profile.address.iter().cloned().collect();
+            // A profile giving both means one device, found by its address
+            self.name = match profile.address {
+                Some(_) => Vec::new(),
+                None => profile.name.iter().cloned().collect(),
+            };
+            self.any = profile.any.unwrap_or_default();
+        }
+        apply!(self, profile, given: adapter);
+        apply!(self, profile, given: scan_timeout);
+        if !given("scan_timeout") {
+            apply!(self, profile, given: wait);
+        }
+        apply!(self, profile, given: mtu);
+        apply!(self, profile, given: write_mode);
+        apply!(self, profile, given: pair);
+        apply!(self, profile, given: connect_timeout);
+        apply!(self, profile, given: discover_timeout);
+        apply!(self, profile, given: connect_retries);
+        apply!(self, profile, given: auto_detect);
+        apply!(self, profile, given: protocol => uuids.protocol);
+        apply!(self, profile, given: service_uuid => uuids.service_uuid);
+        apply!(self, profile, given: rx_uuid => uuids.rx_uuid);
+        apply!(self, profile, given: tx_uuid => uuids.tx_uuid);
+    }
+}
+
+impl From<&DeviceArgs> for LinkOptions {
+    fn from(args: &DeviceArgs) -> Self {
+        LinkOptions {
+            uuids: NusUuids::from(&args.uuids),
+            mtu: args.mtu,
+            write_mode: args.write_mode.unwrap_or(args.uuids.protocol.write_mode()),
+            pairing: args.pair.then(agent::console),
+            connect_timeout: seconds(args.connect_timeout),
+            discover_timeout: seconds(args.discover_timeout),
+            connect_retries: args.connect_retries,
+            fallback: if args.auto_detect {
+                Fallback::Detect
+            } else if io::stdin().is_terminal() && io::stderr().is_terminal() {
+                Fallback::Ask(confirm_service)
+            } else {
+                Fallback::Fail
+            },
+            trace: None,
+            notices: None,
+        }
+    }
+}
+
+/// Asks on the console whether to use a UART-like service found instead of the configured one
+fn confirm_service(uuids: &NusUuids) -> bool {
+    eprint!(
+        "The UART service was not found. Use service {} (RX {}, TX {}) instead? [y/N] ",
+        uuids.service, uuids.rx, uuids.tx
+    );
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && (answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes"))
+}
+
+/// A timeout given in seconds, `None` for 0
+fn seconds(timeout: u64) -> Option<Duration> {
+    (timeout > 0).then(|| Duration::from_secs(timeout))
+}
+
+#[derive(Args, Debug)]
+pub struct ConnectArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Number of reconnection attempts after the link drops (0 disables reconnecting)
+    #[arg(long, default_value_t = 5)]
+    pub reconnect_retries: u32,
+
+    /// Delay before the first reconnection attempt in milliseconds, doubled after each failure
+    #[arg(long, default_value_t = 1000)]
+    pub reconnect_delay: u64,
+
+    /// Upper limit of the delay between reconnection attempts in milliseconds
+    #[arg(long, default_value_t = 30000)]
+    pub reconnect_max_delay: u64,
+
+    /// Most bytes of input kept while reconnecting, sent once the link is back up
+    #[arg(long, default_value_t = 4096)]
+    pub reconnect_buffer: usize,
+
+    /// Keep the link busy every this many seconds, by writing --keepalive-data or reading the
+    /// signal strength
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive: Option<u64>,
+
+    /// Written by the keepalive instead of reading the signal strength, with escapes like \x00
+    #[arg(long, value_name = "DATA", requires = "keepalive")]
+    pub keepalive_data: Option<String>,
+
+    /// Mark the link as stalled when no data arrived for this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stall_timeout: Option<u64>,
+
+    /// Reconnect when the link stalls instead of only marking it
+    #[arg(long, requires = "stall_timeout")]
+    pub stall_reconnect: bool,
+
+    /// Append everything received from the device to this file
+    #[arg(short, long)]
+    pub log: Option<PathBuf>,
+
+    /// Prefix each line in the log file with the time it was received
+    #[arg(long)]
+    pub log_timestamps: bool,
+
+    /// Append the exact bytes received to this file, separate from the text log
+    #[arg(long, value_name = "PATH")]
+    pub capture_raw: Option<PathBuf>,
+
+    /// Write every notification to the capture as a record with its time and length
+    #[arg(long, requires = "capture_raw")]
+    pub capture_framing: bool,
+
+    /// Record every write and notification as ATT packets in a btsnoop file for Wireshark
+    #[arg(long, value_name = "PATH")]
+    pub pcap: Option<PathBuf>,
+
+    /// Write the statistics of the session to this file as JSON when it ends
+    #[arg(long, value_name = "PATH")]
+    pub stats_json: Option<PathBuf>,
+
+    /// Record the session to this file in the asciinema format, for `asciinema play`
+    #[arg(long, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Record typed input as well as received output
+    #[arg(long, requires = "record")]
+    pub record_input: bool,
+
+    /// Show received data as a hex dump instead of text
+    #[arg(long)]
+    pub hex: bool,
+
+    /// Number of received lines kept for scrolling back
+    #[arg(long, default_value_t = 10000)]
+    pub scrollback: usize,
+
+    /// Send PageUp and PageDown to the device, scrolling back takes Shift+PageUp/PageDown
+    #[arg(long)]
+    pub send_page_keys: bool,
+
+    /// Pause between the chunks of a file sent from the menu in milliseconds
+    #[arg(long, default_value_t = 0)]
+    pub chunk_delay: u64,
+
+    /// Wait for the device to grant credits before sending, see the README for the protocol
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = FlowControl::None)]
+    pub flow_control: FlowControl,
+
+    /// Send and receive in acknowledged frames that are retransmitted when lost, for
+    /// firmwares implementing the protocol described in the README
+    #[arg(long, conflicts_with = "flow_control")]
+    pub reliable: bool,
+
+    /// Pause after every character sent in milliseconds, for devices dropping bytes
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub char_delay: u64,
+
+    /// Pause after every line sent in milliseconds, on top of --char-delay
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub line_delay: u64,
+
+    /// Key opening the command menu, e.g. C-a or C-t
+    #[arg(long, default_value_t = EscapeKey::default())]
+    pub escape: EscapeKey,
+
+    /// Sent after every connection, with escapes like \r or \x0c; repeat it to send several
+    /// commands [default: \x0c, Ctrl+L]
+    #[arg(long, value_name = "COMMAND", value_parser = init::parse)]
+    pub init: Vec<InitCommand>,
+
+    /// Pause after each init command in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub init_delay: u64,
+
+    /// Send nothing after connecting, not even Ctrl+L
+    #[arg(long, conflicts_with = "init")]
+    pub no_init: bool,
+
+    /// Send XMODEM transfers in 1024 byte blocks (XMODEM-1K)
+    #[arg(long)]
+    pub xmodem_1k: bool,
+
+    /// Receive ZMODEM transfers the device starts (e.g. with sz) into this directory
+    #[arg(long, value_name = "DIR")]
+    pub zmodem_dir: Option<PathBuf>,
+
+    /// Start in line mode, where input is edited locally and sent on Enter
+    #[arg(long)]
+    pub line_mode: bool,
+
+    /// Show typed input in the output, for firmware that does not echo it
+    #[arg(long)]
+    pub local_echo: bool,
+
+    /// Load the line mode history from this file and append sent lines to it
+    #[arg(long)]
+    pub history_file: Option<PathBuf>,
+
+    /// What Enter sends to the device
+    #[arg(long, value_enum, default_value_t = Newline::Cr)]
+    pub send_newline: Newline,
+
+    /// Line ending used by the device, cr makes a bare CR start a new line
+    #[arg(long, value_enum, default_value_t = Newline::Lf)]
+    pub receive_newline: Newline,
+
+    /// Decode the output as defmt logs, using the strings of this firmware ELF file
+    #[arg(long, value_name = "ELF",
+          conflicts_with_all = ["cobs", "slip", "framing", "cbor", "proto_desc"])]
+    pub defmt: Option<PathBuf>,
+
+    /// Show the output as COBS frames and send each line typed in line mode as a frame
+    #[arg(long, conflicts_with_all = ["slip", "framing"])]
+    pub cobs: bool,
+
+    /// Show the output as SLIP frames and send each line typed in line mode as a frame
+    #[arg(long, conflicts_with = "framing")]
+    pub slip: bool,
+
+    /// Show the output as frames starting with their length, sending lines the same way
+    #[arg(long, value_enum, value_name = "PREFIX")]
+    pub framing: Option<LengthPrefix>,
+
+    /// Show the output, or its frames with a framing option, as CBOR in diagnostic notation
+    #[arg(long)]
+    pub cbor: bool,
+
+    /// Show the output, or its frames with a framing option, as protobuf messages of
+    /// --proto-type, using this descriptor set written by protoc --descriptor_set_out
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "proto_type",
+        conflicts_with = "cbor"
+    )]
+    pub proto_desc: Option<PathBuf>,
+
+    /// Fully qualified name of the messages received, e.g. my.pkg.Message
+    #[arg(long, value_name = "TYPE", requires = "proto_desc")]
+    pub proto_type: Option<String>,
+
+    /// Show a panel with the position, satellites and HDOP of the NMEA sentences received
+    #[arg(long)]
+    pub nmea: bool,
+
+    /// Plot the numbers this regex captures from the received lines, a series per group
+    #[arg(long, value_name = "REGEX", value_parser = plot::pattern)]
+    pub plot: Option<Regex>,
+
+    /// Seconds of the received values shown in the plot
+    #[arg(long, value_name = "SECONDS", default_value_t = 60,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub plot_window: u64,
+
+    /// Write the values captured by the regex of --plot to this file as CSV rows with their time
+    #[arg(long, value_name = "PATH", requires = "plot")]
+    pub csv: Option<PathBuf>,
+
+    /// Rhai script whose functions are called on connecting, disconnecting and receiving
+    #[arg(long, value_name = "PATH")]
+    pub hooks: Option<PathBuf>,
+
+    /// WebAssembly plugin transforming the data received and sent, e.g. to decrypt it
+    #[arg(long, value_name = "PATH")]
+    pub plugin: Option<PathBuf>,
+
+    /// Prefix received lines with the time they arrived, on screen and in the log file
+    #[arg(long, value_enum, value_name = "KIND", num_args = 0..=1,
+          default_missing_value = "absolute")]
+    pub timestamps: Option<TimestampKind>,
+
+    /// strftime format of absolute timestamps
+    #[arg(long, value_name = "FORMAT", default_value = timestamp::DEFAULT_FORMAT)]
+    pub timestamp_format: String,
+
+    /// Copy stdin to the device and its output to stdout instead of opening the terminal,
+    /// the default when stdin is not a terminal
+    #[arg(long)]
+    pub pipe: bool,
+
+    /// In pipe mode, milliseconds without output after stdin ends before exiting
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub pipe_timeout: u64,
+
+    /// Print the output to the normal terminal buffer instead of opening the terminal UI,
+    /// leaving scrollback and copying to the terminal
+    #[arg(long, conflicts_with = "pipe")]
+    pub inline: bool,
+
+    /// Read the Device Information Service on connecting and show its fields
+    #[arg(long)]
+    pub device_info: bool,
+
+    /// Warn when the battery level of the device drops to this percentage
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub battery_alert: Option<u8>,
+
+    /// Capture the mouse for the whole session: the wheel scrolls and dragging selects lines
+    #[arg(long)]
+    pub mouse: bool,
+
+    /// Copy selections by piping them into this shell command instead of using OSC 52
+    #[arg(long, value_name = "COMMAND")]
+    pub copy_command: Option<String>,
+
+    /// Show a desktop notification when the connection is lost
+    #[arg(long)]
+    pub notify: bool,
+
+    /// Show a desktop notification whenever the output matches this regex
+    #[arg(long, value_name = "REGEX", value_parser = trigger::notify_on)]
+    pub notify_on: Vec<Trigger>,
+
+    /// Shell command run whenever the device connects, with it described in NUS_* variables
+    #[arg(long, value_name = "CMD")]
+    pub on_connect: Option<String>,
+
+    /// Shell command run whenever the connection to the device ends
+    #[arg(long, value_name = "CMD")]
+    pub on_disconnect: Option<String>,
+
+    /// Color the log levels and module tags of Zephyr and nRF Connect SDK output
+    #[arg(long)]
+    pub zephyr: bool,
+
+    /// Highlight rules of the profile
+    #[arg(skip)]
+    pub highlight: Vec<Highlight>,
+
+    /// Triggers of the profile
+    #[arg(skip)]
+    pub trigger: Vec<Trigger>,
+
+    /// Macro keys of the profile
+    #[arg(skip)]
+    pub keys: Vec<Macro>,
+}
+
+impl ProfileArgs for ConnectArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn multiple_devices(&self) -> bool {
+        true
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+        apply!(self, profile, given: reconnect_retries);
+        apply!(self, profile, given: reconnect_delay);
+        apply!(self, profile, given: reconnect_max_delay);
+        apply!(self, profile, given: reconnect_buffer);
+        apply!(self, profile, given: keepalive);
+        apply!(self, profile, given: keepalive_data);
+        apply!(self, profile, given: stall_timeout);
+        apply!(self, profile, given: stall_reconnect);
+        apply!(self, profile, given: log);
+        apply!(self, profile, given: log_timestamps);
+        apply!(self, profile, given: capture_raw);
+        apply!(self, profile, given: capture_framing);
+        apply!(self, profile, given: pcap);
+        apply!(self, profile, given: stats_json);
+        apply!(self, profile, given: record);
+        apply!(self, profile, given: record_input);
+        apply!(self, profile, given: hex);
+        apply!(self, profile, given: scrollback);
+        apply!(self, profile, given: send_page_keys);
+        apply!(self, profile, given: chunk_delay);
+        apply!(self, profile, given: flow_control);
+        apply!(self, profile, given: reliable);
+        apply!(self, profile, given: char_delay);
+        apply!(self, profile, given: line_delay);
+        apply!(self, profile, given: escape);
+        if !given("no_init") {
+            apply!(self, profile, given: init);
+        }
+        apply!(self, profile, given: init_delay);
+        if !given("init") {
+            apply!(self, profile, given: no_init);
+        }
+        apply!(self, profile, given: xmodem_1k);
+        apply!(self, profile, given: zmodem_dir);
+        apply!(self, profile, given: line_mode);
+        apply!(self, profile, given: local_echo);
+        apply!(self, profile, given: history_file);
+        apply!(self, profile, given: send_newline);
+        apply!(self, profile, given: receive_newline);
+        apply!(self, profile, given: defmt);
+        apply!(self, profile, given: cobs);
+        apply!(self, profile, given: slip);
+        apply!(self, profile, given: framing);
+        apply!(self, profile, given: cbor);
+        apply!(self, profile, given: proto_desc);
+        apply!(self, profile, given: proto_type);
+        apply!(self, profile, given: nmea);
+        apply!(self, profile, given: plot);
+        apply!(self, profile, given: plot_window);
+        apply!(self, profile, given: csv);
+        apply!(self, profile, given: hooks);
+        apply!(self, profile, given: plugin);
+        apply!(self, profile, given: timestamps);
+        apply!(self, profile, given: timestamp_format);
+        apply!(self, profile, given: zephyr);
+        apply!(self, profile, given: mouse);
+        apply!(self, profile, given: inline);
+        apply!(self, profile, given: pipe_timeout);
+        apply!(self, profile, given: notify);
+        apply!(self, profile, given: notify_on);
+        apply!(self, profile, given: on_connect);
+        apply!(self, profile, given: on_disconnect);
+        apply!(self, profile, given: copy_command);
+        apply!(self, profile, given: battery_alert);
+        apply!(self, profile, given: device_info);
+        if let Some(highlight) = &profile.highlight {
+            self.highlight = highlight.clone();
+        }
+        if let Some(trigger) = &profile.trigger {
+            self.trigger = trigger.clone();
+        }
+        if let Some(keys) = &profile.keys {
+            self.keys = keys.clone();
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct InfoArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+}
+
+impl ProfileArgs for InfoArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct GattArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Read a characteristic, by UUID or the short form of a standard one like 2a19
+    #[arg(long, value_name = "UUID", value_parser = gatt::parse_uuid)]
+    pub read: Vec<Uuid>,
+
+    /// Print the notifications of a characteristic until Ctrl+C is pressed
+    #[arg(long, value_name = "UUID", value_parser = gatt::parse_uuid)]
+    pub subscribe: Vec<Uuid>,
+}
+
+impl ProfileArgs for GattArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SendFileArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// File to send
+    pub file: PathBuf,
+
+    /// Pause between chunks in milliseconds, for devices that cannot keep up
+    #[arg(long, default_value_t = 0)]
+    pub chunk_delay: u64,
+}
+
+impl ProfileArgs for SendFileArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+        apply!(self, profile, given: chunk_delay);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BridgeArgs {
+    #[command(subcommand)]
+    pub kind: BridgeKind,
+
+    /// Serve Prometheus metrics of the link on this address, at /metrics
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Serve an HTTP API to send data, log and reconnect on this address
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub api: Option<SocketAddr>,
+
+    /// Token API requests have to send as `Authorization: Bearer <TOKEN>` [default: random,
+    /// printed at startup]
+    #[arg(
+        long,
+        global = true,
+        value_name = "TOKEN",
+        env = "NUS_API_TOKEN",
+        hide_env_values = true
+    )]
+    pub api_token: Option<String>,
+
+    /// Directory `POST /log/start` creates logs in, the API cannot start logs without it
+    #[arg(long, global = true, value_name = "DIR")]
+    pub api_log_dir: Option<PathBuf>,
+
+    /// Run as a service: reconnect forever and log to the journal under systemd
+    #[arg(long, global = true)]
+    pub daemon: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BridgeKind {
+    /// Serve the device as a raw TCP socket
+    Tcp(TcpBridgeArgs),
+    /// Serve the device as a pseudo-terminal, for programs expecting a serial port
+    Pty(PtyBridgeArgs),
+    /// Serve the device on a Unix domain socket, for local programs
+    Unix(UnixBridgeArgs),
+    /// Publish received lines to an MQTT broker and write messages from it to the device
+    Mqtt(Box<MqttBridgeArgs>),
+    /// Serve the device over WebSocket, for browser dashboards and scripts
+    Ws(WsBridgeArgs),
+    /// Forward SLIP frames of the device as IP packets through a TUN interface (Linux)
+    Tun(TunBridgeArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct TcpBridgeArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Address and port to accept connections on
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    pub listen: SocketAddr,
+
+    /// Negotiate character mode with telnet clients and strip their commands from the input
+    #[arg(long)]
+    pub telnet: bool,
+}
+
+impl ProfileArgs for TcpBridgeArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PtyBridgeArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Symbolic link to create for the terminal, e.g. /tmp/ttyNUS0
+    #[arg(long)]
+    pub link: Option<PathBuf>,
+}
+
+impl ProfileArgs for PtyBridgeArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct UnixBridgeArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Path of the socket, e.g. /run/nus0.sock
+    #[arg(long, visible_alias = "unix-socket")]
+    pub path: PathBuf,
+
+    /// Serve several clients at once instead of one after the other
+    #[arg(long)]
+    pub shared: bool,
+}
+
+impl ProfileArgs for UnixBridgeArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct MqttBridgeArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Broker to connect to, mqtt://host[:port] or mqtts://host[:port] for TLS
+    #[arg(long, value_name = "URL", default_value = "mqtt://localhost:1883")]
+    pub broker: String,
+
+    /// PEM file of the CAs verifying an mqtts:// broker, instead of the well-known ones
+    #[arg(long, value_name = "FILE")]
+    pub ca_file: Option<PathBuf>,
+
+    /// PEM file of the certificate to authenticate with over TLS
+    #[arg(long, value_name = "FILE", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of --client-cert
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// Topic received lines are published to, {name} and {address} are replaced by the device's
+    #[arg(long, value_name = "TEMPLATE", default_value = "nus/{name}/rx")]
+    pub publish_topic: String,
+
+    /// Topic whose messages are written to the device, with the same placeholders
+    #[arg(long, value_name = "TEMPLATE", default_value = "nus/{name}/tx")]
+    pub subscribe_topic: String,
+
+    /// Client identifier, with the same placeholders [default: nus-<address>]
+    #[arg(long, value_name = "TEMPLATE")]
+    pub client_id: Option<String>,
+
+    #[arg(long)]
+    pub username: Option<String>,
+
+    #[arg(long, requires = "username")]
+    pub password: Option<String>,
+
+    /// Quality of service for published messages and the subscription
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=1))]
+    pub qos: u8,
+
+    /// Seconds between keep-alive pings, 0 disables them
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub keep_alive: u16,
+
+    /// Line ending appended to each message written to the device
+    #[arg(long, value_enum)]
+    pub send_newline: Option<Newline>,
+}
+
+impl ProfileArgs for MqttBridgeArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+        apply!(self, profile, given: send_newline);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct WsBridgeArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Address and port to accept WebSocket connections on
+    #[arg(long, visible_alias = "ws-listen", default_value = "127.0.0.1:8765")]
+    pub listen: SocketAddr,
+
+    /// Origin of a web page allowed to connect, e.g. http://localhost:3000 or * for any, can be
+    /// repeated; browsers from other pages are rejected
+    #[arg(long = "origin", visible_alias = "ws-origin", value_name = "ORIGIN")]
+    pub origins: Vec<String>,
+
+    /// Exchange JSON messages like {"type":"rx","data":"..."} instead of raw frames
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl ProfileArgs for WsBridgeArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct TunBridgeArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Name of the interface to create, %d is replaced by the first free number
+    #[arg(long, default_value = "nus%d")]
+    pub interface: String,
+}
+
+impl ProfileArgs for TunBridgeArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ScriptArgs {
+    #[command(subcommand)]
+    pub command: ScriptCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScriptCommand {
+    /// Run a script against a device, exiting with 1 if it fails
+    Run(Box<ScriptRunArgs>),
+    /// Check a script for syntax errors without connecting
+    Check {
+        /// Script file
+        file: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct ScriptRunArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Script file
+    pub file: PathBuf,
+
+    /// Seconds an expect waits unless the script sets another timeout
+    #[arg(long, default_value_t = 10)]
+    pub timeout: u64,
+
+    /// Do not copy the device output to stdout
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+impl ProfileArgs for ScriptRunArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Test file
+    pub file: PathBuf,
+
+    /// Write the JUnit report to this file instead of stdout
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+}
+
+impl ProfileArgs for TestArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DfuArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Firmware image to upload to the secondary slot, e.g. build/zephyr/app_update.bin
+    #[arg(long)]
+    pub image: Option<PathBuf>,
+
+    /// List the images on the device, the default without other actions
+    #[arg(long)]
+    pub list: bool,
+
+    /// Boot the uploaded image once on the next reset, reverting unless it confirms itself
+    #[arg(long, requires = "image")]
+    pub test: bool,
+
+    /// Keep the uploaded image for good, or the running image without --image
+    #[arg(long, conflicts_with = "test")]
+    pub confirm: bool,
+
+    /// Reset the device at the end
+    #[arg(long)]
+    pub reset: bool,
+
+    /// Image bytes sent per request instead of as many as fit into one write
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u16).range(1..))]
+    pub chunk_size: Option<u16>,
+}
+
+impl ProfileArgs for DfuArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    #[command(subcommand)]
+    pub command: BenchCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BenchCommand {
+    /// Push data through the link and report the transfer rate
+    Throughput(ThroughputArgs),
+    /// Measure the round trip time of probes answered by the device
+    Ping(PingArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ThroughputArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Which way data goes, receive and both need a firmware that sends it
+    #[arg(long, value_enum, default_value_t = Direction::Send)]
+    pub direction: Direction,
+
+    /// Bytes to send, and to expect from the device when receiving
+    #[arg(long, default_value_t = 65536)]
+    pub bytes: usize,
+
+    /// Bytes per write [default: as many as the MTU allows]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u16).range(1..))]
+    pub payload: Option<u16>,
+
+    /// Line sent before the test, e.g. a shell command making the firmware stream data
+    #[arg(long, value_name = "LINE")]
+    pub start: Option<String>,
+
+    /// What ends the line given with --start
+    #[arg(long, value_enum, default_value_t = Newline::Cr)]
+    pub send_newline: Newline,
+
+    /// Count received bytes that differ from the test pattern, e.g. for an echoing firmware
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Connection interval of the link in milliseconds, to report packets per interval
+    #[arg(long, value_name = "MS")]
+    pub conn_interval: Option<f64>,
+
+    /// Milliseconds to wait for more data from the device before ending the test
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    pub timeout: u64,
+}
+
+impl ProfileArgs for ThroughputArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+        apply!(self, profile, given: send_newline);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PingArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Number of probes to send
+    #[arg(short, long, default_value_t = 20)]
+    pub count: u32,
+
+    /// Milliseconds between probes
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub interval: u64,
+
+    /// Milliseconds after which a probe without an answer counts as lost
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    pub timeout: u64,
+
+    /// Line sent for each probe, `{probe}` is replaced by the probe, e.g. "echo {probe}"
+    #[arg(long, value_name = "LINE", default_value = "{probe}")]
+    pub probe: String,
+
+    /// Pad each probe to this many bytes
+    #[arg(long, value_name = "BYTES")]
+    pub size: Option<usize>,
+
+    /// Send the probes without a line ending, for firmwares echoing whatever they receive
+    #[arg(long)]
+    pub loopback: bool,
+
+    /// What ends each probe line
+    #[arg(long, value_enum, default_value_t = Newline::Cr)]
+    pub send_newline: Newline,
+}
+
+impl ProfileArgs for PingArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+        apply!(self, profile, given: send_newline);
+    }
+}
+
+/// The UART service, a preset for common modules or the UUIDs of another NUS-like service
+#[derive(Args, Debug)]
+pub struct UuidArgs {
+    /// UART service of the device
+    #[arg(long, value_enum, default_value_t = Protocol::Nus)]
+    pub protocol: Protocol,
+
+    /// UUID of the UART service [default: the one of --protocol]
+    #[arg(long)]
+    pub service_uuid: Option<Uuid>,
+
+    /// UUID of the characteristic written to send data to the device [default: the one of
+    /// --protocol]
+    #[arg(long)]
+    pub rx_uuid: Option<Uuid>,
+
+    /// UUID of the characteristic notifying data received from the device [default: the one of
+    /// --protocol]
+    #[arg(long)]
+    pub tx_uuid: Option<Uuid>,
+}
+
+impl UuidArgs {
+    pub fn service(&self) -> Uuid {
+        NusUuids::from(self).service
+    }
+}
+
+impl From<&UuidArgs> for NusUuids {
+    fn from(args: &UuidArgs) -> Self {
+        let preset = args.protocol.uuids();
+        NusUuids {
+            service: args.service_uuid.unwrap_or(preset.service),
+            rx: args.rx_uuid.unwrap_or(preset.rx),
+            tx: args.tx_uuid.unwrap_or(preset.tx),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Recording (.cast) or log file with timestamps
+    pub file: PathBuf,
+
+    /// Playback speed, 2 plays twice as fast
+    #[arg(short, long, default_value_t = 1.0)]
+    pub speed: f64,
+
+    /// Shorten pauses longer than this many seconds to it
+    #[arg(long, value_name = "SECONDS")]
+    pub max_idle: Option<f64>,
+
+    /// strftime format of the timestamps in a log file
+    #[arg(long, value_name = "FORMAT", default_value = timestamp::DEFAULT_FORMAT)]
+    pub timestamp_format: String,
+}
+
+#[derive(Args, Debug)]
+pub struct SelfTestArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Bytes of pseudo-random data to send
+    #[arg(long, default_value_t = 16384)]
+    pub bytes: usize,
+
+    /// Most bytes sent but not echoed yet, at most what the device buffers
+    #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+    pub window: usize,
+
+    /// Seed of the data, to repeat a failed run [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Milliseconds to wait for the echo before ending the test
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    pub timeout: u64,
+
+    /// Test against the built-in emulator, advertised on this second adapter (Linux)
+    #[arg(long, value_name = "ADAPTER")]
+    pub emulate: Option<String>,
+}
+
+impl ProfileArgs for SelfTestArgs {
+    fn device(&self) -> &DeviceArgs {
+        &self.device
+    }
+
+    fn apply_profile(&mut self, profile: &Profile, given: &dyn Fn(&str) -> bool) {
+        self.device.apply_profile(profile, given);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct EmulateArgs {
+    /// Bluetooth adapter to advertise on, by index, address or name [default: the first]
+    #[arg(long)]
+    pub adapter: Option<String>,
+
+    /// Name to advertise
+    #[arg(long, default_value = "nus-emulator")]
+    pub name: String,
+
+    /// What the emulated device sends
+    #[arg(long, value_enum, default_value_t = Behavior::Echo)]
+    pub behavior: Behavior,
+
+    /// Pause between the lines of the test pattern, in milliseconds
+    #[arg(long, default_value_t = 100)]
+    pub interval: u64,
+
+    /// UART service to emulate
+    #[arg(long, value_enum, default_value_t = Protocol::Nus)]
+    pub protocol: Protocol,
+}
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    /// Bluetooth adapter to use, by index, address or name (like hci0) [default: the first]
+    #[arg(long)]
+    pub adapter: Option<String>,
+
+    /// Scan duration in seconds
+    #[arg(short, long, default_value_t = 5)]
+    pub duration: u64,
+
+    /// List all devices, not only the ones advertising NUS
+    #[arg(long)]
+    pub all: bool,
+
+    /// UART service devices have to advertise
+    #[arg(long, value_enum, default_value_t = Protocol::Nus)]
+    pub protocol: Protocol,
+
+    /// UUID of the UART service devices have to advertise [default: the one of --protocol]
+    #[arg(long)]
+    pub service_uuid: Option<Uuid>,
+
+    /// Print the results as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn searches(args: &[&str]) -> Vec<Search> {
+        let cli = Cli::try_parse_from(["nus_terminal"].iter().chain(args)).unwrap();
+        cli.connect.device.searches()
+    }
+
+    #[test]
+    fn devices_given_by_name_or_address_need_not_advertise_the_service() {
+        let searches = searches(&["--name", "sensor", "--address", "00:11:22:33:44:55"]);
+        assert_eq!(searches.len(), 2);
+        assert!(matches!(&searches[0].filter, DeviceFilter::Name(name) if name == "sensor"));
+        assert!(matches!(&searches[1].filter, DeviceFilter::Address(_)));
+        assert!(searches.iter().all(|search| search.service.is_none()));
+    }
+
+    #[test]
+    fn any_device_has_to_advertise_the_service() {
+        let service = NusUuids::default().service;
+        let searches = searches(&["--any"]);
+        assert!(matches!(searches[0].filter, DeviceFilter::Service(uuid) if uuid == service));
+        assert_eq!(searches[0].service, Some(service));
+    }
+}
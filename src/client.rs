@@ -0,0 +1,206 @@
+//! High level API for embedding NUS connectivity in other programs
+
+use crate::adapter;
+use crate::device::{self, DeviceFilter, DeviceInfo, Search};
+use crate::error::Error;
+use crate::link::{Link, LinkOptions, Notifications};
+use anyhow::Result;
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Adapter;
+use futures::sink::{self, Sink};
+use futures::stream::{self, Stream, StreamExt};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use uuid::Uuid;
+
+type WriteFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Scans for and connects to NUS devices using one Bluetooth adapter
+#[derive(Debug, Clone)]
+pub struct NusClient {
+    adapter: Adapter,
+}
+
+impl NusClient {
+    /// Uses the first Bluetooth adapter of the system
+    pub async fn new() -> Result<NusClient> {
+        Ok(NusClient::with_adapter(adapter::select(None).await?))
+    }
+
+    /// Uses the adapter with the given index, address or name (like `hci0`)
+    pub async fn with_adapter_selector(selector: &str) -> Result<NusClient> {
+        Ok(NusClient::with_adapter(
+            adapter::select(Some(selector)).await?,
+        ))
+    }
+
+    pub fn with_adapter(adapter: Adapter) -> NusClient {
+        NusClient { adapter }
+    }
+
+    pub fn adapter(&self) -> &Adapter {
+        &self.adapter
+    }
+
+    /// Scans for the given time, returns all discovered devices with the strongest signal first
+    ///
+    /// Only devices advertising one of `services` are returned, unless it is empty.
+    pub async fn scan(&self, duration: Duration, services: &[Uuid]) -> Result<Vec<DeviceInfo>> {
+        device::scan(&self.adapter, duration, services).await?;
+        device::discover(&self.adapter).await
+    }
+
+    /// Connects to `device` and subscribes to the data it sends
+    pub async fn connect(&self, device: &DeviceInfo, options: &LinkOptions) -> Result<Connection> {
+        let (link, notifications) = Link::open(device.peripheral.clone(), options).await?;
+        Ok(Connection {
+            link,
+            notifications,
+            received: Vec::new(),
+            write: None,
+        })
+    }
+
+    /// Connects to the first device accepted by `filter` that advertises the service of
+    /// `options`, as soon as it shows up
+    ///
+    /// Scanning gives up after `timeout`, unless it is `None`.
+    pub async fn connect_to(
+        &self,
+        filter: &DeviceFilter,
+        timeout: Option<Duration>,
+        options: &LinkOptions,
+    ) -> Result<Connection> {
+        let search = Search {
+            filter: filter.clone(),
+            service: Some(options.uuids.service),
+            timeout,
+        };
+        let devices = device::wait_for(&self.adapter, &search).await?;
+        let device = devices
+            .first()
+            .ok_or(Error::DeviceNotFound(filter.clone(), timeout))?;
+        self.connect(device, options).await
+    }
+}
+
+/// An open connection to a NUS device
+///
+/// Besides the methods below, the connection is a byte stream through [`AsyncRead`] and
+/// [`AsyncWrite`]. Reading returns end of file once the device disconnected. A write takes
+/// at most one MTU worth of data and is sent in the background, its error is returned by the
+/// next write or flush.
+pub struct Connection {
+    link: Link,
+    notifications: Notifications,
+    /// Data of a notification that did not fit into the last read
+    received: Vec<u8>,
+    /// The write sent in the background
+    write: Option<WriteFuture>,
+}
+
+impl Connection {
+    pub fn link(&self) -> &Link {
+        &self.link
+    }
+
+    /// Splits the connection into a stream of received data and a sink writing to the device
+    ///
+    /// The stream ends when the device disconnects. Each item written to the sink is split into
+    /// as many writes as the MTU requires.
+    pub fn split(
+        self,
+    ) -> (
+        impl Stream<Item = Vec<u8>> + Send + Unpin,
+        impl Sink<Vec<u8>, Error = anyhow::Error>,
+    ) {
+        let leftover = (!self.received.is_empty()).then_some(self.received);
+        let notifications = self.notifications.map(|notification| notification.value);
+        let reader = stream::iter(leftover).chain(notifications);
+        let writer = sink::unfold(self.link, |link, data: Vec<u8>| async move {
+            link.write(&data).await?;
+            Ok(link)
+        });
+        (reader, writer)
+    }
+
+    /// Waits for the next data sent by the device, `None` once it disconnected
+    pub async fn receive(&mut self) -> Option<Vec<u8>> {
+        if !self.received.is_empty() {
+            return Some(std::mem::take(&mut self.received));
+        }
+        self.notifications.next().await.map(|n| n.value)
+    }
+
+    /// Writes `data` and waits until it is sent
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        self.link.write(data).await
+    }
+
+    pub async fn disconnect(self) -> Result<()> {
+        self.link.peripheral.disconnect().await?;
+        Ok(())
+    }
+}
+
+impl Connection {
+    /// Drives the background write to completion
+    fn poll_write_done(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if let Some(write) = &mut self.write {
+            let result = ready!(write.as_mut().poll(cx));
+            self.write = None;
+            result.map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.received.is_empty() {
+            match ready!(this.notifications.poll_next_unpin(cx)) {
+                Some(notification) => this.received = notification.value,
+                // End of file
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = this.received.len().min(buf.remaining());
+        buf.put_slice(&this.received[..n]);
+        this.received.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_done(cx))?;
+        let n = buf.len().min(this.link.max_payload());
+        let link = this.link.clone();
+        let data = buf[..n].to_vec();
+        let mut write: WriteFuture = Box::pin(async move { link.write(&data).await });
+        // Writes without response usually complete right away
+        match write.as_mut().poll(cx) {
+            Poll::Ready(result) => result.map_err(io::Error::other)?,
+            Poll::Pending => this.write = Some(write),
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx)
+    }
+}
@@ -0,0 +1,168 @@
+//! Consistent Overhead Byte Stuffing, framing packets with a 0x00 delimiter
+
+/// Encodes `data` as a frame, including the trailing delimiter
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    // Position of the code byte of the current block
+    let mut code_pos = 0;
+    out.push(0);
+    for (i, &b) in data.iter().enumerate() {
+        if b == 0 {
+            out[code_pos] = (out.len() - code_pos) as u8;
+            code_pos = out.len();
+            out.push(0);
+            continue;
+        }
+        out.push(b);
+        // A full block at the end needs no empty block after it
+        if out.len() - code_pos == 0xff && i + 1 < data.len() {
+            out[code_pos] = 0xff;
+            code_pos = out.len();
+            out.push(0);
+        }
+    }
+    out[code_pos] = (out.len() - code_pos) as u8;
+    out.push(0);
+    out
+}
+
+/// Decodes a single frame without its delimiter, `None` if it is malformed
+pub fn decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut rest = frame;
+    while let Some((&code, tail)) = rest.split_first() {
+        let len = usize::from(code).checked_sub(1)?;
+        if len > tail.len() || tail[..len].contains(&0) {
+            return None;
+        }
+        out.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+        // A block shorter than the maximum stands for the data up to a zero
+        if code != 0xff && !rest.is_empty() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+/// Splits received data into frames
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// Start of a frame whose delimiter was not received yet
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    /// Returns the frames completed by `data`, `None` for the malformed ones
+    pub fn push(&mut self, data: &[u8]) -> Vec<Option<Vec<u8>>> {
+        let mut frames = Vec::new();
+        for &b in data {
+            if b != 0 {
+                self.buffer.push(b);
+            } else if !self.buffer.is_empty() {
+                frames.push(decode(&self.buffer));
+                self.buffer.clear();
+            }
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Examples of the COBS paper, unencoded and encoded with the delimiter
+    fn vectors() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let run: Vec<u8> = (0x01..=0xfe).collect();
+        vec![
+            (vec![0x00], vec![0x01, 0x01, 0x00]),
+            (vec![0x00, 0x00], vec![0x01, 0x01, 0x01, 0x00]),
+            (
+                vec![0x11, 0x22, 0x00, 0x33],
+                vec![0x03, 0x11, 0x22, 0x02, 0x33, 0x00],
+            ),
+            (
+                vec![0x11, 0x22, 0x33, 0x44],
+                vec![0x05, 0x11, 0x22, 0x33, 0x44, 0x00],
+            ),
+            (
+                vec![0x11, 0x00, 0x00, 0x00],
+                vec![0x02, 0x11, 0x01, 0x01, 0x01, 0x00],
+            ),
+            // 254 non-zero bytes fill a block exactly
+            (run.clone(), [&[0xff], &run[..], &[0x00]].concat()),
+            // 255 bytes starting with a zero
+            (
+                [&[0x00], &run[..]].concat(),
+                [&[0x01, 0xff], &run[..], &[0x00]].concat(),
+            ),
+            // 255 non-zero bytes spill into a second block
+            (
+                (0x01..=0xff).collect(),
+                [&[0xff], &run[..], &[0x02, 0xff, 0x00]].concat(),
+            ),
+            (
+                [&(0x02..=0xff).collect::<Vec<u8>>()[..], &[0x00]].concat(),
+                [
+                    &[0xff],
+                    &(0x02..=0xff).collect::<Vec<u8>>()[..],
+                    &[0x01, 0x01, 0x00],
+                ]
+                .concat(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn encodes_the_paper_examples() {
+        for (data, frame) in vectors() {
+            assert_eq!(encode(&data), frame, "{data:02x?}");
+        }
+        assert_eq!(encode(&[]), [0x01, 0x00]);
+    }
+
+    #[test]
+    fn decodes_the_paper_examples() {
+        for (data, frame) in vectors() {
+            assert_eq!(decode(&frame[..frame.len() - 1]), Some(data));
+        }
+        assert_eq!(decode(&[0x01]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        // A code of zero, a block running past the end and a zero inside a block
+        assert_eq!(decode(&[0x00]), None);
+        assert_eq!(decode(&[0x05, 0x11, 0x22]), None);
+        assert_eq!(decode(&[0x03, 0x11, 0x00]), None);
+    }
+
+    #[test]
+    fn decoder_joins_frames_split_across_pushes() {
+        let data: Vec<Vec<u8>> = vectors().into_iter().map(|(data, _)| data).collect();
+        let stream: Vec<u8> = data.iter().flat_map(|data| encode(data)).collect();
+        for split in [1, 2, 3, 7, 254, 255, 256] {
+            let mut decoder = Decoder::default();
+            let frames: Vec<_> = stream
+                .chunks(split)
+                .flat_map(|chunk| decoder.push(chunk))
+                .collect();
+            let expected: Vec<_> = data.iter().cloned().map(Some).collect();
+            assert_eq!(frames, expected, "split every {split} bytes");
+        }
+    }
+
+    #[test]
+    fn decoder_skips_empty_frames_and_reports_malformed_ones() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.push(&[0x00, 0x00, 0x03, 0x11]),
+            Vec::<Option<Vec<u8>>>::new()
+        );
+        assert_eq!(
+            decoder.push(&[0x00, 0x02, 0x22, 0x00]),
+            [None, Some(vec![0x22])]
+        );
+    }
+}
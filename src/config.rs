@@ -0,0 +1,387 @@
+//! Configuration file with named device profiles
+//!
+//! ```toml
+//! [profiles.devkit]
+//! name = "DevKit"
+//! log = "/tmp/devkit.log"
+//! log_timestamps = true
+//! ```
+
+use crate::flow::FlowControl;
+use crate::highlight::{self, Highlight};
+use crate::init::{self, InitCommand};
+use crate::length_prefix::LengthPrefix;
+use crate::link::WriteMode;
+use crate::macros::{self, Macro};
+use crate::menu::EscapeKey;
+use crate::nus::Protocol;
+use crate::plot;
+use crate::screen::Newline;
+use crate::timestamp::Kind as TimestampKind;
+use crate::toml::{self, Table, Value};
+use crate::trigger::{self, Trigger};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Settings applied by `--profile`, each overridden by the corresponding command line option
+#[derive(Debug, Default, Clone)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub address: Option<String>,
+    pub any: Option<bool>,
+    pub adapter: Option<String>,
+    pub scan_timeout: Option<u64>,
+    pub wait: Option<bool>,
+    pub protocol: Option<Protocol>,
+    pub service_uuid: Option<Uuid>,
+    pub rx_uuid: Option<Uuid>,
+    pub tx_uuid: Option<Uuid>,
+    pub mtu: Option<u16>,
+    pub write_mode: Option<WriteMode>,
+    pub pair: Option<bool>,
+    pub connect_timeout: Option<u64>,
+    pub discover_timeout: Option<u64>,
+    pub connect_retries: Option<u32>,
+    pub auto_detect: Option<bool>,
+    pub log: Option<PathBuf>,
+    pub log_timestamps: Option<bool>,
+    pub capture_raw: Option<PathBuf>,
+    pub capture_framing: Option<bool>,
+    pub pcap: Option<PathBuf>,
+    pub stats_json: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub zephyr: Option<bool>,
+    pub mouse: Option<bool>,
+    pub inline: Option<bool>,
+    pub pipe_timeout: Option<u64>,
+    pub notify: Option<bool>,
+    pub notify_on: Option<Vec<Trigger>>,
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    pub copy_command: Option<String>,
+    pub battery_alert: Option<u8>,
+    pub device_info: Option<bool>,
+    pub record_input: Option<bool>,
+    pub hex: Option<bool>,
+    pub scrollback: Option<usize>,
+    pub send_page_keys: Option<bool>,
+    pub reconnect_retries: Option<u32>,
+    pub reconnect_delay: Option<u64>,
+    pub reconnect_max_delay: Option<u64>,
+    pub reconnect_buffer: Option<usize>,
+    pub keepalive: Option<u64>,
+    pub keepalive_data: Option<String>,
+    pub stall_timeout: Option<u64>,
+    pub stall_reconnect: Option<bool>,
+    pub chunk_delay: Option<u64>,
+    pub flow_control: Option<FlowControl>,
+    pub reliable: Option<bool>,
+    pub char_delay: Option<u64>,
+    pub line_delay: Option<u64>,
+    pub escape: Option<EscapeKey>,
+    pub init: Option<Vec<InitCommand>>,
+    pub init_delay: Option<u64>,
+    pub no_init: Option<bool>,
+    pub xmodem_1k: Option<bool>,
+    pub zmodem_dir: Option<PathBuf>,
+    pub line_mode: Option<bool>,
+    pub local_echo: Option<bool>,
+    pub history_file: Option<PathBuf>,
+    pub send_newline: Option<Newline>,
+    pub receive_newline: Option<Newline>,
+    pub defmt: Option<PathBuf>,
+    pub cobs: Option<bool>,
+    pub slip: Option<bool>,
+    pub framing: Option<LengthPrefix>,
+    pub cbor: Option<bool>,
+    pub proto_desc: Option<PathBuf>,
+    pub proto_type: Option<String>,
+    pub nmea: Option<bool>,
+    pub plot: Option<Regex>,
+    pub plot_window: Option<u64>,
+    pub csv: Option<PathBuf>,
+    pub hooks: Option<PathBuf>,
+    pub plugin: Option<PathBuf>,
+    pub timestamps: Option<TimestampKind>,
+    pub timestamp_format: Option<String>,
+    /// Highlight rules added to those applying to all sessions
+    pub highlight: Option<Vec<Highlight>>,
+    /// Triggers added to those applying to all sessions
+    pub trigger: Option<Vec<Trigger>>,
+    /// Macros added to those applying to all sessions, replacing them for the same key
+    pub keys: Option<Vec<Macro>>,
+}
+
+/// Location of the configuration file: `$XDG_CONFIG_HOME/nus-terminal/config.toml`, falling
+/// back to `~/.config/nus-terminal/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("nus-terminal").join("config.toml"))
+}
+
+fn read(path: &Path) -> Result<Table> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read config file {}", path.display()))?;
+    toml::parse(&content).with_context(|| format!("Could not parse config file {}", path.display()))
+}
+
+/// Highlights, triggers and macro keys applying to all sessions
+#[derive(Debug, Default)]
+pub struct Rules {
+    pub highlight: Vec<Highlight>,
+    pub trigger: Vec<Trigger>,
+    pub keys: Vec<Macro>,
+}
+
+/// Loads the rules applying to all sessions from the configuration file at `path`, or the
+/// default one if it exists
+pub fn load_rules(path: Option<&Path>) -> Result<Rules> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Rules::default()),
+        },
+    };
+    let config = read(&path)?;
+    let rules = || -> Result<Rules> {
+        Ok(Rules {
+            highlight: config
+                .get("highlight")
+                .map(highlight::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            trigger: config
+                .get("trigger")
+                .map(trigger::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            keys: config
+                .get("keys")
+                .map(macros::parse)
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    };
+    rules().with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+/// Loads the profile `name` from the configuration file at `path`
+pub fn load_profile(path: &Path, name: &str) -> Result<Profile> {
+    let config = read(path)?;
+
+    let profile = match config.get("profiles") {
+        Some(Value::Table(profiles)) => profiles.get(name),
+        Some(_) => bail!("'profiles' in {} must be a table", path.display()),
+        None => None,
+    };
+    match profile {
+        Some(Value::Table(table)) => {
+            Profile::from_table(table).with_context(|| format!("Invalid profile '{name}'"))
+        }
+        Some(_) => bail!("Profile '{name}' must be a table"),
+        None => bail!("No profile '{name}' in {}", path.display()),
+    }
+}
+
+impl Profile {
+    fn from_table(table: &Table) -> Result<Profile> {
+        let mut profile = Profile::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "name" => profile.name = Some(string(key, value)?),
+                "address" => profile.address = Some(string(key, value)?),
+                "any" => profile.any = Some(boolean(key, value)?),
+                "adapter" => {
+                    profile.adapter = Some(match value {
+                        Value::Integer(index) => index.to_string(),
+                        _ => string(key, value)?,
+                    })
+                }
+                "scan_timeout" => profile.scan_timeout = Some(integer(key, value)?),
+                "wait" => profile.wait = Some(boolean(key, value)?),
+                "protocol" => {
+                    let protocol = string(key, value)?;
+                    profile.protocol = Some(
+                        Protocol::from_str(&protocol, true).map_err(|e| anyhow!("'{key}': {e}"))?,
+                    );
+                }
+                "service_uuid" => profile.service_uuid = Some(uuid(key, value)?),
+                "rx_uuid" => profile.rx_uuid = Some(uuid(key, value)?),
+                "tx_uuid" => profile.tx_uuid = Some(uuid(key, value)?),
+                "mtu" => match integer(key, value)? {
+                    mtu @ 23.. => profile.mtu = Some(mtu),
+                    _ => bail!("'mtu' must be at least 23"),
+                },
+                "write_mode" => {
+                    let mode = string(key, value)?;
+                    profile.write_mode = Some(
+                        WriteMode::from_str(&mode, true).map_err(|e| anyhow!("'{key}': {e}"))?,
+                    );
+                }
+                "pair" => profile.pair = Some(boolean(key, value)?),
+                "connect_timeout" => profile.connect_timeout = Some(integer(key, value)?),
+                "discover_timeout" => profile.discover_timeout = Some(integer(key, value)?),
+                "connect_retries" => profile.connect_retries = Some(integer(key, value)?),
+                "auto_detect" => profile.auto_detect = Some(boolean(key, value)?),
+                "log" => profile.log = Some(expand_home(&string(key, value)?)),
+                "log_timestamps" => profile.log_timestamps = Some(boolean(key, value)?),
+                "capture_raw" => profile.capture_raw = Some(expand_home(&string(key, value)?)),
+                "capture_framing" => profile.capture_framing = Some(boolean(key, value)?),
+                "pcap" => profile.pcap = Some(expand_home(&string(key, value)?)),
+                "mouse" => profile.mouse = Some(boolean(key, value)?),
+                "inline" => profile.inline = Some(boolean(key, value)?),
+                "pipe_timeout" => profile.pipe_timeout = Some(integer(key, value)?),
+                "notify" => profile.notify = Some(boolean(key, value)?),
+                "notify_on" => {
+                    // A pattern or a list of them, like repeating --notify-on
+                    let patterns = match value {
+                        Value::Array(items) => items.as_slice(),
+                        _ => std::slice::from_ref(value),
+                    };
+                    let triggers = patterns
+                        .iter()
+                        .map(|pattern| {
+                            trigger::notify_on(&string(key, pattern)?)
+                                .map_err(|e| anyhow!("'{key}': {e}"))
+                        })
+                        .collect::<Result<_>>()?;
+                    profile.notify_on = Some(triggers);
+                }
+                "on_connect" => profile.on_connect = Some(string(key, value)?),
+                "on_disconnect" => profile.on_disconnect = Some(string(key, value)?),
+                "copy_command" => profile.copy_command = Some(string(key, value)?),
+                "battery_alert" => match integer(key, value)? {
+                    percent @ 1..=100 => profile.battery_alert = Some(percent),
+                    _ => bail!("'battery_alert' must be from 1 to 100"),
+                },
+                "device_info" => profile.device_info = Some(boolean(key, value)?),
+                "zephyr" => profile.zephyr = Some(boolean(key, value)?),
+                "stats_json" => profile.stats_json = Some(expand_home(&string(key, value)?)),
+                "record" => profile.record = Some(expand_home(&string(key, value)?)),
+                "record_input" => profile.record_input = Some(boolean(key, value)?),
+                "hex" => profile.hex = Some(boolean(key, value)?),
+                "scrollback" => profile.scrollback = Some(integer(key, value)?),
+                "send_page_keys" => profile.send_page_keys = Some(boolean(key, value)?),
+                "reconnect_retries" => profile.reconnect_retries = Some(integer(key, value)?),
+                "reconnect_delay" => profile.reconnect_delay = Some(integer(key, value)?),
+                "reconnect_max_delay" => profile.reconnect_max_delay = Some(integer(key, value)?),
+                "reconnect_buffer" => profile.reconnect_buffer = Some(integer(key, value)?),
+                "keepalive" => profile.keepalive = Some(integer(key, value)?),
+                "keepalive_data" => {
+                    let data = string(key, value)?;
+                    init::unescape(&data).with_context(|| format!("'{key}'"))?;
+                    profile.keepalive_data = Some(data);
+                }
+                "stall_timeout" => profile.stall_timeout = Some(integer(key, value)?),
+                "stall_reconnect" => profile.stall_reconnect = Some(boolean(key, value)?),
+                "chunk_delay" => profile.chunk_delay = Some(integer(key, value)?),
+                "flow_control" => {
+                    let mode = string(key, value)?;
+                    profile.flow_control = Some(
+                        FlowControl::from_str(&mode, true).map_err(|e| anyhow!("'{key}': {e}"))?,
+                    );
+                }
+                "reliable" => profile.reliable = Some(boolean(key, value)?),
+                "char_delay" => profile.char_delay = Some(integer(key, value)?),
+                "line_delay" => profile.line_delay = Some(integer(key, value)?),
+                "escape" => {
+                    let escape = string(key, value)?;
+                    profile.escape = Some(escape.parse().map_err(|e| anyhow!("'{key}': {e}"))?);
+                }
+                "init" => profile.init = Some(init::parse_profile(value)?),
+                "init_delay" => profile.init_delay = Some(integer(key, value)?),
+                "no_init" => profile.no_init = Some(boolean(key, value)?),
+                "xmodem_1k" => profile.xmodem_1k = Some(boolean(key, value)?),
+                "zmodem_dir" => profile.zmodem_dir = Some(expand_home(&string(key, value)?)),
+                "line_mode" => profile.line_mode = Some(boolean(key, value)?),
+                "local_echo" => profile.local_echo = Some(boolean(key, value)?),
+                "send_newline" => profile.send_newline = Some(newline(key, value)?),
+                "receive_newline" => profile.receive_newline = Some(newline(key, value)?),
+                "history_file" => profile.history_file = Some(expand_home(&string(key, value)?)),
+                "defmt" => profile.defmt = Some(expand_home(&string(key, value)?)),
+                "cobs" => profile.cobs = Some(boolean(key, value)?),
+                "slip" => profile.slip = Some(boolean(key, value)?),
+                "cbor" => profile.cbor = Some(boolean(key, value)?),
+                "proto_desc" => profile.proto_desc = Some(expand_home(&string(key, value)?)),
+                "proto_type" => profile.proto_type = Some(string(key, value)?),
+                "nmea" => profile.nmea = Some(boolean(key, value)?),
+                "plot" => {
+                    let pattern = string(key, value)?;
+                    profile.plot =
+                        Some(plot::pattern(&pattern).map_err(|e| anyhow!("'{key}': {e}"))?);
+                }
+                "plot_window" => profile.plot_window = Some(integer(key, value)?),
+                "csv" => profile.csv = Some(expand_home(&string(key, value)?)),
+                "hooks" => profile.hooks = Some(expand_home(&string(key, value)?)),
+                "plugin" => profile.plugin = Some(expand_home(&string(key, value)?)),
+                "framing" => {
+                    let prefix = string(key, value)?;
+                    profile.framing = Some(
+                        LengthPrefix::from_str(&prefix, true)
+                            .map_err(|e| anyhow!("'{key}': {e}"))?,
+                    );
+                }
+                "timestamps" => {
+                    let kind = string(key, value)?;
+                    let kind = TimestampKind::from_str(&kind, true)
+                        .map_err(|e| anyhow!("'{key}': {e}"))?;
+                    profile.timestamps = Some(kind);
+                }
+                "timestamp_format" => profile.timestamp_format = Some(string(key, value)?),
+                "highlight" => profile.highlight = Some(highlight::parse(value)?),
+                "trigger" => profile.trigger = Some(trigger::parse(value)?),
+                "keys" => profile.keys = Some(macros::parse(value)?),
+                _ => bail!("Unknown setting '{key}'"),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+pub fn type_error(key: &str, expected: &str, value: &Value) -> anyhow::Error {
+    anyhow!("'{key}' must be a {expected}, not a {}", value.type_name())
+}
+
+pub fn string(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(type_error(key, "string", value)),
+    }
+}
+
+pub fn boolean(key: &str, value: &Value) -> Result<bool> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        _ => Err(type_error(key, "boolean", value)),
+    }
+}
+
+pub fn integer<T: TryFrom<i64>>(key: &str, value: &Value) -> Result<T> {
+    match value {
+        Value::Integer(i) => T::try_from(*i).map_err(|_| anyhow!("'{key}' is out of range")),
+        _ => Err(type_error(key, "integer", value)),
+    }
+}
+
+fn newline(key: &str, value: &Value) -> Result<Newline> {
+    Newline::from_str(&string(key, value)?, true).map_err(|e| anyhow!("'{key}': {e}"))
+}
+
+fn uuid(key: &str, value: &Value) -> Result<Uuid> {
+    Uuid::parse_str(&string(key, value)?).map_err(|e| anyhow!("'{key}' is not a UUID: {e}"))
+}
+
+/// Expands a leading `~/` to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
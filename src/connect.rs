@@ -0,0 +1,3154 @@
+use crate::agent;
+use crate::battery;
+use crate::btsnoop::Btsnoop;
+use crate::capture::RawCapture;
+use crate::cast::Recording;
+use crate::cbor;
+use crate::cli::ConnectArgs;
+use crate::cobs;
+use crate::config;
+use crate::decode;
+use crate::defmt;
+use crate::device::{self, DeviceFilter, DeviceInfo, Search};
+use crate::device_info::{self, DeviceInformation};
+use crate::error::Error;
+use crate::flow::{Credits, FlowControl};
+use crate::highlight;
+use crate::hooks;
+use crate::init;
+use crate::length_prefix::{self, LengthPrefix};
+use crate::line_editor::{LineEditor, Outcome};
+use crate::link::{ConnectionState, Fallback, Link, LinkOptions, LinkStatus, Notices, Trace};
+use crate::macros::{Macro, Step};
+use crate::menu::{self, EscapeKey};
+use crate::mtu::ATT_HEADER_LEN;
+use crate::notify;
+use crate::nus::NusUuids;
+use crate::pairing::{Agent, Request};
+use crate::pipe;
+use crate::plot::{Csv, Plot};
+use crate::plugin::{self, Plugin};
+use crate::protobuf;
+use crate::reliable::{self, Channel, Retransmission};
+use crate::screen::{Filter, FrameFormat, Framing, Newline, Screen};
+use crate::session_log::SessionLog;
+use crate::slip;
+use crate::stats::{self, Stats};
+use crate::timestamp::{self, Kind as TimestampKind, Timestamps};
+use crate::transfer::{self, Pacing, Progress, Tap};
+use crate::transport::{self, ByteStream, Transport};
+use crate::trigger::{Action, Triggers};
+use crate::ui::{self, Indicators, OutputRows, Prompt, TabLabel};
+use crate::websocket;
+use crate::xmodem::{self, BlockSize};
+use crate::ymodem;
+use crate::zmodem;
+use anyhow::{Context, Result, anyhow, bail};
+use btleplug::api::{Central, CentralEvent, Peripheral as _};
+use btleplug::platform::{Adapter, Peripheral};
+use clap::ValueEnum;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+use crossterm::{ExecutableCommand, event, terminal};
+use futures::future;
+use futures::stream::StreamExt;
+use log::info;
+use ratatui::Terminal;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Position, Rect};
+use regex::{Regex, RegexBuilder};
+use std::io::{self, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, mpsc};
+
+/// Number of pending input payloads before typing blocks
+const WRITE_QUEUE_LEN: usize = 64;
+
+/// Number of events queued for the `--hooks` script before receiving waits for it
+const HOOK_EVENTS_LEN: usize = 64;
+
+/// How often the signal strength of the connected device is refreshed
+const RSSI_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Interval of looking for frames of the reliable protocol to retransmit
+const RETRANSMIT_CHECK: Duration = Duration::from_millis(100);
+
+/// Time waited for credits before telling the user, who may have forgotten the firmware side
+const CREDIT_WARNING: Duration = Duration::from_secs(3);
+
+/// Lines scrolled by a turn of the mouse wheel
+const MOUSE_SCROLL: isize = 3;
+
+/// Label of the prompt sending hex bytes
+const HEX_PROMPT: &str = "Hex bytes (Esc to close)";
+const FILTER_PROMPT: &str = "Show lines matching (empty for all)";
+const FILTER_OUT_PROMPT: &str = "Hide lines matching (empty for none)";
+const SEARCH_PROMPT: &str = "Search (Up/Down: older/newer match)";
+const SEARCH_NOT_FOUND_PROMPT: &str = "Search, not found (Up/Down: older/newer match)";
+
+/// Shortest time between redraws for new output
+const FRAME_TIME: Duration = Duration::from_millis(16);
+
+/// How often the status bar is redrawn when nothing else changes
+const STATUS_REFRESH: Duration = Duration::from_secs(1);
+
+/// How often the progress of a file transfer is redrawn
+const PROGRESS_REFRESH: Duration = Duration::from_millis(100);
+
+/// Longest time notifications following each other are collected before they are shown
+const BATCH_TIME: Duration = Duration::from_millis(5);
+
+/// Most bytes of notifications shown at once
+const BATCH_LEN: usize = 16 * 1024;
+
+/// Time given to closing the links when the session ends
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// SGR colors of the device labels in the merged view, used in turn
+const LABEL_COLORS: [u8; 6] = [36, 32, 33, 35, 34, 31];
+
+/// How to retry after the link drops
+#[derive(Debug, Clone, Copy)]
+struct ReconnectPolicy {
+    retries: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl From<&ConnectArgs> for ReconnectPolicy {
+    fn from(args: &ConnectArgs) -> Self {
+        ReconnectPolicy {
+            retries: args.reconnect_retries,
+            initial_delay: Duration::from_millis(args.reconnect_delay),
+            max_delay: Duration::from_millis(args.reconnect_max_delay),
+        }
+    }
+}
+
+/// Runs an interactive terminal session with the devices selected by `args`
+///
+/// Returns the exit code requested by a trigger, if one ended the session.
+pub async fn run(central: &Adapter, args: &ConnectArgs) -> Result<Option<i32>> {
+    let searches = args.device.searches();
+    if args.pipe || !io::stdin().is_terminal() {
+        if searches.len() > 1 {
+            bail!("Pipe mode connects to a single device");
+        }
+        return pipe::run(central, args).await.map(|()| None);
+    }
+    if args.reliable && args.flow_control != FlowControl::None {
+        bail!("--reliable cannot be combined with --flow-control");
+    }
+    if args.inline && searches.len() > 1 {
+        bail!("Inline mode connects to a single device");
+    }
+
+    // Local files first, so mistakes show up before waiting for the device
+    let numbered = searches.len() > 1;
+    let files = (1..=searches.len())
+        .map(|n| TabFiles::open(args, numbered.then_some(n)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let line_editor = LineEditor::new(args.history_file.clone())?;
+    let defmt = args
+        .defmt
+        .as_deref()
+        .map(defmt::Decoder::load)
+        .transpose()?;
+    let protobuf = match (&args.proto_desc, &args.proto_type) {
+        (Some(path), Some(message)) => Some(protobuf::Decoder::load(path, message)?),
+        _ => None,
+    };
+    let hooks = args.hooks.as_deref().map(hooks::load).transpose()?;
+    let plugin = args
+        .plugin
+        .as_deref()
+        .map(plugin::Module::load)
+        .transpose()?;
+
+    let timestamps = Timestamps::new(
+        args.timestamps.unwrap_or(TimestampKind::Absolute),
+        &args.timestamp_format,
+    )?;
+    let mut rules = config::load_rules(args.device.config.as_deref())?;
+    rules.highlight.extend(args.highlight.iter().cloned());
+    if args.zephyr {
+        // First, so rules of the user take precedence
+        rules.highlight.splice(0..0, highlight::zephyr());
+    }
+    rules.trigger.extend(args.trigger.iter().cloned());
+    rules.trigger.extend(args.notify_on.iter().cloned());
+    rules.keys.extend(args.keys.iter().cloned());
+    let macros = std::mem::take(&mut rules.keys);
+
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    for search in &searches {
+        let device = device::select(central, search).await?;
+        if devices.iter().any(|d| d.address == device.address) {
+            bail!("{} was selected twice", device.display_name());
+        }
+        devices.push(device);
+    }
+
+    // Interleaves the lines of all devices, when there is more than one
+    let redraw = Arc::new(Notify::new());
+    let merged = (devices.len() > 1).then(|| {
+        let mut view = Screen::new(args.scrollback, decode::Mode::Text, Newline::Lf);
+        view.set_highlights(rules.highlight.clone());
+        view.set_redraw(redraw.clone());
+        Arc::new(Mutex::new(view))
+    });
+    let (pairing_agent, pairing_requests) = mpsc::unbounded_channel();
+    let setup = TabSetup {
+        args,
+        defmt,
+        protobuf,
+        hooks,
+        plugin,
+        timestamps,
+        rules,
+        merged: merged.clone(),
+        exit_code: Arc::new(Mutex::new(None)),
+        pairing: args.device.pair.then_some(pairing_agent),
+        redraw: redraw.clone(),
+    };
+    let mut tabs = Vec::with_capacity(devices.len());
+    for (i, (device, files)) in devices.into_iter().zip(files).enumerate() {
+        tabs.push(setup.open(central, i + 1, device, files).await?);
+    }
+    let exit_code = setup.exit_code;
+    let started = Instant::now();
+
+    let links: Vec<CurrentLink> = tabs.iter().map(|tab| tab.current_link.clone()).collect();
+    let raw_terminal = if args.inline {
+        RawTerminal::enter_inline()?
+    } else {
+        RawTerminal::enter()?
+    };
+    set_panic_hook(links.clone());
+    let terminated = terminated();
+    tokio::pin!(terminated);
+    let mut input = event::EventStream::new();
+    let mut term = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    let mut session = Session {
+        tabs,
+        active: 0,
+        escape: args.escape,
+        chunk_delay: Duration::from_millis(args.chunk_delay),
+        xmodem_block_size: if args.xmodem_1k {
+            BlockSize::OneK
+        } else {
+            BlockSize::Standard
+        },
+        prompt: None,
+        menu: false,
+        line_editor,
+        line_mode: args.line_mode || args.cobs || args.slip || args.framing.is_some(),
+        send_newline: args.send_newline,
+        packets: if args.cobs {
+            Some(Packets::Cobs)
+        } else if args.slip {
+            Some(Packets::Slip)
+        } else {
+            args.framing.map(Packets::Prefixed)
+        },
+        // Inline the terminal scrolls itself, with Shift+PageUp
+        send_page_keys: args.send_page_keys || args.inline,
+        macros,
+        local_echo: args.local_echo,
+        broadcast: false,
+        merged,
+        show_merged: false,
+        help: false,
+        last_search: String::new(),
+        mouse: args.mouse,
+        copy_command: args.copy_command.clone(),
+        output_rows: OutputRows::default(),
+        resume_after_selection: false,
+        mouse_captured: false,
+        inline: args.inline,
+        overlay: None,
+        battery_alert: args.battery_alert,
+        pairing_requests,
+    };
+
+    let mut result = Ok(None);
+    loop {
+        // A lost tab stays open while the others are still connected
+        if session
+            .tabs
+            .iter()
+            .all(|tab| tab.status.lock().unwrap().state == ConnectionState::Lost)
+        {
+            result = Err(Error::ConnectionLost.into());
+            break;
+        }
+        if let Some(code) = *exit_code.lock().unwrap() {
+            result = Ok(Some(code));
+            break;
+        }
+        while let Ok(request) = session.pairing_requests.try_recv() {
+            session.pairing_request(request);
+        }
+
+        let labels = session.tab_labels();
+        for (i, tab) in session.tabs.iter_mut().enumerate() {
+            if i == session.active || session.show_merged {
+                tab.seen_rx = tab.status.lock().unwrap().rx_bytes;
+            }
+        }
+        let progress = session.tab().transfer.lock().unwrap().clone();
+        if session.inline {
+            session.print_inline()?;
+            // Mouse events asked for by the device come for the whole terminal
+            session.output_rows.pane = Rect::from((Position::ORIGIN, term.size()?));
+        } else {
+            session.draw(&mut term, &labels, progress.as_ref())?;
+        }
+        let next_frame = tokio::time::Instant::now() + FRAME_TIME;
+        // Every tab's bell is taken, so none rings again later
+        let mut bell = false;
+        for tab in &session.tabs {
+            bell |= tab.screen.lock().unwrap().take_bell();
+        }
+        if bell {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(b"\x07");
+            let _ = stdout.flush();
+        }
+
+        // Sleeps until there is something to do, the status bar is refreshed now and then
+        let refresh = if progress.is_some() {
+            PROGRESS_REFRESH
+        } else {
+            STATUS_REFRESH
+        };
+        let wake = tokio::select! {
+            event = input.next() => Wake::Input(event),
+            Some(request) = session.pairing_requests.recv() => Wake::Pairing(request),
+            _ = &mut terminated => Wake::Terminated,
+            // Output arriving in a stream is drawn once per frame
+            _ = async {
+                redraw.notified().await;
+                tokio::time::sleep_until(next_frame).await;
+            } => Wake::Redraw,
+            _ = tokio::time::sleep(refresh) => Wake::Redraw,
+        };
+        match wake {
+            Wake::Input(Some(Ok(event::Event::Key(key_event))))
+                if session
+                    .handle_key(key_event, page_size(&term))
+                    .await
+                    .is_break() =>
+            {
+                break;
+            }
+            Wake::Input(Some(Ok(event::Event::Paste(text)))) => session.paste(&text).await,
+            Wake::Input(Some(Ok(event::Event::Mouse(mouse)))) => session.handle_mouse(mouse).await,
+            Wake::Input(Some(Err(e))) => {
+                result = Err(e.into());
+                break;
+            }
+            Wake::Input(None) => break,
+            Wake::Pairing(request) => session.pairing_request(request),
+            Wake::Terminated => {
+                info!("Terminated by a signal");
+                break;
+            }
+            Wake::Input(Some(Ok(_))) | Wake::Redraw => {}
+        }
+    }
+
+    if session.inline {
+        session.end_inline();
+    }
+    drop(raw_terminal);
+    close_links(&links).await;
+    let duration = started.elapsed();
+    let stats: Vec<Stats> = session
+        .tabs
+        .iter()
+        .map(|tab| Stats::new(&tab.status.lock().unwrap(), duration))
+        .collect();
+    for stats in &stats {
+        info!("{}", stats.summary());
+    }
+    if let Some(path) = &args.stats_json {
+        stats::write_json(path, &stats)?;
+    }
+    info!("NUS terminal exited");
+    if let Ok(Some(code)) = result {
+        info!("Exit code {code} requested by a trigger");
+    }
+
+    result
+}
+
+/// What the connection of every tab is set up with
+struct TabSetup<'a> {
+    args: &'a ConnectArgs,
+    defmt: Option<defmt::Decoder>,
+    /// Given with `--proto-desc` and `--proto-type`
+    protobuf: Option<protobuf::Decoder>,
+    /// Given with `--hooks`, run by every tab on its own
+    hooks: Option<rhai::AST>,
+    /// Given with `--plugin`, instantiated by every tab on its own
+    plugin: Option<plugin::Module>,
+    timestamps: Timestamps,
+    rules: config::Rules,
+    /// View receiving the lines of all tabs
+    merged: Option<Arc<Mutex<Screen>>>,
+    /// Set by a trigger of any tab ending the session
+    exit_code: Arc<Mutex<Option<i32>>>,
+    /// Asks in the terminal UI when pairing again as the link is reestablished
+    pairing: Option<Agent>,
+    /// Woken by the screens when they change
+    redraw: Arc<Notify>,
+}
+
+/// What woke up the loop of the terminal UI
+enum Wake {
+    Input(Option<io::Result<event::Event>>),
+    Pairing(Request),
+    Terminated,
+    Redraw,
+}
+
+impl TabSetup<'_> {
+    /// Connects to `device` and starts forwarding its data to tab `number`
+    async fn open(
+        &self,
+        central: &Adapter,
+        number: usize,
+        device: DeviceInfo,
+        files: TabFiles,
+    ) -> Result<Tab> {
+        let args = self.args;
+        let options = LinkOptions {
+            trace: files.pcap.clone().map(|pcap| pcap as Arc<dyn Trace>),
+            ..LinkOptions::from(&args.device)
+        };
+        let (link, received) = open_link(central, device.peripheral.clone(), &options).await?;
+
+        let mode = if args.hex {
+            decode::Mode::Hex
+        } else {
+            decode::Mode::Text
+        };
+        let mut screen = Screen::new(args.scrollback, mode, args.receive_newline);
+        if let Some(defmt) = &self.defmt {
+            screen.set_framing(Framing::Defmt(defmt.clone()));
+        } else if args.cobs {
+            screen.set_framing(Framing::Cobs(cobs::Decoder::default()));
+        } else if args.slip {
+            screen.set_framing(Framing::Slip(slip::Decoder::default()));
+        } else if let Some(prefix) = args.framing {
+            screen.set_framing(Framing::Prefixed(length_prefix::Decoder::new(prefix)));
+        } else if let Some(protobuf) = &self.protobuf {
+            screen.set_framing(Framing::Protobuf(protobuf::Delimited::new(
+                protobuf.clone(),
+            )));
+        } else if args.cbor {
+            screen.set_framing(Framing::Cbor(cbor::Sequence::default()));
+        }
+        if args.nmea {
+            screen.follow_nmea();
+        }
+        if let Some(pattern) = &args.plot {
+            let window = Duration::from_secs(args.plot_window);
+            screen.set_plot(Plot::new(pattern.clone(), window));
+        }
+        if let Some(protobuf) = &self.protobuf {
+            screen.set_frame_format(FrameFormat::Protobuf(protobuf.clone()));
+        } else if args.cbor {
+            screen.set_frame_format(FrameFormat::Cbor);
+        }
+        screen.set_timestamps(self.timestamps.clone(), args.timestamps.is_some());
+        screen.set_highlights(self.rules.highlight.clone());
+        screen.set_redraw(self.redraw.clone());
+        if args.inline {
+            screen.set_inline();
+        }
+        if let Some(merged) = &self.merged {
+            let color = LABEL_COLORS[(number - 1) % LABEL_COLORS.len()];
+            let label = format!(
+                "\x1b[1;{color}m[{number} {}]\x1b[0m ",
+                device.display_name()
+            );
+            screen.set_merged(merged.clone(), label);
+        }
+        let screen = Arc::new(Mutex::new(screen));
+        if let Some(pcap) = &files.pcap {
+            pcap.set_notices(Arc::new(ScreenNotices(screen.clone())));
+        }
+        let log = files.log.map(|log| Arc::new(Mutex::new(log)));
+        let recording = files
+            .recording
+            .map(|recording| Arc::new(Mutex::new(recording)));
+        let current_link: CurrentLink = Arc::new(Mutex::new(Some(Arc::new(link.clone()))));
+        let status = Arc::new(Mutex::new(LinkStatus {
+            name: device.display_name().to_string(),
+            address: device.address.clone(),
+            state: ConnectionState::Connected,
+            rssi: device.rssi,
+            mtu: link.mtu,
+            tx_bytes: 0,
+            tx_packets: 0,
+            rx_notifications: 0,
+            reconnects: 0,
+            rx_bytes: 0,
+            pending: 0,
+            battery: None,
+        }));
+
+        let tap = Tap::default();
+        let transfer = Arc::default();
+        let device_info = Arc::new(Mutex::new(None));
+        let reconnected = Arc::new(Notify::new());
+        let reconnect_request = Arc::new(Notify::new());
+        let (write_queue, queued) = mpsc::channel(WRITE_QUEUE_LEN);
+        let credits = (args.flow_control == FlowControl::Credits).then(Arc::default);
+        let reliable = args.reliable.then(|| Arc::new(Channel::default()));
+        let plugin = self
+            .plugin
+            .as_ref()
+            .map(plugin::Module::instantiate)
+            .transpose()?
+            .map(|plugin| Arc::new(Mutex::new(plugin)));
+        let script_status = Arc::new(Mutex::new(None));
+        let hooks = self.hooks.clone().map(|ast| {
+            let (events, received) = mpsc::channel(HOOK_EVENTS_LEN);
+            let host = hooks::Host {
+                write_queue: write_queue.clone(),
+                screen: screen.clone(),
+                status: script_status.clone(),
+            };
+            tokio::spawn(hooks::run(ast, received, host));
+            events
+        });
+        let supervisor = Supervisor {
+            connector: Rescan {
+                central: central.clone(),
+                device,
+                options: LinkOptions {
+                    // A detected service once accepted is the one looked for from now on
+                    uuids: NusUuids {
+                        service: link.rx_char.service_uuid,
+                        rx: link.rx_char.uuid,
+                        tx: link.tx_char.uuid,
+                    },
+                    pairing: self.pairing.clone(),
+                    fallback: match options.fallback {
+                        // Nobody can answer on the console once the terminal UI is up
+                        Fallback::Ask(_) => Fallback::Fail,
+                        fallback => fallback,
+                    },
+                    // The terminal UI is up by the time the link is reopened
+                    notices: Some(Arc::new(ScreenNotices(screen.clone()))),
+                    ..options
+                },
+            },
+            current_link: current_link.clone(),
+            status: status.clone(),
+            policy: ReconnectPolicy::from(args),
+            log: log.clone(),
+            recording: recording.clone(),
+            capture: files.capture.map(Mutex::new),
+            csv: files.csv.map(Mutex::new),
+            screen: screen.clone(),
+            tap: tap.clone(),
+            transfer: Arc::clone(&transfer),
+            zmodem_dir: args.zmodem_dir.clone(),
+            zmodem: Mutex::default(),
+            reconnected: reconnected.clone(),
+            reconnect_request: reconnect_request.clone(),
+            triggers: Mutex::new(Triggers::new(self.rules.trigger.clone())),
+            write_queue: write_queue.clone(),
+            exit_code: self.exit_code.clone(),
+            battery_alert: args.battery_alert,
+            init: if args.no_init {
+                Vec::new()
+            } else {
+                init::sequence(&args.init, Duration::from_millis(args.init_delay))
+            },
+            read_device_info: args.device_info,
+            device_info: device_info.clone(),
+            keepalive: args.keepalive.map(Duration::from_secs),
+            keepalive_data: args
+                .keepalive_data
+                .as_deref()
+                .map(init::unescape)
+                .transpose()
+                .context("Invalid --keepalive-data")?,
+            stall_timeout: args.stall_timeout.map(Duration::from_secs),
+            stall_reconnect: args.stall_reconnect,
+            notify: args.notify,
+            on_connect: args.on_connect.clone(),
+            on_disconnect: args.on_disconnect.clone(),
+            credits: credits.clone(),
+            reliable: reliable.clone(),
+            hooks,
+            plugin: plugin.clone(),
+        };
+        tokio::spawn(supervisor.run(link, received));
+
+        let writer = Writer {
+            current_link: current_link.clone(),
+            status: status.clone(),
+            screen: screen.clone(),
+            reconnected,
+            buffer_limit: args.reconnect_buffer,
+            pacing: Pacing::from(args),
+            credits,
+            reliable,
+            plugin,
+            pending: Vec::new(),
+            overflowed: false,
+        };
+        tokio::spawn(writer.run(queued));
+
+        Ok(Tab {
+            screen,
+            status,
+            log,
+            recording,
+            write_queue,
+            tap,
+            transfer,
+            reconnect_request,
+            seen_rx: 0,
+            device_info,
+            current_link,
+            script_status,
+        })
+    }
+}
+
+/// A pattern finding `text` literally, ignoring case unless it has upper case letters
+fn search_pattern(text: &str) -> Option<Regex> {
+    if text.is_empty() {
+        return None;
+    }
+    let ignore_case = !text.chars().any(char::is_uppercase);
+    RegexBuilder::new(&regex::escape(text))
+        .case_insensitive(ignore_case)
+        .build()
+        .ok()
+}
+
+/// Files a tab writes what it receives to
+struct TabFiles {
+    log: Option<SessionLog>,
+    recording: Option<Recording>,
+    capture: Option<RawCapture>,
+    pcap: Option<Arc<Btsnoop>>,
+    csv: Option<Csv>,
+}
+
+impl TabFiles {
+    /// Opens the files given on the command line, with `number` added to their names
+    fn open(args: &ConnectArgs, number: Option<usize>) -> Result<TabFiles> {
+        Ok(TabFiles {
+            log: open_log(args, number)?,
+            recording: open_recording(args, number)?,
+            capture: open_capture(args, number)?,
+            pcap: open_pcap(args, number)?,
+            csv: open_csv(args, number)?,
+        })
+    }
+}
+
+/// Opens the file given with `--log`, with `number` added to its name for a tab
+pub fn open_log(args: &ConnectArgs, number: Option<usize>) -> Result<Option<SessionLog>> {
+    let Some(path) = &args.log else {
+        return Ok(None);
+    };
+    let path = numbered(path, number);
+    let timestamps = match args.timestamps {
+        Some(kind) => Some(Timestamps::new(kind, &args.timestamp_format)?),
+        None if args.log_timestamps => Some(Timestamps::new(
+            TimestampKind::Absolute,
+            timestamp::LOG_FORMAT,
+        )?),
+        None => None,
+    };
+    SessionLog::open(&path, timestamps).map(Some)
+}
+
+/// Creates the recording given with `--record`, with `number` added to its name for a tab
+fn open_recording(args: &ConnectArgs, number: Option<usize>) -> Result<Option<Recording>> {
+    let Some(path) = &args.record else {
+        return Ok(None);
+    };
+    Recording::create(&numbered(path, number), args.record_input)
+        .with_context(|| format!("Could not create {}", path.display()))
+        .map(Some)
+}
+
+/// Opens the file given with `--capture-raw`, with `number` added to its name for a tab
+pub fn open_capture(args: &ConnectArgs, number: Option<usize>) -> Result<Option<RawCapture>> {
+    let Some(path) = &args.capture_raw else {
+        return Ok(None);
+    };
+    RawCapture::open(&numbered(path, number), args.capture_framing)
+        .with_context(|| format!("Could not open {}", path.display()))
+        .map(Some)
+}
+
+/// Creates the btsnoop file given with `--pcap`, with `number` added to its name for a tab
+pub fn open_pcap(args: &ConnectArgs, number: Option<usize>) -> Result<Option<Arc<Btsnoop>>> {
+    let Some(path) = &args.pcap else {
+        return Ok(None);
+    };
+    let path = numbered(path, number);
+    Btsnoop::create(&path)
+        .with_context(|| format!("Could not create {}", path.display()))
+        .map(|pcap| Some(Arc::new(pcap)))
+}
+
+/// Creates the file given with `--csv`, with `number` added to its name for a tab
+pub fn open_csv(args: &ConnectArgs, number: Option<usize>) -> Result<Option<Csv>> {
+    let (Some(path), Some(pattern)) = (&args.csv, &args.plot) else {
+        return Ok(None);
+    };
+    let path = numbered(path, number);
+    Csv::create(&path, pattern.clone())
+        .with_context(|| format!("Could not create {}", path.display()))
+        .map(Some)
+}
+
+/// `path` with `number` added to its name, session.log becomes session-1.log
+fn numbered(path: &Path, number: Option<usize>) -> PathBuf {
+    let Some(number) = number else {
+        return path.to_path_buf();
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}-{number}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{number}"),
+    };
+    path.with_file_name(name)
+}
+
+/// What is done with the input of the prompt
+#[derive(Debug)]
+enum PromptAction {
+    File(FileAction),
+    /// Send the hex bytes entered, keeping the prompt open for more
+    Hex,
+    /// Filter the shown lines, hiding the matching ones if `invert`
+    Filter {
+        invert: bool,
+    },
+    /// Search the scrollback while typing, starting from the view scrolled back by `from`
+    Search {
+        from: usize,
+    },
+    /// Answer a request of a pairing
+    Pairing(Request),
+}
+
+/// What is done with the path entered in the file prompt
+#[derive(Debug, Clone, Copy)]
+enum FileAction {
+    Send,
+    XmodemSend,
+    XmodemReceive,
+    YmodemSend,
+    YmodemReceive,
+}
+
+impl FileAction {
+    fn prompt(self) -> &'static str {
+        match self {
+            FileAction::Send => "Send file",
+            FileAction::XmodemSend => "XMODEM send file",
+            FileAction::XmodemReceive => "XMODEM receive into",
+            FileAction::YmodemSend => "YMODEM send file or directory",
+            FileAction::YmodemReceive => "YMODEM receive into directory",
+        }
+    }
+}
+
+/// Framing of the packets typed in line mode, with `--cobs`, `--slip` or `--framing`
+#[derive(Debug, Clone, Copy)]
+enum Packets {
+    Cobs,
+    Slip,
+    Prefixed(LengthPrefix),
+}
+
+impl Packets {
+    /// `data` as a frame, `None` if it is too long for a length prefix
+    fn encode(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Packets::Cobs => Some(cobs::encode(data)),
+            Packets::Slip => Some(slip::encode(data)),
+            Packets::Prefixed(prefix) => prefix.encode(data),
+        }
+    }
+}
+
+/// A connected device with its own output, log and file transfers
+struct Tab {
+    screen: Arc<Mutex<Screen>>,
+    status: Arc<Mutex<LinkStatus>>,
+    log: Option<Arc<Mutex<SessionLog>>>,
+    /// Given with `--record`
+    recording: Option<Arc<Mutex<Recording>>>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    /// Notified to make the supervisor drop and reopen the link
+    reconnect_request: Arc<Notify>,
+    /// Bytes received when the tab was last shown, to mark tabs with new output
+    seen_rx: u64,
+    /// Read from the Device Information Service with `--device-info`
+    device_info: Arc<Mutex<Option<DeviceInformation>>>,
+    /// Closed when the session ends
+    current_link: CurrentLink,
+    /// Set by the `--hooks` script with `set_status`
+    script_status: Arc<Mutex<Option<String>>>,
+}
+
+/// State of the interactive terminal, driven by the keys pressed
+struct Session {
+    tabs: Vec<Tab>,
+    /// Index of the tab shown and receiving input
+    active: usize,
+    escape: EscapeKey,
+    chunk_delay: Duration,
+    xmodem_block_size: BlockSize,
+    prompt: Option<(PromptAction, Prompt)>,
+    /// Whether the escape key was pressed and the next key selects a command
+    menu: bool,
+    line_editor: LineEditor,
+    /// Whether input is edited locally and sent a line at a time
+    line_mode: bool,
+    /// What is sent for Enter
+    send_newline: Newline,
+    /// How input is framed as packets, if it is
+    packets: Option<Packets>,
+    /// Whether PageUp and PageDown without Shift go to the device instead of scrolling
+    send_page_keys: bool,
+    /// Keys bound to strings, the last one for a key applies
+    macros: Vec<Macro>,
+    /// Whether typed input is shown in the output, for devices not echoing it
+    local_echo: bool,
+    /// Whether input goes to all devices instead of the one shown
+    broadcast: bool,
+    /// View interleaving the lines of all devices, with more than one
+    merged: Option<Arc<Mutex<Screen>>>,
+    /// Whether the merged view is shown instead of the active tab
+    show_merged: bool,
+    /// Whether the help overlay is open, until the next key
+    help: bool,
+    /// Battery level in percent shown as low
+    battery_alert: Option<u8>,
+    /// Text of the last search, offered again when searching next
+    last_search: String,
+    /// Whether mouse events are captured all the time, not just while selecting
+    mouse: bool,
+    /// Given with `--copy-command`, the text is copied with OSC 52 otherwise
+    copy_command: Option<String>,
+    /// Where the output was last drawn, for mouse selections
+    output_rows: OutputRows,
+    /// Whether the view was frozen for the selection and follows the output once it ends
+    resume_after_selection: bool,
+    /// Whether mouse events are captured at the moment
+    mouse_captured: bool,
+    /// Requests of pairings while reconnecting, answered in the prompt
+    pairing_requests: mpsc::UnboundedReceiver<Request>,
+    /// Whether output is printed to the normal terminal buffer instead of drawn
+    inline: bool,
+    /// The line printed below the output inline, like a prompt, along with the incomplete
+    /// line of output it was printed below, if it has a row of its own
+    overlay: Option<(String, Option<String>)>,
+}
+
+impl Session {
+    /// The tab shown and receiving input
+    fn tab(&self) -> &Tab {
+        &self.tabs[self.active]
+    }
+
+    /// The output shown, the active tab's or the merged view
+    fn shown_screen(&self) -> Arc<Mutex<Screen>> {
+        match &self.merged {
+            Some(merged) if self.show_merged => merged.clone(),
+            _ => self.tab().screen.clone(),
+        }
+    }
+
+    /// Names and states of the tabs for the tab bar
+    fn tab_labels(&self) -> Vec<TabLabel> {
+        self.tabs
+            .iter()
+            .map(|tab| {
+                let status = tab.status.lock().unwrap();
+                TabLabel {
+                    name: status.name.clone(),
+                    state: status.state,
+                    unseen: status.rx_bytes > tab.seen_rx,
+                }
+            })
+            .collect()
+    }
+
+    /// Shows the tab `offset` places after the active one, wrapping around
+    fn switch_tab(&mut self, offset: isize) {
+        if self.tabs.len() < 2 {
+            self.status_msg("Only one device is connected");
+            return;
+        }
+        let count = self.tabs.len() as isize;
+        self.active = (self.active as isize + offset).rem_euclid(count) as usize;
+    }
+
+    /// Settings of the active tab and the session, as listed in the help overlay
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let tab = self.tab();
+        let status = tab.status.lock().unwrap();
+        let screen = tab.screen.lock().unwrap();
+        let mut connection = status.state.to_string();
+        if let Some(rssi) = status.rssi {
+            connection.push_str(&format!(", RSSI {rssi} dBm"));
+        }
+        let mut input = String::from(if self.line_mode {
+            "line mode"
+        } else {
+            "keys as pressed"
+        });
+        match self.packets {
+            Some(Packets::Cobs) => input.push_str(", COBS frames"),
+            Some(Packets::Slip) => input.push_str(", SLIP frames"),
+            Some(Packets::Prefixed(_)) => input.push_str(", length-prefixed frames"),
+            None => {}
+        }
+        if self.local_echo {
+            input.push_str(", local echo");
+        }
+        if self.broadcast {
+            input.push_str(", broadcast to all devices");
+        }
+        let mut view = String::from(match screen.mode() {
+            decode::Mode::Text => "text",
+            decode::Mode::Hex => "hex",
+        });
+        if self.show_merged {
+            view.push_str(", merged");
+        }
+        let logging = match &tab.log {
+            Some(log) => {
+                let log = log.lock().unwrap();
+                let state = if log.is_enabled() { "" } else { " (paused)" };
+                format!("{}{state}", log.path().display())
+            }
+            None => "off".to_string(),
+        };
+        if let Some(battery) = status.battery {
+            connection.push_str(&format!(", battery {battery}%"));
+        }
+        let mut settings = vec![
+            ("Device", format!("{} ({})", status.name, status.address)),
+            ("Connection", connection),
+            (
+                "MTU",
+                format!(
+                    "{} ({} bytes per write)",
+                    status.mtu,
+                    status.mtu.saturating_sub(ATT_HEADER_LEN)
+                ),
+            ),
+            ("Enter sends", value_name(self.send_newline)),
+            ("Device lines end with", value_name(screen.newline())),
+            ("Input", input),
+            ("View", view),
+            (
+                "Timestamps",
+                screen
+                    .timestamps_shown()
+                    .map_or("off".to_string(), value_name),
+            ),
+            ("Logging", logging),
+        ];
+        if !self.macros.is_empty() {
+            let keys: Vec<&str> = self.macros.iter().map(Macro::name).collect();
+            settings.push(("Macro keys", keys.join(", ")));
+        }
+        if let Some(info) = &*tab.device_info.lock().unwrap() {
+            settings.extend(info.fields.iter().cloned());
+        }
+        settings
+    }
+
+    /// Handles a key press, breaks when the terminal should quit
+    async fn handle_key(&mut self, key: KeyEvent, page_size: isize) -> ControlFlow<()> {
+        if self.help {
+            self.help = false;
+            return ControlFlow::Continue(());
+        }
+        if self.prompt.is_some() {
+            self.edit_prompt(key).await;
+            return ControlFlow::Continue(());
+        }
+        if self.shown_screen().lock().unwrap().selection().is_some() {
+            self.edit_selection(key, page_size as usize);
+            return ControlFlow::Continue(());
+        }
+        if self.menu {
+            self.menu = false;
+            let command = match key.code {
+                _ if self.escape.matches(&key) => Some(menu::Command::SendEscape),
+                KeyCode::Char(c) => menu::command(c),
+                _ => None,
+            };
+            return match command {
+                Some(command) => self.run_command(command).await,
+                None => ControlFlow::Continue(()),
+            };
+        }
+        if self.escape.matches(&key) {
+            self.menu = true;
+            return ControlFlow::Continue(());
+        }
+        if let Some(binding) = self.macros.iter().rev().find(|m| m.matches(&key)) {
+            self.play_macro(binding.steps().to_vec());
+            return ControlFlow::Continue(());
+        }
+
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::PageUp if shift || !self.send_page_keys => {
+                self.shown_screen().lock().unwrap().scroll(page_size)
+            }
+            KeyCode::PageDown if shift || !self.send_page_keys => {
+                self.shown_screen().lock().unwrap().scroll(-page_size)
+            }
+            KeyCode::Up if shift => self.shown_screen().lock().unwrap().scroll(1),
+            KeyCode::Down if shift => self.shown_screen().lock().unwrap().scroll(-1),
+            _ if self.line_mode => self.edit_line(key).await,
+            KeyCode::Enter => self.send_input(self.send_newline.bytes().to_vec()),
+            _ => {
+                if let Some(data) = key_bytes(&key) {
+                    self.send_input(data);
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Handles mouse events, captured while selecting, for the device or with `--mouse`
+    async fn handle_mouse(&mut self, mouse: MouseEvent) {
+        let position = self.output_rows.position(mouse.row);
+        let screen = self.shown_screen();
+        let selecting = screen.lock().unwrap().selection().is_some();
+        // Like in xterm, Shift keeps the mouse local while the device asks for it
+        if !selecting && !mouse.modifiers.contains(KeyModifiers::SHIFT) {
+            let pane = self.output_rows.pane;
+            let inside = pane.x <= mouse.column
+                && mouse.column < pane.right()
+                && pane.y <= mouse.row
+                && mouse.row < pane.bottom();
+            let column = mouse.column.saturating_sub(pane.x) + 1;
+            let row = mouse.row.saturating_sub(pane.y) + 1;
+            let reporting = screen.lock().unwrap().mouse().clone();
+            if reporting.enabled() {
+                if let Some(report) = reporting.encode(&mouse, column, row).filter(|_| inside) {
+                    self.send(report);
+                }
+                return;
+            }
+        }
+        if self.inline {
+            return;
+        }
+        match mouse.kind {
+            MouseEventKind::ScrollUp => screen.lock().unwrap().scroll(MOUSE_SCROLL),
+            MouseEventKind::ScrollDown => screen.lock().unwrap().scroll(-MOUSE_SCROLL),
+            MouseEventKind::Down(MouseButton::Left) => {
+                if let Some(position) = position {
+                    self.start_selection(position);
+                }
+            }
+            MouseEventKind::Drag(MouseButton::Left) if selecting => {
+                if let Some(position) = position {
+                    screen.lock().unwrap().select(position, true);
+                }
+            }
+            MouseEventKind::Up(MouseButton::Left) if selecting => self.copy_selection(),
+            _ => {}
+        }
+    }
+
+    /// Selects the line at `position` for copying, freezing the view meanwhile
+    fn start_selection(&mut self, position: usize) {
+        let screen = self.shown_screen();
+        let mut screen = screen.lock().unwrap();
+        if screen.frozen().is_none() {
+            screen.freeze();
+            self.resume_after_selection = true;
+        }
+        screen.select(position, false);
+    }
+
+    /// Moves the end of the selection with the keys, Enter copying it
+    fn edit_selection(&mut self, key: KeyEvent, rows: usize) {
+        let screen = self.shown_screen();
+        let mut screen = screen.lock().unwrap();
+        let Some(selection) = screen.selection() else {
+            return;
+        };
+        let cursor = match key.code {
+            KeyCode::Up | KeyCode::Char('k') => selection.cursor + 1,
+            KeyCode::Down | KeyCode::Char('j') => selection.cursor.saturating_sub(1),
+            KeyCode::PageUp => selection.cursor + rows,
+            KeyCode::PageDown => selection.cursor.saturating_sub(rows),
+            // Starts the selection over at the cursor
+            KeyCode::Char(' ') => {
+                screen.select(selection.cursor, false);
+                return;
+            }
+            KeyCode::Enter | KeyCode::Char('y') => {
+                drop(screen);
+                self.copy_selection();
+                return;
+            }
+            KeyCode::Esc | KeyCode::Char('q') => {
+                drop(screen);
+                self.end_selection();
+                return;
+            }
+            _ => return,
+        };
+        screen.select(cursor, true);
+        if let Some(selection) = screen.selection() {
+            screen.reveal(selection.cursor, rows);
+        }
+    }
+
+    /// Copies the selected lines to the clipboard and ends the selection
+    fn copy_selection(&mut self) {
+        let text = self.shown_screen().lock().unwrap().selected_text();
+        self.end_selection();
+        let Some(text) = text else {
+            return;
+        };
+        let result = match &self.copy_command {
+            Some(command) => copy_with(command, &text),
+            // Terminals supporting OSC 52 put the text on the system clipboard
+            None => {
+                let mut stdout = io::stdout();
+                write!(
+                    stdout,
+                    "\x1b]52;c;{}\x07",
+                    websocket::base64(text.as_bytes())
+                )
+                .and_then(|()| stdout.flush())
+            }
+        };
+        match result {
+            Ok(()) => self.status_msg(&format!("Copied {} lines", text.lines().count().max(1))),
+            Err(e) => self.status_msg(&format!("Copying failed: {e}")),
+        }
+    }
+
+    fn end_selection(&mut self) {
+        let screen = self.shown_screen();
+        let mut screen = screen.lock().unwrap();
+        screen.clear_selection();
+        if std::mem::take(&mut self.resume_after_selection) {
+            screen.resume();
+        }
+    }
+
+    /// Draws the terminal UI, noting where the output went
+    fn draw(
+        &mut self,
+        term: &mut Terminal<CrosstermBackend<io::Stdout>>,
+        labels: &[TabLabel],
+        progress: Option<&Progress>,
+    ) -> io::Result<()> {
+        let tab = self.tab();
+        let link_status = tab.status.lock().unwrap().clone();
+        let script_status = tab.script_status.lock().unwrap().clone();
+        let settings = self.help.then(|| self.settings());
+        let indicators = Indicators {
+            logging: tab.log.as_ref().map(|log| log.lock().unwrap().is_enabled()),
+            transfer: progress,
+            prompt: self.prompt.as_ref().map(|(_, prompt)| prompt),
+            line: self.line_mode.then_some(&self.line_editor),
+            escape: self.escape,
+            menu: self.menu,
+            tabs: labels,
+            active_tab: self.active,
+            broadcast: self.broadcast,
+            merged: self.show_merged,
+            help: settings.as_deref(),
+            battery_alert: self.battery_alert,
+            script_status: script_status.as_deref(),
+        };
+        let screen = self.shown_screen();
+        let mut output_rows = OutputRows::default();
+        term.draw(|f| {
+            output_rows = ui::draw(f, &screen.lock().unwrap(), &link_status, indicators);
+        })?;
+        // Full-screen programs draw on a grid the size of the pane, the same for every tab
+        let pane = output_rows.pane;
+        for tab in &self.tabs {
+            tab.screen
+                .lock()
+                .unwrap()
+                .resize_grid(pane.width, pane.height);
+        }
+        self.output_rows = output_rows;
+        self.update_mouse_capture()?;
+        Ok(())
+    }
+
+    /// Prints the output added since the last call to the normal terminal buffer, with
+    /// `--inline`, and below it the prompt or line being edited
+    fn print_inline(&mut self) -> io::Result<()> {
+        let screen = self.tab().screen.clone();
+        let mut screen = screen.lock().unwrap();
+        let text = screen.take_inline();
+        let overlay = self.overlay_line();
+        if text.is_empty() && overlay.as_ref() == self.overlay.as_ref().map(|(line, _)| line) {
+            return Ok(());
+        }
+        let mut out = String::new();
+        if let Some((_, above)) = self.overlay.take() {
+            out.push_str("\r\x1b[K");
+            if let Some(above) = above {
+                out.push_str("\x1b[A\r");
+                out.push_str(&above);
+            }
+        }
+        // Raw mode leaves line feeds to move down only
+        out.push_str(&text.replace('\n', "\r\n"));
+        if let Some(line) = overlay {
+            let partial = screen.partial();
+            let above = (!partial.is_empty()).then(|| partial.to_string());
+            if above.is_some() {
+                out.push_str("\r\n");
+            }
+            out.push_str(&format!("\x1b[7m{line}\x1b[0m"));
+            self.overlay = Some((line, above));
+        }
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+
+    /// What is shown below the output inline, in place of the bars of the terminal UI
+    fn overlay_line(&self) -> Option<String> {
+        let line = if let Some((_, prompt)) = &self.prompt {
+            format!("{}{}", prompt.label, prompt.input)
+        } else if self.menu {
+            let keys: String = menu::COMMANDS.iter().map(|(key, _, _)| *key).collect();
+            format!("{} [{keys}], ? shows them", self.escape)
+        } else if self.line_mode {
+            format!("> {}", self.line_editor.line())
+        } else {
+            return None;
+        };
+        // A row of its own, or clearing it would leave the start behind
+        let width = terminal::size().map(|(width, _)| width).unwrap_or(80) as usize;
+        let skip = line.chars().count().saturating_sub(width.saturating_sub(1));
+        Some(line.chars().skip(skip).collect())
+    }
+
+    /// Clears the overlay and ends an incomplete line of output, so the shell prompt
+    /// starts on a line of its own after quitting
+    fn end_inline(&mut self) {
+        let partial = !self.tab().screen.lock().unwrap().partial().is_empty();
+        let mut out = String::new();
+        if let Some((_, above)) = self.overlay.take() {
+            out.push_str("\r\x1b[K");
+            if above.is_some() {
+                out.push_str("\x1b[A");
+            }
+        }
+        if partial {
+            out.push_str("\r\n");
+        }
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(out.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    /// Captures the mouse while its events are wanted: by a selection, by the program
+    /// running on the device or with `--mouse`
+    fn update_mouse_capture(&mut self) -> io::Result<()> {
+        let screen = self.shown_screen();
+        let screen = screen.lock().unwrap();
+        let wanted = self.mouse || screen.selection().is_some() || screen.mouse().enabled();
+        if wanted != self.mouse_captured {
+            if wanted {
+                io::stdout().execute(event::EnableMouseCapture)?;
+            } else {
+                io::stdout().execute(event::DisableMouseCapture)?;
+            }
+            self.mouse_captured = wanted;
+        }
+        Ok(())
+    }
+
+    /// Handles text pasted into the local terminal, which arrives in one piece
+    async fn paste(&mut self, text: &str) {
+        self.menu = false;
+        if let Some((_, prompt)) = &mut self.prompt {
+            prompt
+                .input
+                .push_str(text.lines().next().unwrap_or_default());
+            return;
+        }
+        if self.line_mode {
+            // Every complete line is sent as if typed, the rest stays in the editor
+            let mut lines = text.split('\n').peekable();
+            while let Some(line) = lines.next() {
+                self.line_editor.insert(line);
+                if lines.peek().is_some() {
+                    self.edit_line(KeyEvent::from(KeyCode::Enter)).await;
+                }
+            }
+            return;
+        }
+        // Queued in writes of their own with the chunk delay between them, so long pastes do
+        // not overrun the device, and in the background so the pauses do not hold up the UI
+        let data = self.send_newline.translate(text.as_bytes());
+        if self.local_echo {
+            self.tab().screen.lock().unwrap().echo(&data);
+        }
+        self.record_input(&data);
+        let mtu = self.tab().status.lock().unwrap().mtu;
+        let chunk_size = usize::from(mtu.saturating_sub(ATT_HEADER_LEN).max(1));
+        let mut steps = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            if !steps.is_empty() && !self.chunk_delay.is_zero() {
+                steps.push(Step::Delay(self.chunk_delay));
+            }
+            steps.push(Step::Send(chunk.to_vec()));
+        }
+        self.spawn_steps(steps, None);
+    }
+
+    /// Edits the line in line mode, sending it on Enter
+    async fn edit_line(&mut self, key: KeyEvent) {
+        match self.line_editor.handle_key(&key) {
+            Outcome::Edited => {}
+            Outcome::Submit(line) => {
+                if let Err(e) = self.line_editor.remember(&line) {
+                    self.status_msg(&format!("Could not save history: {e}"));
+                }
+                let mut data = line.into_bytes();
+                // A frame is a packet of its own, it needs no line ending
+                if self.packets.is_none() {
+                    data.extend_from_slice(self.send_newline.bytes());
+                }
+                self.send_input(data);
+            }
+            // Control keys like Ctrl+C still reach the device right away
+            Outcome::Ignored => {
+                if let Some(data) = key_bytes(&key) {
+                    self.send_input(data);
+                }
+            }
+        }
+    }
+
+    /// Queues typed input for the device, framed when sending packets
+    fn send_input(&self, data: Vec<u8>) {
+        if self.local_echo {
+            self.tab().screen.lock().unwrap().echo(&data);
+        }
+        self.record_input(&data);
+        let Some(packets) = self.packets else {
+            self.send(data);
+            return;
+        };
+        match packets.encode(&data) {
+            Some(frame) => self.send(frame),
+            None => self.status_msg(&format!(
+                "Not sent, {} bytes do not fit in a frame",
+                data.len()
+            )),
+        }
+    }
+
+    /// Adds typed input to the recording, if it records input
+    fn record_input(&self, data: &[u8]) {
+        if let Some(recording) = &self.tab().recording
+            && let Err(e) = recording.lock().unwrap().input(data)
+        {
+            self.status_msg(&format!("Recording failed: {e}"));
+        }
+    }
+
+    /// Sends what a macro key is bound to, pausing in a task of its own
+    fn play_macro(&self, steps: Vec<Step>) {
+        self.spawn_steps(steps, self.packets);
+    }
+
+    /// Queues the data of `steps` in a task of its own, framed as `packets` if given
+    fn spawn_steps(&self, steps: Vec<Step>, packets: Option<Packets>) {
+        let queues: Vec<_> = if self.broadcast {
+            self.tabs
+                .iter()
+                .map(|tab| tab.write_queue.clone())
+                .collect()
+        } else {
+            vec![self.tab().write_queue.clone()]
+        };
+        tokio::spawn(async move {
+            for step in steps {
+                match step {
+                    Step::Send(data) => {
+                        let data = match packets {
+                            Some(packets) => match packets.encode(&data) {
+                                Some(frame) => frame,
+                                // Too long for the length prefix
+                                None => continue,
+                            },
+                            None => data,
+                        };
+                        // Waiting is fine here, in a task of its own rather than the UI loop
+                        for queue in &queues {
+                            let _ = queue.send(data.clone()).await;
+                        }
+                    }
+                    Step::Delay(delay) => tokio::time::sleep(delay).await,
+                }
+            }
+        });
+    }
+
+    /// Queues input for the device, or for all of them in broadcast mode
+    fn send(&self, data: Vec<u8>) {
+        // Never waits, the UI loop would freeze while the link cannot keep up
+        let tabs = if self.broadcast {
+            &self.tabs[..]
+        } else {
+            std::slice::from_ref(self.tab())
+        };
+        for tab in tabs {
+            if let Err(mpsc::error::TrySendError::Full(_)) = tab.write_queue.try_send(data.clone())
+            {
+                tab.screen
+                    .lock()
+                    .unwrap()
+                    .status("Input dropped, the write queue is full");
+            }
+        }
+    }
+
+    fn status_msg(&self, msg: &str) {
+        self.tab().screen.lock().unwrap().status(msg);
+    }
+
+    async fn run_command(&mut self, command: menu::Command) -> ControlFlow<()> {
+        if self.inline && command.needs_view() {
+            self.status_msg("Not available with --inline, use the scrollback of the terminal");
+            return ControlFlow::Continue(());
+        }
+        match command {
+            menu::Command::Quit => return ControlFlow::Break(()),
+            menu::Command::ToggleLog => {
+                let msg = match &self.tab().log {
+                    Some(log) if log.lock().unwrap().toggle() => "Logging resumed",
+                    Some(_) => "Logging paused",
+                    None => "No log file given (--log)",
+                };
+                self.status_msg(msg);
+            }
+            menu::Command::ToggleHex => self.tab().screen.lock().unwrap().toggle_hex(),
+            menu::Command::ToggleLineMode => {
+                self.line_mode = !self.line_mode;
+                self.status_msg(if self.line_mode {
+                    "Line mode, input is sent on Enter"
+                } else {
+                    "Character mode, keys are sent as they are pressed"
+                });
+            }
+            menu::Command::CycleTimestamps => {
+                let kind = self.tab().screen.lock().unwrap().cycle_timestamps();
+                self.status_msg(match kind {
+                    None => "Timestamps off",
+                    Some(TimestampKind::Absolute) => "Timestamps show the time of day",
+                    Some(TimestampKind::Relative) => "Timestamps show the time since the start",
+                    Some(TimestampKind::Delta) => {
+                        "Timestamps show the time since the previous line"
+                    }
+                });
+            }
+            menu::Command::SendFile => self.open_prompt(FileAction::Send),
+            menu::Command::SendHex => {
+                self.prompt = Some((PromptAction::Hex, Prompt::new(HEX_PROMPT)));
+            }
+            menu::Command::Filter => {
+                let prompt = Prompt::new(FILTER_PROMPT);
+                self.prompt = Some((PromptAction::Filter { invert: false }, prompt));
+            }
+            menu::Command::FilterOut => {
+                let prompt = Prompt::new(FILTER_OUT_PROMPT);
+                self.prompt = Some((PromptAction::Filter { invert: true }, prompt));
+            }
+            menu::Command::Search => {
+                let mut prompt = Prompt::new(SEARCH_PROMPT);
+                prompt.input = self.last_search.clone();
+                let screen = self.shown_screen();
+                let mut screen = screen.lock().unwrap();
+                screen.set_search(search_pattern(&prompt.input));
+                let from = screen.offset();
+                self.prompt = Some((PromptAction::Search { from }, prompt));
+            }
+            menu::Command::Select => {
+                let offset = self.shown_screen().lock().unwrap().offset();
+                self.start_selection(offset);
+            }
+            menu::Command::Freeze => self.shown_screen().lock().unwrap().freeze(),
+            menu::Command::Resume => self.shown_screen().lock().unwrap().resume(),
+            menu::Command::XmodemSend => self.open_prompt(FileAction::XmodemSend),
+            menu::Command::XmodemReceive => self.open_prompt(FileAction::XmodemReceive),
+            menu::Command::YmodemSend => self.open_prompt(FileAction::YmodemSend),
+            menu::Command::YmodemReceive => self.open_prompt(FileAction::YmodemReceive),
+            menu::Command::AbortTransfer => match self.tab().transfer.lock().unwrap().as_ref() {
+                Some(progress) => {
+                    progress.abort();
+                    self.status_msg("Aborting transfer");
+                }
+                None => self.status_msg("No file transfer running"),
+            },
+            menu::Command::Reconnect => self.tab().reconnect_request.notify_one(),
+            menu::Command::ToggleBroadcast if self.tabs.len() < 2 => {
+                self.status_msg("Only one device is connected");
+            }
+            menu::Command::ToggleEcho => {
+                self.local_echo = !self.local_echo;
+                self.status_msg(if self.local_echo {
+                    "Local echo on"
+                } else {
+                    "Local echo off"
+                });
+            }
+            menu::Command::ToggleBroadcast => {
+                self.broadcast = !self.broadcast;
+                self.status_msg(if self.broadcast {
+                    "Broadcast, input goes to all devices"
+                } else {
+                    "Input goes to the device shown"
+                });
+            }
+            menu::Command::ToggleMerged if self.merged.is_none() => {
+                self.status_msg("Only one device is connected");
+            }
+            menu::Command::ToggleMerged => {
+                self.show_merged = !self.show_merged;
+            }
+            menu::Command::ShowHelp if self.inline => {
+                for (name, value) in self.settings() {
+                    self.status_msg(&format!("{name}: {value}"));
+                }
+                for (key, command, description) in menu::COMMANDS {
+                    if !command.needs_view() {
+                        self.status_msg(&format!("{} {key}  {description}", self.escape));
+                    }
+                }
+            }
+            menu::Command::ShowHelp => self.help = true,
+            menu::Command::NextTab => self.switch_tab(1),
+            menu::Command::PreviousTab => self.switch_tab(-1),
+            menu::Command::SendEscape => self.send(vec![self.escape.byte()]),
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn open_prompt(&mut self, action: FileAction) {
+        if self.tab().transfer.lock().unwrap().is_some() {
+            self.status_msg("A file transfer is already running");
+        } else {
+            self.prompt = Some((PromptAction::File(action), Prompt::new(action.prompt())));
+        }
+    }
+
+    /// Shows what a pairing needs, opening the prompt if the user has to answer
+    fn pairing_request(&mut self, request: Request) {
+        self.status_msg(&agent::notice(&request));
+        if let Some(label) = agent::label(&request) {
+            self.menu = false;
+            self.prompt = Some((PromptAction::Pairing(request), Prompt::new(label)));
+        }
+    }
+
+    async fn edit_prompt(&mut self, key: KeyEvent) {
+        if let Some((PromptAction::Search { .. }, _)) = &self.prompt {
+            self.edit_search(key);
+            return;
+        }
+        let Some((_, prompt)) = &mut self.prompt else {
+            return;
+        };
+        match key.code {
+            KeyCode::Enter => match self.prompt.take().unwrap() {
+                (PromptAction::File(action), prompt) => {
+                    self.start_transfer(action, PathBuf::from(prompt.input))
+                }
+                (PromptAction::Pairing(request), prompt) => agent::answer(request, &prompt.input),
+                (PromptAction::Hex, prompt) => {
+                    match decode::parse_hex(&prompt.input) {
+                        Ok(data) if data.is_empty() => {}
+                        Ok(data) => self.send(data),
+                        Err(e) => self.status_msg(&format!("Not sent, {e}")),
+                    }
+                    self.prompt = Some((PromptAction::Hex, Prompt::new(HEX_PROMPT)));
+                }
+                (PromptAction::Filter { invert }, prompt) => self.set_filter(&prompt.input, invert),
+                (PromptAction::Search { .. }, _) => unreachable!("edited by edit_search"),
+            },
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Backspace => {
+                prompt.input.pop();
+            }
+            KeyCode::Char(c) => prompt.input.push(c),
+            _ => {}
+        }
+    }
+
+    /// Edits the search prompt, scrolling to the matches while the text is typed
+    fn edit_search(&mut self, key: KeyEvent) {
+        let screen = self.shown_screen();
+        let mut screen = screen.lock().unwrap();
+        let Some((PromptAction::Search { from }, prompt)) = &mut self.prompt else {
+            return;
+        };
+        let offset = screen.offset();
+        let found = match key.code {
+            KeyCode::Enter => {
+                // The matches stay highlighted until the next search
+                self.last_search = std::mem::take(&mut prompt.input);
+                self.prompt = None;
+                return;
+            }
+            KeyCode::Esc => {
+                screen.set_search(None);
+                screen.scroll(*from as isize - offset as isize);
+                self.prompt = None;
+                return;
+            }
+            KeyCode::Up => screen.find(offset, true, false),
+            KeyCode::Down => screen.find(offset, false, false),
+            KeyCode::Char(_) | KeyCode::Backspace => {
+                match key.code {
+                    KeyCode::Char(c) => prompt.input.push(c),
+                    _ => drop(prompt.input.pop()),
+                }
+                screen.set_search(search_pattern(&prompt.input));
+                // Each edit searches again from where the search started
+                screen.find(*from, true, true)
+                    || screen.find(*from, false, false)
+                    || prompt.input.is_empty()
+            }
+            _ => return,
+        };
+        prompt.label = if found {
+            SEARCH_PROMPT
+        } else {
+            SEARCH_NOT_FOUND_PROMPT
+        };
+    }
+
+    /// Filters the lines of the shown view by `pattern`, an empty one showing all
+    fn set_filter(&self, pattern: &str, invert: bool) {
+        if pattern.is_empty() {
+            self.shown_screen().lock().unwrap().set_filter(None);
+            self.status_msg("Showing all lines");
+            return;
+        }
+        match Regex::new(pattern) {
+            Ok(pattern) => {
+                let filter = Filter { pattern, invert };
+                self.shown_screen().lock().unwrap().set_filter(Some(filter));
+            }
+            Err(e) => self.status_msg(&format!("Invalid filter: {e}")),
+        }
+    }
+
+    fn start_transfer(&self, action: FileAction, path: PathBuf) {
+        let queue = self.tab().write_queue.clone();
+        match action {
+            FileAction::Send => {
+                let mtu = self.tab().status.lock().unwrap().mtu;
+                transfer::spawn(
+                    &path,
+                    mtu.saturating_sub(ATT_HEADER_LEN) as usize,
+                    self.chunk_delay,
+                    queue,
+                    self.tab().transfer.clone(),
+                    self.tab().screen.clone(),
+                );
+            }
+            FileAction::XmodemSend => xmodem::spawn_send(
+                &path,
+                self.xmodem_block_size,
+                queue,
+                self.tab().tap.clone(),
+                self.tab().transfer.clone(),
+                self.tab().screen.clone(),
+            ),
+            FileAction::XmodemReceive => xmodem::spawn_receive(
+                path,
+                queue,
+                self.tab().tap.clone(),
+                self.tab().transfer.clone(),
+                self.tab().screen.clone(),
+            ),
+            FileAction::YmodemSend => ymodem::spawn_send(
+                &path,
+                queue,
+                self.tab().tap.clone(),
+                self.tab().transfer.clone(),
+                self.tab().screen.clone(),
+            ),
+            FileAction::YmodemReceive => ymodem::spawn_receive(
+                path,
+                queue,
+                self.tab().tap.clone(),
+                self.tab().transfer.clone(),
+                self.tab().screen.clone(),
+            ),
+        }
+    }
+}
+
+/// Numbers in the VT220 sequences of F5 to F12, which skip 16 and 22
+const FUNCTION_KEY_CODES: [u8; 8] = [15, 17, 18, 19, 20, 21, 23, 24];
+
+/// Translates a key press into the bytes a terminal would send for it, as xterm does
+fn key_bytes(key: &KeyEvent) -> Option<Vec<u8>> {
+    let mut data = match key.code {
+        KeyCode::Backspace => b"\x08".to_vec(),
+        KeyCode::Esc => b"\x1b".to_vec(),
+        KeyCode::Char(c) if c.is_ascii() && key.modifiers.contains(KeyModifiers::CONTROL) => {
+            vec![c as u8 & 0x1F]
+        }
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+        KeyCode::Left => b"\x1b[D".to_vec(),
+        KeyCode::Right => b"\x1b[C".to_vec(),
+        KeyCode::Up => b"\x1b[A".to_vec(),
+        KeyCode::Down => b"\x1b[B".to_vec(),
+        KeyCode::Tab => b"\t".to_vec(),
+        KeyCode::Home => b"\x1b[H".to_vec(),
+        KeyCode::End => b"\x1b[F".to_vec(),
+        KeyCode::Insert => b"\x1b[2~".to_vec(),
+        KeyCode::Delete => b"\x1b[3~".to_vec(),
+        KeyCode::PageUp => b"\x1b[5~".to_vec(),
+        KeyCode::PageDown => b"\x1b[6~".to_vec(),
+        KeyCode::F(n @ 1..=4) => vec![0x1b, b'O', b'P' + n - 1],
+        KeyCode::F(n @ 5..=12) => {
+            format!("\x1b[{}~", FUNCTION_KEY_CODES[usize::from(n) - 5]).into_bytes()
+        }
+        _ => return None,
+    };
+    // Meta sends ESC first, e.g. Alt+b for readline's backward-word
+    if key.modifiers.contains(KeyModifiers::ALT)
+        && matches!(key.code, KeyCode::Char(_) | KeyCode::Backspace)
+    {
+        data.insert(0, 0x1b);
+    }
+    Some(data)
+}
+
+/// Number of lines scrolled by PageUp/PageDown, leaving one line of context
+fn page_size<B: ratatui::backend::Backend>(term: &Terminal<B>) -> isize {
+    let rows = term.size().map(|area| area.height).unwrap_or(24);
+    // The status bar takes up one row
+    rows.saturating_sub(2).max(1) as isize
+}
+
+/// Whether the alternate screen was entered, so restoring the terminal leaves it
+static ALTERNATE_SCREEN: AtomicBool = AtomicBool::new(false);
+
+/// Raw mode and the alternate screen of the terminal UI, left again when dropped
+pub struct RawTerminal;
+
+impl RawTerminal {
+    pub fn enter() -> Result<RawTerminal> {
+        RawTerminal::open(true)
+    }
+
+    /// Raw mode on the normal screen buffer, for `--inline`
+    pub fn enter_inline() -> Result<RawTerminal> {
+        RawTerminal::open(false)
+    }
+
+    fn open(alternate_screen: bool) -> Result<RawTerminal> {
+        terminal::enable_raw_mode()?;
+        // From here on dropping restores the terminal, whatever fails next
+        let raw_terminal = RawTerminal;
+        if alternate_screen {
+            ALTERNATE_SCREEN.store(true, Ordering::Relaxed);
+            io::stdout().execute(terminal::EnterAlternateScreen)?;
+        }
+        io::stdout().execute(event::EnableBracketedPaste)?;
+        Ok(raw_terminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Leaves raw mode and the alternate screen, errors are of no use at this point
+fn restore_terminal() {
+    let mut stdout = io::stdout();
+    let _ = stdout.execute(event::DisableBracketedPaste);
+    let _ = stdout.execute(event::DisableMouseCapture);
+    let _ = terminal::disable_raw_mode();
+    if ALTERNATE_SCREEN.swap(false, Ordering::Relaxed) {
+        let _ = stdout.execute(terminal::LeaveAlternateScreen);
+    }
+}
+
+/// Makes a panic restore the terminal and close `links` before it is reported, then exit
+///
+/// A panicking task would otherwise leave the session running without its terminal.
+fn set_panic_hook(links: Vec<CurrentLink>) {
+    let runtime = tokio::runtime::Handle::current();
+    let report = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic| {
+        restore_terminal();
+        // The hook cannot wait for the links itself, but the runtime's other threads can
+        let (closed, done) = std::sync::mpsc::channel();
+        let links = links.clone();
+        let runtime = runtime.clone();
+        std::thread::spawn(move || {
+            runtime.block_on(close_links(&links));
+            let _ = closed.send(());
+        });
+        let _ = done.recv_timeout(CLOSE_TIMEOUT);
+        report(panic);
+        std::process::exit(101);
+    }));
+}
+
+/// Unsubscribes from and disconnects the devices still connected
+async fn close_links(links: &[CurrentLink]) {
+    // Taken from the mutexes first, a panic may have poisoned them
+    let links: Vec<_> = links
+        .iter()
+        .filter_map(|link| link.lock().ok()?.clone())
+        .collect();
+    let closing = future::join_all(links.iter().map(|link| link.disconnect()));
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, closing).await;
+}
+
+/// Completes on SIGTERM or SIGHUP, which end a session like quitting does
+pub async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let (Ok(mut terminate), Ok(mut hangup)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::hangup()),
+        ) {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = hangup.recv() => {}
+            }
+            return;
+        }
+    }
+    future::pending().await
+}
+
+/// The name of a setting as given on the command line, e.g. `crlf`
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or(String::new(), |value| value.get_name().to_string())
+}
+
+/// The link input is written to, `None` while disconnected
+type CurrentLink = Arc<Mutex<Option<Arc<dyn Transport>>>>;
+
+/// Writes queued input to the current link one payload at a time, preserving its order
+struct Writer {
+    current_link: CurrentLink,
+    status: Arc<Mutex<LinkStatus>>,
+    screen: Arc<Mutex<Screen>>,
+    /// Notified by the supervisor when the link is back up
+    reconnected: Arc<Notify>,
+    /// Most bytes held back while disconnected
+    buffer_limit: usize,
+    /// Pauses between the characters and lines written
+    pacing: Pacing,
+    /// Bytes the device allows to be sent, with `--flow-control credits`
+    credits: Option<Arc<Credits>>,
+    /// Numbered and acknowledged frames carrying the data, with `--reliable`
+    reliable: Option<Arc<Channel>>,
+    /// Given with `--plugin`, shared with the supervisor
+    plugin: Option<Arc<Mutex<Plugin>>>,
+    /// Input typed while the link is down, sent once it is back up
+    pending: Vec<u8>,
+    /// Whether input was dropped since the link went down
+    overflowed: bool,
+}
+
+impl Writer {
+    async fn run(mut self, mut queued: mpsc::Receiver<Vec<u8>>) {
+        loop {
+            tokio::select! {
+                data = queued.recv() => {
+                    let Some(data) = data else {
+                        return;
+                    };
+                    // Transformed as it is taken from the queue, so the plugin sees the data
+                    // in order even when it is held back
+                    let Some(data) = self.transform(data) else {
+                        continue;
+                    };
+                    let link = self.current_link.lock().unwrap().clone();
+                    match link {
+                        Some(link) if self.pending.is_empty() => self.write(&link, &data).await,
+                        _ => self.hold_back(&data),
+                    }
+                }
+                _ = self.reconnected.notified() => {
+                    let link = self.current_link.lock().unwrap().clone();
+                    self.overflowed = false;
+                    if let Some(link) = link
+                        && !self.pending.is_empty()
+                    {
+                        let pending = std::mem::take(&mut self.pending);
+                        self.write(&link, &pending).await;
+                        self.screen.lock().unwrap().status(&format!(
+                            "Sent {} bytes typed while disconnected",
+                            pending.len()
+                        ));
+                        self.status.lock().unwrap().pending = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// `data` as the plugin turns it, `None` if the plugin failed
+    fn transform(&self, data: Vec<u8>) -> Option<Vec<u8>> {
+        let Some(plugin) = &self.plugin else {
+            return Some(data);
+        };
+        let transformed = plugin.lock().unwrap().send(&data);
+        match transformed {
+            Ok(data) => Some(data),
+            Err(e) => {
+                self.screen
+                    .lock()
+                    .unwrap()
+                    .status(&format!("Not sent, {e:#}"));
+                None
+            }
+        }
+    }
+
+    async fn write(&self, link: &Arc<dyn Transport>, data: &[u8]) {
+        let written = match (&self.credits, &self.reliable) {
+            (Some(credits), _) => self.write_with_credits(credits, link.as_ref(), data).await,
+            (_, Some(reliable)) => write_reliable(reliable, link.as_ref(), data).await,
+            _ => self.pacing.write(link.as_ref(), data).await,
+        };
+        match written {
+            Ok(()) => {
+                let mut status = self.status.lock().unwrap();
+                status.tx_bytes += data.len() as u64;
+                status.tx_packets += data.len().div_ceil(link.max_payload().max(1)) as u64;
+            }
+            Err(e) => self
+                .screen
+                .lock()
+                .unwrap()
+                .status(&format!("Write failed: {e}")),
+        }
+    }
+
+    /// Writes `data` as the credits granted by the device allow
+    async fn write_with_credits(
+        &self,
+        credits: &Credits,
+        link: &dyn Transport,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            let taken = match tokio::time::timeout(CREDIT_WARNING, credits.take(rest.len())).await {
+                Ok(taken) => taken,
+                Err(_) => {
+                    self.screen
+                        .lock()
+                        .unwrap()
+                        .status("Waiting for the device to grant credits (--flow-control)");
+                    credits.take(rest.len()).await
+                }
+            };
+            self.pacing.write(link, &rest[..taken]).await?;
+            rest = &rest[taken..];
+        }
+        Ok(())
+    }
+
+    /// Adds `data` to the pending input, dropping what does not fit into the buffer
+    fn hold_back(&mut self, data: &[u8]) {
+        let room = self.buffer_limit - self.pending.len();
+        if data.len() > room && !self.overflowed {
+            self.overflowed = true;
+            self.screen
+                .lock()
+                .unwrap()
+                .status("Disconnected, input is dropped until the link is back up");
+        }
+        self.pending
+            .extend_from_slice(&data[..data.len().min(room)]);
+        self.status.lock().unwrap().pending = self.pending.len();
+    }
+}
+
+/// Writes `data` in frames as the window allows, a frame whose write fails is retransmitted
+async fn write_reliable(reliable: &Channel, link: &dyn Transport, data: &[u8]) -> Result<()> {
+    let size = link.max_payload().saturating_sub(reliable::OVERHEAD).max(1);
+    let mut result = Ok(());
+    for chunk in data.chunks(size) {
+        let frame = reliable.send(chunk).await;
+        if let Err(e) = link.write(&frame).await {
+            result = Err(e);
+        }
+    }
+    result
+}
+
+/// The open link of a tab, as far as the supervisor uses it besides writing
+trait DeviceLink: Transport + Clone + 'static {
+    /// ATT MTU negotiated for the link
+    fn mtu(&self) -> u16;
+
+    /// Current signal strength, `None` if it is not known
+    fn rssi(&self) -> impl Future<Output = Option<i16>> + Send;
+
+    /// Reads the Device Information Service, `None` if the device has none
+    fn device_info(&self) -> impl Future<Output = Result<Option<DeviceInformation>>> + Send;
+
+    /// The battery level and its changes, `None` if the device has no Battery Service
+    fn battery(&self) -> impl Future<Output = Result<Option<(u8, battery::Levels)>>> + Send;
+}
+
+impl DeviceLink for Link {
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    async fn rssi(&self) -> Option<i16> {
+        self.peripheral.properties().await.ok()??.rssi
+    }
+
+    async fn device_info(&self) -> Result<Option<DeviceInformation>> {
+        device_info::read(&self.peripheral).await
+    }
+
+    async fn battery(&self) -> Result<Option<(u8, battery::Levels)>> {
+        battery::watch(&self.peripheral).await
+    }
+}
+
+/// Opens the link of a tab again after it dropped
+trait Connector: Send + Sync + 'static {
+    type Link: DeviceLink;
+
+    fn reopen(&self) -> impl Future<Output = Result<(Self::Link, ByteStream)>> + Send;
+}
+
+/// Scans for the device by its address again and opens a new BLE link to it
+struct Rescan {
+    central: Adapter,
+    device: DeviceInfo,
+    options: LinkOptions,
+}
+
+impl Connector for Rescan {
+    type Link = Link;
+
+    async fn reopen(&self) -> Result<(Link, ByteStream)> {
+        let search = Search {
+            filter: DeviceFilter::Address(self.device.address.clone()),
+            service: None,
+            timeout: Some(device::SCAN_DURATION),
+        };
+        let peripheral = device::wait_for(&self.central, &search)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("device not found"))?
+            .peripheral;
+        open_link(&self.central, peripheral, &self.options).await
+    }
+}
+
+/// Shows the messages about reopening a link on the screen of its tab
+#[derive(Debug)]
+struct ScreenNotices(Arc<Mutex<Screen>>);
+
+impl Notices for ScreenNotices {
+    fn notice(&self, msg: &str) {
+        self.0.lock().unwrap().status(msg);
+    }
+}
+
+/// Opens the link to `peripheral`, its data ending when the adapter reports the disconnection
+///
+/// BlueZ keeps the notification stream open after a disconnection, so the end of the stream
+/// alone does not tell that the link is gone. Used by the bridges too.
+pub async fn open_link(
+    central: &Adapter,
+    peripheral: Peripheral,
+    options: &LinkOptions,
+) -> Result<(Link, ByteStream)> {
+    // Subscribe before connecting so no disconnection event can be missed
+    let events = central.events().await?;
+    let (link, notifications) = Link::open(peripheral, options).await?;
+    let id = link.peripheral.id();
+    let disconnected = events.any(move |event| {
+        future::ready(matches!(event, CentralEvent::DeviceDisconnected(lost) if lost == id))
+    });
+    let received = transport::bytes(notifications).take_until(disconnected);
+    Ok((link, Box::pin(received)))
+}
+
+/// Owns the link in the background, reconnecting whenever it drops
+struct Supervisor<C> {
+    connector: C,
+    current_link: CurrentLink,
+    status: Arc<Mutex<LinkStatus>>,
+    policy: ReconnectPolicy,
+    log: Option<Arc<Mutex<SessionLog>>>,
+    recording: Option<Arc<Mutex<Recording>>>,
+    /// Given with `--capture-raw`, only written by the supervisor
+    capture: Option<Mutex<RawCapture>>,
+    /// Given with `--csv`, only written by the supervisor
+    csv: Option<Mutex<Csv>>,
+    screen: Arc<Mutex<Screen>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    /// Where ZMODEM downloads started by the device go, with `--zmodem-dir`
+    zmodem_dir: Option<PathBuf>,
+    zmodem: Mutex<zmodem::Detector>,
+    reconnected: Arc<Notify>,
+    reconnect_request: Arc<Notify>,
+    triggers: Mutex<Triggers>,
+    /// Queue of the writer, used by triggers sending to the device
+    write_queue: mpsc::Sender<Vec<u8>>,
+    /// Set by a trigger ending the session
+    exit_code: Arc<Mutex<Option<i32>>>,
+    /// Battery level in percent at which the user is warned
+    battery_alert: Option<u8>,
+    /// Sent after every connection, each command followed by its pause
+    init: Vec<(Vec<u8>, Duration)>,
+    /// Whether the Device Information Service is read after connecting
+    read_device_info: bool,
+    device_info: Arc<Mutex<Option<DeviceInformation>>>,
+    /// Interval of the keepalive, which writes `keepalive_data` or else reads the RSSI
+    keepalive: Option<Duration>,
+    keepalive_data: Option<Vec<u8>>,
+    /// Time without notifications after which the link counts as stalled
+    stall_timeout: Option<Duration>,
+    /// Whether a stalled link is reconnected instead of only marked
+    stall_reconnect: bool,
+    /// Whether losing the connection shows a desktop notification
+    notify: bool,
+    /// Shell commands run as the device connects and disconnects
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+    /// Bytes the device allows to be sent, with `--flow-control credits`
+    credits: Option<Arc<Credits>>,
+    /// Numbered and acknowledged frames carrying the data, with `--reliable`
+    reliable: Option<Arc<Channel>>,
+    /// Events passed to the `--hooks` script
+    hooks: Option<mpsc::Sender<hooks::Event>>,
+    /// Given with `--plugin`, shared with the writer
+    plugin: Option<Arc<Mutex<Plugin>>>,
+}
+
+/// Why [`Supervisor::pump`] stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkEnd {
+    Lost,
+    /// The user asked to reconnect
+    Requested,
+    /// Nothing was received for the stall timeout
+    Stalled,
+}
+
+impl<C: Connector> Supervisor<C> {
+    /// Forwards received data to the terminal until reconnecting is given up
+    async fn run(self, mut link: C::Link, mut received: ByteStream) {
+        loop {
+            // The grants would only come in while pumping, so init does not wait for them
+            if let Some(credits) = &self.credits {
+                credits.reset();
+            }
+            if let Some(reliable) = &self.reliable {
+                for frame in reliable.reset() {
+                    let _ = link.write(&frame).await;
+                }
+            }
+            for (data, delay) in &self.init {
+                if !data.is_empty() {
+                    let _ = self.write_now(&link, data).await;
+                }
+                tokio::time::sleep(*delay).await;
+            }
+            self.hook(hooks::Event::Connected);
+            if let Some(command) = &self.on_connect {
+                self.spawn_command(command);
+            }
+
+            if self.read_device_info {
+                self.show_device_info(&link).await;
+            }
+            let mut battery = self.watch_battery(&link).await;
+            let end = self.pump(&link, &mut received, &mut battery).await;
+            *self.current_link.lock().unwrap() = None;
+            let _ = link.disconnect().await;
+            self.hook(hooks::Event::Disconnected);
+            if let Some(command) = &self.on_disconnect {
+                self.spawn_command(command);
+            }
+            if self.notify && end == LinkEnd::Lost {
+                self.notify("Connection lost".to_string());
+            }
+
+            let Some(reconnected) = self.reconnect(end).await else {
+                self.set_state(ConnectionState::Lost);
+                return;
+            };
+            (link, received) = reconnected;
+
+            self.status("Reconnected");
+            {
+                let mut status = self.status.lock().unwrap();
+                status.mtu = link.mtu();
+                status.reconnects += 1;
+            }
+            *self.current_link.lock().unwrap() = Some(Arc::new(link.clone()));
+            self.set_state(ConnectionState::Connected);
+            self.reconnected.notify_one();
+        }
+    }
+
+    /// Retries opening the link with exponential backoff, `None` if all attempts failed
+    ///
+    /// A reconnect requested by the user or after a stall is attempted even if reconnecting is
+    /// disabled.
+    async fn reconnect(&self, end: LinkEnd) -> Option<(C::Link, ByteStream)> {
+        let (retries, reason) = match end {
+            LinkEnd::Requested => (self.policy.retries.max(1), "Disconnected"),
+            LinkEnd::Stalled => (self.policy.retries.max(1), "Link stalled"),
+            LinkEnd::Lost => (self.policy.retries, "Connection lost"),
+        };
+        if retries == 0 {
+            self.status(reason);
+            return None;
+        }
+        self.set_state(ConnectionState::Reconnecting);
+
+        let mut delay = self.policy.initial_delay;
+        for attempt in 1..=retries {
+            self.status(&format!(
+                "{reason}, reconnecting in {:.1}s (attempt {attempt}/{retries})",
+                delay.as_secs_f32(),
+            ));
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.policy.max_delay);
+
+            match self.connector.reopen().await {
+                Ok(reconnected) => return Some(reconnected),
+                Err(e) => self.status(&format!("Reconnect failed: {e}")),
+            }
+        }
+
+        self.status("Giving up reconnecting");
+        None
+    }
+
+    fn status(&self, msg: &str) {
+        self.screen.lock().unwrap().status(msg);
+    }
+
+    /// Starts a shell command with the device described in its environment
+    fn spawn_command(&self, command: &str) {
+        let env = {
+            let status = self.status.lock().unwrap();
+            device_env(&status.name, &status.address, status.rssi, status.mtu)
+        };
+        if let Err(e) = run_command(command, &env) {
+            self.status(&format!("Could not run `{command}`: {e}"));
+        }
+    }
+
+    /// Passes `event` to the `--hooks` script, dropping it while the script is behind
+    fn hook(&self, event: hooks::Event) {
+        if let Some(hooks) = &self.hooks
+            && let Err(mpsc::error::TrySendError::Full(_)) = hooks.try_send(event)
+        {
+            self.status("Hooks script is behind, an event was dropped");
+        }
+    }
+
+    /// Starts a ZMODEM download if `data` holds its start, returns where the start begins
+    fn detect_zmodem(&self, data: &[u8]) -> Option<usize> {
+        let dir = self.zmodem_dir.as_ref()?;
+        let start = self.zmodem.lock().unwrap().find(data)?;
+        // Another transfer waiting for its own replies may see the same bytes
+        if self.transfer.lock().unwrap().is_some() {
+            return None;
+        }
+        zmodem::spawn_receive(
+            dir.clone(),
+            self.write_queue.clone(),
+            self.tap.clone(),
+            self.transfer.clone(),
+            self.screen.clone(),
+        );
+        Some(start)
+    }
+
+    /// Runs the actions of the triggers set off by received data
+    fn run_triggers(&self, data: &[u8]) {
+        let fired: Vec<_> = self
+            .triggers
+            .lock()
+            .unwrap()
+            .receive(data)
+            .into_iter()
+            .map(|trigger| (trigger.pattern().to_string(), trigger.actions().to_vec()))
+            .collect();
+        for (pattern, actions) in fired {
+            for action in actions {
+                match action {
+                    // Waiting for room could wait forever, for a writer waiting for credits
+                    // or acknowledgements that only come in once this returns
+                    Action::Send(data) => {
+                        if let Err(mpsc::error::TrySendError::Full(_)) =
+                            self.write_queue.try_send(data)
+                        {
+                            self.status(&format!(
+                                "/{pattern}/ matched, not sent as the write queue is full"
+                            ));
+                        }
+                    }
+                    Action::Run(command) => self.spawn_command(&command),
+                    Action::Notify(message) => self.notify(message),
+                    Action::Bell => self.screen.lock().unwrap().ring_bell(),
+                    Action::StopLog => {
+                        if let Some(log) = &self.log {
+                            log.lock().unwrap().pause();
+                            self.status(&format!("Logging paused, /{pattern}/ matched"));
+                        }
+                    }
+                    Action::Exit(code) => {
+                        self.status(&format!("/{pattern}/ matched, exiting with code {code}"));
+                        *self.exit_code.lock().unwrap() = Some(code);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes `data` without waiting for credits or acknowledgements, which only come in
+    /// while pumping
+    async fn write_now(&self, link: &C::Link, data: &[u8]) -> Result<()> {
+        let transformed = match &self.plugin {
+            Some(plugin) => Some(plugin.lock().unwrap().send(data)?),
+            None => None,
+        };
+        let data = transformed.as_deref().unwrap_or(data);
+        if let Some(credits) = &self.credits {
+            credits.charge(data.len());
+        }
+        match &self.reliable {
+            Some(reliable) => link.write(&reliable.send_now(data)).await,
+            None => link.write(data).await,
+        }
+    }
+
+    /// Shows a desktop notification about the device, without holding up the data
+    fn notify(&self, message: String) {
+        let summary = self.status.lock().unwrap().name.clone();
+        let screen = self.screen.clone();
+        tokio::spawn(async move {
+            if let Err(e) = notify::send(&summary, &message).await {
+                screen
+                    .lock()
+                    .unwrap()
+                    .status(&format!("Notification failed: {e}"));
+            }
+        });
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.status.lock().unwrap().state = state;
+    }
+
+    /// Reads the Device Information Service and lists its fields on the screen
+    async fn show_device_info(&self, link: &C::Link) {
+        match link.device_info().await {
+            Ok(Some(info)) => {
+                for (label, value) in &info.fields {
+                    self.status(&format!("{label}: {value}"));
+                }
+                *self.device_info.lock().unwrap() = Some(info);
+            }
+            Ok(None) => self.status("The device has no Device Information Service"),
+            Err(e) => self.status(&format!("Could not read the device information: {e}")),
+        }
+    }
+
+    /// Shows the battery level if the device has a Battery Service, returns its changes
+    async fn watch_battery(&self, link: &C::Link) -> Option<battery::Levels> {
+        match link.battery().await {
+            Ok(Some((level, levels))) => {
+                self.set_battery(level);
+                Some(levels)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.status(&format!("Could not read the battery level: {e}"));
+                None
+            }
+        }
+    }
+
+    /// Adds the data of a notification to the `--capture-raw` file
+    fn capture(&self, data: &[u8]) {
+        if let Some(capture) = &self.capture
+            && let Err(e) = capture.lock().unwrap().write(data)
+        {
+            self.status(&format!("Writing to the capture failed: {e}"));
+        }
+    }
+
+    /// Updates the battery level, warning once when it drops to the alert threshold
+    fn set_battery(&self, level: u8) {
+        let previous = self.status.lock().unwrap().battery.replace(level);
+        if let Some(alert) = self.battery_alert
+            && level <= alert
+            && previous.is_none_or(|previous| previous > alert)
+        {
+            self.status(&format!("Battery low: {level}%"));
+            self.screen.lock().unwrap().ring_bell();
+        }
+    }
+
+    /// Prints received data until the link drops or a reconnect is due
+    async fn pump(
+        &self,
+        link: &C::Link,
+        received: &mut ByteStream,
+        battery: &mut Option<battery::Levels>,
+    ) -> LinkEnd {
+        let mut rssi_poll = tokio::time::interval(RSSI_POLL_INTERVAL);
+        let mut keepalive = self
+            .keepalive
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+        let mut last_rx = tokio::time::Instant::now();
+        let mut stalled = false;
+        loop {
+            let stall_deadline = last_rx + self.stall_timeout.unwrap_or_default();
+            tokio::select! {
+                value = received.next() => match value {
+                    Some(value) => {
+                        // Every notification is a record of its own in a framed capture, also
+                        // while a transfer takes the data
+                        self.capture(&value);
+                        // At high data rates the screen, log and triggers then work through
+                        // a few large chunks instead of many small notifications
+                        let (value, count, lost) =
+                            batch(value, received, |value| self.capture(value)).await;
+                        last_rx = tokio::time::Instant::now();
+                        if stalled {
+                            stalled = false;
+                            self.set_state(ConnectionState::Connected);
+                            self.status("Receiving again");
+                        }
+                        {
+                            let mut status = self.status.lock().unwrap();
+                            status.rx_bytes += value.len() as u64;
+                            status.rx_notifications += count;
+                        }
+                        // Without the grants of flow control or the frames of the reliable
+                        // protocol, all but the capture get
+                        let unwrapped = if let Some(credits) = &self.credits {
+                            Some(credits.receive(&value))
+                        } else if let Some(reliable) = &self.reliable {
+                            let received = reliable.receive(&value);
+                            for reply in &received.replies {
+                                let _ = link.write(reply).await;
+                            }
+                            Some(received.data)
+                        } else {
+                            None
+                        };
+                        let output = unwrapped.as_deref().unwrap_or(&value);
+                        let transformed = self.plugin.as_ref().map(|plugin| {
+                            let transformed = plugin.lock().unwrap().receive(output);
+                            transformed.unwrap_or_else(|e| {
+                                self.status(&format!("Received data dropped, {e:#}"));
+                                Vec::new()
+                            })
+                        });
+                        let output = transformed.as_deref().unwrap_or(output);
+                        let tap = self.tap.lock().unwrap().clone();
+                        if let Some(tap) = tap {
+                            let _ = tap.send(output.to_vec());
+                        } else {
+                            // The start of a ZMODEM download and what follows it is not shown
+                            let output = match self.detect_zmodem(output) {
+                                Some(start) => &output[..start],
+                                None => output,
+                            };
+                            self.screen.lock().unwrap().receive(output);
+
+                            if let Some(log) = &self.log
+                                && let Err(e) = log.lock().unwrap().write(output)
+                            {
+                                self.status(&format!("Writing to log failed: {e}"));
+                            }
+                            if let Some(recording) = &self.recording
+                                && let Err(e) = recording.lock().unwrap().output(output)
+                            {
+                                self.status(&format!("Recording failed: {e}"));
+                            }
+                            if let Some(csv) = &self.csv
+                                && let Err(e) = csv.lock().unwrap().write(output)
+                            {
+                                self.status(&format!("Writing to the CSV file failed: {e}"));
+                            }
+                            self.hook(hooks::Event::Received(output.to_vec()));
+                            self.run_triggers(output);
+                        }
+                        if lost {
+                            return LinkEnd::Lost;
+                        }
+                    }
+                    None => return LinkEnd::Lost,
+                },
+                Some(level) = next_level(battery) => self.set_battery(level),
+                _ = self.reconnect_request.notified() => return LinkEnd::Requested,
+                Some(retransmission) = due(self.reliable.as_deref()) => {
+                    if retransmission.retries == reliable::MAX_RETRIES {
+                        self.status(&format!(
+                            "No acknowledgement from the device after {} retransmissions",
+                            reliable::MAX_RETRIES
+                        ));
+                    }
+                    for frame in &retransmission.frames {
+                        let _ = link.write(frame).await;
+                    }
+                }
+                _ = tick(&mut keepalive) => match &self.keepalive_data {
+                    Some(data) => {
+                        if let Err(e) = self.write_now(link, data).await {
+                            self.status(&format!("Keepalive failed: {e}"));
+                        }
+                    }
+                    None => self.status.lock().unwrap().rssi = link.rssi().await,
+                },
+                _ = tokio::time::sleep_until(stall_deadline),
+                    if self.stall_timeout.is_some() && !stalled =>
+                {
+                    if self.stall_reconnect {
+                        return LinkEnd::Stalled;
+                    }
+                    stalled = true;
+                    self.set_state(ConnectionState::Stalled);
+                    self.status(&format!(
+                        "No data for {}s, the link may be stalled",
+                        self.stall_timeout.unwrap_or_default().as_secs(),
+                    ));
+                }
+                _ = rssi_poll.tick() => {
+                    if let Some(rssi) = link.rssi().await {
+                        self.status.lock().unwrap().rssi = Some(rssi);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The next battery level, `None` right away without a Battery Service
+async fn next_level(levels: &mut Option<battery::Levels>) -> Option<u8> {
+    levels.as_mut()?.next().await
+}
+
+/// Waits until frames of the reliable protocol are due for retransmission, never without it
+async fn due(reliable: Option<&Channel>) -> Option<Retransmission> {
+    let reliable = reliable?;
+    loop {
+        if let Some(retransmission) = reliable.due() {
+            return Some(retransmission);
+        }
+        tokio::time::sleep(RETRANSMIT_CHECK).await;
+    }
+}
+
+/// Adds the notifications arriving within [`BATCH_TIME`] to `data`, up to [`BATCH_LEN`]
+/// bytes, along with the number of notifications and whether they ended meanwhile
+///
+/// `each` is called with every notification added.
+async fn batch(
+    mut data: Vec<u8>,
+    received: &mut ByteStream,
+    mut each: impl FnMut(&[u8]),
+) -> (Vec<u8>, u64, bool) {
+    let deadline = tokio::time::Instant::now() + BATCH_TIME;
+    let mut count = 1;
+    while data.len() < BATCH_LEN {
+        match tokio::time::timeout_at(deadline, received.next()).await {
+            Ok(Some(value)) => {
+                each(&value);
+                data.extend_from_slice(&value);
+                count += 1;
+            }
+            Ok(None) => return (data, count, true),
+            Err(_) => break,
+        }
+    }
+    (data, count, false)
+}
+
+/// Completes at the next tick of an optional interval, never without one
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Copies `text` by writing it to the standard input of a shell command
+fn copy_with(command: &str, text: &str) -> io::Result<()> {
+    #[cfg(windows)]
+    let mut process = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    process.arg("/C");
+    #[cfg(not(windows))]
+    let mut process = tokio::process::Command::new("sh");
+    #[cfg(not(windows))]
+    process.arg("-c");
+
+    let mut child = process
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take();
+    let text = text.to_string();
+    tokio::spawn(async move {
+        if let Some(stdin) = &mut stdin {
+            let _ = tokio::io::AsyncWriteExt::write_all(stdin, text.as_bytes()).await;
+        }
+        // Closed so the command sees the end of the text
+        drop(stdin);
+        child.wait().await
+    });
+    Ok(())
+}
+
+/// Variables describing the device to the commands run for it
+pub fn device_env(
+    name: &str,
+    address: &str,
+    rssi: Option<i16>,
+    mtu: u16,
+) -> Vec<(&'static str, String)> {
+    vec![
+        ("NUS_NAME", name.to_string()),
+        ("NUS_ADDRESS", address.to_string()),
+        (
+            "NUS_RSSI",
+            rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+        ),
+        ("NUS_MTU", mtu.to_string()),
+    ]
+}
+
+/// Starts a shell command in the background with the variables `env`, its output is discarded
+pub fn run_command(command: &str, env: &[(&str, String)]) -> io::Result<()> {
+    #[cfg(windows)]
+    let mut process = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    process.arg("/C");
+    #[cfg(not(windows))]
+    let mut process = tokio::process::Command::new("sh");
+    #[cfg(not(windows))]
+    process.arg("-c");
+
+    let mut child = process
+        .arg(command)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    tokio::spawn(async move { child.wait().await });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ansi;
+    use crate::transport::{MockDevice, MockTransport, mock};
+    use crate::trigger;
+
+    impl DeviceLink for MockTransport {
+        fn mtu(&self) -> u16 {
+            self.max_payload() as u16 + ATT_HEADER_LEN
+        }
+
+        async fn rssi(&self) -> Option<i16> {
+            None
+        }
+
+        async fn device_info(&self) -> Result<Option<DeviceInformation>> {
+            Ok(None)
+        }
+
+        async fn battery(&self) -> Result<Option<(u8, battery::Levels)>> {
+            Ok(None)
+        }
+    }
+
+    /// Hands out prepared mock links, failing once they are used up
+    struct Prepared(Mutex<Vec<(MockTransport, ByteStream)>>);
+
+    impl Connector for Prepared {
+        type Link = MockTransport;
+
+        async fn reopen(&self) -> Result<(MockTransport, ByteStream)> {
+            self.0
+                .lock()
+                .unwrap()
+                .pop()
+                .ok_or(anyhow!("device not found"))
+        }
+    }
+
+    /// State shared by the writer and supervisor of a tab, as [`TabSetup::open`] sets it up
+    struct Shared {
+        current_link: CurrentLink,
+        status: Arc<Mutex<LinkStatus>>,
+        screen: Arc<Mutex<Screen>>,
+        reconnected: Arc<Notify>,
+    }
+
+    fn shared(link: Option<MockTransport>) -> Shared {
+        let current_link: CurrentLink = Arc::new(Mutex::new(
+            link.map(|link| Arc::new(link) as Arc<dyn Transport>),
+        ));
+        Shared {
+            current_link,
+            status: Arc::new(Mutex::new(LinkStatus {
+                name: "Test".to_string(),
+                address: "00:11:22:33:44:55".to_string(),
+                state: ConnectionState::Connected,
+                rssi: None,
+                mtu: 23,
+                tx_bytes: 0,
+                tx_packets: 0,
+                rx_bytes: 0,
+                rx_notifications: 0,
+                reconnects: 0,
+                pending: 0,
+                battery: None,
+            })),
+            screen: Arc::new(Mutex::new(Screen::new(
+                100,
+                decode::Mode::Text,
+                Newline::Lf,
+            ))),
+            reconnected: Arc::new(Notify::new()),
+        }
+    }
+
+    fn writer(shared: &Shared, buffer_limit: usize) -> Writer {
+        Writer {
+            current_link: shared.current_link.clone(),
+            status: shared.status.clone(),
+            screen: shared.screen.clone(),
+            reconnected: shared.reconnected.clone(),
+            buffer_limit,
+            pacing: Pacing::default(),
+            credits: None,
+            reliable: None,
+            plugin: None,
+            pending: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    fn supervisor<C: Connector>(connector: C, shared: &Shared) -> Supervisor<C> {
+        let (write_queue, _) = mpsc::channel(WRITE_QUEUE_LEN);
+        Supervisor {
+            connector,
+            current_link: shared.current_link.clone(),
+            status: shared.status.clone(),
+            policy: ReconnectPolicy {
+                retries: 2,
+                initial_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(1),
+            },
+            log: None,
+            recording: None,
+            capture: None,
+            csv: None,
+            screen: shared.screen.clone(),
+            tap: Tap::default(),
+            transfer: Arc::default(),
+            zmodem_dir: None,
+            zmodem: Mutex::default(),
+            reconnected: shared.reconnected.clone(),
+            reconnect_request: Arc::new(Notify::new()),
+            triggers: Mutex::new(Triggers::new(Vec::new())),
+            write_queue,
+            exit_code: Arc::default(),
+            battery_alert: None,
+            init: Vec::new(),
+            read_device_info: false,
+            device_info: Arc::default(),
+            keepalive: None,
+            keepalive_data: None,
+            stall_timeout: None,
+            stall_reconnect: false,
+            notify: false,
+            on_connect: None,
+            on_disconnect: None,
+            credits: None,
+            reliable: None,
+            hooks: None,
+            plugin: None,
+        }
+    }
+
+    /// The lines on the screen from the top, without the status message styling
+    fn lines(screen: &Mutex<Screen>) -> Vec<String> {
+        let screen = screen.lock().unwrap();
+        let mut lines: Vec<String> = screen
+            .view_rev()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let text: String = ansi::render(line).cells.iter().map(|(c, _)| c).collect();
+                text.trim_start_matches("[nus-terminal] ").to_string()
+            })
+            .collect();
+        lines.reverse();
+        lines
+    }
+
+    /// Waits until `done` holds, the paused clock of the tests moving on meanwhile
+    async fn until(done: impl Fn() -> bool) {
+        while !done() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Collects writes made to `device` until `len` bytes arrived
+    async fn received(device: &mut MockDevice, len: usize) -> Vec<Vec<u8>> {
+        let mut writes = Vec::new();
+        while writes.iter().map(Vec::len).sum::<usize>() < len {
+            writes.push(device.receive().await.unwrap());
+        }
+        writes
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writer_keeps_the_order_of_the_input() {
+        let (link, _received, mut device) = mock(4);
+        let shared = shared(Some(link));
+        let (write_queue, queued) = mpsc::channel(WRITE_QUEUE_LEN);
+        tokio::spawn(writer(&shared, 1024).run(queued));
+
+        for data in ["hello", " ", "world"] {
+            write_queue.send(data.as_bytes().to_vec()).await.unwrap();
+        }
+        let writes = received(&mut device, 11).await;
+        assert!(writes.iter().all(|write| write.len() <= 4));
+        assert_eq!(writes.concat(), b"hello world");
+
+        let status = shared.status.lock().unwrap();
+        assert_eq!(status.tx_bytes, 11);
+        // 4 + 1, 1 and 4 + 1 bytes
+        assert_eq!(status.tx_packets, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writer_holds_back_input_until_reconnected() {
+        let (link, _received, mut device) = mock(20);
+        let shared = shared(None);
+        let (write_queue, queued) = mpsc::channel(WRITE_QUEUE_LEN);
+        tokio::spawn(writer(&shared, 1024).run(queued));
+
+        write_queue.send(b"typed ".to_vec()).await.unwrap();
+        write_queue.send(b"offline".to_vec()).await.unwrap();
+        until(|| shared.status.lock().unwrap().pending == 13).await;
+        assert!(device.written().is_empty());
+
+        *shared.current_link.lock().unwrap() = Some(Arc::new(link));
+        shared.reconnected.notify_one();
+        assert_eq!(received(&mut device, 13).await.concat(), b"typed offline");
+        until(|| shared.status.lock().unwrap().pending == 0).await;
+        assert_eq!(
+            lines(&shared.screen),
+            ["Sent 13 bytes typed while disconnected"]
+        );
+
+        // Sent right away again once nothing is pending
+        write_queue.send(b"!".to_vec()).await.unwrap();
+        assert_eq!(device.receive().await.unwrap(), b"!");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writer_drops_what_does_not_fit_into_the_buffer() {
+        let (link, _received, mut device) = mock(20);
+        let shared = shared(None);
+        let (write_queue, queued) = mpsc::channel(WRITE_QUEUE_LEN);
+        tokio::spawn(writer(&shared, 4).run(queued));
+
+        write_queue.send(b"abc".to_vec()).await.unwrap();
+        write_queue.send(b"def".to_vec()).await.unwrap();
+        write_queue.send(b"ghi".to_vec()).await.unwrap();
+        until(|| shared.status.lock().unwrap().pending == 4).await;
+        // Told only once
+        assert_eq!(
+            lines(&shared.screen),
+            ["Disconnected, input is dropped until the link is back up"]
+        );
+
+        *shared.current_link.lock().unwrap() = Some(Arc::new(link));
+        shared.reconnected.notify_one();
+        assert_eq!(received(&mut device, 4).await.concat(), b"abcd");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervisor_reconnects_after_the_link_drops() {
+        let (first, first_received, mut first_device) = mock(20);
+        let (second, second_received, mut second_device) = mock(20);
+        let shared = shared(Some(first.clone()));
+        let connector = Prepared(Mutex::new(vec![(second, second_received)]));
+        let mut supervisor = supervisor(connector, &shared);
+        supervisor.init = vec![(b"init\r".to_vec(), Duration::ZERO)];
+        tokio::spawn(supervisor.run(first, first_received));
+
+        assert_eq!(first_device.receive().await.unwrap(), b"init\r");
+        first_device.send(b"first\n");
+        until(|| shared.status.lock().unwrap().rx_bytes == 6).await;
+
+        // Dropping the device ends the data received from it
+        drop(first_device);
+        until(|| shared.status.lock().unwrap().state == ConnectionState::Reconnecting).await;
+        assert!(shared.current_link.lock().unwrap().is_none());
+
+        // The init sequence is sent again on the new link
+        assert_eq!(second_device.receive().await.unwrap(), b"init\r");
+        until(|| shared.status.lock().unwrap().state == ConnectionState::Connected).await;
+        assert!(shared.current_link.lock().unwrap().is_some());
+        assert_eq!(shared.status.lock().unwrap().reconnects, 1);
+        second_device.send(b"second\n");
+        until(|| shared.status.lock().unwrap().rx_bytes == 13).await;
+
+        assert_eq!(
+            lines(&shared.screen),
+            [
+                "first",
+                "Connection lost, reconnecting in 0.1s (attempt 1/2)",
+                "Reconnected",
+                "second",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervisor_gives_up_when_reconnecting_fails() {
+        let (link, received, device) = mock(20);
+        let shared = shared(Some(link.clone()));
+        let connector = Prepared(Mutex::new(Vec::new()));
+        tokio::spawn(supervisor(connector, &shared).run(link, received));
+
+        drop(device);
+        until(|| shared.status.lock().unwrap().state == ConnectionState::Lost).await;
+        assert_eq!(
+            lines(&shared.screen),
+            [
+                "Connection lost, reconnecting in 0.1s (attempt 1/2)",
+                "Reconnect failed: device not found",
+                "Connection lost, reconnecting in 0.2s (attempt 2/2)",
+                "Reconnect failed: device not found",
+                "Giving up reconnecting",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervisor_disconnects_when_asked_to_reconnect() {
+        let (link, received, device) = mock(20);
+        let (second, second_received, _second_device) = mock(20);
+        let shared = shared(Some(link.clone()));
+        let connector = Prepared(Mutex::new(vec![(second, second_received)]));
+        let supervisor = supervisor(connector, &shared);
+        let reconnect_request = supervisor.reconnect_request.clone();
+        tokio::spawn(supervisor.run(link, received));
+
+        reconnect_request.notify_one();
+        until(|| shared.status.lock().unwrap().reconnects == 1).await;
+        assert!(!device.is_connected());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn capture_keeps_every_notification_while_a_transfer_takes_the_data() {
+        let path = std::env::temp_dir().join(format!("nus-capture-{}", std::process::id()));
+        let (link, received, device) = mock(20);
+        let shared = shared(Some(link.clone()));
+        let mut supervisor = supervisor(Prepared(Mutex::new(Vec::new())), &shared);
+        supervisor.capture = Some(Mutex::new(RawCapture::open(&path, true).unwrap()));
+        let (tap, mut tapped) = mpsc::unbounded_channel();
+        *supervisor.tap.lock().unwrap() = Some(tap);
+        tokio::spawn(supervisor.run(link, received));
+
+        // Arriving together, the notifications are batched
+        for data in ["one", "two", "three"] {
+            device.send(data.as_bytes());
+        }
+        assert_eq!(tapped.recv().await.unwrap(), b"onetwothree");
+        assert_eq!(shared.status.lock().unwrap().rx_notifications, 3);
+
+        let capture = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut records = Vec::new();
+        let mut rest = &capture[..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            records.push(&rest[12..12 + len]);
+            rest = &rest[12 + len..];
+        }
+        assert_eq!(records, [&b"one"[..], b"two", b"three"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn triggers_do_not_wait_for_a_full_write_queue() {
+        let (link, received, device) = mock(20);
+        let shared = shared(Some(link.clone()));
+        let mut supervisor = supervisor(Prepared(Mutex::new(Vec::new())), &shared);
+        let rules =
+            crate::toml::parse("[[trigger]]\npattern = 'login:'\nsend = \"root\\n\"").unwrap();
+        supervisor.triggers = Mutex::new(Triggers::new(trigger::parse(&rules["trigger"]).unwrap()));
+        // Like a writer waiting for credits, nothing takes the queued input
+        let (write_queue, mut queued) = mpsc::channel(1);
+        supervisor.write_queue = write_queue;
+        tokio::spawn(supervisor.run(link, received));
+
+        device.send(b"login: ");
+        assert_eq!(queued.recv().await.unwrap(), b"root\n");
+        device.send(b"\nlogin: ");
+        device.send(b"\nlogin: ");
+        until(|| shared.status.lock().unwrap().rx_bytes == 23).await;
+        // Receiving goes on
+        device.send(b"\nready\n");
+        until(|| shared.status.lock().unwrap().rx_bytes == 30).await;
+        assert_eq!(
+            lines(&shared.screen),
+            [
+                "login: ",
+                "login: ",
+                "login: ",
+                "/login:/ matched, not sent as the write queue is full",
+                "ready",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hooks_echoing_lines_do_not_stall_receiving_while_waiting_for_credits() {
+        let (link, received, mut device) = mock(20);
+        let shared = shared(Some(link.clone()));
+        let credits: Arc<Credits> = Arc::default();
+        let (write_queue, queued) = mpsc::channel(WRITE_QUEUE_LEN);
+        let mut writer = writer(&shared, 1024);
+        writer.credits = Some(credits.clone());
+        tokio::spawn(writer.run(queued));
+
+        let mut supervisor = supervisor(Prepared(Mutex::new(Vec::new())), &shared);
+        supervisor.credits = Some(credits);
+        supervisor.write_queue = write_queue.clone();
+        let ast = rhai::Engine::new()
+            .compile("fn on_line(line) { send(line + \"\\n\"); }")
+            .unwrap();
+        let (events, hook_events) = mpsc::channel(HOOK_EVENTS_LEN);
+        let host = hooks::Host {
+            write_queue,
+            screen: shared.screen.clone(),
+            status: Arc::default(),
+        };
+        tokio::spawn(hooks::run(ast, hook_events, host));
+        supervisor.hooks = Some(events);
+        tokio::spawn(supervisor.run(link, received));
+
+        // Far more echoes than the write queue and the hook events hold, without a grant
+        let count = 4 * (WRITE_QUEUE_LEN + HOOK_EVENTS_LEN);
+        for i in 0..count {
+            device.send(format!("{i:04}\n").as_bytes());
+            tokio::task::yield_now().await;
+        }
+        until(|| shared.status.lock().unwrap().rx_bytes == 5 * count as u64).await;
+        assert!(
+            lines(&shared.screen).contains(
+                &"Hooks script sent data, dropped as the write queue is full".to_string()
+            )
+        );
+
+        // The writer goes on once credits are granted, with the first echo
+        device.send(&[0x1d, 0x05, 0x00]);
+        assert_eq!(device.receive().await.unwrap(), b"0000\n");
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn key_bytes_as_xterm_sends_them() {
+        let none = KeyModifiers::NONE;
+        let cases: [(KeyEvent, &[u8]); 10] = [
+            (key(KeyCode::Char('a'), none), b"a"),
+            (key(KeyCode::Char('\u{e9}'), none), "\u{e9}".as_bytes()),
+            (key(KeyCode::Char('c'), KeyModifiers::CONTROL), b"\x03"),
+            (key(KeyCode::Char('b'), KeyModifiers::ALT), b"\x1bb"),
+            (key(KeyCode::Backspace, KeyModifiers::ALT), b"\x1b\x08"),
+            (key(KeyCode::Up, none), b"\x1b[A"),
+            (key(KeyCode::Delete, none), b"\x1b[3~"),
+            (key(KeyCode::F(1), none), b"\x1bOP"),
+            (key(KeyCode::F(5), none), b"\x1b[15~"),
+            (key(KeyCode::F(12), none), b"\x1b[24~"),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(key_bytes(&key).as_deref(), Some(expected), "{key:?}");
+        }
+        assert_eq!(key_bytes(&key(KeyCode::F(13), none)), None);
+        assert_eq!(key_bytes(&key(KeyCode::CapsLock, none)), None);
+    }
+}
@@ -0,0 +1,159 @@
+//! Conversion of received bytes into displayed text
+
+use std::fmt::Write;
+
+const HEX_LINE_LEN: usize = 16;
+
+/// How received data is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// UTF-8 text, passing escape sequences through to the output pane
+    Text,
+    /// Offset/hex/ASCII dump of every notification
+    Hex,
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    mode: Mode,
+    /// Start of a UTF-8 sequence split across notifications
+    incomplete: Vec<u8>,
+    /// Number of bytes received, shown as the offset in hex dumps
+    offset: u64,
+}
+
+impl Decoder {
+    pub fn new(mode: Mode) -> Decoder {
+        Decoder {
+            mode,
+            incomplete: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switches between text and hex mode, returns text to show at the switch
+    pub fn toggle_hex(&mut self) -> String {
+        self.mode = match self.mode {
+            Mode::Text => Mode::Hex,
+            Mode::Hex => Mode::Text,
+        };
+        // Hex dumps always start on a line of their own
+        let mut text = String::from_utf8_lossy(&std::mem::take(&mut self.incomplete)).into_owned();
+        if self.mode == Mode::Hex {
+            text.push_str("\r\n");
+        }
+        text
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> String {
+        let text = match self.mode {
+            Mode::Text => self.text(data),
+            Mode::Hex => hex_dump(self.offset, data),
+        };
+        self.offset += data.len() as u64;
+        text
+    }
+
+    fn text(&mut self, data: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.incomplete);
+        bytes.extend_from_slice(data);
+
+        // Hold back a UTF-8 sequence cut off by the end of the notification
+        let complete = bytes.len() - incomplete_tail(&bytes);
+        self.incomplete = bytes.split_off(complete);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// Returns the length of an unfinished UTF-8 sequence at the end of `bytes`
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for (back, &b) in bytes.iter().rev().take(3).enumerate() {
+        if b & 0xc0 == 0x80 {
+            // Continuation byte, the sequence starts further back
+            continue;
+        }
+        let len = match b {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        return if len > back + 1 { back + 1 } else { 0 };
+    }
+    0
+}
+
+/// Reads bytes written as hex pairs, like `DE AD BE EF` or `deadbeef`
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("an odd number of hex digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            // from_str_radix alone would take a sign, reading "+f" as 0x0f
+            if !pair.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("'{pair}' is not a hex byte"));
+            }
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("'{pair}' is not a hex byte"))
+        })
+        .collect()
+}
+
+/// Formats `data` as lines of offset, hex bytes and printable ASCII
+pub fn hex_dump(offset: u64, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(HEX_LINE_LEN).enumerate() {
+        let _ = write!(out, "{:08x}  ", offset + (i * HEX_LINE_LEN) as u64);
+        for column in 0..HEX_LINE_LEN {
+            match line.get(column) {
+                Some(b) => {
+                    let _ = write!(out, "{b:02x} ");
+                }
+                None => out.push_str("   "),
+            }
+            if column == HEX_LINE_LEN / 2 - 1 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_pairs() {
+        assert_eq!(parse_hex("DE AD be ef").unwrap(), [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse_hex("0a0B\t0c").unwrap(), [0x0a, 0x0b, 0x0c]);
+        assert_eq!(parse_hex(" ").unwrap(), []);
+    }
+
+    #[test]
+    fn not_hex_pairs() {
+        assert_eq!(parse_hex("abc").unwrap_err(), "an odd number of hex digits");
+        for pair in ["+f", "-1", "0x", "zz", "é1"] {
+            assert_eq!(
+                parse_hex(pair).unwrap_err(),
+                format!("'{pair}' is not a hex byte")
+            );
+        }
+    }
+}
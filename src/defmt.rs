@@ -0,0 +1,731 @@
+//! Decoding of defmt logs, using the string table of the firmware ELF file
+//!
+//! The common parts of the defmt wire format are supported: the rzCOBS and raw encodings,
+//! timestamps, integer, float, bool, char, string and byte slice arguments, nested `Format`
+//! values including derived enums, bitfields and the usual display hints.
+
+use crate::elf;
+use crate::json;
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// How frames are delimited on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    /// Frames compressed and terminated by 0x00, the default
+    Rzcobs,
+    /// Frames sent back to back without framing
+    Raw,
+}
+
+/// An interned string of the firmware
+#[derive(Debug, Clone)]
+struct Entry {
+    tag: String,
+    format: String,
+}
+
+/// A parsed `{...}` parameter of a format string
+#[derive(Debug, Clone)]
+struct Param {
+    /// Position of the argument it refers to
+    position: usize,
+    kind: Kind,
+    hint: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    /// Unsigned integer of the given number of bytes
+    Unsigned(usize),
+    Signed(usize),
+    Usize,
+    F32,
+    F64,
+    Bool,
+    Char,
+    Str,
+    /// Interned string
+    Istr,
+    Bytes,
+    ByteArray(usize),
+    /// Value implementing `Format`
+    Format,
+    FormatSlice,
+    /// Bits `start..end` of an unsigned integer
+    BitField(u32, u32),
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Param(Param),
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Unsigned(u128),
+    Signed(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// A nested value, already formatted
+    Formatted(String),
+}
+
+/// Binary data read front to back
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if len > self.data.len() {
+            bail!("frame too short");
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    /// Reads a little endian unsigned integer of `len` bytes
+    fn unsigned(&mut self, len: usize) -> Result<u128> {
+        let bytes = self.take(len)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &b| value << 8 | u128::from(b)))
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.unsigned(2)? as u16)
+    }
+
+    fn leb128(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            value |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("invalid LEB128 number")
+    }
+}
+
+/// Turns defmt frames received from the device into log lines
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    entries: HashMap<u16, Entry>,
+    /// Format of the timestamp preceding the arguments of every log frame
+    timestamp: Option<String>,
+    encoding: Encoding,
+    /// Received bytes of a frame that is not complete yet
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    /// Reads the string table from the firmware ELF file at `path`
+    pub fn load(path: &Path) -> Result<Decoder> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let symbols =
+            elf::symbols(&data).with_context(|| format!("Could not read {}", path.display()))?;
+
+        let mut entries = HashMap::new();
+        let mut timestamp = None;
+        let mut encoding = Encoding::Rzcobs;
+        for symbol in symbols {
+            if let Some(name) = symbol.name.strip_prefix("_defmt_encoding_ = ") {
+                encoding = match name {
+                    "rzcobs" => Encoding::Rzcobs,
+                    "raw" => Encoding::Raw,
+                    _ => bail!("Unsupported defmt encoding '{name}'"),
+                };
+                continue;
+            }
+            if symbol.section != ".defmt" {
+                continue;
+            }
+            let Some(mut fields) = json::parse_string_object(&symbol.name) else {
+                continue;
+            };
+            let (Some(tag), Some(format)) = (fields.remove("tag"), fields.remove("data")) else {
+                continue;
+            };
+            if tag == "defmt_timestamp" {
+                timestamp = Some(format);
+                continue;
+            }
+            let Ok(index) = u16::try_from(symbol.value - symbol.section_address) else {
+                continue;
+            };
+            entries.insert(index, Entry { tag, format });
+        }
+        if entries.is_empty() {
+            bail!("No defmt strings found in {}", path.display());
+        }
+        Ok(Decoder {
+            entries,
+            timestamp,
+            encoding,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Decodes the frames completed by `data`, returns their log lines
+    pub fn decode(&mut self, data: &[u8]) -> String {
+        self.buffer.extend_from_slice(data);
+        let mut out = String::new();
+        match self.encoding {
+            Encoding::Rzcobs => {
+                while let Some(end) = self.buffer.iter().position(|&b| b == 0) {
+                    let frame: Vec<u8> = self.buffer.drain(..=end).collect();
+                    let frame = &frame[..end];
+                    if frame.is_empty() {
+                        continue;
+                    }
+                    let line = match rzcobs_decode(frame) {
+                        Some(frame) => self
+                            .frame(&mut Reader { data: &frame })
+                            .unwrap_or_else(|e| invalid_frame(&e, &frame)),
+                        None => invalid_frame(&anyhow!("invalid rzCOBS data"), frame),
+                    };
+                    out.push_str(&line);
+                }
+            }
+            Encoding::Raw => loop {
+                let mut reader = Reader { data: &self.buffer };
+                match self.frame(&mut reader) {
+                    Ok(line) => {
+                        let consumed = self.buffer.len() - reader.data.len();
+                        self.buffer.drain(..consumed);
+                        out.push_str(&line);
+                    }
+                    // Wait for the rest of the frame
+                    Err(_) if !self.buffer.is_empty() && self.buffer.len() < 1024 => break,
+                    Err(e) => {
+                        // Without framing there is no way to find the next frame
+                        if !self.buffer.is_empty() {
+                            out.push_str(&invalid_frame(&e, &std::mem::take(&mut self.buffer)));
+                        }
+                        break;
+                    }
+                }
+            },
+        }
+        out
+    }
+
+    /// Formats the log frame read by `reader` as a line
+    fn frame(&self, reader: &mut Reader) -> Result<String> {
+        let index = reader.u16()?;
+        let entry = self
+            .entries
+            .get(&index)
+            .ok_or(anyhow!("unknown string index {index}"))?;
+        let (level, color) = match entry.tag.as_str() {
+            "defmt_trace" => ("TRACE", "\x1b[2m"),
+            "defmt_debug" => ("DEBUG", "\x1b[36m"),
+            "defmt_info" => ("INFO ", "\x1b[32m"),
+            "defmt_warn" => ("WARN ", "\x1b[33m"),
+            "defmt_error" => ("ERROR", "\x1b[31m"),
+            "defmt_println" => ("", ""),
+            tag => bail!("string {index} is not a log message but {tag}"),
+        };
+
+        let mut line = String::new();
+        if let Some(timestamp) = &self.timestamp {
+            let _ = write!(line, "{} ", self.format(timestamp, reader)?);
+        }
+        // println! output has no level
+        if !level.is_empty() {
+            let _ = write!(line, "{color}{level}\x1b[0m ");
+        }
+        line.push_str(&self.format(&entry.format, reader)?);
+        line.push_str("\r\n");
+        Ok(line)
+    }
+
+    /// Formats the arguments read by `reader` according to `format`
+    fn format(&self, format: &str, reader: &mut Reader) -> Result<String> {
+        let segments = parse_format(format)?;
+        let params: Vec<&Param> = segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Param(param) => Some(param),
+                Segment::Literal(_) => None,
+            })
+            .collect();
+
+        // Arguments are encoded once each, in the order of their positions
+        let count = params.iter().map(|p| p.position + 1).max().unwrap_or(0);
+        let mut values = Vec::with_capacity(count);
+        for position in 0..count {
+            let mut kinds = params.iter().filter(|p| p.position == position);
+            let first = kinds
+                .next()
+                .ok_or(anyhow!("argument {position} is never used"))?;
+            let kind = match first.kind {
+                // All bitfields of an argument share an integer wide enough for the highest bit
+                Kind::BitField(_, end) => {
+                    let end = params
+                        .iter()
+                        .filter(|p| p.position == position)
+                        .filter_map(|p| match p.kind {
+                            Kind::BitField(_, end) => Some(end),
+                            _ => None,
+                        })
+                        .fold(end, u32::max);
+                    Kind::Unsigned(end.div_ceil(8).next_power_of_two() as usize)
+                }
+                ref kind => kind.clone(),
+            };
+            values.push(self.value(&kind, reader)?);
+        }
+
+        let mut out = String::new();
+        for segment in &segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Param(param) => render(&mut out, param, &values[param.position]),
+            }
+        }
+        Ok(out)
+    }
+
+    fn value(&self, kind: &Kind, reader: &mut Reader) -> Result<Value> {
+        Ok(match kind {
+            Kind::Unsigned(len) => Value::Unsigned(reader.unsigned(*len)?),
+            Kind::Signed(len) => {
+                let value = reader.unsigned(*len)?;
+                // Sign extend from the width of the argument
+                let shift = 128 - 8 * *len as u32;
+                Value::Signed(((value << shift) as i128) >> shift)
+            }
+            Kind::Usize => Value::Unsigned(u128::from(reader.leb128()?)),
+            Kind::F32 => Value::Float(f64::from(f32::from_bits(reader.unsigned(4)? as u32))),
+            Kind::F64 => Value::Float(f64::from_bits(reader.unsigned(8)? as u64)),
+            Kind::Bool => Value::Bool(reader.unsigned(1)? != 0),
+            Kind::Char => Value::Char(
+                char::from_u32(reader.unsigned(4)? as u32).unwrap_or(char::REPLACEMENT_CHARACTER),
+            ),
+            Kind::Str => {
+                let len = reader.leb128()? as usize;
+                Value::Str(String::from_utf8_lossy(reader.take(len)?).into_owned())
+            }
+            Kind::Istr => {
+                let index = reader.u16()?;
+                let entry = self
+                    .entries
+                    .get(&index)
+                    .ok_or(anyhow!("unknown string index {index}"))?;
+                Value::Str(entry.format.clone())
+            }
+            Kind::Bytes => {
+                let len = reader.leb128()? as usize;
+                Value::Bytes(reader.take(len)?.to_vec())
+            }
+            Kind::ByteArray(len) => Value::Bytes(reader.take(*len)?.to_vec()),
+            Kind::Format => {
+                let index = reader.u16()?;
+                Value::Formatted(self.nested(index, reader)?)
+            }
+            Kind::FormatSlice => {
+                let len = reader.leb128()?;
+                // The elements share the format, which is sent once
+                let index = reader.u16()?;
+                let items = (0..len)
+                    .map(|_| self.nested(index, reader))
+                    .collect::<Result<Vec<_>>>()?;
+                Value::Formatted(format!("[{}]", items.join(", ")))
+            }
+            Kind::BitField(..) => unreachable!("read as the integer holding it"),
+        })
+    }
+
+    /// Formats the data of a value implementing `Format` with the string `index`
+    fn nested(&self, index: u16, reader: &mut Reader) -> Result<String> {
+        let entry = self
+            .entries
+            .get(&index)
+            .ok_or(anyhow!("unknown string index {index}"))?;
+        if entry.tag == "defmt_derived" {
+            let variants = split_variants(&entry.format);
+            if variants.len() > 1 {
+                let discriminant = if variants.len() > 256 {
+                    usize::from(reader.u16()?)
+                } else {
+                    usize::from(reader.take(1)?[0])
+                };
+                let variant = variants
+                    .get(discriminant)
+                    .ok_or(anyhow!("invalid enum discriminant {discriminant}"))?;
+                return self.format(variant, reader);
+            }
+        }
+        self.format(&entry.format, reader)
+    }
+}
+
+/// Shows a frame that could not be decoded as a hex dump
+fn invalid_frame(error: &anyhow::Error, frame: &[u8]) -> String {
+    let hex: Vec<String> = frame.iter().map(|b| format!("{b:02x}")).collect();
+    format!("\x1b[31m[defmt] {error}:\x1b[0m {}\r\n", hex.join(" "))
+}
+
+/// Splits the format of a derived enum into the formats of its variants
+fn split_variants(format: &str) -> Vec<&str> {
+    let mut variants = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in format.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '|' if depth == 0 => {
+                variants.push(&format[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    variants.push(&format[start..]);
+    variants
+}
+
+fn parse_format(format: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut next_position = 0;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'{').is_some() => literal.push('{'),
+            '}' if chars.next_if_eq(&'}').is_some() => literal.push('}'),
+            '{' => {
+                let spec: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Param(parse_param(&spec, &mut next_position)?));
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Parses a parameter like `=u8`, `0=u8:x`, `:?` or `=0..4`
+fn parse_param(spec: &str, next_position: &mut usize) -> Result<Param> {
+    let (head, hint) = match spec.split_once(':') {
+        Some((head, hint)) => (head, Some(hint.to_string())),
+        None => (spec, None),
+    };
+    let (position, kind) = match head.split_once('=') {
+        Some((position, kind)) => (position, kind),
+        None => (head, "?"),
+    };
+    let position = if position.is_empty() {
+        *next_position += 1;
+        *next_position - 1
+    } else {
+        position
+            .parse()
+            .map_err(|_| anyhow!("invalid parameter {{{spec}}}"))?
+    };
+    let kind = match kind {
+        "u8" => Kind::Unsigned(1),
+        "u16" => Kind::Unsigned(2),
+        "u32" => Kind::Unsigned(4),
+        "u64" => Kind::Unsigned(8),
+        "u128" => Kind::Unsigned(16),
+        "i8" => Kind::Signed(1),
+        "i16" => Kind::Signed(2),
+        "i32" | "isize" => Kind::Signed(4),
+        "i64" => Kind::Signed(8),
+        "i128" => Kind::Signed(16),
+        "usize" => Kind::Usize,
+        "f32" => Kind::F32,
+        "f64" => Kind::F64,
+        "bool" => Kind::Bool,
+        "char" => Kind::Char,
+        "str" => Kind::Str,
+        "istr" => Kind::Istr,
+        "[u8]" => Kind::Bytes,
+        "?" => Kind::Format,
+        "[?]" => Kind::FormatSlice,
+        kind => {
+            if let Some(len) = kind
+                .strip_prefix("[u8;")
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                Kind::ByteArray(len.trim().parse()?)
+            } else if let Some((start, end)) = kind.split_once("..") {
+                Kind::BitField(start.parse()?, end.parse()?)
+            } else {
+                bail!("unsupported parameter type '{kind}'")
+            }
+        }
+    };
+    Ok(Param {
+        position,
+        kind,
+        hint,
+    })
+}
+
+fn render(out: &mut String, param: &Param, value: &Value) {
+    let hint = param.hint.as_deref().unwrap_or("");
+    match (value, &param.kind) {
+        (Value::Unsigned(v), Kind::BitField(start, end)) => {
+            let bits = (v >> start) & ((1 << (end - start)) - 1);
+            render_integer(out, bits, hint);
+        }
+        (Value::Unsigned(v), _) => match hint {
+            "us" | "tus" => {
+                let _ = write!(out, "{}.{:06}", v / 1_000_000, v % 1_000_000);
+            }
+            "ms" | "tms" => {
+                let _ = write!(out, "{}.{:03}", v / 1000, v % 1000);
+            }
+            _ => render_integer(out, *v, hint),
+        },
+        (Value::Signed(v), _) => {
+            let _ = write!(out, "{v}");
+        }
+        (Value::Float(v), _) => {
+            let _ = write!(out, "{v}");
+        }
+        (Value::Bool(v), _) => {
+            let _ = write!(out, "{v}");
+        }
+        (Value::Char(c), _) => out.push(*c),
+        (Value::Str(s), _) => out.push_str(s),
+        (Value::Bytes(bytes), _) if hint == "a" => {
+            out.push_str("b\"");
+            for &b in bytes {
+                out.extend(std::ascii::escape_default(b).map(char::from));
+            }
+            out.push('"');
+        }
+        (Value::Bytes(bytes), _) => {
+            out.push('[');
+            for (i, &b) in bytes.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                render_integer(out, u128::from(b), hint);
+            }
+            out.push(']');
+        }
+        (Value::Formatted(s), _) => out.push_str(s),
+    }
+}
+
+fn render_integer(out: &mut String, value: u128, hint: &str) {
+    let _ = match hint {
+        "x" => write!(out, "{value:x}"),
+        "#x" => write!(out, "{value:#x}"),
+        "X" => write!(out, "{value:X}"),
+        "#X" => write!(out, "{value:#X}"),
+        "b" => write!(out, "{value:b}"),
+        "#b" => write!(out, "{value:#b}"),
+        "o" => write!(out, "{value:o}"),
+        "#o" => write!(out, "{value:#o}"),
+        _ => write!(out, "{value}"),
+    };
+}
+
+/// Decodes a frame in the reverse zero-compressing COBS encoding used by defmt
+///
+/// The frame is decoded back to front. Zero bytes the encoder padded the final group with
+/// end up after the data, where they are ignored by the frame parser.
+fn rzcobs_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(frame.len() * 2);
+    let mut bytes = frame.iter().rev().copied();
+    while let Some(code) = bytes.next() {
+        match code {
+            0x00 => return None,
+            // Seven bytes, the bits tell which of them are zero
+            0x01..=0x7f => {
+                for bit in (0..7).rev() {
+                    if code & (1 << bit) != 0 {
+                        out.push(0);
+                    } else {
+                        out.push(bytes.next()?);
+                    }
+                }
+            }
+            // A run of non-zero bytes followed by a zero
+            0x80..=0xfe => {
+                out.push(0);
+                for _ in 0..usize::from(code & 0x7f) + 7 {
+                    out.push(bytes.next()?);
+                }
+            }
+            // A run of non-zero bytes of the maximum length, without a zero
+            0xff => {
+                for _ in 0..134 {
+                    out.push(bytes.next()?);
+                }
+            }
+        }
+    }
+    out.reverse();
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decoded data and its encoding, from the tests of the reference implementation
+    const RZCOBS: &[(&[u8], &[u8])] = &[
+        (&[], &[]),
+        (&[0x00], &[0x7f]),
+        (&[0x00, 0x00], &[0x7f]),
+        (&[0; 7], &[0x7f]),
+        (&[0; 8], &[0x7f, 0x7f]),
+        (&[0x01], &[0x01, 0x7e]),
+        (&[0x01, 0x00], &[0x01, 0x7e]),
+        (&[0x00, 0x01], &[0x01, 0x7d]),
+        (&[0x01, 0x02], &[0x01, 0x02, 0x7c]),
+        (
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00],
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x40],
+        ),
+        (
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x80],
+        ),
+        (
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x00],
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x80],
+        ),
+        (
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88],
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x81],
+        ),
+        (
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff],
+            &[0x7f, 0xff, 0x3f],
+        ),
+        (
+            &[0, 0, 0, 0, 0, 0x44, 0, 0, 0, 0, 0, 0, 0, 0xff],
+            &[0x44, 0x5f, 0xff, 0x3f],
+        ),
+    ];
+
+    #[test]
+    fn rzcobs_vectors() {
+        for (decoded, encoded) in RZCOBS {
+            let out = rzcobs_decode(encoded).unwrap();
+            // Padding of the last group decodes to zeros after the data
+            assert_eq!(&out[..decoded.len()], *decoded, "{encoded:02x?}");
+            assert!(
+                out[decoded.len()..].iter().all(|&b| b == 0),
+                "{encoded:02x?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rzcobs_runs_without_zeros() {
+        // The longest run ending in a zero, and the longest one without
+        let data: Vec<u8> = (1..=0x86).collect();
+        let mut encoded = data[..0x85].to_vec();
+        encoded.push(0xfe);
+        assert_eq!(
+            rzcobs_decode(&encoded).unwrap(),
+            [&data[..0x85], &[0]].concat()
+        );
+        let mut encoded = data.clone();
+        encoded.push(0xff);
+        assert_eq!(rzcobs_decode(&encoded).unwrap(), data);
+        encoded.push(0x7f);
+        assert_eq!(
+            rzcobs_decode(&encoded).unwrap(),
+            [&data[..], &[0; 7]].concat()
+        );
+    }
+
+    #[test]
+    fn invalid_rzcobs() {
+        // A zero inside the frame and runs longer than the frame
+        for frame in [&[0x01, 0x00, 0x7e][..], &[0x01, 0x02, 0x80], &[0x01, 0xff]] {
+            assert_eq!(rzcobs_decode(frame), None, "{frame:02x?}");
+        }
+    }
+
+    fn decoder(encoding: Encoding) -> Decoder {
+        let entry = |tag: &str, format: &str| Entry {
+            tag: tag.to_string(),
+            format: format.to_string(),
+        };
+        Decoder {
+            entries: HashMap::from([
+                (1, entry("defmt_info", "value {=u8} offset {=i16}")),
+                (2, entry("defmt_warn", "state {} {=str}")),
+                (3, entry("defmt_derived", "Idle|Busy({=u8})")),
+                (4, entry("defmt_println", "{=[u8]:x}")),
+            ]),
+            timestamp: Some("{=u32:us}".to_string()),
+            encoding,
+            buffer: Vec::new(),
+        }
+    }
+
+    const INFO: &str = "1.500000 \x1b[32mINFO \x1b[0m value 42 offset -2\r\n";
+    const WARN: &str = "0.002000 \x1b[33mWARN \x1b[0m state Busy(7) ok\r\n";
+
+    #[test]
+    fn decodes_rzcobs_frames() {
+        let mut decoder = decoder(Encoding::Rzcobs);
+        // Index 1, timestamp 1500000, 42 and -2
+        let info = [0x01, 0x60, 0xe3, 0x16, 0x2a, 0x22, 0xfe, 0xff, 0x7c, 0x00];
+        // Index 2, timestamp 2000, variant 1 of the enum with string 3 holding 7, and "ok"
+        let warn = [
+            0x02, 0xd0, 0x07, 0x03, 0x32, 0x01, 0x07, 0x02, 0x6f, 0x6b, 0x41, 0x00,
+        ];
+        assert_eq!(decoder.decode(&info), INFO);
+        // Split across notifications, after an empty frame
+        let data = [&[0x00], &warn[..]].concat();
+        let (first, second) = data.split_at(5);
+        assert_eq!(decoder.decode(first), "");
+        assert_eq!(decoder.decode(second), WARN);
+    }
+
+    #[test]
+    fn decodes_raw_frames() {
+        let mut decoder = decoder(Encoding::Raw);
+        let info = [0x01, 0x00, 0x60, 0xe3, 0x16, 0x00, 0x2a, 0xfe, 0xff];
+        let println = [0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xbe, 0xef];
+        let data = [&info[..], &println[..]].concat();
+        let (first, second) = data.split_at(12);
+        assert_eq!(decoder.decode(first), INFO);
+        assert_eq!(decoder.decode(second), "0.000000 [be, ef]\r\n");
+    }
+
+    #[test]
+    fn shows_undecodable_frames_as_hex() {
+        let mut decoder = decoder(Encoding::Rzcobs);
+        // Index 9 is not in the table
+        assert_eq!(
+            decoder.decode(&[0x09, 0x7e, 0x00]),
+            "\x1b[31m[defmt] unknown string index 9:\x1b[0m 09 00 00 00 00 00 00\r\n"
+        );
+        assert_eq!(
+            decoder.decode(&[0x01, 0x02, 0x80, 0x00]),
+            "\x1b[31m[defmt] invalid rzCOBS data:\x1b[0m 01 02 80\r\n"
+        );
+    }
+}
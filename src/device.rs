@@ -0,0 +1,202 @@
+use crate::error::Error;
+use crate::picker;
+use anyhow::{Result, anyhow};
+use btleplug::api::{BDAddr, Central, CentralEvent, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::stream::StreamExt;
+use log::info;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long to look for a device unless told otherwise
+pub const SCAN_DURATION: Duration = Duration::from_secs(5);
+
+/// Selects which discovered peripherals are candidates for connecting
+#[derive(Debug, Clone)]
+pub enum DeviceFilter {
+    /// Local name contains the given string
+    Name(String),
+    /// Bluetooth address (or the platform identifier on macOS) equals the given string
+    Address(String),
+    /// Advertises the given service, whatever the name of the device
+    Service(Uuid),
+}
+
+impl DeviceFilter {
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DeviceFilter::Name(filter) => device
+                .name
+                .as_deref()
+                .is_some_and(|n| n.contains(filter.as_str())),
+            DeviceFilter::Address(filter) => device.address.eq_ignore_ascii_case(filter),
+            DeviceFilter::Service(service) => device.advertises(*service),
+        }
+    }
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceFilter::Name(name) => write!(f, "name: {name}"),
+            DeviceFilter::Address(address) => write!(f, "address: {address}"),
+            DeviceFilter::Service(service) => write!(f, "advertising: {service}"),
+        }
+    }
+}
+
+/// A peripheral found during scanning, along with the advertised data shown to the user
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub peripheral: Peripheral,
+    pub name: Option<String>,
+    /// Bluetooth address, or the CoreBluetooth identifier where the address is hidden
+    pub address: String,
+    pub rssi: Option<i16>,
+    pub services: Vec<Uuid>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl DeviceInfo {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("(unnamed)")
+    }
+
+    pub fn advertises(&self, service: Uuid) -> bool {
+        self.services.contains(&service)
+    }
+}
+
+/// Scans for advertising peripherals for the given time
+///
+/// Only peripherals advertising one of `services` are reported, unless it is empty. The
+/// filtering is left to the Bluetooth stack, which also looks at scan responses.
+pub async fn scan(central: &Adapter, duration: Duration, services: &[Uuid]) -> Result<()> {
+    let filter = ScanFilter {
+        services: services.to_vec(),
+    };
+    central.start_scan(filter).await?;
+    tokio::time::sleep(duration).await;
+    central.stop_scan().await?;
+    Ok(())
+}
+
+/// What to look for when selecting a device
+#[derive(Debug, Clone)]
+pub struct Search {
+    pub filter: DeviceFilter,
+    /// Service the device has to advertise, if any
+    pub service: Option<Uuid>,
+    /// How long to scan before giving up, `None` to wait until the device shows up
+    pub timeout: Option<Duration>,
+}
+
+/// Scans until a device accepted by `search` advertises, returns the matching devices known
+/// by then, or none if the timeout passed first
+pub async fn wait_for(central: &Adapter, search: &Search) -> Result<Vec<DeviceInfo>> {
+    // Subscribe before scanning so no advertisement can be missed
+    let mut events = central.events().await?;
+    let filter = ScanFilter {
+        services: search.service.into_iter().collect(),
+    };
+    central.start_scan(filter).await?;
+
+    let found = async {
+        while let Some(event) = events.next().await {
+            let (CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id)) = event
+            else {
+                continue;
+            };
+            let Ok(peripheral) = central.peripheral(&id).await else {
+                continue;
+            };
+            if let Some(device) = info(peripheral).await?
+                && search.filter.matches(&device)
+            {
+                // Others may have been seen already, they are offered as well
+                return find(central, &search.filter).await;
+            }
+        }
+        Ok(Vec::new())
+    };
+    let result = match search.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, found)
+            .await
+            .unwrap_or(Ok(Vec::new())),
+        None => found.await,
+    };
+    central.stop_scan().await?;
+    result
+}
+
+/// Waits for a device matching `search`, letting the user pick one if there are several
+///
+/// A [`DeviceFilter::Service`] filter takes the device with the strongest signal instead.
+pub async fn select(central: &Adapter, search: &Search) -> Result<DeviceInfo> {
+    match search.timeout {
+        Some(_) => info!("Trying to find device ({})", search.filter),
+        None => info!("Waiting for device ({})", search.filter),
+    }
+
+    let mut devices = wait_for(central, search).await?;
+    let index = match devices.len() {
+        0 => {
+            if search.timeout.is_some() {
+                info!("--wait keeps looking until the device shows up");
+            }
+            return Err(Error::DeviceNotFound(search.filter.clone(), search.timeout).into());
+        }
+        1 => 0,
+        _ if matches!(search.filter, DeviceFilter::Service(_)) => 0,
+        _ => picker::pick(&devices)?.ok_or(anyhow!("No device selected"))?,
+    };
+    let device = devices.swap_remove(index);
+    info!(
+        "Connecting to {} ({})",
+        device.display_name(),
+        device.address
+    );
+    Ok(device)
+}
+
+/// Returns all discovered peripherals accepted by `filter`
+pub async fn find(central: &Adapter, filter: &DeviceFilter) -> Result<Vec<DeviceInfo>> {
+    let mut devices = discover(central).await?;
+    devices.retain(|d| filter.matches(d));
+    Ok(devices)
+}
+
+/// Returns all peripherals discovered so far, strongest signal first
+pub async fn discover(central: &Adapter) -> Result<Vec<DeviceInfo>> {
+    let mut devices = Vec::new();
+    for p in central.peripherals().await? {
+        devices.extend(info(p).await?);
+    }
+
+    // Strongest signal first, so the closest board is preselected in the picker
+    devices.sort_by_key(|d| std::cmp::Reverse(d.rssi.unwrap_or(i16::MIN)));
+    Ok(devices)
+}
+
+/// Collects what the peripheral advertised, `None` if nothing is known about it yet
+async fn info(peripheral: Peripheral) -> Result<Option<DeviceInfo>> {
+    let Some(props) = peripheral.properties().await? else {
+        return Ok(None);
+    };
+    // macOS does not expose peripheral addresses, only an opaque identifier
+    let address = if props.address == BDAddr::default() {
+        peripheral.id().to_string()
+    } else {
+        props.address.to_string()
+    };
+    Ok(Some(DeviceInfo {
+        peripheral,
+        name: props.local_name,
+        address,
+        rssi: props.rssi,
+        services: props.services,
+        manufacturer_data: props.manufacturer_data,
+    }))
+}
@@ -0,0 +1,55 @@
+//! The standard Device Information Service, naming the hardware and firmware of a device
+
+use anyhow::Result;
+use btleplug::api::Peripheral as _;
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::platform::Peripheral;
+use uuid::Uuid;
+
+pub const DEVICE_INFORMATION_SERVICE_UUID: Uuid = uuid_from_u16(0x180a);
+
+/// The text characteristics of the service with their labels, in the order shown
+const FIELDS: [(u16, &str); 6] = [
+    (0x2a29, "Manufacturer"),
+    (0x2a24, "Model"),
+    (0x2a25, "Serial number"),
+    (0x2a27, "Hardware revision"),
+    (0x2a26, "Firmware revision"),
+    (0x2a28, "Software revision"),
+];
+
+/// What the Device Information Service of a device reports
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInformation {
+    /// Label and value of each field the device has
+    pub fields: Vec<(&'static str, String)>,
+}
+
+/// Reads the fields of the Device Information Service, `None` if the device has none
+///
+/// Has to be called after service discovery.
+pub async fn read(peripheral: &Peripheral) -> Result<Option<DeviceInformation>> {
+    let chars = peripheral.characteristics();
+    if !chars
+        .iter()
+        .any(|c| c.service_uuid == DEVICE_INFORMATION_SERVICE_UUID)
+    {
+        return Ok(None);
+    }
+    let mut info = DeviceInformation::default();
+    for (uuid, label) in FIELDS {
+        let Some(char) = chars.iter().find(|c| {
+            c.service_uuid == DEVICE_INFORMATION_SERVICE_UUID && c.uuid == uuid_from_u16(uuid)
+        }) else {
+            continue;
+        };
+        let value = peripheral.read(char).await?;
+        // Some firmwares include the terminating NUL of their C strings
+        let value = String::from_utf8_lossy(&value)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string();
+        info.fields.push((label, value));
+    }
+    Ok(Some(info))
+}
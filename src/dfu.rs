@@ -0,0 +1,397 @@
+//! Firmware updates over the MCUmgr SMP service, as used by MCUboot based firmwares
+//!
+//! Uploads go to the secondary slot; marking the image for test and resetting the device
+//! makes MCUboot swap it in, confirming it keeps it for good.
+
+use crate::cbor::{self, Value};
+use crate::cli::DfuArgs;
+use crate::device::{self, DeviceFilter};
+use crate::error::Error;
+use crate::link::{Link, LinkOptions, Notifications};
+use crate::nus::NusUuids;
+use crate::transfer::Progress;
+use anyhow::{Context, Result, anyhow, bail};
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Adapter;
+use futures::stream::StreamExt;
+use log::{debug, info, warn};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const SMP_SERVICE_UUID: Uuid = Uuid::from_u128(0x8d53dc1d_1db7_4cd3_868b_8a527460aa84);
+pub const SMP_CHAR_UUID: Uuid = Uuid::from_u128(0xda2e7828_fbce_4e01_ae9e_261174997c48);
+
+const HEADER_LEN: usize = 8;
+
+const OP_READ: u8 = 0;
+const OP_WRITE: u8 = 2;
+
+const GROUP_OS: u16 = 0;
+const GROUP_IMAGE: u16 = 1;
+
+const OS_RESET: u8 = 5;
+const IMAGE_STATE: u8 = 0;
+const IMAGE_UPLOAD: u8 = 1;
+
+/// How long a response may take, erasing the slot before the first chunk can be slow
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fewest image bytes sent per request, larger requests than fit into one write are left
+/// for the device to reassemble
+const MIN_CHUNK_LEN: usize = 64;
+
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+/// Client of the SMP service of a connected device
+struct Smp {
+    link: Link,
+    notifications: Notifications,
+    seq: u8,
+    /// Received data not forming a complete response yet
+    buffer: Vec<u8>,
+}
+
+impl Smp {
+    /// Sends a request and waits for its response, failing if the device reports an error
+    async fn request(&mut self, op: u8, group: u16, id: u8, body: &Value) -> Result<Value> {
+        self.seq = self.seq.wrapping_add(1);
+        let frame = self.frame(op, group, id, body);
+        self.link.write(&frame).await?;
+
+        let response = tokio::time::timeout(RESPONSE_TIMEOUT, self.response(op + 1, group, id))
+            .await
+            .map_err(|_| anyhow!("No response from the device"))??;
+        debug!("SMP response: {response}");
+        check(&response)?;
+        Ok(response)
+    }
+
+    fn frame(&self, op: u8, group: u16, id: u8, body: &Value) -> Vec<u8> {
+        let body = cbor::encode(body);
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+        frame.extend_from_slice(&[op, 0]);
+        frame.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&group.to_be_bytes());
+        frame.extend_from_slice(&[self.seq, id]);
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    /// Collects notifications until the response to the last request is complete
+    async fn response(&mut self, op: u8, group: u16, id: u8) -> Result<Value> {
+        loop {
+            while self.buffer.len() >= HEADER_LEN {
+                let len = usize::from(u16::from_be_bytes([self.buffer[2], self.buffer[3]]));
+                if self.buffer.len() < HEADER_LEN + len {
+                    break;
+                }
+                let frame: Vec<u8> = self.buffer.drain(..HEADER_LEN + len).collect();
+                let matches = frame[0] == op
+                    && u16::from_be_bytes([frame[4], frame[5]]) == group
+                    && frame[6] == self.seq
+                    && frame[7] == id;
+                if matches {
+                    let (value, _) = cbor::decode(&frame[HEADER_LEN..])
+                        .context("Invalid response from the device")?;
+                    return Ok(value);
+                }
+                debug!("Ignoring SMP frame {frame:02x?}");
+            }
+            let notification = self
+                .notifications
+                .next()
+                .await
+                .ok_or(Error::ConnectionLost)?;
+            self.buffer.extend_from_slice(&notification.value);
+        }
+    }
+
+    /// Largest frame that fits into a single write
+    fn max_frame_len(&self) -> usize {
+        self.link.max_payload()
+    }
+
+    async fn images(&mut self) -> Result<Vec<Image>> {
+        let response = self
+            .request(OP_READ, GROUP_IMAGE, IMAGE_STATE, &cbor::map([]))
+            .await?;
+        response
+            .get("images")
+            .and_then(Value::as_array)
+            .ok_or(anyhow!("Invalid image state: {response}"))?
+            .iter()
+            .map(Image::parse)
+            .collect()
+    }
+
+    /// Marks the image with `hash` to be booted once, or for good with `confirm`
+    ///
+    /// Without a hash, `confirm` keeps the running image.
+    async fn set_state(&mut self, hash: Option<&[u8]>, confirm: bool) -> Result<()> {
+        let mut body = vec![(Value::Text("confirm".to_string()), Value::Bool(confirm))];
+        if let Some(hash) = hash {
+            body.push((Value::Text("hash".to_string()), Value::Bytes(hash.to_vec())));
+        }
+        self.request(OP_WRITE, GROUP_IMAGE, IMAGE_STATE, &Value::Map(body))
+            .await?;
+        Ok(())
+    }
+
+    async fn upload(
+        &mut self,
+        image: &[u8],
+        chunk_len: Option<usize>,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        let mut offset = 0;
+        while offset < image.len() {
+            let len = chunk_len.unwrap_or_else(|| self.chunk_len(image.len(), offset));
+            let end = (offset + len).min(image.len());
+            let body = upload_request(image.len(), offset, &image[offset..end]);
+            let response = self
+                .request(OP_WRITE, GROUP_IMAGE, IMAGE_UPLOAD, &body)
+                .await
+                .with_context(|| format!("Upload failed at offset {offset}"))?;
+            // The device tells where to continue, e.g. after a chunk it already had
+            offset = response
+                .get("off")
+                .and_then(Value::as_i64)
+                .and_then(|off| usize::try_from(off).ok())
+                .ok_or(anyhow!("Invalid upload response: {response}"))?;
+            progress.transferred = offset.min(image.len());
+            print_progress(progress, image.len());
+        }
+        eprintln!();
+        Ok(())
+    }
+
+    /// Number of image bytes making the upload request at `offset` fit into one write
+    fn chunk_len(&self, total: usize, offset: usize) -> usize {
+        let empty = upload_request(total, offset, &[]);
+        // The length of the data grows its head by up to two bytes
+        let overhead = HEADER_LEN + cbor::encode(&empty).len() + 2;
+        self.max_frame_len()
+            .saturating_sub(overhead)
+            .max(MIN_CHUNK_LEN)
+    }
+
+    /// Resets the device, which may drop the connection before responding
+    async fn reset(&mut self) -> Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let frame = self.frame(OP_WRITE, GROUP_OS, OS_RESET, &cbor::map([]));
+        self.link.write(&frame).await?;
+        let response = self.response(OP_WRITE + 1, GROUP_OS, OS_RESET);
+        match tokio::time::timeout(Duration::from_secs(2), response).await {
+            Ok(Ok(response)) => check(&response),
+            // No response before the device restarted
+            Ok(Err(_)) | Err(_) => Ok(()),
+        }
+    }
+}
+
+fn upload_request(total: usize, offset: usize, data: &[u8]) -> Value {
+    let mut body = vec![
+        (
+            Value::Text("off".to_string()),
+            Value::Unsigned(offset as u64),
+        ),
+        (Value::Text("data".to_string()), Value::Bytes(data.to_vec())),
+    ];
+    if offset == 0 {
+        body.push((Value::Text("image".to_string()), Value::Unsigned(0)));
+        body.push((
+            Value::Text("len".to_string()),
+            Value::Unsigned(total as u64),
+        ));
+    }
+    Value::Map(body)
+}
+
+/// Fails if a response reports an error, in the SMP version 1 or 2 format
+fn check(response: &Value) -> Result<()> {
+    if let Some(rc) = response.get("rc").and_then(Value::as_i64)
+        && rc != 0
+    {
+        bail!("The device reported error {rc} ({})", error_name(rc));
+    }
+    if let Some(err) = response.get("err") {
+        let rc = err.get("rc").and_then(Value::as_i64).unwrap_or(-1);
+        let group = err.get("group").and_then(Value::as_i64).unwrap_or(-1);
+        if rc != 0 {
+            bail!("The device reported error {rc} of group {group}");
+        }
+    }
+    Ok(())
+}
+
+/// Name of an MCUmgr error code
+fn error_name(rc: i64) -> &'static str {
+    match rc {
+        1 => "unknown error",
+        2 => "out of memory",
+        3 => "invalid value",
+        4 => "timeout",
+        5 => "no such entry",
+        6 => "bad state",
+        7 => "response too long",
+        8 => "not supported",
+        9 => "corrupt",
+        10 => "busy",
+        11 => "access denied",
+        _ => "unknown",
+    }
+}
+
+/// An image slot as reported by the device
+#[derive(Debug)]
+struct Image {
+    image: i64,
+    slot: i64,
+    version: String,
+    hash: Vec<u8>,
+    /// Flags that are set, like `active` or `confirmed`
+    flags: Vec<&'static str>,
+}
+
+impl Image {
+    fn parse(value: &Value) -> Result<Image> {
+        let flag = |name| value.get(name).and_then(Value::as_bool).unwrap_or(false);
+        Ok(Image {
+            image: value.get("image").and_then(Value::as_i64).unwrap_or(0),
+            slot: value
+                .get("slot")
+                .and_then(Value::as_i64)
+                .ok_or(anyhow!("Invalid image: {value}"))?,
+            version: value
+                .get("version")
+                .and_then(Value::as_text)
+                .unwrap_or_default()
+                .to_string(),
+            hash: value
+                .get("hash")
+                .and_then(Value::as_bytes)
+                .unwrap_or_default()
+                .to_vec(),
+            flags: ["active", "confirmed", "pending", "permanent", "bootable"]
+                .into_iter()
+                .filter(|&name| flag(name))
+                .collect(),
+        })
+    }
+}
+
+fn print_images(images: &[Image]) {
+    println!(
+        "{:<6} {:<4} {:<16} {:<34} HASH",
+        "IMAGE", "SLOT", "VERSION", "FLAGS"
+    );
+    for image in images {
+        let hash: String = image.hash.iter().map(|b| format!("{b:02x}")).collect();
+        println!(
+            "{:<6} {:<4} {:<16} {:<34} {hash}",
+            image.image,
+            image.slot,
+            image.version,
+            image.flags.join(" "),
+        );
+    }
+}
+
+fn print_progress(progress: &Progress, total: usize) {
+    let mut stderr = std::io::stderr();
+    let _ = write!(
+        stderr,
+        "\r{} {} {:3}% ({}/{} bytes)",
+        progress.name,
+        progress.bar(PROGRESS_BAR_WIDTH),
+        progress.percent().unwrap_or(0),
+        progress.transferred,
+        total
+    );
+    let _ = stderr.flush();
+}
+
+/// Runs the firmware update steps selected by `args`
+pub async fn run(central: &Adapter, args: &DfuArgs) -> Result<()> {
+    let image = args
+        .image
+        .as_deref()
+        .map(|path| {
+            std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+        })
+        .transpose()?;
+
+    let mut search = args.device.search();
+    search.service = Some(SMP_SERVICE_UUID);
+    if let DeviceFilter::Service(_) = search.filter {
+        search.filter = DeviceFilter::Service(SMP_SERVICE_UUID);
+    }
+    let device = device::select(central, &search).await?;
+    let options = LinkOptions {
+        uuids: NusUuids {
+            service: SMP_SERVICE_UUID,
+            rx: SMP_CHAR_UUID,
+            tx: SMP_CHAR_UUID,
+        },
+        ..LinkOptions::from(&args.device)
+    };
+    let (link, notifications) = Link::open(device.peripheral, &options)
+        .await
+        .context("Could not open the SMP service")?;
+    let mut smp = Smp {
+        link,
+        notifications,
+        seq: 0,
+        buffer: Vec::new(),
+    };
+
+    let result = update(&mut smp, args, image.as_deref()).await;
+    let _ = smp.link.peripheral.disconnect().await;
+    result
+}
+
+async fn update(smp: &mut Smp, args: &DfuArgs, image: Option<&[u8]>) -> Result<()> {
+    let mut uploaded = None;
+    if let (Some(path), Some(image)) = (&args.image, image) {
+        info!("Uploading {} ({} bytes)", path.display(), image.len());
+        let mut progress = Progress::new("DFU", path, Some(image.len()), Arc::default());
+        smp.upload(image, args.chunk_size.map(usize::from), &mut progress)
+            .await?;
+
+        // The hash of the uploaded image selects it for test or confirmation
+        let images = smp.images().await?;
+        uploaded = images
+            .into_iter()
+            .find(|image| image.image == 0 && image.slot == 1)
+            .map(|image| image.hash);
+        if uploaded.is_none() {
+            warn!("The uploaded image is not listed by the device");
+        }
+    }
+
+    if args.test || args.confirm {
+        let hash = match (&args.image, &uploaded) {
+            (Some(_), Some(hash)) => Some(hash.as_slice()),
+            (Some(_), None) => bail!("Cannot mark the uploaded image, its hash is unknown"),
+            (None, _) => None,
+        };
+        smp.set_state(hash, args.confirm).await?;
+        match (hash, args.confirm) {
+            (Some(_), false) => info!("The new image will be tested on the next reset"),
+            (Some(_), true) => info!("The new image will be used from the next reset on"),
+            (None, _) => info!("The running image is confirmed"),
+        }
+    }
+
+    if args.list || (image.is_none() && !args.confirm && !args.reset) {
+        print_images(&smp.images().await?);
+    }
+
+    if args.reset {
+        smp.reset().await?;
+        info!("Device reset");
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! Reading the symbol table of ELF files, just enough to find the defmt string table
+
+use anyhow::{Result, anyhow, bail};
+
+const SHT_SYMTAB: u32 = 2;
+
+/// A symbol of an ELF file
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    /// Name of the section the symbol is defined in, empty for undefined symbols
+    pub section: String,
+    /// Start address of that section
+    pub section_address: u64,
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+/// Little endian reader of a 32 or 64 bit ELF file
+struct Elf<'a> {
+    data: &'a [u8],
+    wide: bool,
+}
+
+impl Elf<'_> {
+    fn bytes(&self, offset: u64, len: usize) -> Result<&[u8]> {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.data.get(start..start.checked_add(len)?))
+            .ok_or(anyhow!("ELF file is truncated"))
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into()?))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into()?))
+    }
+
+    /// Reads an address or size, which takes 8 bytes in 64 bit files and 4 otherwise
+    fn word(&self, offset: u64) -> Result<u64> {
+        if self.wide {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    fn section(&self, offset: u64) -> Result<Section> {
+        let w = if self.wide { 8 } else { 4 };
+        Ok(Section {
+            name: self.u32(offset)?,
+            kind: self.u32(offset + 4)?,
+            address: self.word(offset + 8 + w)?,
+            offset: self.word(offset + 8 + 2 * w)?,
+            size: self.word(offset + 8 + 3 * w)?,
+            link: self.u32(offset + 8 + 4 * w)?,
+        })
+    }
+
+    /// Reads the NUL terminated string at `offset` of the string table `table`
+    fn string(&self, table: &Section, offset: u32) -> Result<String> {
+        let table = self.bytes(table.offset, table.size as usize)?;
+        let start = offset as usize;
+        let len = table
+            .get(start..)
+            .and_then(|rest| rest.iter().position(|&b| b == 0))
+            .ok_or(anyhow!("Invalid string offset in ELF file"))?;
+        Ok(String::from_utf8_lossy(&table[start..start + len]).into_owned())
+    }
+}
+
+/// Returns the symbols of the little endian ELF file `data`
+pub fn symbols(data: &[u8]) -> Result<Vec<Symbol>> {
+    if data.get(..4) != Some(b"\x7fELF") {
+        bail!("Not an ELF file");
+    }
+    let wide = match data.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => bail!("Unknown ELF class"),
+    };
+    if data.get(5) != Some(&1) {
+        bail!("Only little endian ELF files are supported");
+    }
+    let elf = Elf { data, wide };
+
+    let (header_offset, entry_size, count, names_index) = if wide {
+        (
+            elf.u64(0x28)?,
+            elf.u16(0x3a)?,
+            elf.u16(0x3c)?,
+            elf.u16(0x3e)?,
+        )
+    } else {
+        (
+            u64::from(elf.u32(0x20)?),
+            elf.u16(0x2e)?,
+            elf.u16(0x30)?,
+            elf.u16(0x32)?,
+        )
+    };
+    let sections = (0..u64::from(count))
+        .map(|i| elf.section(header_offset + i * u64::from(entry_size)))
+        .collect::<Result<Vec<_>>>()?;
+    let section_names = sections
+        .get(usize::from(names_index))
+        .ok_or(anyhow!("Missing section names in ELF file"))?;
+
+    let mut symbols = Vec::new();
+    for table in sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+        let names = sections
+            .get(table.link as usize)
+            .ok_or(anyhow!("Missing symbol names in ELF file"))?;
+        let symbol_size = if wide { 24 } else { 16 };
+        for i in 0..table.size / symbol_size {
+            let offset = table.offset + i * symbol_size;
+            let (name, value, section) = if wide {
+                (elf.u32(offset)?, elf.u64(offset + 8)?, elf.u16(offset + 6)?)
+            } else {
+                (
+                    elf.u32(offset)?,
+                    u64::from(elf.u32(offset + 4)?),
+                    elf.u16(offset + 14)?,
+                )
+            };
+            let (section, section_address) = match sections.get(usize::from(section)) {
+                Some(s) if section != 0 => (elf.string(section_names, s.name)?, s.address),
+                _ => (String::new(), 0),
+            };
+            symbols.push(Symbol {
+                name: elf.string(names, name)?,
+                value,
+                section,
+                section_address,
+            });
+        }
+    }
+    Ok(symbols)
+}
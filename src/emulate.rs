@@ -0,0 +1,381 @@
+//! A UART service of this machine's own, for trying the client and scripts without a device
+//!
+//! On Linux the service is registered with BlueZ as a GATT application and advertised next to
+//! the name given with `--name`. A second adapter, or a second machine, connects to it.
+
+use crate::cli::EmulateArgs;
+use crate::nus::NusUuids;
+use anyhow::Result;
+use btleplug::platform::Adapter;
+use clap::ValueEnum;
+use std::time::Duration;
+
+/// What the emulated device sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Behavior {
+    /// Sends back whatever is written to it
+    #[default]
+    Echo,
+    /// Sends numbered lines of a test pattern, for spotting lost or reordered data
+    Pattern,
+}
+
+/// The device to emulate
+#[derive(Debug, Clone)]
+pub struct Emulation {
+    /// Name advertised
+    pub name: String,
+    pub behavior: Behavior,
+    /// Pause between the lines of the test pattern
+    pub interval: Duration,
+    pub uuids: NusUuids,
+}
+
+impl From<&EmulateArgs> for Emulation {
+    fn from(args: &EmulateArgs) -> Self {
+        Emulation {
+            name: args.name.clone(),
+            behavior: args.behavior,
+            interval: Duration::from_millis(args.interval),
+            uuids: args.protocol.uuids(),
+        }
+    }
+}
+
+/// Characters of a pattern line after its number
+const PATTERN: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// Line `n` of the test pattern
+fn pattern_line(n: u64) -> Vec<u8> {
+    format!("{n:08} {PATTERN}\r\n").into_bytes()
+}
+
+pub async fn run(central: &Adapter, args: &EmulateArgs) -> Result<()> {
+    let stop = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    serve(central, &Emulation::from(args), stop).await
+}
+
+/// Advertises the emulated device on `central` until `stop` resolves
+#[cfg(target_os = "linux")]
+pub async fn serve(
+    central: &Adapter,
+    emulation: &Emulation,
+    stop: impl Future<Output = ()>,
+) -> Result<()> {
+    server::serve(central, emulation, stop).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn serve(
+    _central: &Adapter,
+    _emulation: &Emulation,
+    _stop: impl Future<Output = ()>,
+) -> Result<()> {
+    anyhow::bail!("Emulating a device needs BlueZ, which is only available on Linux")
+}
+
+/// The GATT application and advertisement served to BlueZ
+#[cfg(target_os = "linux")]
+mod server {
+    use super::{Behavior, Emulation, pattern_line};
+    use crate::nus::NusUuids;
+    use anyhow::{Result, anyhow};
+    use btleplug::api::Central as _;
+    use btleplug::platform::Adapter;
+    use dbus::Message;
+    use dbus::arg::{PropMap, RefArg, Variant, prop_cast};
+    use dbus::channel::{MatchingReceiver, Sender as _};
+    use dbus::message::MatchRule;
+    use dbus::nonblock::{Proxy, SyncConnection};
+    use dbus::strings::{ErrorName, Interface, Member, Path};
+    use log::{debug, info};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const APP_PATH: &str = "/org/nus_terminal/emulator";
+    const SERVICE_PATH: &str = "/org/nus_terminal/emulator/service0";
+    const RX_PATH: &str = "/org/nus_terminal/emulator/service0/char0";
+    const TX_PATH: &str = "/org/nus_terminal/emulator/service0/char1";
+    const ADVERTISEMENT_PATH: &str = "/org/nus_terminal/emulator/advertisement0";
+
+    const SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+    const CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+    const ADVERTISEMENT_INTERFACE: &str = "org.bluez.LEAdvertisement1";
+    const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+    /// Payload of a notification until the client's MTU is known
+    const DEFAULT_PAYLOAD: usize = 20;
+
+    const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// What the method calls of BlueZ change
+    #[derive(Debug)]
+    struct State {
+        /// Whether a client subscribed to the notifications
+        notifying: bool,
+        /// Longest notification the client accepts
+        payload: usize,
+    }
+
+    /// The objects of the application, answering BlueZ
+    struct Gatt {
+        uuids: NusUuids,
+        name: String,
+        behavior: Behavior,
+        state: Mutex<State>,
+    }
+
+    impl Gatt {
+        /// Whether one characteristic is written and notifies, like on HM-10 modules
+        fn shared_characteristic(&self) -> bool {
+            self.uuids.rx == self.uuids.tx
+        }
+
+        /// The interfaces and properties of the object at `path`
+        fn properties(&self, path: &str) -> Option<HashMap<String, PropMap>> {
+            let mut props = PropMap::new();
+            let interface = match path {
+                SERVICE_PATH => {
+                    props.insert("UUID".into(), variant(self.uuids.service.to_string()));
+                    props.insert("Primary".into(), variant(true));
+                    SERVICE_INTERFACE
+                }
+                RX_PATH => {
+                    let mut flags = vec!["write", "write-without-response"];
+                    if self.shared_characteristic() {
+                        flags.push("notify");
+                        let notifying = self.state.lock().unwrap().notifying;
+                        props.insert("Notifying".into(), variant(notifying));
+                    }
+                    props.insert("UUID".into(), variant(self.uuids.rx.to_string()));
+                    props.insert("Service".into(), variant(Path::from(SERVICE_PATH)));
+                    props.insert("Flags".into(), variant(strings(&flags)));
+                    CHARACTERISTIC_INTERFACE
+                }
+                TX_PATH if !self.shared_characteristic() => {
+                    let notifying = self.state.lock().unwrap().notifying;
+                    props.insert("UUID".into(), variant(self.uuids.tx.to_string()));
+                    props.insert("Service".into(), variant(Path::from(SERVICE_PATH)));
+                    props.insert("Flags".into(), variant(strings(&["notify"])));
+                    props.insert("Notifying".into(), variant(notifying));
+                    CHARACTERISTIC_INTERFACE
+                }
+                ADVERTISEMENT_PATH => {
+                    let service = self.uuids.service.to_string();
+                    props.insert("Type".into(), variant("peripheral".to_string()));
+                    props.insert("ServiceUUIDs".into(), variant(vec![service]));
+                    props.insert("LocalName".into(), variant(self.name.clone()));
+                    ADVERTISEMENT_INTERFACE
+                }
+                _ => return None,
+            };
+            Some(HashMap::from([(interface.to_string(), props)]))
+        }
+
+        /// The path of the characteristic notifications are sent from
+        fn tx_path(&self) -> &'static str {
+            if self.shared_characteristic() {
+                RX_PATH
+            } else {
+                TX_PATH
+            }
+        }
+
+        fn handle(&self, call: &Message, connection: &SyncConnection) -> Message {
+            let path = call.path().map(|p| p.to_string()).unwrap_or_default();
+            let member = call.member().map(|m| m.to_string()).unwrap_or_default();
+            match (path.as_str(), member.as_str()) {
+                (APP_PATH, "GetManagedObjects") => {
+                    let mut objects = HashMap::new();
+                    for path in [SERVICE_PATH, RX_PATH, TX_PATH] {
+                        if let Some(interfaces) = self.properties(path) {
+                            objects.insert(Path::from(path), interfaces);
+                        }
+                    }
+                    call.method_return().append1(objects)
+                }
+                (path, "Get") => {
+                    let (interface, name): (String, String) = call.read2().unwrap_or_default();
+                    let value = self
+                        .properties(path)
+                        .and_then(|mut i| i.remove(&interface))
+                        .and_then(|mut props| props.remove(&name));
+                    match value {
+                        Some(value) => call.method_return().append1(value),
+                        None => error(call, "org.freedesktop.DBus.Error.InvalidArgs"),
+                    }
+                }
+                (path, "GetAll") => {
+                    let interface: String = call.read1().unwrap_or_default();
+                    match self.properties(path).and_then(|mut i| i.remove(&interface)) {
+                        Some(props) => call.method_return().append1(props),
+                        None => error(call, "org.freedesktop.DBus.Error.InvalidArgs"),
+                    }
+                }
+                (RX_PATH, "WriteValue") => {
+                    let Ok((data, options)) = call.read2::<Vec<u8>, PropMap>() else {
+                        return error(call, "org.bluez.Error.InvalidArguments");
+                    };
+                    if let Some(&mtu) = prop_cast::<u16>(&options, "mtu") {
+                        self.state.lock().unwrap().payload = usize::from(mtu.saturating_sub(3));
+                    }
+                    debug!("Received {} bytes", data.len());
+                    if self.behavior == Behavior::Echo {
+                        self.notify(connection, &data);
+                    }
+                    call.method_return()
+                }
+                (path, "StartNotify" | "StopNotify") if path == self.tx_path() => {
+                    let notifying = member == "StartNotify";
+                    self.state.lock().unwrap().notifying = notifying;
+                    info!(
+                        "Client {}",
+                        if notifying {
+                            "subscribed"
+                        } else {
+                            "unsubscribed"
+                        }
+                    );
+                    call.method_return()
+                }
+                (RX_PATH | TX_PATH, "ReadValue") => error(call, "org.bluez.Error.NotPermitted"),
+                (ADVERTISEMENT_PATH, "Release") => call.method_return(),
+                _ => error(call, "org.freedesktop.DBus.Error.UnknownMethod"),
+            }
+        }
+
+        /// Sends `data` to the subscribed client, split to fit its MTU, returns whether there
+        /// was one
+        fn notify(&self, connection: &SyncConnection, data: &[u8]) -> bool {
+            let (notifying, payload) = {
+                let state = self.state.lock().unwrap();
+                (state.notifying, state.payload.max(1))
+            };
+            if !notifying {
+                return false;
+            }
+            for chunk in data.chunks(payload) {
+                let changed = PropMap::from([("Value".to_string(), variant(chunk.to_vec()))]);
+                let signal = Message::signal(
+                    &Path::from(self.tx_path()),
+                    &Interface::from(PROPERTIES_INTERFACE),
+                    &Member::from("PropertiesChanged"),
+                )
+                .append3(CHARACTERISTIC_INTERFACE, changed, Vec::<String>::new());
+                let _ = connection.send(signal);
+            }
+            true
+        }
+    }
+
+    fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+        Variant(Box::new(value))
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn error(call: &Message, name: &str) -> Message {
+        call.error(&ErrorName::from(name), c"Not supported by the emulator")
+    }
+
+    pub async fn serve(
+        central: &Adapter,
+        emulation: &Emulation,
+        stop: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let description = central.adapter_info().await?;
+        let id = description
+            .split_whitespace()
+            .next()
+            .ok_or(anyhow!("Unknown adapter {description}"))?;
+        let adapter_path = format!("/org/bluez/{id}");
+
+        let gatt = Arc::new(Gatt {
+            uuids: emulation.uuids,
+            name: emulation.name.clone(),
+            behavior: emulation.behavior,
+            state: Mutex::new(State {
+                notifying: false,
+                payload: DEFAULT_PAYLOAD,
+            }),
+        });
+
+        // A connection of its own, bluez-async has no way to serve D-Bus objects
+        let (resource, connection) = dbus_tokio::connection::new_system_sync()?;
+        let connection_task = tokio::spawn(resource);
+        let handler = gatt.clone();
+        let token = connection.start_receive(
+            MatchRule::new_method_call().with_namespaced_path(APP_PATH),
+            Box::new(move |call, connection| {
+                let _ = connection.send(handler.handle(&call, connection));
+                true
+            }),
+        );
+
+        let adapter = Proxy::new(
+            "org.bluez",
+            adapter_path.as_str(),
+            CALL_TIMEOUT,
+            connection.clone(),
+        );
+        let registered: Result<(), dbus::Error> = adapter
+            .method_call(
+                "org.bluez.GattManager1",
+                "RegisterApplication",
+                (Path::from(APP_PATH), PropMap::new()),
+            )
+            .await;
+        registered.map_err(|e| anyhow!("Could not register the GATT service: {e}"))?;
+        let advertised: Result<(), dbus::Error> = adapter
+            .method_call(
+                "org.bluez.LEAdvertisingManager1",
+                "RegisterAdvertisement",
+                (Path::from(ADVERTISEMENT_PATH), PropMap::new()),
+            )
+            .await;
+        advertised.map_err(|e| anyhow!("Could not advertise: {e}"))?;
+        info!(
+            "Advertising {} as '{}' on {id}",
+            emulation.uuids.service, emulation.name
+        );
+
+        let generate = async {
+            let mut interval = tokio::time::interval(emulation.interval);
+            // Numbered from the first line a client receives
+            let mut n = 1;
+            loop {
+                interval.tick().await;
+                if gatt.notify(&connection, &pattern_line(n)) {
+                    n += 1;
+                }
+            }
+        };
+        tokio::select! {
+            _ = generate, if emulation.behavior == Behavior::Pattern => {}
+            _ = stop => {}
+        }
+
+        let _: Result<(), dbus::Error> = adapter
+            .method_call(
+                "org.bluez.LEAdvertisingManager1",
+                "UnregisterAdvertisement",
+                (Path::from(ADVERTISEMENT_PATH),),
+            )
+            .await;
+        let _: Result<(), dbus::Error> = adapter
+            .method_call(
+                "org.bluez.GattManager1",
+                "UnregisterApplication",
+                (Path::from(APP_PATH),),
+            )
+            .await;
+        connection.stop_receive(token);
+        connection_task.abort();
+        Ok(())
+    }
+}
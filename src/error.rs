@@ -0,0 +1,58 @@
+//! Failures callers may want to tell apart from other errors
+//!
+//! Functions of this crate return [`anyhow::Error`]s, which hold an [`Error`] for these
+//! failures; `error.downcast_ref::<Error>()` finds it, also below added context.
+
+use crate::device::DeviceFilter;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// There is no Bluetooth adapter, or none matching the selector
+    AdapterNotFound(Option<String>),
+    /// The adapter with this name is turned off
+    AdapterPoweredOff(String),
+    /// No device matched the filter, within the timeout if one was given
+    DeviceNotFound(DeviceFilter, Option<Duration>),
+    /// The device lacks the named characteristic of the UART service
+    CharacteristicNotFound(&'static str),
+    /// The device disconnected and could not be reached again
+    ConnectionLost,
+}
+
+impl Error {
+    /// Exit code of the `nus_terminal` binary for this failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::AdapterNotFound(_) | Error::AdapterPoweredOff(_) => 3,
+            Error::DeviceNotFound(..) => 4,
+            Error::CharacteristicNotFound(_) => 5,
+            Error::ConnectionLost => 6,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AdapterNotFound(Some(selector)) => {
+                write!(f, "No bluetooth adapter '{selector}', see `list-adapters`")
+            }
+            Error::AdapterNotFound(None) => write!(f, "No bluetooth adapter found"),
+            Error::AdapterPoweredOff(name) => write!(f, "Bluetooth adapter {name} is powered off"),
+            Error::DeviceNotFound(filter, Some(timeout)) => write!(
+                f,
+                "Could not find a matching device ({filter}) within {}s",
+                timeout.as_secs()
+            ),
+            Error::DeviceNotFound(filter, None) => {
+                write!(f, "Could not find a matching device ({filter})")
+            }
+            Error::CharacteristicNotFound(name) => write!(f, "{name} characteristic not found"),
+            Error::ConnectionLost => write!(f, "Connection lost"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
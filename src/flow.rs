@@ -0,0 +1,103 @@
+//! Credit-based flow control with cooperating firmware
+//!
+//! The device tells the terminal how many bytes it may send, so large uploads do not overrun
+//! its UART or ring buffer. Only the device sends anything for it, embedded in its output:
+//!
+//! - `1D lo hi` grants `hi << 8 | lo` more bytes; credits add up, and the terminal starts
+//!   with none after every connection, so the firmware grants the free room of its buffer
+//!   on connecting and then whatever it has consumed since
+//! - `1D 00 00`, a grant of zero, stands for a 0x1D byte of the output itself
+//!
+//! The grants are taken out of the output before anything else sees it. Everything the
+//! terminal writes uses up credits; typed input, pastes, files and triggers wait for them,
+//! only the init sequence and keepalives are sent without waiting.
+
+use clap::ValueEnum;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Marks a grant in the output of the device, ASCII group separator
+const GRANT: u8 = 0x1d;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum FlowControl {
+    /// Send as fast as the link allows
+    #[default]
+    None,
+    /// Wait for the credits granted by the device
+    Credits,
+}
+
+/// The bytes the device allows to be sent, shared by the writer and the receiving side
+#[derive(Debug, Default)]
+pub struct Credits {
+    state: Mutex<State>,
+    granted: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Bytes that may be sent, less than zero after writes that did not wait
+    balance: i64,
+    /// Bytes of a grant received so far, split between notifications
+    partial: Option<Vec<u8>>,
+}
+
+impl Credits {
+    /// Takes the grants out of received data, returns the output they were embedded in
+    pub fn receive(&self, data: &[u8]) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        let mut output = Vec::with_capacity(data.len());
+        let mut granted = 0;
+        for &byte in data {
+            match &mut state.partial {
+                Some(grant) => {
+                    grant.push(byte);
+                    if let [lo, hi] = grant[..] {
+                        state.partial = None;
+                        match u16::from_le_bytes([lo, hi]) {
+                            0 => output.push(GRANT),
+                            n => granted += i64::from(n),
+                        }
+                    }
+                }
+                None if byte == GRANT => state.partial = Some(Vec::with_capacity(2)),
+                None => output.push(byte),
+            }
+        }
+        if granted > 0 {
+            state.balance += granted;
+            self.granted.notify_one();
+        }
+        output
+    }
+
+    /// Waits until there are credits, then takes them for at most `wanted` bytes
+    pub async fn take(&self, wanted: usize) -> usize {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.balance > 0 {
+                    let taken = state.balance.min(wanted as i64);
+                    state.balance -= taken;
+                    if state.balance > 0 {
+                        // Passed on to another writer waiting
+                        self.granted.notify_one();
+                    }
+                    return taken as usize;
+                }
+            }
+            self.granted.notified().await;
+        }
+    }
+
+    /// Uses up credits for bytes sent without waiting for them
+    pub fn charge(&self, sent: usize) {
+        self.state.lock().unwrap().balance -= sent as i64;
+    }
+
+    /// Forgets the credits, a new connection starts without any
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+}
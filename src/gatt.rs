@@ -0,0 +1,174 @@
+//! Listing and accessing the GATT services of a device, whatever they are
+
+use crate::battery::{BATTERY_LEVEL_UUID, BATTERY_SERVICE_UUID};
+use crate::cli::GattArgs;
+use crate::device;
+use crate::device_info::DEVICE_INFORMATION_SERVICE_UUID;
+use crate::dfu::{SMP_CHAR_UUID, SMP_SERVICE_UUID};
+use crate::error::Error;
+use crate::nus::{NUS_RX_CHAR_UUID, NUS_SERVICE_UUID, NUS_TX_CHAR_UUID};
+use anyhow::{Result, anyhow};
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{CharPropFlags, Characteristic, Peripheral as _};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::stream::StreamExt;
+use log::info;
+use uuid::Uuid;
+
+/// Names of well-known services, characteristics and descriptors
+const NAMES: &[(Uuid, &str)] = &[
+    (uuid_from_u16(0x1800), "Generic Access"),
+    (uuid_from_u16(0x1801), "Generic Attribute"),
+    (DEVICE_INFORMATION_SERVICE_UUID, "Device Information"),
+    (BATTERY_SERVICE_UUID, "Battery"),
+    (NUS_SERVICE_UUID, "Nordic UART"),
+    (SMP_SERVICE_UUID, "MCUmgr SMP"),
+    (uuid_from_u16(0x2a00), "Device Name"),
+    (uuid_from_u16(0x2a01), "Appearance"),
+    (
+        uuid_from_u16(0x2a04),
+        "Peripheral Preferred Connection Parameters",
+    ),
+    (uuid_from_u16(0x2a05), "Service Changed"),
+    (uuid_from_u16(0x2aa6), "Central Address Resolution"),
+    (BATTERY_LEVEL_UUID, "Battery Level"),
+    (uuid_from_u16(0x2a29), "Manufacturer Name"),
+    (uuid_from_u16(0x2a24), "Model Number"),
+    (uuid_from_u16(0x2a25), "Serial Number"),
+    (uuid_from_u16(0x2a27), "Hardware Revision"),
+    (uuid_from_u16(0x2a26), "Firmware Revision"),
+    (uuid_from_u16(0x2a28), "Software Revision"),
+    (NUS_RX_CHAR_UUID, "NUS RX"),
+    (NUS_TX_CHAR_UUID, "NUS TX"),
+    (SMP_CHAR_UUID, "SMP"),
+    (uuid_from_u16(0x2900), "Characteristic Extended Properties"),
+    (uuid_from_u16(0x2901), "Characteristic User Description"),
+    (uuid_from_u16(0x2902), "Client Characteristic Configuration"),
+    (uuid_from_u16(0x2904), "Characteristic Presentation Format"),
+];
+
+const PROPERTIES: [(CharPropFlags, &str); 8] = [
+    (CharPropFlags::BROADCAST, "broadcast"),
+    (CharPropFlags::READ, "read"),
+    (
+        CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        "write-without-response",
+    ),
+    (CharPropFlags::WRITE, "write"),
+    (CharPropFlags::NOTIFY, "notify"),
+    (CharPropFlags::INDICATE, "indicate"),
+    (CharPropFlags::AUTHENTICATED_SIGNED_WRITES, "signed-write"),
+    (CharPropFlags::EXTENDED_PROPERTIES, "extended-properties"),
+];
+
+/// Parses a UUID, either in full or as the 16 bit short form of a standard one like `2a19`
+pub fn parse_uuid(s: &str) -> Result<Uuid, String> {
+    if s.len() == 4
+        && let Ok(short) = u16::from_str_radix(s, 16)
+    {
+        return Ok(uuid_from_u16(short));
+    }
+    Uuid::parse_str(s).map_err(|e| format!("'{s}' is not a UUID: {e}"))
+}
+
+/// A UUID followed by its name if it is a known one
+fn label(uuid: Uuid) -> String {
+    match NAMES.iter().find(|(known, _)| *known == uuid) {
+        Some((_, name)) => format!("{uuid} ({name})"),
+        None => uuid.to_string(),
+    }
+}
+
+/// Hex bytes followed by the value as text, if it is printable
+fn value(data: &[u8]) -> String {
+    let hex: Vec<String> = data.iter().map(|b| format!("{b:02x}")).collect();
+    let mut value = hex.join(" ");
+    if let Ok(text) = std::str::from_utf8(data)
+        && !text.is_empty()
+        && !text.trim_end_matches('\0').chars().any(char::is_control)
+    {
+        value.push_str(&format!(" {:?}", text.trim_end_matches('\0')));
+    }
+    value
+}
+
+/// Runs the `gatt` subcommand
+pub async fn run(central: &Adapter, args: &GattArgs) -> Result<()> {
+    let mut search = args.device.search();
+    // The point is looking at devices whose UART service is not found
+    if !args.device.any {
+        search.service = None;
+    }
+    let device = device::select(central, &search).await?;
+    let peripheral = device.peripheral;
+    peripheral.connect().await?;
+    let result = explore(&peripheral, args).await;
+    let _ = peripheral.disconnect().await;
+    result
+}
+
+async fn explore(peripheral: &Peripheral, args: &GattArgs) -> Result<()> {
+    peripheral.discover_services().await?;
+    if args.read.is_empty() && args.subscribe.is_empty() {
+        print_tree(peripheral);
+        return Ok(());
+    }
+
+    for uuid in &args.read {
+        let char = find(peripheral, *uuid)?;
+        let data = peripheral.read(&char).await?;
+        println!("{}: {}", label(*uuid), value(&data));
+    }
+    if args.subscribe.is_empty() {
+        return Ok(());
+    }
+
+    let mut notifications = peripheral.notifications().await?;
+    for uuid in &args.subscribe {
+        peripheral.subscribe(&find(peripheral, *uuid)?).await?;
+    }
+    info!("Printing notifications until Ctrl+C is pressed");
+    loop {
+        tokio::select! {
+            notification = notifications.next() => {
+                let Some(notification) = notification else {
+                    return Err(Error::ConnectionLost.into());
+                };
+                if args.subscribe.contains(&notification.uuid) {
+                    println!("{}: {}", label(notification.uuid), value(&notification.value));
+                }
+            }
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+fn find(peripheral: &Peripheral, uuid: Uuid) -> Result<Characteristic> {
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid)
+        .ok_or(anyhow!("The device has no characteristic {uuid}"))
+}
+
+fn print_tree(peripheral: &Peripheral) {
+    for service in peripheral.services() {
+        let kind = if service.primary { "" } else { " secondary" };
+        println!("Service{kind} {}", label(service.uuid));
+        for char in &service.characteristics {
+            let properties: Vec<&str> = PROPERTIES
+                .iter()
+                .filter(|(flag, _)| char.properties.contains(*flag))
+                .map(|(_, name)| *name)
+                .collect();
+            println!(
+                "  Characteristic {} [{}]",
+                label(char.uuid),
+                properties.join(", ")
+            );
+            for descriptor in &char.descriptors {
+                println!("    Descriptor {}", label(descriptor.uuid));
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Highlighting of device output matching regular expressions
+//!
+//! ```toml
+//! [[highlight]]
+//! pattern = "ERROR|<err>"
+//! color = "red"
+//! bold = true
+//! line = true           # the whole line instead of just the match
+//! ```
+
+use crate::ansi::RenderedLine;
+use crate::config;
+use crate::toml::{Table, Value};
+use anyhow::{Context, Result, anyhow, bail};
+use ratatui::style::{Color, Modifier, Style};
+use regex::Regex;
+
+/// Style applied to output matching a pattern
+#[derive(Debug, Clone)]
+pub struct Highlight {
+    pattern: Regex,
+    /// Changes made to the style of the highlighted text
+    style: Style,
+    /// Whether the whole line is highlighted instead of the matched text
+    line: bool,
+}
+
+/// Reads the rules of an array of `[[highlight]]` tables
+pub fn parse(value: &Value) -> Result<Vec<Highlight>> {
+    let Value::Array(items) = value else {
+        bail!("'highlight' must be an array of tables ([[highlight]])");
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| match item {
+            Value::Table(table) => {
+                rule(table).with_context(|| format!("Invalid highlight {}", i + 1))
+            }
+            _ => bail!("Highlight {} must be a table", i + 1),
+        })
+        .collect()
+}
+
+fn rule(table: &Table) -> Result<Highlight> {
+    let mut pattern = None;
+    let mut style = Style::default();
+    let mut line = false;
+    for (key, value) in table {
+        match key.as_str() {
+            "pattern" => {
+                let source = config::string(key, value)?;
+                pattern = Some(Regex::new(&source).map_err(|e| anyhow!("invalid regex: {e}"))?);
+            }
+            "color" => style = style.fg(color(key, value)?),
+            "background" => style = style.bg(color(key, value)?),
+            "bold" => style = modifier(style, Modifier::BOLD, config::boolean(key, value)?),
+            "dim" => style = modifier(style, Modifier::DIM, config::boolean(key, value)?),
+            "italic" => style = modifier(style, Modifier::ITALIC, config::boolean(key, value)?),
+            "underline" => {
+                style = modifier(style, Modifier::UNDERLINED, config::boolean(key, value)?)
+            }
+            "reverse" => style = modifier(style, Modifier::REVERSED, config::boolean(key, value)?),
+            "line" => line = config::boolean(key, value)?,
+            _ => bail!("Unknown setting '{key}'"),
+        }
+    }
+    Ok(Highlight {
+        pattern: pattern.ok_or(anyhow!("'pattern' is required"))?,
+        style,
+        line,
+    })
+}
+
+fn modifier(style: Style, modifier: Modifier, enabled: bool) -> Style {
+    if enabled {
+        style.add_modifier(modifier)
+    } else {
+        style.remove_modifier(modifier)
+    }
+}
+
+/// Parses a color name like `red` or `light-blue`, or `#rrggbb`
+fn color(key: &str, value: &Value) -> Result<Color> {
+    let name = config::string(key, value)?;
+    if let Some(hex) = name.strip_prefix('#')
+        && hex.len() == 6
+        && let Ok(rgb) = u32::from_str_radix(hex, 16)
+    {
+        return Ok(Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+    }
+    let normalized = name.to_ascii_lowercase().replace(['-', '_', ' '], "");
+    Ok(match normalized.as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => bail!("'{key}': unknown color '{name}'"),
+    })
+}
+
+/// The marking of the matches of a scrollback search
+pub fn search(pattern: Regex) -> Highlight {
+    Highlight {
+        pattern,
+        style: Style::default().fg(Color::Black).bg(Color::Yellow),
+        line: false,
+    }
+}
+
+impl Highlight {
+    pub fn matches(&self, text: &str) -> bool {
+        self.pattern.is_match(text)
+    }
+}
+
+/// Rules for the output of Zephyr and nRF Connect SDK logging and shell, given with `--zephyr`
+///
+/// Lines look like `[00:00:01.234,567] <wrn> module: message`. Warnings and errors are
+/// colored as a whole, the way Zephyr colors them itself when its output is colored.
+pub fn zephyr() -> Vec<Highlight> {
+    let rule = |pattern: &str, style: Style, line: bool| Highlight {
+        pattern: Regex::new(pattern).expect("valid preset pattern"),
+        style,
+        line,
+    };
+    let plain = Style::default();
+    vec![
+        rule(
+            r"^\[(?:\d{2}:\d{2}:\d{2}\.\d{3},\d{3}|\d{8})\]",
+            plain.add_modifier(Modifier::DIM),
+            false,
+        ),
+        rule(
+            r"<(?:err|wrn|inf|dbg)> [\w.-]+:",
+            plain.fg(Color::Cyan),
+            false,
+        ),
+        rule(r"<dbg>", plain.add_modifier(Modifier::DIM), true),
+        rule(r"<inf>", plain.fg(Color::Green), false),
+        rule(r"<wrn>", plain.fg(Color::Yellow), true),
+        rule(r"<err>", plain.fg(Color::Red), true),
+        rule(r"<(?:wrn|err)>", plain.add_modifier(Modifier::BOLD), false),
+        rule(
+            r"^uart:~\$",
+            plain.fg(Color::Green).add_modifier(Modifier::BOLD),
+            false,
+        ),
+    ]
+}
+
+/// Applies the rules to a displayed line, later rules taking precedence
+pub fn apply(rules: &[Highlight], line: &mut RenderedLine) {
+    if rules.is_empty() {
+        return;
+    }
+    let text: String = line.cells.iter().map(|&(c, _)| c).collect();
+    // Cell index of every byte offset a match can start or end at
+    let mut cell_at = vec![0; text.len() + 1];
+    for (cell, (offset, c)) in text.char_indices().enumerate() {
+        cell_at[offset..offset + c.len_utf8()].fill(cell);
+    }
+    cell_at[text.len()] = line.cells.len();
+
+    for rule in rules {
+        if rule.line {
+            if rule.pattern.is_match(&text) {
+                for (_, style) in &mut line.cells {
+                    *style = style.patch(rule.style);
+                }
+            }
+            continue;
+        }
+        for m in rule.pattern.find_iter(&text) {
+            for (_, style) in &mut line.cells[cell_at[m.start()]..cell_at[m.end()]] {
+                *style = style.patch(rule.style);
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Rhai scripts hooked into the session with `--hooks`
+//!
+//! The functions a script defines are called as the session goes on:
+//!
+//! ```rhai
+//! fn on_connect() { send("version\r\n"); }
+//! fn on_disconnect() { log("gone after " + this.lines + " lines"); }
+//! fn on_line(line) {
+//!     this.lines = (this.lines ?? 0) + 1;
+//!     if line.contains("ERROR") { set_status("errors seen"); }
+//! }
+//! fn on_bytes(data) {}
+//! fn poll() { send("status\r\n"); }
+//!
+//! every(5000, "poll");
+//! ```
+//!
+//! Statements outside the functions run once when the tab opens. Rhai functions cannot see its
+//! variables, so state kept between calls goes in `this`, a map shared by all of them.
+
+use crate::screen::Screen;
+use anyhow::{Context, Result};
+use rhai::{AST, Blob, CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Longest line passed to `on_line`, longer ones are cut off
+const MAX_LINE_LEN: usize = 4096;
+
+/// Operations a call may take, so a loop that never ends fails instead of hanging the tab
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// Compiles the script given with `--hooks`, shared by the tabs
+pub fn load(path: &Path) -> Result<AST> {
+    Engine::new()
+        .compile_file(path.to_path_buf())
+        .with_context(|| format!("Invalid hooks script {}", path.display()))
+}
+
+/// What happened in the session
+#[derive(Debug)]
+pub enum Event {
+    Connected,
+    Disconnected,
+    Received(Vec<u8>),
+}
+
+/// Where the script acts on the tab
+#[derive(Debug)]
+pub struct Host {
+    pub write_queue: mpsc::Sender<Vec<u8>>,
+    pub screen: Arc<Mutex<Screen>>,
+    /// Set with `set_status`, shown in the status bar
+    pub status: Arc<Mutex<Option<String>>>,
+}
+
+/// Something a script asked for during a call, done once it returns
+#[derive(Debug)]
+enum Action {
+    Send(Vec<u8>),
+    Log(String),
+    Status(Option<String>),
+    Timer(Timer),
+}
+
+#[derive(Debug)]
+struct Timer {
+    due: Instant,
+    /// Period of a timer set with `every`, `None` with `after`
+    every: Option<Duration>,
+    function: String,
+}
+
+/// A script with its state, run for one tab
+struct Hooks {
+    engine: Engine,
+    ast: AST,
+    /// The map scripts use as `this`
+    state: Dynamic,
+    actions: Arc<Mutex<Vec<Action>>>,
+    timers: Vec<Timer>,
+    /// Start of a line whose ending was not received yet
+    line: Vec<u8>,
+    host: Host,
+}
+
+/// Runs `ast` for a tab until the supervisor sending the events is gone
+pub async fn run(ast: AST, mut events: mpsc::Receiver<Event>, host: Host) {
+    let mut hooks = Hooks::new(ast, host);
+    if let Err(e) = hooks
+        .engine
+        .run_ast_with_scope(&mut Scope::new(), &hooks.ast)
+    {
+        hooks.status(&format!("Hooks script failed: {e}"));
+    }
+    hooks.act();
+    loop {
+        let next = hooks.timers.iter().map(|timer| timer.due).min();
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Event::Connected) => hooks.call("on_connect", ()),
+                Some(Event::Disconnected) => hooks.call("on_disconnect", ()),
+                Some(Event::Received(data)) => hooks.receive(data),
+                None => return,
+            },
+            _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                hooks.fire_timers();
+            }
+        }
+    }
+}
+
+impl Hooks {
+    fn new(ast: AST, host: Host) -> Hooks {
+        let actions: Arc<Mutex<Vec<Action>>> = Arc::default();
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let queue = actions.clone();
+        engine.register_fn("send", move |text: &str| {
+            queue
+                .lock()
+                .unwrap()
+                .push(Action::Send(text.as_bytes().to_vec()));
+        });
+        let queue = actions.clone();
+        engine.register_fn("send", move |data: Blob| {
+            queue.lock().unwrap().push(Action::Send(data));
+        });
+        let queue = actions.clone();
+        engine.register_fn("log", move |text: &str| {
+            queue.lock().unwrap().push(Action::Log(text.to_string()));
+        });
+        // Printing would go to the terminal under the UI
+        let queue = actions.clone();
+        engine.on_print(move |text| queue.lock().unwrap().push(Action::Log(text.to_string())));
+        let queue = actions.clone();
+        engine.on_debug(move |text, _, _| {
+            queue.lock().unwrap().push(Action::Log(text.to_string()));
+        });
+        let queue = actions.clone();
+        engine.register_fn("set_status", move |text: &str| {
+            let status = (!text.is_empty()).then(|| text.to_string());
+            queue.lock().unwrap().push(Action::Status(status));
+        });
+        for (name, periodic) in [("every", true), ("after", false)] {
+            let queue = actions.clone();
+            engine.register_fn(name, move |ms: i64, function: &str| {
+                let delay = Duration::from_millis(ms.max(1) as u64);
+                queue.lock().unwrap().push(Action::Timer(Timer {
+                    due: Instant::now() + delay,
+                    every: periodic.then_some(delay),
+                    function: function.to_string(),
+                }));
+            });
+        }
+
+        Hooks {
+            engine,
+            ast,
+            state: Map::new().into(),
+            actions,
+            timers: Vec::new(),
+            line: Vec::new(),
+            host,
+        }
+    }
+
+    /// Calls the function `name` if the script defines it with as many parameters as `args`
+    fn call(&mut self, name: &str, args: impl FuncArgs) {
+        let mut values = Vec::new();
+        args.parse(&mut values);
+        let defined = self
+            .ast
+            .iter_functions()
+            .any(|function| function.name == name && function.params.len() == values.len());
+        if !defined {
+            return;
+        }
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            name,
+            values,
+        );
+        if let Err(e) = result {
+            self.status(&format!("Hook {name} failed: {e}"));
+        }
+        self.act();
+    }
+
+    fn receive(&mut self, data: Vec<u8>) {
+        for &b in &data {
+            match b {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if !line.is_empty() {
+                        let line = String::from_utf8_lossy(&line).into_owned();
+                        self.call("on_line", (line,));
+                    }
+                }
+                _ if self.line.len() < MAX_LINE_LEN => self.line.push(b),
+                _ => {}
+            }
+        }
+        self.call("on_bytes", (Blob::from(data),));
+    }
+
+    fn fire_timers(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.timers)
+            .into_iter()
+            .partition(|timer| timer.due <= now);
+        self.timers = waiting;
+        for mut timer in due {
+            self.call(&timer.function, ());
+            if let Some(every) = timer.every {
+                // A tab busy for longer than the period skips the calls it missed
+                timer.due = (timer.due + every).max(now);
+                self.timers.push(timer);
+            }
+        }
+    }
+
+    /// Does what the script asked for in the last call
+    fn act(&mut self) {
+        let actions = std::mem::take(&mut *self.actions.lock().unwrap());
+        for action in actions {
+            match action {
+                // Waiting for room could wait forever, for a writer waiting for credits or
+                // acknowledgements that only come in once the supervisor passed on more events
+                Action::Send(data) => {
+                    if let Err(mpsc::error::TrySendError::Full(_)) =
+                        self.host.write_queue.try_send(data)
+                    {
+                        self.status("Hooks script sent data, dropped as the write queue is full");
+                    }
+                }
+                Action::Log(text) => self.status(&text),
+                Action::Status(status) => *self.host.status.lock().unwrap() = status,
+                Action::Timer(timer) => {
+                    let defined = self.ast.iter_functions().any(|function| {
+                        function.name == timer.function && function.params.is_empty()
+                    });
+                    if defined {
+                        self.timers.push(timer);
+                    } else {
+                        self.status(&format!("No function {}() for the timer", timer.function));
+                    }
+                }
+            }
+        }
+    }
+
+    fn status(&self, msg: &str) {
+        self.host.screen.lock().unwrap().status(msg);
+    }
+}
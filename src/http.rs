@@ -0,0 +1,211 @@
+//! Just enough HTTP/1.1 for the endpoints of bridges, one request per connection
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Longest request head accepted
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Longest request body accepted
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Time a client has to send the whole request, so a slow one cannot hold a connection open
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds the endpoint enabled with the command line option `option`
+pub async fn bind(addr: SocketAddr, option: &str) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Could not listen on {addr} for {option}"))
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query
+    pub path: String,
+    /// Names and values of the header fields, in the order they were sent
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the header field `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads a request, `None` if the client closed the connection before sending one
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<Request>> {
+    tokio::time::timeout(REQUEST_TIMEOUT, read(stream))
+        .await
+        .map_err(|_| anyhow!("Request not received in time"))?
+}
+
+async fn read(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<Request>> {
+    let mut data = Vec::new();
+    let end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_HEAD_LEN {
+            bail!("Request too long");
+        }
+        let mut buf = [0; 1024];
+        match stream.read(&mut buf).await? {
+            0 => return Ok(None),
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    };
+    let mut body = data.split_off(end + 4);
+    let head = String::from_utf8_lossy(&data);
+
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    let mut length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse().context("Invalid Content-Length")?;
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    if length > MAX_BODY_LEN {
+        bail!("Request body too long");
+    }
+    while body.len() < length {
+        let mut buf = [0; 4096];
+        match stream.read(&mut buf).await? {
+            0 => bail!("Client closed the connection during the request"),
+            n => body.extend_from_slice(&buf[..n]),
+        }
+    }
+    body.truncate(length);
+    Ok(Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+/// Sends the response and closes the connection
+pub async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the request a client sends in `chunks`
+    async fn request(chunks: &[&[u8]]) -> Result<Option<Request>> {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                client.write_all(&chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        read_request(&mut server).await
+    }
+
+    #[tokio::test]
+    async fn parses_method_path_headers_and_body() {
+        let request = request(&[
+            b"POST /send?x=1 HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n",
+            b"content-length: 7\r\nAuthorization: Bearer abc\r\n\r\nreb",
+            b"oot\rtrailing",
+        ])
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/send")
+        );
+        assert_eq!(request.header("HOST"), Some("127.0.0.1:8080"));
+        assert_eq!(request.header("authorization"), Some("Bearer abc"));
+        assert_eq!(request.header("Origin"), None);
+        // Cut off at the length given
+        assert_eq!(request.body, b"reboot\r");
+    }
+
+    #[tokio::test]
+    async fn closed_before_a_request_is_none() {
+        assert!(request(&[]).await.unwrap().is_none());
+        assert!(request(&[b"GET / HTTP/1.1\r\n"]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_invalid_requests() {
+        let head = [b"GET / HTTP/1.1\r\nX: ".as_slice(), &[b'a'; MAX_HEAD_LEN]].concat();
+        assert!(request(&[&head]).await.is_err());
+        let length = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LEN + 1
+        );
+        assert!(request(&[length.as_bytes()]).await.is_err());
+        assert!(
+            request(&[b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"])
+                .await
+                .is_err()
+        );
+        // Closed in the middle of the body
+        assert!(
+            request(&[b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\nabc"])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_a_slow_client() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"GET /status HTTP/1.1\r\n").await.unwrap();
+        let error = read_request(&mut server).await.unwrap_err();
+        assert_eq!(error.to_string(), "Request not received in time");
+    }
+
+    #[tokio::test]
+    async fn responds_and_closes() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        respond(&mut server, "200 OK", "application/json", "{}")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\
+             Connection: close\r\n\r\n{}"
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Printing what a device reports about itself
+
+use crate::battery;
+use crate::cli::InfoArgs;
+use crate::device;
+use crate::device_info;
+use crate::link::{Link, LinkOptions};
+use anyhow::Result;
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Adapter;
+
+/// Runs the `info` subcommand
+pub async fn run(central: &Adapter, args: &InfoArgs) -> Result<()> {
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, _notifications) =
+        Link::open(device.peripheral.clone(), &LinkOptions::from(&args.device)).await?;
+
+    let mut fields = vec![
+        ("Name", device.display_name().to_string()),
+        ("Address", device.address.clone()),
+        ("MTU", link.mtu.to_string()),
+    ];
+    if let Some(rssi) = device.rssi {
+        fields.push(("RSSI", format!("{rssi} dBm")));
+    }
+    let info = device_info::read(&link.peripheral).await;
+    let battery = battery::watch(&link.peripheral).await;
+    let _ = link.peripheral.disconnect().await;
+
+    match info? {
+        Some(info) => fields.extend(info.fields),
+        None => fields.push(("Device information", "not available".to_string())),
+    }
+    if let Some((level, _)) = battery? {
+        fields.push(("Battery", format!("{level}%")));
+    }
+    let width = fields
+        .iter()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    for (label, value) in fields {
+        println!("{:width$} {value}", format!("{label}:"));
+    }
+    Ok(())
+}
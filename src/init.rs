@@ -0,0 +1,107 @@
+//! Commands sent to the device after every connection
+//!
+//! By default this is Ctrl+L, asking a shell to redraw its prompt. A profile can give a list
+//! of commands, each optionally followed by a pause:
+//!
+//! ```toml
+//! init = ['\x0c', { send = 'AT+ECHO=0\r', delay = 200 }, 'status\r']
+//! ```
+
+use crate::config;
+use crate::toml::Value;
+use anyhow::{Context, Result, anyhow, bail};
+use std::time::Duration;
+
+/// Sent when no init sequence is given, Ctrl+L
+pub const DEFAULT: &[u8] = b"\x0c";
+
+/// One command of the init sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitCommand {
+    pub data: Vec<u8>,
+    /// Pause after sending it, `--init-delay` if not given
+    pub delay: Option<Duration>,
+}
+
+/// Parses a command given with `--init`
+pub fn parse(s: &str) -> Result<InitCommand> {
+    Ok(InitCommand {
+        data: unescape(s)?,
+        delay: None,
+    })
+}
+
+/// Reads `init` of a profile, a string or an array of strings and `{ send, delay }` tables
+pub fn parse_profile(value: &Value) -> Result<Vec<InitCommand>> {
+    let items = match value {
+        Value::Array(items) => items.as_slice(),
+        _ => std::slice::from_ref(value),
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| command(item).with_context(|| format!("Invalid init command {}", i + 1)))
+        .collect()
+}
+
+fn command(value: &Value) -> Result<InitCommand> {
+    let table = match value {
+        Value::String(s) => return parse(s),
+        Value::Table(table) => table,
+        _ => bail!("must be a string or a table with 'send' and 'delay'"),
+    };
+    let mut data = None;
+    let mut delay = None;
+    for (key, value) in table {
+        match key.as_str() {
+            "send" => data = Some(unescape(&config::string(key, value)?)?),
+            "delay" => delay = Some(Duration::from_millis(config::integer(key, value)?)),
+            _ => bail!("Unknown setting '{key}'"),
+        }
+    }
+    Ok(InitCommand {
+        data: data.ok_or(anyhow!("'send' is required"))?,
+        delay,
+    })
+}
+
+/// Replaces C-like escapes like `\r` and `\e`, `\xNN` giving a raw byte
+pub fn unescape(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('e') => '\x1b',
+                Some('0') => '\0',
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    let byte = u8::from_str_radix(&hex, 16)
+                        .map_err(|_| anyhow!("invalid escape sequence \\x{hex}"))?;
+                    out.push(byte);
+                    continue;
+                }
+                Some('\\') => '\\',
+                Some(c) => bail!("invalid escape sequence \\{c}"),
+                None => bail!("'\\' at the end"),
+            },
+            c => c,
+        };
+        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    Ok(out)
+}
+
+/// The commands sent after connecting with the pause after each, Ctrl+L if none are given
+pub fn sequence(commands: &[InitCommand], delay: Duration) -> Vec<(Vec<u8>, Duration)> {
+    if commands.is_empty() {
+        return vec![(DEFAULT.to_vec(), delay)];
+    }
+    commands
+        .iter()
+        .map(|command| (command.data.clone(), command.delay.unwrap_or(delay)))
+        .collect()
+}
@@ -0,0 +1,112 @@
+//! Minimal helpers for emitting JSON output and reading simple JSON objects
+
+use std::collections::HashMap;
+
+/// Returns `s` as a quoted JSON string literal
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns `value` as a JSON number, or `null` if absent
+pub fn opt_number<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "null".to_string())
+}
+
+/// Returns `s` as a JSON string literal, or `null` if absent
+pub fn opt_string(s: Option<&str>) -> String {
+    s.map(string).unwrap_or_else(|| "null".to_string())
+}
+
+/// Parses an object whose values are all strings, like `{"tag":"info","data":"hi"}`
+pub fn parse_string_object(s: &str) -> Option<HashMap<String, String>> {
+    let mut chars = s.trim().chars().peekable();
+    let mut object = HashMap::new();
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Some(object);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        let key = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value = parse_string(&mut chars)?;
+        object.insert(key, value);
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => break,
+            _ => return None,
+        }
+    }
+    chars.next().is_none().then_some(object)
+}
+
+/// Parses an array of a number followed by strings, like `[1.5, "o", "text"]`
+pub fn parse_event(s: &str) -> Option<(f64, Vec<String>)> {
+    let s = s.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (number, rest) = s.split_once(',')?;
+    let number = number.trim().parse().ok()?;
+    let mut chars = rest.chars().peekable();
+    let mut strings = Vec::new();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        strings.push(parse_string(&mut chars)?);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some(',') => continue,
+            None => return Some((number, strings)),
+            Some(_) => return None,
+        }
+    }
+}
+
+fn parse_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => s.push(match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\x08',
+                'f' => '\x0c',
+                'u' => {
+                    let hex: String = chars.take(4).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                c => c,
+            }),
+            c => s.push(c),
+        }
+    }
+}
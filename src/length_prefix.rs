@@ -0,0 +1,143 @@
+//! Frames starting with the length of their data, for protocols without delimiters
+
+/// Encoding of the length before the data of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LengthPrefix {
+    /// Little-endian u16
+    Len16le,
+    /// A single byte
+    Len8,
+}
+
+impl LengthPrefix {
+    fn len(self) -> usize {
+        match self {
+            LengthPrefix::Len16le => 2,
+            LengthPrefix::Len8 => 1,
+        }
+    }
+
+    /// Longest data a frame can hold
+    pub fn max_data_len(self) -> usize {
+        match self {
+            LengthPrefix::Len16le => usize::from(u16::MAX),
+            LengthPrefix::Len8 => usize::from(u8::MAX),
+        }
+    }
+
+    /// Encodes `data` as a frame, `None` if it is too long for the prefix
+    pub fn encode(self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() > self.max_data_len() {
+            return None;
+        }
+        let mut out = Vec::with_capacity(self.len() + data.len());
+        match self {
+            LengthPrefix::Len16le => out.extend_from_slice(&(data.len() as u16).to_le_bytes()),
+            LengthPrefix::Len8 => out.push(data.len() as u8),
+        }
+        out.extend_from_slice(data);
+        Some(out)
+    }
+}
+
+/// Reassembles frames split across notifications
+#[derive(Debug)]
+pub struct Decoder {
+    prefix: LengthPrefix,
+    /// Start of a frame whose data was not all received yet
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(prefix: LengthPrefix) -> Decoder {
+        Decoder {
+            prefix,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Returns the frames completed by `data`
+    ///
+    /// Wrapped in `Some` like the frames of the other decoders, every length is valid.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Option<Vec<u8>>> {
+        self.buffer.extend_from_slice(data);
+        let header = self.prefix.len();
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while let Some(prefix) = self.buffer.get(start..start + header) {
+            let len = match self.prefix {
+                LengthPrefix::Len16le => usize::from(u16::from_le_bytes([prefix[0], prefix[1]])),
+                LengthPrefix::Len8 => usize::from(prefix[0]),
+            };
+            let Some(frame) = self.buffer.get(start + header..start + header + len) else {
+                break;
+            };
+            frames.push(Some(frame.to_vec()));
+            start += header + len;
+        }
+        self.buffer.drain(..start);
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_prefixes_the_length() {
+        assert_eq!(
+            LengthPrefix::Len16le.encode(&[0xaa; 0x0102]).unwrap()[..3],
+            [0x02, 0x01, 0xaa]
+        );
+        assert_eq!(
+            LengthPrefix::Len8.encode(&[0x01, 0x02]),
+            Some(vec![2, 0x01, 0x02])
+        );
+        assert_eq!(LengthPrefix::Len8.encode(&[]), Some(vec![0]));
+    }
+
+    #[test]
+    fn encode_rejects_data_too_long_for_the_prefix() {
+        assert!(LengthPrefix::Len8.encode(&[0; 255]).is_some());
+        assert_eq!(LengthPrefix::Len8.encode(&[0; 256]), None);
+        assert!(LengthPrefix::Len16le.encode(&[0; 65535]).is_some());
+        assert_eq!(LengthPrefix::Len16le.encode(&[0; 65536]), None);
+    }
+
+    #[test]
+    fn decoder_joins_frames_split_across_pushes() {
+        let data: Vec<Vec<u8>> = vec![vec![], vec![0x01], (0..0xff).collect(), vec![0x00; 3]];
+        for prefix in [LengthPrefix::Len16le, LengthPrefix::Len8] {
+            let stream: Vec<u8> = data
+                .iter()
+                .flat_map(|data| prefix.encode(data).unwrap())
+                .collect();
+            for split in [1, 2, 3, 7, 256, stream.len()] {
+                let mut decoder = Decoder::new(prefix);
+                let frames: Vec<_> = stream
+                    .chunks(split)
+                    .flat_map(|chunk| decoder.push(chunk))
+                    .collect();
+                let expected: Vec<_> = data.iter().cloned().map(Some).collect();
+                assert_eq!(frames, expected, "{prefix:?} split every {split} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_holds_back_a_truncated_frame() {
+        let mut decoder = Decoder::new(LengthPrefix::Len16le);
+        // Half a prefix, then a prefix promising more data than arrives
+        assert_eq!(decoder.push(&[0x03]), Vec::<Option<Vec<u8>>>::new());
+        assert_eq!(
+            decoder.push(&[0x00, 0x01, 0x02]),
+            Vec::<Option<Vec<u8>>>::new()
+        );
+        assert_eq!(
+            decoder.push(&[0x03, 0x01, 0x00]),
+            [Some(vec![0x01, 0x02, 0x03])]
+        );
+        assert_eq!(decoder.push(&[0x04]), [Some(vec![0x04])]);
+    }
+}
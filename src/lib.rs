@@ -0,0 +1,27 @@
+//! Connectivity to devices exposing the Nordic UART Service (NUS)
+//!
+//! [`NusClient`] scans for devices and connects to them, giving a stream of received data and
+//! a sink for data written to the device. The modules below it are the building blocks used
+//! by the `nus_terminal` binary and can be used directly for finer control.
+
+pub mod adapter;
+pub mod battery;
+#[cfg(target_os = "linux")]
+mod bluez;
+pub mod client;
+pub mod device;
+pub mod device_info;
+pub mod error;
+pub mod link;
+pub mod mtu;
+pub mod nus;
+pub mod pairing;
+mod picker;
+pub mod transport;
+
+pub use client::{Connection, NusClient};
+pub use device::{DeviceFilter, DeviceInfo};
+pub use error::Error;
+pub use link::{Fallback, Link, LinkOptions, Trace, WriteMode};
+pub use nus::{NusUuids, Protocol};
+pub use transport::{ByteStream, Transport};
@@ -0,0 +1,292 @@
+//! Local line editing for line mode, where input is only sent on Enter
+
+use anyhow::{Context, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Most lines kept in the history
+const HISTORY_LEN: usize = 1000;
+
+/// What a key press did to the line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Edited,
+    /// Enter was pressed, the line is to be sent
+    Submit(String),
+    /// The key does not edit the line and goes to the device as is
+    Ignored,
+}
+
+/// A readline-like line editor with history
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    /// Position of the cursor in `line`
+    cursor: usize,
+    history: Vec<String>,
+    /// Index of the history entry being shown, `None` while editing a new line
+    browsing: Option<usize>,
+    /// The new line put aside while browsing the history
+    draft: Vec<char>,
+    /// File the history is loaded from and appended to
+    history_file: Option<PathBuf>,
+}
+
+impl LineEditor {
+    /// Creates an editor with the history stored in `history_file`, if given
+    pub fn new(history_file: Option<PathBuf>) -> Result<LineEditor> {
+        let mut history = Vec::new();
+        if let Some(path) = &history_file {
+            match std::fs::read_to_string(path) {
+                Ok(content) => history.extend(content.lines().map(str::to_string)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Could not read history {}", path.display()));
+                }
+            }
+            history.drain(..history.len().saturating_sub(HISTORY_LEN));
+        }
+        Ok(LineEditor {
+            history,
+            history_file,
+            ..LineEditor::default()
+        })
+    }
+
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    /// Position of the cursor in characters
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Inserts pasted text at the cursor, leaving out control characters
+    pub fn insert(&mut self, text: &str) {
+        for c in text.chars().filter(|c| !c.is_control()) {
+            self.line.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    pub fn handle_key(&mut self, key: &KeyEvent) -> Outcome {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => {
+                let line = self.line();
+                self.set_line(Vec::new());
+                self.browsing = None;
+                return Outcome::Submit(line);
+            }
+            KeyCode::Char('e') if ctrl => self.cursor = self.line.len(),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('b') if ctrl => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Char('f') if ctrl => self.cursor = (self.cursor + 1).min(self.line.len()),
+            KeyCode::Char('u') if ctrl => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('k') if ctrl => self.line.truncate(self.cursor),
+            KeyCode::Char('w') if ctrl => {
+                // Back to the start of the word, skipping the whitespace after it
+                let mut start = self.cursor;
+                while start > 0 && self.line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            KeyCode::Char(_) if ctrl => return Outcome::Ignored,
+            KeyCode::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.line.len(),
+            KeyCode::Up => self.previous(),
+            KeyCode::Down => self.next(),
+            KeyCode::Esc => {
+                self.set_line(Vec::new());
+                self.browsing = None;
+            }
+            KeyCode::Backspace | KeyCode::Delete => {}
+            _ => return Outcome::Ignored,
+        }
+        Outcome::Edited
+    }
+
+    /// Adds a sent line to the history, appending it to the history file
+    pub fn remember(&mut self, line: &str) -> io::Result<()> {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return Ok(());
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > HISTORY_LEN {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{line}")?;
+        }
+        Ok(())
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.line = line;
+        self.cursor = self.line.len();
+    }
+
+    /// Shows the history entry before the current one
+    fn previous(&mut self) {
+        let index = match self.browsing {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.line);
+                self.history.len() - 1
+            }
+            Some(index) => index.saturating_sub(1),
+        };
+        self.browsing = Some(index);
+        self.set_line(self.history[index].chars().collect());
+    }
+
+    /// Shows the history entry after the current one, or the new line after the last
+    fn next(&mut self) {
+        match self.browsing {
+            None => {}
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.set_line(self.history[index + 1].chars().collect());
+            }
+            Some(_) => {
+                self.browsing = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set_line(draft);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(editor: &mut LineEditor, code: KeyCode) -> Outcome {
+        editor.handle_key(&KeyEvent::from(code))
+    }
+
+    fn ctrl(editor: &mut LineEditor, c: char) -> Outcome {
+        editor.handle_key(&KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL))
+    }
+
+    fn type_text(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            assert_eq!(press(editor, KeyCode::Char(c)), Outcome::Edited);
+        }
+    }
+
+    #[test]
+    fn editing_the_line() {
+        let mut editor = LineEditor::default();
+        type_text(&mut editor, "hllo");
+        press(&mut editor, KeyCode::Home);
+        press(&mut editor, KeyCode::Right);
+        type_text(&mut editor, "e");
+        assert_eq!((editor.line().as_str(), editor.cursor()), ("hello", 2));
+
+        press(&mut editor, KeyCode::End);
+        type_text(&mut editor, " big world");
+        ctrl(&mut editor, 'w');
+        assert_eq!(editor.line(), "hello big ");
+        ctrl(&mut editor, 'a');
+        press(&mut editor, KeyCode::Delete);
+        ctrl(&mut editor, 'f');
+        ctrl(&mut editor, 'k');
+        assert_eq!(editor.line(), "e");
+        press(&mut editor, KeyCode::Backspace);
+        press(&mut editor, KeyCode::Backspace);
+        assert_eq!((editor.line().as_str(), editor.cursor()), ("", 0));
+    }
+
+    #[test]
+    fn enter_submits_and_clears_the_line() {
+        let mut editor = LineEditor::default();
+        type_text(&mut editor, "AT");
+        assert_eq!(
+            press(&mut editor, KeyCode::Enter),
+            Outcome::Submit("AT".to_string())
+        );
+        assert_eq!(editor.line(), "");
+        assert_eq!(
+            press(&mut editor, KeyCode::Enter),
+            Outcome::Submit(String::new())
+        );
+    }
+
+    #[test]
+    fn keys_not_editing_the_line_are_ignored() {
+        let mut editor = LineEditor::default();
+        type_text(&mut editor, "abc");
+        assert_eq!(ctrl(&mut editor, 'c'), Outcome::Ignored);
+        assert_eq!(press(&mut editor, KeyCode::F(1)), Outcome::Ignored);
+        assert_eq!(editor.line(), "abc");
+    }
+
+    #[test]
+    fn pasted_text_leaves_out_control_characters() {
+        let mut editor = LineEditor::default();
+        type_text(&mut editor, "ad");
+        press(&mut editor, KeyCode::Left);
+        editor.insert("b\tc\r");
+        assert_eq!((editor.line().as_str(), editor.cursor()), ("abcd", 3));
+    }
+
+    #[test]
+    fn browsing_the_history_keeps_the_draft() {
+        let mut editor = LineEditor::default();
+        for line in ["first", "second", "second", "  "] {
+            editor.remember(line).unwrap();
+        }
+        type_text(&mut editor, "draft");
+
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.line(), "second");
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.line(), "first");
+        // The oldest entry stays
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.line(), "first");
+        press(&mut editor, KeyCode::Down);
+        press(&mut editor, KeyCode::Down);
+        assert_eq!(editor.line(), "draft");
+    }
+
+    #[test]
+    fn history_is_loaded_and_appended() {
+        let path = std::env::temp_dir().join(format!("nus-history-{}", std::process::id()));
+        std::fs::write(&path, "old\n").unwrap();
+        let mut editor = LineEditor::new(Some(path.clone())).unwrap();
+        editor.remember("new").unwrap();
+        press(&mut editor, KeyCode::Up);
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.line(), "old");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old\nnew\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
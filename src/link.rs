@@ -0,0 +1,329 @@
+use crate::error::Error;
+use crate::mtu::{self, ATT_HEADER_LEN, DEFAULT_MTU};
+use crate::nus::{self, NusUuids};
+use crate::pairing::{self, Agent};
+use anyhow::{Result, anyhow};
+use btleplug::api::{CharPropFlags, Characteristic, Peripheral as _, ValueNotification, WriteType};
+use btleplug::platform::Peripheral;
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+use log::debug;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+/// Settings used when opening a link
+#[derive(Debug, Clone)]
+pub struct LinkOptions {
+    pub uuids: NusUuids,
+    /// ATT MTU to use instead of the one reported by the Bluetooth stack
+    pub mtu: Option<u16>,
+    pub write_mode: WriteMode,
+    /// Pair with the device if it is not bonded yet, asking this agent for the passkey
+    pub pairing: Option<Agent>,
+    /// Time a connection attempt may take, `None` to wait as long as the Bluetooth stack does
+    pub connect_timeout: Option<Duration>,
+    /// Time the service discovery may take
+    pub discover_timeout: Option<Duration>,
+    /// How often connecting and discovering are tried again after failing
+    pub connect_retries: u32,
+    /// What to do when the device lacks the service of `uuids`
+    pub fallback: Fallback,
+    /// Told about every write and notification of the link
+    pub trace: Option<Arc<dyn Trace>>,
+    /// Told what happens while opening the link instead of the log, e.g. so messages show up
+    /// in a terminal UI instead of being printed over it
+    pub notices: Option<Arc<dyn Notices>>,
+}
+
+impl LinkOptions {
+    /// Passes `msg` to the notices, logs it at `level` without them
+    fn notice(&self, level: log::Level, msg: &str) {
+        match &self.notices {
+            Some(notices) => notices.notice(msg),
+            None => log::log!(level, "{msg}"),
+        }
+    }
+}
+
+/// Observer of the ATT traffic of a link, e.g. to record it for Wireshark
+pub trait Trace: fmt::Debug + Send + Sync {
+    /// `data` is about to be written to the RX characteristic in a single write
+    fn write(&self, data: &[u8], write_type: WriteType);
+    /// The device acknowledged a write with response
+    fn write_response(&self);
+    /// The TX characteristic notified `data`
+    fn notification(&self, data: &[u8]);
+}
+
+/// Receiver of the messages about opening a link
+pub trait Notices: fmt::Debug + Send + Sync {
+    fn notice(&self, msg: &str);
+}
+
+/// What [`Link::open`] does when the device lacks the configured UART service
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Fallback {
+    /// Fail with [`Error::CharacteristicNotFound`]
+    #[default]
+    Fail,
+    /// Use another service with a notify and a write characteristic if the function accepts
+    /// it, see [`nus::detect`]
+    Ask(fn(&NusUuids) -> bool),
+    /// Use another service with a notify and a write characteristic without asking
+    Detect,
+}
+
+/// How data is written to the RX characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum WriteMode {
+    /// Without response if the characteristic supports it, with response otherwise
+    #[default]
+    Auto,
+    /// Write requests, acknowledged by the device
+    WithResponse,
+    /// Write commands, which are faster but not acknowledged
+    WithoutResponse,
+}
+
+/// State of the link as shown to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// No data arrived for the stall timeout, the link may have dropped silently
+    Stalled,
+    Reconnecting,
+    /// Reconnecting was given up, the session cannot continue
+    Lost,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionState::Connected => "connected",
+            ConnectionState::Stalled => "stalled",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Lost => "connection lost",
+        })
+    }
+}
+
+/// Live information about the link, shown in the status bar
+#[derive(Debug, Clone)]
+pub struct LinkStatus {
+    pub name: String,
+    pub address: String,
+    pub state: ConnectionState,
+    pub rssi: Option<i16>,
+    pub mtu: u16,
+    pub tx_bytes: u64,
+    /// Writes of at most the MTU the sent bytes took
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_notifications: u64,
+    /// Times the link was reestablished after dropping
+    pub reconnects: u32,
+    /// Input held back until the link is reestablished
+    pub pending: usize,
+    /// Charge reported by the Battery Service in percent, if the device has one
+    pub battery: Option<u8>,
+}
+
+/// An established NUS connection to a peripheral
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub peripheral: Peripheral,
+    pub rx_char: Characteristic,
+    pub tx_char: Characteristic,
+    pub mtu: u16,
+    pub write_type: WriteType,
+    pub trace: Option<Arc<dyn Trace>>,
+}
+
+impl Link {
+    /// Connects to `peripheral`, discovers the NUS characteristics and subscribes to TX
+    pub async fn open(
+        peripheral: Peripheral,
+        options: &LinkOptions,
+    ) -> Result<(Link, Notifications)> {
+        let uuids = &options.uuids;
+        connect(&peripheral, options).await?;
+
+        let chars = peripheral.characteristics();
+        let (rx_char, tx_char) = match uart_characteristics(&chars, uuids) {
+            Ok(found) => found,
+            Err(e) => {
+                let Some(detected) = nus::detect(&chars) else {
+                    return Err(e);
+                };
+                let accepted = match options.fallback {
+                    // Also without a terminal to ask on, so say how to use it anyway
+                    Fallback::Fail => {
+                        return Err(e.context(format!(
+                            "The UART service was not found, --auto-detect uses service {} instead",
+                            detected.service
+                        )));
+                    }
+                    Fallback::Ask(confirm) => confirm(&detected),
+                    Fallback::Detect => true,
+                };
+                if !accepted {
+                    return Err(e);
+                }
+                let msg = format!("Using service {} as the UART", detected.service);
+                options.notice(log::Level::Info, &msg);
+                uart_characteristics(&chars, &detected)?
+            }
+        };
+
+        peripheral.subscribe(&tx_char).await?;
+        // Notifications of other characteristics, like the battery level, are not UART data
+        let tx = tx_char.uuid;
+        let trace = options.trace.clone();
+        let notifications: Notifications = Box::pin(
+            peripheral
+                .notifications()
+                .await?
+                .filter(move |notification| future::ready(notification.uuid == tx))
+                .inspect(move |notification| {
+                    if let Some(trace) = &trace {
+                        trace.notification(&notification.value);
+                    }
+                }),
+        );
+
+        let mtu = match options.mtu {
+            Some(mtu) => mtu,
+            None => mtu::query(peripheral.address(), rx_char.service_uuid, rx_char.uuid)
+                .await
+                .unwrap_or(DEFAULT_MTU),
+        };
+        debug!("Using ATT MTU of {mtu} bytes");
+
+        let write_type = match options.write_mode {
+            WriteMode::WithResponse => WriteType::WithResponse,
+            WriteMode::WithoutResponse => WriteType::WithoutResponse,
+            WriteMode::Auto
+                if rx_char
+                    .properties
+                    .contains(CharPropFlags::WRITE_WITHOUT_RESPONSE) =>
+            {
+                WriteType::WithoutResponse
+            }
+            WriteMode::Auto if rx_char.properties.contains(CharPropFlags::WRITE) => {
+                WriteType::WithResponse
+            }
+            WriteMode::Auto => return Err(anyhow!("RX characteristic is not writable")),
+        };
+        debug!("Writing {write_type:?}");
+
+        Ok((
+            Link {
+                peripheral,
+                rx_char,
+                tx_char,
+                mtu,
+                write_type,
+                trace: options.trace.clone(),
+            },
+            notifications,
+        ))
+    }
+
+    /// Writes `data` to the RX characteristic, split into as many writes as the MTU requires
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(self.max_payload()) {
+            if let Some(trace) = &self.trace {
+                trace.write(chunk, self.write_type);
+            }
+            self.peripheral
+                .write(&self.rx_char, chunk, self.write_type)
+                .await?;
+            if let Some(trace) = &self.trace
+                && self.write_type == WriteType::WithResponse
+            {
+                trace.write_response();
+            }
+        }
+        Ok(())
+    }
+
+    /// Unsubscribes from TX and disconnects, so the device is ready for the next connection
+    pub async fn close(&self) -> Result<()> {
+        // The link is closed even if the device no longer answers the unsubscribe
+        let _ = self.peripheral.unsubscribe(&self.tx_char).await;
+        Ok(self.peripheral.disconnect().await?)
+    }
+
+    /// Largest number of bytes that fit in a single write
+    pub fn max_payload(&self) -> usize {
+        usize::from(self.mtu.saturating_sub(ATT_HEADER_LEN).max(1))
+    }
+}
+
+/// The RX and TX characteristics of the service given by `uuids`
+fn uart_characteristics(
+    chars: &BTreeSet<Characteristic>,
+    uuids: &NusUuids,
+) -> Result<(Characteristic, Characteristic)> {
+    let find = |uuid, name| {
+        chars
+            .iter()
+            .find(|c| c.service_uuid == uuids.service && c.uuid == uuid)
+            .cloned()
+            .ok_or(Error::CharacteristicNotFound(name))
+    };
+    Ok((find(uuids.rx, "RX")?, find(uuids.tx, "TX")?))
+}
+
+/// Connects to `peripheral` and discovers its services, trying again after failures
+async fn connect(peripheral: &Peripheral, options: &LinkOptions) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        let mut result = within(options.connect_timeout, "Connecting", peripheral.connect()).await;
+        if result.is_ok() {
+            // Before subscribing, which devices requiring encryption refuse over a plain link
+            if let Some(agent) = &options.pairing
+                && pairing::pair(peripheral, agent).await?
+            {
+                let msg = format!("Paired with {}", peripheral.address());
+                options.notice(log::Level::Info, &msg);
+            }
+            result = within(
+                options.discover_timeout,
+                "Service discovery",
+                peripheral.discover_services(),
+            )
+            .await;
+        }
+        match result {
+            Err(e) if attempt < options.connect_retries => {
+                attempt += 1;
+                let msg = format!("{e}, retrying ({attempt}/{})", options.connect_retries);
+                options.notice(log::Level::Warn, &msg);
+                let _ = peripheral.disconnect().await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Waits for `operation`, failing if it takes longer than `timeout`
+async fn within<T>(
+    timeout: Option<Duration>,
+    what: &str,
+    operation: impl Future<Output = btleplug::Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, operation)
+            .await
+            .map_err(|_| anyhow!("{what} timed out after {}s", timeout.as_secs()))?
+            .map_err(Into::into),
+        None => Ok(operation.await?),
+    }
+}
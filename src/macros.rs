@@ -0,0 +1,138 @@
+//! Keys sending user-defined strings
+//!
+//! ```toml
+//! [keys]
+//! F5 = 'reboot\r'
+//! "Alt+s" = 'AT+SLEEP=1\r%delay(500)%AT+STATUS\r'
+//! ```
+
+use crate::config;
+use crate::init;
+use crate::toml::Value;
+use anyhow::{Context, Result, anyhow, bail};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::time::Duration;
+
+/// Part of what a macro sends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Send(Vec<u8>),
+    /// A pause given with `%delay(ms)%`
+    Delay(Duration),
+}
+
+/// A key bound to a string
+#[derive(Debug, Clone)]
+pub struct Macro {
+    /// The key as written in the configuration file
+    name: String,
+    code: KeyCode,
+    modifiers: KeyModifiers,
+    steps: Vec<Step>,
+}
+
+impl Macro {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        match (self.code, key.code) {
+            // Shift only changes the case of letters, which is compared without it
+            (KeyCode::Char(bound), KeyCode::Char(c)) => {
+                bound == c.to_ascii_lowercase()
+                    && self.modifiers == key.modifiers.difference(KeyModifiers::SHIFT)
+            }
+            (code, pressed) => code == pressed && self.modifiers == key.modifiers,
+        }
+    }
+}
+
+/// Reads the macros of a `[keys]` table
+pub fn parse(value: &Value) -> Result<Vec<Macro>> {
+    let Value::Table(table) = value else {
+        bail!("'keys' must be a table ([keys])");
+    };
+    table
+        .iter()
+        .map(|(name, value)| {
+            let (code, modifiers) = key(name)?;
+            let steps = steps(&config::string(name, value)?)
+                .with_context(|| format!("Invalid macro for '{name}'"))?;
+            Ok(Macro {
+                name: name.clone(),
+                code,
+                modifiers,
+                steps,
+            })
+        })
+        .collect()
+}
+
+/// Parses a key like `F5`, `Ctrl+F1`, `Alt+s` or `M-x`
+fn key(name: &str) -> Result<(KeyCode, KeyModifiers)> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = name;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        let modifier = [
+            ("ctrl+", KeyModifiers::CONTROL),
+            ("c-", KeyModifiers::CONTROL),
+            ("alt+", KeyModifiers::ALT),
+            ("meta+", KeyModifiers::ALT),
+            ("m-", KeyModifiers::ALT),
+            ("shift+", KeyModifiers::SHIFT),
+            ("s-", KeyModifiers::SHIFT),
+        ]
+        .into_iter()
+        .find(|(prefix, _)| lower.starts_with(prefix) && lower.len() > prefix.len());
+        let Some((prefix, modifier)) = modifier else {
+            break;
+        };
+        modifiers |= modifier;
+        rest = &rest[prefix.len()..];
+    }
+    let mut chars = rest.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(c), None) if !c.is_control() => KeyCode::Char(c.to_ascii_lowercase()),
+        _ => match rest.to_ascii_lowercase().strip_prefix('f').map(str::parse) {
+            Some(Ok(n @ 1..=12)) => KeyCode::F(n),
+            _ => bail!("'{name}' is not a key like F5, Ctrl+F1 or Alt+s"),
+        },
+    };
+    if matches!(code, KeyCode::Char(_)) {
+        // Like when matching, Shift is left to the case of the letter
+        modifiers.remove(KeyModifiers::SHIFT);
+    }
+    if modifiers.is_empty() && matches!(code, KeyCode::Char(_)) {
+        bail!("'{name}' is typed like any other character, bind it with Ctrl+ or Alt+");
+    }
+    Ok((code, modifiers))
+}
+
+/// Splits a string with escapes like `\r` at its `%delay(ms)%` placeholders
+fn steps(s: &str) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("%delay(") {
+        if start > 0 {
+            steps.push(Step::Send(init::unescape(&rest[..start])?));
+        }
+        let after = &rest[start + "%delay(".len()..];
+        let end = after.find(")%").ok_or(anyhow!("'%delay(' without ')%'"))?;
+        let millis = after[..end]
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid delay '{}', expected milliseconds", &after[..end]))?;
+        steps.push(Step::Delay(Duration::from_millis(millis)));
+        rest = &after[end + ")%".len()..];
+    }
+    if !rest.is_empty() {
+        steps.push(Step::Send(init::unescape(rest)?));
+    }
+    Ok(steps)
+}
@@ -1,146 +1,172 @@
-use anyhow::{Result, anyhow};
-use btleplug::api::{
-    Central, Manager as _, Peripheral as _, ScanFilter, ValueNotification, WriteType,
+use anyhow::Result;
+use clap::CommandFactory;
+use cli::{Cli, Command, ScriptArgs, ScriptCommand};
+use log::{error, info};
+use nus_terminal::{
+    NusClient, adapter, battery, device, device_info, error, link, mtu, nus, pairing, transport,
 };
-use btleplug::platform::Manager;
-use clap::Parser;
-use crossterm::event::KeyModifiers;
-use crossterm::{ExecutableCommand, event, terminal};
-use futures::stream::StreamExt;
-use log::info;
-use std::io::{self, Write};
-use std::sync::Arc;
-use std::time::Duration;
-use uuid::Uuid;
-
-const NUS_RX_CHAR_UUID: Uuid = Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e); // Write
-const NUS_TX_CHAR_UUID: Uuid = Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e); // Notify
-
-/// Nordic UART Service Client app
-#[derive(Parser, Debug)]
-#[command(version, about, long_about=None)]
-struct Args {
-    /// BLE device name filter
-    #[arg(short, long)]
-    name: String,
-}
+use std::io::Write;
+
+mod agent;
+mod ansi;
+mod api;
+mod bench;
+mod bridge;
+mod btsnoop;
+mod capture;
+mod cast;
+mod cbor;
+mod cli;
+mod cobs;
+mod config;
+mod connect;
+mod decode;
+mod defmt;
+mod dfu;
+mod elf;
+mod emulate;
+mod flow;
+mod gatt;
+mod highlight;
+mod hooks;
+mod http;
+mod info;
+mod init;
+mod json;
+mod length_prefix;
+mod line_editor;
+mod macros;
+mod menu;
+mod metrics;
+mod mouse;
+mod mqtt;
+mod nmea;
+mod notify;
+mod pipe;
+mod plot;
+mod plugin;
+mod protobuf;
+mod pty;
+mod reliable;
+mod replay;
+mod scan;
+mod screen;
+mod script;
+mod self_test;
+mod session_log;
+mod slip;
+mod stats;
+mod systemd;
+mod test_runner;
+mod timestamp;
+mod toml;
+mod transfer;
+mod trigger;
+mod tun;
+mod ui;
+mod vt;
+mod websocket;
+mod xmodem;
+mod ymodem;
+mod zmodem;
+
+/// Exit code of a script or test run that did not pass
+const EXIT_FAILED: i32 = 1;
+/// Exit code of errors without a code of their own, see [`error::Error::exit_code`]
+const EXIT_ERROR: i32 = 2;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::builder()
+async fn main() {
+    let logger = env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
-
-    let args = Args::parse();
-
-    let manager = Manager::new().await?;
-    let adapters = manager.adapters().await?;
-    let central = adapters
-        .first()
-        .ok_or(anyhow!("No bluetooth adapter found"))?;
-
-    info!("Trying to find device (filter: {})", args.name);
-    central.start_scan(ScanFilter::default()).await?;
-    tokio::time::sleep(Duration::from_secs(5)).await;
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(systemd::Logger(logger))).expect("logger set twice");
 
-    let peripherals = central.peripherals().await?;
-    let peripheral = Arc::new(
-        peripherals
-            .into_iter()
-            .find(|p| {
-                if let Ok(Some(props)) = futures::executor::block_on(p.properties()) {
-                    if let Some(name) = props.local_name {
-                        return name.contains(&args.name);
-                    }
-                }
-                false
-            })
-            .ok_or(anyhow!("Could not find a device with given name"))?,
-    );
-
-    peripheral.connect().await?;
-    peripheral.discover_services().await?;
-
-    let chars = peripheral.characteristics();
-    let rx_char = Arc::new(
-        chars
-            .iter()
-            .find(|c| c.uuid == NUS_RX_CHAR_UUID)
-            .expect("RX characteristic not found")
-            .clone(),
-    );
-    let tx_char = chars
-        .iter()
-        .find(|c| c.uuid == NUS_TX_CHAR_UUID)
-        .expect("TX characteristic not found")
-        .clone();
-
-    peripheral.subscribe(&tx_char).await?;
+    if let Err(e) = run().await {
+        error!("{e:#}");
+        std::process::exit(exit_code(&e));
+    }
+}
 
-    let rx_char = Arc::new(rx_char);
+async fn run() -> Result<()> {
+    let cli = Cli::parse_with_profile()?;
+    if let Some(Command::Script(ScriptArgs {
+        command: ScriptCommand::Check { file },
+    })) = &cli.command
+    {
+        let script = script::load(file)?;
+        info!("{}: {} commands, no errors", file.display(), script.len());
+        return Ok(());
+    }
 
-    // Listen for BLE notifications
-    let mut notif_stream = peripheral.notifications().await?;
+    if let Some(Command::Completions { shell }) = &cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        let mut script = Vec::new();
+        clap_complete::generate(*shell, &mut command, name, &mut script);
+        std::io::stdout().write_all(&script)?;
+        return Ok(());
+    }
 
-    let mut stdout = io::stdout();
-    terminal::enable_raw_mode()?;
-    stdout.execute(terminal::EnterAlternateScreen)?;
+    if let Some(Command::Replay(args)) = &cli.command {
+        return replay::run(args);
+    }
 
-    let p = peripheral.clone();
-    let ch = rx_char.clone();
-    tokio::spawn(async move {
-        let _ = p
-            .write(&ch, &['l' as u8 & 0x1F], WriteType::WithoutResponse)
-            .await;
-    });
+    if let Some(Command::ListAdapters) = &cli.command {
+        return scan::list_adapters().await;
+    }
 
-    tokio::spawn(async move {
-        while let Some(ValueNotification { value, .. }) = notif_stream.next().await {
-            let s = String::from_utf8_lossy(&value);
-            print!("{}", s);
-            let _ = stdout.flush();
-        }
-    });
+    let client = match cli.adapter() {
+        Some(selector) => NusClient::with_adapter_selector(selector).await?,
+        None => NusClient::new().await?,
+    };
+    let central = client.adapter();
+
+    match &cli.command {
+        Some(Command::Scan(args)) => scan::run(central, args).await?,
+        Some(Command::Emulate(args)) => emulate::run(central, args).await?,
+        Some(Command::Info(args)) => info::run(central, args).await?,
+        Some(Command::Gatt(args)) => gatt::run(central, args).await?,
+        Some(Command::Connect(args)) => exit_with_code(connect::run(central, args).await?),
+        Some(Command::Bridge(args)) => bridge::run(central, args).await?,
+        Some(Command::SendFile(args)) => transfer::run(central, args).await?,
+        Some(Command::Dfu(args)) => dfu::run(central, args).await?,
+        Some(Command::Bench(args)) => bench::run(central, args).await?,
+        Some(Command::Script(ScriptArgs {
+            command: ScriptCommand::Run(args),
+        })) => exit_with_outcome(script::run(central, args).await)?,
+        Some(Command::Test(args)) => exit_with_outcome(test_runner::run(central, args).await)?,
+        Some(Command::SelfTest(args)) => exit_with_outcome(self_test::run(central, args).await)?,
+        Some(
+            Command::Script(_)
+            | Command::ListAdapters
+            | Command::Completions { .. }
+            | Command::Replay(_),
+        ) => unreachable!("handled above"),
+        None => exit_with_code(connect::run(central, &cli.connect).await?),
+    }
 
-    loop {
-        if event::poll(Duration::from_millis(50)).unwrap() {
-            if let event::Event::Key(key_event) = event::read().unwrap() {
-                let data = match key_event.code {
-                    event::KeyCode::Esc => {
-                        break;
-                    }
-                    event::KeyCode::Backspace => Some(b"\x08".to_vec()),
-                    event::KeyCode::Char(c) => {
-                        let c = if key_event.modifiers.contains(KeyModifiers::CONTROL) {
-                            c as u8 & 0x1F
-                        } else {
-                            c as u8
-                        };
-                        Some(vec![c])
-                    }
-                    event::KeyCode::Left => Some(b"\x1b[D".to_vec()),
-                    event::KeyCode::Right => Some(b"\x1b[C".to_vec()),
-                    event::KeyCode::Up => Some(b"\x1b[A".to_vec()),
-                    event::KeyCode::Down => Some(b"\x1b[B".to_vec()),
-                    event::KeyCode::Enter => Some(b"\r".to_vec()),
-                    event::KeyCode::Tab => Some(b"\t".to_vec()),
-                    _ => None,
-                };
+    Ok(())
+}
 
-                if let Some(data) = data {
-                    let p = peripheral.clone();
-                    let ch = rx_char.clone();
-                    tokio::spawn(async move {
-                        let _ = p.write(&ch, &data, WriteType::WithoutResponse).await;
-                    });
-                }
-            }
-        }
+/// Exits with the code requested by a trigger, if any
+fn exit_with_code(code: Option<i32>) {
+    if let Some(code) = code {
+        std::process::exit(code);
     }
+}
 
-    terminal::disable_raw_mode()?;
-    std::io::stdout().execute(terminal::LeaveAlternateScreen)?;
-    info!("NUS terminal exited");
-
+/// Exits with [`EXIT_FAILED`] if a script, test run or self-test failed
+fn exit_with_outcome(outcome: Result<bool>) -> Result<()> {
+    if !outcome? {
+        std::process::exit(EXIT_FAILED);
+    }
     Ok(())
 }
+
+/// The exit code telling why the program failed
+fn exit_code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<error::Error>()
+        .map_or(EXIT_ERROR, error::Error::exit_code)
+}
@@ -0,0 +1,171 @@
+//! Commands reached with the escape prefix, like picocom's Ctrl+A
+//!
+//! Every key besides the prefix goes to the device, so local actions are triggered by the
+//! prefix followed by a command key.
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Quit,
+    ToggleLog,
+    ToggleHex,
+    ToggleLineMode,
+    ToggleEcho,
+    CycleTimestamps,
+    SendFile,
+    SendHex,
+    Filter,
+    FilterOut,
+    Search,
+    Select,
+    Freeze,
+    Resume,
+    XmodemSend,
+    XmodemReceive,
+    YmodemSend,
+    YmodemReceive,
+    AbortTransfer,
+    Reconnect,
+    NextTab,
+    PreviousTab,
+    ToggleBroadcast,
+    ToggleMerged,
+    ShowHelp,
+    /// Sends the prefix key itself to the device
+    SendEscape,
+}
+
+impl Command {
+    /// Whether the command works on the view of the terminal UI, which there is none of
+    /// with `--inline`
+    pub fn needs_view(self) -> bool {
+        matches!(
+            self,
+            Command::Filter
+                | Command::FilterOut
+                | Command::Search
+                | Command::Select
+                | Command::Freeze
+                | Command::Resume
+        )
+    }
+}
+
+/// Command keys with their description, in the order shown in the menu
+pub const COMMANDS: &[(char, Command, &str)] = &[
+    ('q', Command::Quit, "Quit"),
+    ('l', Command::ToggleLog, "Pause/resume logging"),
+    ('h', Command::ToggleHex, "Toggle hex view"),
+    (
+        'e',
+        Command::ToggleLineMode,
+        "Toggle line mode (local editing)",
+    ),
+    ('o', Command::ToggleEcho, "Toggle local echo"),
+    (
+        't',
+        Command::CycleTimestamps,
+        "Cycle timestamps (off/absolute/relative/delta)",
+    ),
+    ('s', Command::SendFile, "Send file"),
+    ('x', Command::SendHex, "Send hex bytes"),
+    ('f', Command::Filter, "Show only lines matching a regex"),
+    ('v', Command::FilterOut, "Hide lines matching a regex"),
+    ('/', Command::Search, "Search the scrollback"),
+    ('y', Command::Select, "Select lines to copy"),
+    (
+        'z',
+        Command::Freeze,
+        "Freeze the view, output keeps collecting",
+    ),
+    ('c', Command::Resume, "Resume following the output"),
+    ('u', Command::XmodemSend, "XMODEM send (upload)"),
+    ('d', Command::XmodemReceive, "XMODEM receive (download)"),
+    ('w', Command::YmodemSend, "YMODEM send a file or directory"),
+    (
+        'g',
+        Command::YmodemReceive,
+        "YMODEM receive into a directory",
+    ),
+    ('k', Command::AbortTransfer, "Abort file transfer"),
+    ('r', Command::Reconnect, "Reconnect"),
+    ('n', Command::NextTab, "Next device tab"),
+    ('p', Command::PreviousTab, "Previous device tab"),
+    (
+        'b',
+        Command::ToggleBroadcast,
+        "Toggle broadcast (input to all devices)",
+    ),
+    (
+        'm',
+        Command::ToggleMerged,
+        "Toggle merged view of all devices",
+    ),
+    ('?', Command::ShowHelp, "Show settings and keys"),
+    ('a', Command::SendEscape, "Send the escape key"),
+];
+
+/// Returns the command bound to `key` in the menu
+pub fn command(key: char) -> Option<Command> {
+    COMMANDS
+        .iter()
+        .find(|(k, _, _)| k.eq_ignore_ascii_case(&key))
+        .map(|&(_, command, _)| command)
+}
+
+/// Control key opening the menu, written like `C-a`, `Ctrl+A` or `^A`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscapeKey {
+    /// The letter pressed together with Ctrl, in lower case
+    letter: char,
+}
+
+impl EscapeKey {
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char(c) => {
+                key.modifiers.contains(KeyModifiers::CONTROL)
+                    && c.eq_ignore_ascii_case(&self.letter)
+            }
+            _ => false,
+        }
+    }
+
+    /// The control character sent to the device for this key
+    pub fn byte(&self) -> u8 {
+        self.letter as u8 & 0x1f
+    }
+}
+
+impl Default for EscapeKey {
+    fn default() -> Self {
+        EscapeKey { letter: 'a' }
+    }
+}
+
+impl FromStr for EscapeKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let letter = ["c-", "ctrl-", "ctrl+", "^"]
+            .iter()
+            .find_map(|prefix| lower.strip_prefix(prefix))
+            .and_then(|rest| {
+                let mut chars = rest.chars();
+                chars.next().filter(|_| chars.next().is_none())
+            })
+            .filter(|c| c.is_ascii_lowercase())
+            .ok_or(format!("'{s}' is not a control key like C-a"))?;
+        Ok(EscapeKey { letter })
+    }
+}
+
+impl fmt::Display for EscapeKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ctrl+{}", self.letter.to_ascii_uppercase())
+    }
+}
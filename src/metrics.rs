@@ -0,0 +1,183 @@
+//! Counters of a bridge, served in the Prometheus text format with `--metrics`
+//!
+//! A plain HTTP server answers `GET /metrics`, every sample carrying the address and name
+//! of the device as labels.
+
+use crate::http;
+use anyhow::Result;
+use log::warn;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+
+/// What the bridge counted since it started
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub rx_bytes: AtomicU64,
+    pub rx_notifications: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_writes: AtomicU64,
+    pub write_errors: AtomicU64,
+    pub reconnects: AtomicU64,
+    pub connected: AtomicBool,
+    device: Mutex<Device>,
+}
+
+#[derive(Debug, Default)]
+struct Device {
+    address: String,
+    name: String,
+    rssi: Option<i16>,
+}
+
+impl Metrics {
+    /// Labels the samples with the device once it is connected
+    pub fn set_device(&self, address: String) {
+        self.device.lock().unwrap().address = address;
+        self.connected.store(true, Ordering::Relaxed);
+    }
+
+    /// Takes over the name and signal strength last reported by the device
+    pub fn set_properties(&self, name: Option<String>, rssi: Option<i16>) {
+        let mut device = self.device.lock().unwrap();
+        if let Some(name) = name {
+            device.name = name;
+        }
+        if rssi.is_some() {
+            device.rssi = rssi;
+        }
+    }
+
+    /// The address, name and signal strength of the device, as last seen
+    pub fn device(&self) -> (String, Option<String>, Option<i16>) {
+        let device = self.device.lock().unwrap();
+        let name = Some(device.name.clone()).filter(|name| !name.is_empty());
+        (device.address.clone(), name, device.rssi)
+    }
+
+    /// The counters as a JSON object
+    pub fn stats_json(&self) -> String {
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        format!(
+            "{{\"rx_bytes\":{},\"rx_notifications\":{},\"tx_bytes\":{},\"tx_writes\":{},\
+             \"write_errors\":{},\"reconnects\":{}}}",
+            count(&self.rx_bytes),
+            count(&self.rx_notifications),
+            count(&self.tx_bytes),
+            count(&self.tx_writes),
+            count(&self.write_errors),
+            count(&self.reconnects)
+        )
+    }
+
+    /// The samples in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let device = self.device.lock().unwrap();
+        let labels = format!(
+            "{{address=\"{}\",name=\"{}\"}}",
+            escape(&device.address),
+            escape(&device.name)
+        );
+        let mut out = String::new();
+        let mut sample = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name}{labels} {value}\n"
+            );
+        };
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        sample(
+            "nus_rx_bytes_total",
+            "counter",
+            "Bytes received from the device",
+            count(&self.rx_bytes),
+        );
+        sample(
+            "nus_rx_notifications_total",
+            "counter",
+            "Notifications received from the device",
+            count(&self.rx_notifications),
+        );
+        sample(
+            "nus_tx_bytes_total",
+            "counter",
+            "Bytes written to the device",
+            count(&self.tx_bytes),
+        );
+        sample(
+            "nus_tx_writes_total",
+            "counter",
+            "Writes of clients passed to the device",
+            count(&self.tx_writes),
+        );
+        sample(
+            "nus_write_errors_total",
+            "counter",
+            "Writes to the device that failed",
+            count(&self.write_errors),
+        );
+        sample(
+            "nus_reconnects_total",
+            "counter",
+            "Times the device was connected again after losing it",
+            count(&self.reconnects),
+        );
+        sample(
+            "nus_connected",
+            "gauge",
+            "Whether the device is connected",
+            u8::from(self.connected.load(Ordering::Relaxed)).to_string(),
+        );
+        // Left out until the adapter reports it, a made-up value would skew graphs
+        if let Some(rssi) = device.rssi {
+            sample(
+                "nus_rssi_dbm",
+                "gauge",
+                "Signal strength of the device",
+                rssi.to_string(),
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a label value, which is quoted
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers scrapers on `listener` until the bridge exits
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Could not accept a metrics connection: {e}");
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &metrics).await {
+                warn!("Metrics request of {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let Some(request) = http::read_request(&mut stream).await? else {
+        return Ok(());
+    };
+    let (status, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => ("200 OK", metrics.render()),
+        ("GET", _) => ("404 Not Found", "Not found, see /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let content_type = "text/plain; version=0.0.4; charset=utf-8";
+    http::respond(&mut stream, status, content_type, &body).await
+}
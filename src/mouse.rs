@@ -0,0 +1,166 @@
+//! Mouse reporting requested by programs running on the device
+//!
+//! Full-screen programs switch on xterm mouse tracking with private modes like
+//! `ESC [ ? 1000 h`. The modes are followed in the received output, and local mouse events
+//! are encoded the way the program asked for, in the SGR format with mode 1006.
+
+use crossterm::event::{KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+
+/// Which mouse events are reported, each level including the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Tracking {
+    /// Mode 9, button presses only
+    X10,
+    /// Mode 1000, presses, releases and the wheel
+    Normal,
+    /// Mode 1002, motion while a button is held as well
+    ButtonEvent,
+    /// Mode 1003, all motion
+    AnyEvent,
+}
+
+/// How reports are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Encoding {
+    /// Bytes offset by 32, limited to 223 columns and rows
+    #[default]
+    Default,
+    /// Mode 1006, `ESC [ < b ; x ; y M`
+    Sgr,
+    /// Mode 1015, `ESC [ b ; x ; y M`
+    Urxvt,
+}
+
+/// Where an escape sequence being received stands, as it may span notifications
+#[derive(Debug, Clone, Default)]
+enum Parser {
+    #[default]
+    Ground,
+    Escape,
+    Csi,
+    /// Parameters of a private mode sequence `ESC [ ?`
+    Private(String),
+}
+
+/// The mouse modes the device has switched on
+#[derive(Debug, Clone, Default)]
+pub struct Reporting {
+    tracking: Option<Tracking>,
+    encoding: Encoding,
+    parser: Parser,
+}
+
+impl Reporting {
+    /// Whether the device wants mouse events
+    pub fn enabled(&self) -> bool {
+        self.tracking.is_some()
+    }
+
+    /// Follows the mode changes in received output
+    pub fn scan(&mut self, data: &[u8]) {
+        for &b in data {
+            self.parser = match (std::mem::take(&mut self.parser), b) {
+                (_, 0x1b) => Parser::Escape,
+                (Parser::Escape, b'[') => Parser::Csi,
+                // A full reset
+                (Parser::Escape, b'c') => {
+                    self.tracking = None;
+                    self.encoding = Encoding::Default;
+                    Parser::Ground
+                }
+                (Parser::Csi, b'?') => Parser::Private(String::new()),
+                (Parser::Private(mut params), b'0'..=b'9' | b';') if params.len() < 32 => {
+                    params.push(b as char);
+                    Parser::Private(params)
+                }
+                (Parser::Private(params), b'h' | b'l') => {
+                    for mode in params.split(';') {
+                        self.set(mode, b == b'h');
+                    }
+                    Parser::Ground
+                }
+                _ => Parser::Ground,
+            };
+        }
+    }
+
+    fn set(&mut self, mode: &str, on: bool) {
+        let tracking = match mode {
+            "9" => Tracking::X10,
+            "1000" => Tracking::Normal,
+            "1002" => Tracking::ButtonEvent,
+            "1003" => Tracking::AnyEvent,
+            "1006" | "1015" => {
+                let encoding = if mode == "1006" {
+                    Encoding::Sgr
+                } else {
+                    Encoding::Urxvt
+                };
+                if on {
+                    self.encoding = encoding;
+                } else if self.encoding == encoding {
+                    self.encoding = Encoding::Default;
+                }
+                return;
+            }
+            _ => return,
+        };
+        if on {
+            self.tracking = Some(tracking);
+        } else if self.tracking == Some(tracking) {
+            self.tracking = None;
+        }
+    }
+
+    /// The report of `event` at 1-based `column` and `row`, if the device wants it
+    pub fn encode(&self, event: &MouseEvent, column: u16, row: u16) -> Option<Vec<u8>> {
+        let tracking = self.tracking?;
+        let button = |button: MouseButton| match button {
+            MouseButton::Left => 0,
+            MouseButton::Middle => 1,
+            MouseButton::Right => 2,
+        };
+        let (code, release) = match event.kind {
+            MouseEventKind::Down(pressed) => (button(pressed), false),
+            MouseEventKind::Up(released) if tracking >= Tracking::Normal => {
+                (button(released), true)
+            }
+            MouseEventKind::ScrollUp if tracking >= Tracking::Normal => (64, false),
+            MouseEventKind::ScrollDown if tracking >= Tracking::Normal => (65, false),
+            MouseEventKind::Drag(held) if tracking >= Tracking::ButtonEvent => {
+                (button(held) + 32, false)
+            }
+            MouseEventKind::Moved if tracking == Tracking::AnyEvent => (35, false),
+            _ => return None,
+        };
+        let mut code = code;
+        if tracking != Tracking::X10 {
+            if event.modifiers.contains(KeyModifiers::SHIFT) {
+                code += 4;
+            }
+            if event.modifiers.contains(KeyModifiers::ALT) {
+                code += 8;
+            }
+            if event.modifiers.contains(KeyModifiers::CONTROL) {
+                code += 16;
+            }
+        }
+        let (column, row) = (u32::from(column), u32::from(row));
+        Some(match self.encoding {
+            Encoding::Sgr => {
+                let end = if release { 'm' } else { 'M' };
+                format!("\x1b[<{code};{column};{row}{end}").into_bytes()
+            }
+            // The other encodings do not tell which button was released
+            Encoding::Urxvt => {
+                let code = if release { 3 } else { code };
+                format!("\x1b[{};{column};{row}M", code + 32).into_bytes()
+            }
+            Encoding::Default => {
+                let code = if release { 3 } else { code };
+                let byte = |value: u32| u8::try_from(value + 32).ok();
+                vec![0x1b, b'[', b'M', byte(code)?, byte(column)?, byte(row)?]
+            }
+        })
+    }
+}
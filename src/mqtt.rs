@@ -0,0 +1,481 @@
+//! Minimal MQTT 3.1.1 client for the MQTT bridge
+//!
+//! Supports what the bridge needs: connecting with optional credentials, over TCP or TLS,
+//! one subscription, publishing with QoS 0 or 1 and keep-alive pings.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+const DEFAULT_PORT: u16 = 1883;
+
+const DEFAULT_TLS_PORT: u16 = 8883;
+
+/// Largest remaining length the variable length encoding can express
+const MAX_REMAINING_LEN: usize = 268_435_455;
+
+/// Largest packet accepted from the broker
+const MAX_PACKET_LEN: usize = 1024 * 1024;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Address of a broker, `mqtt://host:port`, `mqtts://host:port` or just `host[:port]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    /// Whether the connection is made over TLS
+    pub tls: bool,
+}
+
+impl FromStr for Broker {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Broker> {
+        let (rest, tls) = match s.split_once("://") {
+            None => (s, false),
+            Some(("mqtt" | "tcp", rest)) => (rest, false),
+            Some(("mqtts" | "ssl" | "tls", rest)) => (rest, true),
+            Some((scheme, _)) => bail!("unknown scheme '{scheme}', expected mqtt:// or mqtts://"),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.strip_prefix('[') {
+            // An IPv6 address, e.g. [::1]:1883
+            Some(v6) => {
+                let (host, after) = v6.split_once(']').ok_or(anyhow!("missing ']'"))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match rest.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            },
+        };
+        if host.is_empty() {
+            bail!("no host given");
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| anyhow!("invalid port '{port}'"))?,
+            None if tls => DEFAULT_TLS_PORT,
+            None => DEFAULT_PORT,
+        };
+        Ok(Broker {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Seconds without packets after which the broker drops the connection, 0 to disable
+    pub keep_alive: u16,
+    pub tls: TlsOptions,
+}
+
+/// Certificates for brokers reached over TLS
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM file of the CA certificates the broker is verified with, instead of the
+    /// well-known CAs
+    pub ca_file: Option<PathBuf>,
+    /// PEM files of the certificate and private key the client authenticates with
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsOptions {
+    fn config(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_file {
+            Some(path) => {
+                for cert in read_certs(path)? {
+                    roots
+                        .add(cert)
+                        .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        match &self.client_cert {
+            Some((cert, key)) => {
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .with_context(|| format!("Could not read the private key {}", key.display()))?;
+                builder
+                    .with_client_auth_cert(read_certs(cert)?, key)
+                    .context("Invalid client certificate")
+            }
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+/// The certificates in a PEM file
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Could not read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// A connection to the broker, plain or encrypted
+trait ByteStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> ByteStream for S {}
+
+/// A packet received from the broker, its type, flags and the rest of the packet
+#[derive(Debug)]
+pub struct Packet {
+    kind: u8,
+    flags: u8,
+    body: Vec<u8>,
+}
+
+pub struct Connection {
+    writer: WriteHalf<Box<dyn ByteStream>>,
+    packets: mpsc::Receiver<Result<Packet>>,
+    next_id: u16,
+}
+
+impl Connection {
+    /// Connects to the broker and waits for it to accept the session
+    pub async fn open(broker: &Broker, options: &ConnectOptions) -> Result<Connection> {
+        let stream = TcpStream::connect((broker.host.as_str(), broker.port))
+            .await
+            .with_context(|| format!("Could not connect to the broker at {broker}"))?;
+        stream.set_nodelay(true)?;
+        let stream: Box<dyn ByteStream> = if broker.tls {
+            let connector = TlsConnector::from(Arc::new(options.tls.config()?));
+            let name = ServerName::try_from(broker.host.clone())
+                .with_context(|| format!("Invalid broker name {}", broker.host))?;
+            let stream = connector
+                .connect(name, stream)
+                .await
+                .with_context(|| format!("TLS handshake with the broker at {broker} failed"))?;
+            Box::new(stream)
+        } else {
+            Box::new(stream)
+        };
+        let (reader, writer) = tokio::io::split(stream);
+
+        // Reading in a task of its own keeps `recv` cancel safe
+        let (sender, packets) = mpsc::channel(64);
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let packet = read_packet(&mut reader).await;
+                let failed = packet.is_err();
+                if sender.send(packet).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut connection = Connection {
+            writer,
+            packets,
+            next_id: 1,
+        };
+        connection
+            .send(CONNECT << 4, &connect_body(options))
+            .await?;
+        let packet = connection.recv().await?;
+        if packet.kind != CONNACK || packet.body.len() != 2 {
+            bail!("Broker did not acknowledge the connection");
+        }
+        match packet.body[1] {
+            0 => Ok(connection),
+            1 => bail!("Broker refused the connection: unsupported protocol version"),
+            2 => bail!("Broker refused the connection: client identifier rejected"),
+            3 => bail!("Broker refused the connection: server unavailable"),
+            4 => bail!("Broker refused the connection: bad user name or password"),
+            5 => bail!("Broker refused the connection: not authorized"),
+            code => bail!("Broker refused the connection (code {code})"),
+        }
+    }
+
+    /// Waits for the next packet from the broker, cancel safe
+    pub async fn recv(&mut self) -> Result<Packet> {
+        match self.packets.recv().await {
+            Some(packet) => packet,
+            None => Err(anyhow!("Connection to the broker lost")),
+        }
+    }
+
+    /// Acknowledges a received packet, returns the payload if it carries a message
+    pub async fn handle(&mut self, packet: Packet) -> Result<Option<Vec<u8>>> {
+        match packet.kind {
+            PUBLISH => {
+                let qos = (packet.flags >> 1) & 3;
+                let mut reader = Fields(&packet.body);
+                reader.string()?;
+                if qos > 0 {
+                    let id = reader.u16()?;
+                    // QoS 2 is never granted, as subscriptions ask for 1 at most
+                    self.send(PUBACK << 4, &id.to_be_bytes()).await?;
+                }
+                Ok(Some(reader.0.to_vec()))
+            }
+            SUBACK => {
+                if packet.body.get(2) == Some(&0x80) {
+                    bail!("Broker rejected the subscription");
+                }
+                Ok(None)
+            }
+            PUBACK | PINGRESP => Ok(None),
+            kind => bail!("Unexpected packet of type {kind} from the broker"),
+        }
+    }
+
+    pub async fn subscribe(&mut self, filter: &str, qos: u8) -> Result<()> {
+        let mut body = self.packet_id().to_be_bytes().to_vec();
+        put_string(&mut body, filter.as_bytes());
+        body.push(qos);
+        // The flags of SUBSCRIBE are fixed to 0b0010
+        self.send(SUBSCRIBE << 4 | 2, &body).await
+    }
+
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], qos: u8) -> Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+        put_string(&mut body, topic.as_bytes());
+        if qos > 0 {
+            body.extend_from_slice(&self.packet_id().to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        self.send(PUBLISH << 4 | qos << 1, &body).await
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.send(PINGREQ << 4, &[]).await
+    }
+
+    /// Ends the session, so the broker does not treat it as lost
+    pub async fn disconnect(mut self) {
+        let _ = self.send(DISCONNECT << 4, &[]).await;
+        let _ = self.writer.shutdown().await;
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_id;
+        // Zero is not a valid packet identifier
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    async fn send(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        if body.len() > MAX_REMAINING_LEN {
+            bail!("Message too long for MQTT");
+        }
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(body);
+        self.writer
+            .write_all(&packet)
+            .await
+            .context("Connection to the broker lost")
+    }
+}
+
+fn connect_body(options: &ConnectOptions) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, b"MQTT");
+    // Protocol level 4 is MQTT 3.1.1
+    body.push(4);
+    let mut flags = 0x02; // Clean session
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&options.keep_alive.to_be_bytes());
+    put_string(&mut body, options.client_id.as_bytes());
+    if let Some(username) = &options.username {
+        put_string(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &options.password {
+        put_string(&mut body, password.as_bytes());
+    }
+    body
+}
+
+/// Appends a length prefixed string
+fn put_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> Result<Packet> {
+    let header = reader
+        .read_u8()
+        .await
+        .context("Connection to the broker lost")?;
+    let mut len = 0;
+    for i in 0.. {
+        if i == 4 {
+            bail!("Invalid packet length from the broker");
+        }
+        let byte = reader.read_u8().await?;
+        len |= usize::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if len > MAX_PACKET_LEN {
+        bail!("Packet of {len} bytes from the broker is too large");
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Packet {
+        kind: header >> 4,
+        flags: header & 0x0f,
+        body,
+    })
+}
+
+/// Reads the fields of a packet body
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn u16(&mut self) -> Result<u16> {
+        let Some((bytes, rest)) = self.0.split_first_chunk() else {
+            bail!("Truncated packet from the broker");
+        };
+        self.0 = rest;
+        Ok(u16::from_be_bytes(*bytes))
+    }
+
+    /// Reads a length prefixed UTF-8 string
+    fn string(&mut self) -> Result<String> {
+        let len = usize::from(self.u16()?);
+        if len > self.0.len() {
+            bail!("Truncated packet from the broker");
+        }
+        let (s, rest) = self.0.split_at(len);
+        self.0 = rest;
+        String::from_utf8(s.to_vec()).context("Invalid topic from the broker")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broker(host: &str, port: u16, tls: bool) -> Broker {
+        Broker {
+            host: host.to_string(),
+            port,
+            tls,
+        }
+    }
+
+    #[test]
+    fn broker_urls() {
+        let cases = [
+            ("localhost", broker("localhost", 1883, false)),
+            (
+                "mqtt://example.com:1884/",
+                broker("example.com", 1884, false),
+            ),
+            ("tcp://10.0.0.1", broker("10.0.0.1", 1883, false)),
+            ("mqtts://example.com", broker("example.com", 8883, true)),
+            ("ssl://example.com:443", broker("example.com", 443, true)),
+            ("[::1]:1883", broker("::1", 1883, false)),
+            ("tls://[::1]", broker("::1", 8883, true)),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(url.parse::<Broker>().unwrap(), expected, "{url}");
+        }
+        assert_eq!(broker("::1", 1883, false).to_string(), "[::1]:1883");
+    }
+
+    #[test]
+    fn invalid_broker_urls() {
+        for (url, error) in [
+            (
+                "http://example.com",
+                "unknown scheme 'http', expected mqtt:// or mqtts://",
+            ),
+            ("mqtt://", "no host given"),
+            ("example.com:port", "invalid port 'port'"),
+            ("[::1", "missing ']'"),
+        ] {
+            assert_eq!(url.parse::<Broker>().unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn tls_without_certificates_uses_the_well_known_cas() {
+        assert!(TlsOptions::default().config().is_ok());
+        let missing = TlsOptions {
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            client_cert: None,
+        };
+        let error = missing.config().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Could not read certificates from /nonexistent/ca.pem"
+        );
+    }
+
+    #[tokio::test]
+    async fn packets_are_read_with_their_length() {
+        let data: &[u8] = &[PUBLISH << 4 | 2, 0x03, 0, 1, b't', DISCONNECT << 4, 0];
+        let mut reader = data;
+        let packet = read_packet(&mut reader).await.unwrap();
+        assert_eq!((packet.kind, packet.flags), (PUBLISH, 2));
+        assert_eq!(packet.body, [0, 1, b't']);
+        let packet = read_packet(&mut reader).await.unwrap();
+        assert_eq!((packet.kind, packet.body.len()), (DISCONNECT, 0));
+        assert!(read_packet(&mut reader).await.is_err());
+
+        // More than four length bytes
+        let mut reader: &[u8] = &[PUBLISH << 4, 0x80, 0x80, 0x80, 0x80, 0x01];
+        let error = read_packet(&mut reader).await.unwrap_err().to_string();
+        assert_eq!(error, "Invalid packet length from the broker");
+    }
+}
@@ -0,0 +1,35 @@
+//! Querying the ATT MTU negotiated by the platform Bluetooth stack
+//!
+//! btleplug does not expose the MTU, so it is read from the stack directly where possible.
+
+use btleplug::api::BDAddr;
+use uuid::Uuid;
+
+/// ATT MTU every BLE link supports, used when the negotiated one is unknown
+pub const DEFAULT_MTU: u16 = 23;
+
+/// Size of the ATT write header, which the payload has to leave room for
+pub const ATT_HEADER_LEN: u16 = 3;
+
+/// Returns the MTU BlueZ negotiated for the characteristic `char_uuid` of `service_uuid` on
+/// the connected device with the given address
+#[cfg(target_os = "linux")]
+pub async fn query(address: BDAddr, service_uuid: Uuid, char_uuid: Uuid) -> Option<u16> {
+    let session = crate::bluez::session().await?;
+    let device = session
+        .get_devices()
+        .await
+        .ok()?
+        .into_iter()
+        .find(|d| d.connected && <[u8; 6]>::from(d.mac_address) == address.into_inner())?;
+    session
+        .get_service_characteristic_by_uuid(&device.id, service_uuid, char_uuid)
+        .await
+        .ok()?
+        .mtu
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn query(_address: BDAddr, _service_uuid: Uuid, _char_uuid: Uuid) -> Option<u16> {
+    None
+}
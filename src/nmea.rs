@@ -0,0 +1,337 @@
+//! NMEA 0183 sentences of GPS receivers, followed for the fix panel of `--nmea`
+//!
+//! Only sentences with a valid checksum count. RMC gives the time, position, speed and
+//! course, GGA the quality of the fix, the satellites used, HDOP and altitude, and GSV the
+//! satellites in view of every constellation.
+
+use std::collections::BTreeMap;
+
+/// Longest sentence kept while waiting for its line ending, NMEA allows 82 characters
+const MAX_SENTENCE_LEN: usize = 256;
+
+/// What the receiver reported last
+#[derive(Debug, Clone, Default)]
+pub struct Fix {
+    /// UTC time of the fix as hh:mm:ss
+    pub time: Option<String>,
+    /// Whether RMC reported the fix as valid
+    pub active: Option<bool>,
+    /// Degrees north
+    pub latitude: Option<f64>,
+    /// Degrees east
+    pub longitude: Option<f64>,
+    /// Meters above mean sea level
+    pub altitude: Option<f64>,
+    /// GGA fix quality, 0 for none
+    pub quality: Option<u8>,
+    pub satellites_used: Option<u32>,
+    pub hdop: Option<f64>,
+    pub speed_knots: Option<f64>,
+    /// Degrees from true north
+    pub course: Option<f64>,
+    /// Satellites in view by talker, e.g. `GP` for GPS and `GL` for GLONASS
+    pub in_view: BTreeMap<String, u32>,
+    /// Sentences with a valid checksum
+    pub sentences: u64,
+    /// Sentences dropped for a wrong or missing checksum
+    pub bad_checksums: u64,
+}
+
+impl Fix {
+    /// Name of the GGA fix quality
+    pub fn quality_name(&self) -> Option<&'static str> {
+        Some(match self.quality? {
+            0 => "no fix",
+            1 => "GPS",
+            2 => "DGPS",
+            3 => "PPS",
+            4 => "RTK fixed",
+            5 => "RTK float",
+            6 => "estimated",
+            7 => "manual",
+            8 => "simulated",
+            _ => "unknown",
+        })
+    }
+}
+
+/// Collects sentences from received data and updates the fix with them
+#[derive(Debug, Default)]
+pub struct Tracker {
+    /// Start of a sentence whose line ending was not received yet
+    line: Vec<u8>,
+    fix: Fix,
+}
+
+impl Tracker {
+    pub fn fix(&self) -> &Fix {
+        &self.fix
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        for &b in data {
+            match b {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if !line.is_empty() {
+                        self.sentence(&String::from_utf8_lossy(&line));
+                    }
+                }
+                // A new sentence starts at every $, also after a line that was cut off
+                b'$' => self.line = vec![b],
+                _ if self.line.len() < MAX_SENTENCE_LEN => self.line.push(b),
+                _ => self.line.clear(),
+            }
+        }
+    }
+
+    fn sentence(&mut self, line: &str) {
+        let Some(body) = line.strip_prefix('$') else {
+            return;
+        };
+        let Some(body) = checked(body) else {
+            self.fix.bad_checksums += 1;
+            return;
+        };
+        self.fix.sentences += 1;
+        let fields: Vec<&str> = body.split(',').collect();
+        let address = fields[0];
+        // Proprietary sentences start with P and have no talker
+        if address.len() != 5 || address.starts_with('P') {
+            return;
+        }
+        let (talker, kind) = address.split_at(2);
+        match kind {
+            "RMC" => self.rmc(&fields),
+            "GGA" => self.gga(&fields),
+            "GSV" => {
+                if let Some(in_view) = field(&fields, 3) {
+                    self.fix.in_view.insert(talker.to_string(), in_view);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rmc(&mut self, fields: &[&str]) {
+        let fix = &mut self.fix;
+        fix.time = time(fields.get(1).copied().unwrap_or_default()).or(fix.time.take());
+        fix.active = fields.get(2).map(|status| *status == "A");
+        if fix.active == Some(false) {
+            // Receivers keep reporting the last position after losing the fix
+            (fix.latitude, fix.longitude) = (None, None);
+        } else if let Some((latitude, longitude)) = position(&fields[3.min(fields.len())..]) {
+            fix.latitude = Some(latitude);
+            fix.longitude = Some(longitude);
+        }
+        fix.speed_knots = field(fields, 7);
+        fix.course = field(fields, 8);
+    }
+
+    fn gga(&mut self, fields: &[&str]) {
+        let fix = &mut self.fix;
+        fix.time = time(fields.get(1).copied().unwrap_or_default()).or(fix.time.take());
+        fix.quality = field(fields, 6);
+        if fix.quality == Some(0) {
+            (fix.latitude, fix.longitude) = (None, None);
+        } else if let Some((latitude, longitude)) = position(&fields[2.min(fields.len())..]) {
+            fix.latitude = Some(latitude);
+            fix.longitude = Some(longitude);
+        }
+        fix.satellites_used = field(fields, 7);
+        fix.hdop = field(fields, 8);
+        fix.altitude = field(fields, 9);
+    }
+}
+
+/// The body of a sentence without `$`, `None` unless it ends with a matching `*hh` checksum
+fn checked(sentence: &str) -> Option<&str> {
+    let (body, checksum) = sentence.trim_end().rsplit_once('*')?;
+    // from_str_radix alone would take a sign, reading "+A" as 0x0a
+    if checksum.len() != 2 || !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let expected = u8::from_str_radix(checksum, 16).ok()?;
+    let actual = body.bytes().fold(0, |sum, b| sum ^ b);
+    (actual == expected).then_some(body)
+}
+
+fn field<T: std::str::FromStr>(fields: &[&str], index: usize) -> Option<T> {
+    fields.get(index)?.parse().ok()
+}
+
+/// hhmmss.ss as hh:mm:ss
+fn time(field: &str) -> Option<String> {
+    let digits = field.get(..6)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!(
+        "{}:{}:{}",
+        &digits[..2],
+        &digits[2..4],
+        &digits[4..]
+    ))
+}
+
+/// Latitude and longitude from the four fields `ddmm.mm,N,dddmm.mm,E`
+fn position(fields: &[&str]) -> Option<(f64, f64)> {
+    let latitude = degrees(fields.first()?, 2)?;
+    let longitude = degrees(fields.get(2)?, 3)?;
+    let latitude = match *fields.get(1)? {
+        "N" => latitude,
+        "S" => -latitude,
+        _ => return None,
+    };
+    let longitude = match *fields.get(3)? {
+        "E" => longitude,
+        "W" => -longitude,
+        _ => return None,
+    };
+    Some((latitude, longitude))
+}
+
+/// Degrees from degrees and minutes, with `digits` digits of degrees
+fn degrees(field: &str, digits: usize) -> Option<f64> {
+    let degrees: f64 = field.get(..digits)?.parse().ok()?;
+    let minutes: f64 = field.get(digits..)?.parse().ok()?;
+    Some(degrees + minutes / 60.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n";
+
+    fn tracker(data: &str) -> Tracker {
+        let mut tracker = Tracker::default();
+        tracker.push(data.as_bytes());
+        tracker
+    }
+
+    #[test]
+    fn follows_rmc_and_gga() {
+        let fix = tracker(&format!("{RMC}{GGA}")).fix().clone();
+        assert_eq!(fix.time.as_deref(), Some("12:35:19"));
+        assert_eq!(fix.active, Some(true));
+        assert!((fix.latitude.unwrap() - 48.1173).abs() < 1e-9);
+        assert!((fix.longitude.unwrap() - (11.0 + 31.0 / 60.0)).abs() < 1e-9);
+        assert_eq!(fix.speed_knots, Some(22.4));
+        assert_eq!(fix.course, Some(84.4));
+        assert_eq!(fix.quality, Some(1));
+        assert_eq!(fix.quality_name(), Some("GPS"));
+        assert_eq!(fix.satellites_used, Some(8));
+        assert_eq!(fix.hdop, Some(0.9));
+        assert_eq!(fix.altitude, Some(545.4));
+        assert_eq!((fix.sentences, fix.bad_checksums), (2, 0));
+    }
+
+    #[test]
+    fn southern_and_western_positions_are_negative() {
+        let fix = tracker("$GNRMC,235959.00,A,3350.000,S,15112.000,W,,,,,*20\n")
+            .fix()
+            .clone();
+        assert_eq!(fix.time.as_deref(), Some("23:59:59"));
+        assert_eq!(fix.active, Some(true));
+        assert!((fix.latitude.unwrap() + (33.0 + 50.0 / 60.0)).abs() < 1e-9);
+        assert!((fix.longitude.unwrap() + (151.0 + 12.0 / 60.0)).abs() < 1e-9);
+        assert_eq!(fix.speed_knots, None);
+    }
+
+    #[test]
+    fn losing_the_fix_clears_the_position() {
+        // The last position is still reported while the fix is invalid
+        let void = "$GNRMC,235959.00,V,3350.000,S,15112.000,W,,,,,*37\n";
+        let fix = tracker(&format!("{RMC}{void}")).fix().clone();
+        assert_eq!(fix.active, Some(false));
+        assert_eq!(fix.time.as_deref(), Some("23:59:59"));
+        assert_eq!((fix.latitude, fix.longitude), (None, None));
+
+        let no_fix = "$GPGGA,123520,,,,,0,00,99.99,,M,,M,,*4F\r\n";
+        let fix = tracker(&format!("{GGA}{no_fix}")).fix().clone();
+        assert_eq!(fix.quality_name(), Some("no fix"));
+        assert_eq!((fix.latitude, fix.longitude), (None, None));
+
+        // Until the fix is back
+        let fix = tracker(&format!("{GGA}{no_fix}{RMC}")).fix().clone();
+        assert!(fix.latitude.is_some() && fix.longitude.is_some());
+    }
+
+    #[test]
+    fn counts_satellites_in_view_by_talker() {
+        let fix = tracker(concat!(
+            "$GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75\r\n",
+            "$GLGSV,1,1,03,65,10,100,30,66,20,200,35,67,30,300,40*55\r\n",
+            // Proprietary, counted but not followed
+            "$PGRME,15.0,M,45.0,M,25.0,M*1C\r\n",
+        ))
+        .fix()
+        .clone();
+        assert_eq!(
+            fix.in_view.into_iter().collect::<Vec<_>>(),
+            [("GL".to_string(), 3), ("GP".to_string(), 8)]
+        );
+        assert_eq!(fix.sentences, 3);
+    }
+
+    #[test]
+    fn joins_sentences_split_across_pushes() {
+        let data = format!("{RMC}{GGA}");
+        let mut tracker = Tracker::default();
+        for chunk in data.as_bytes().chunks(5) {
+            tracker.push(chunk);
+        }
+        assert_eq!(tracker.fix().sentences, 2);
+        assert_eq!(tracker.fix().altitude, Some(545.4));
+    }
+
+    #[test]
+    fn drops_sentences_with_a_bad_checksum() {
+        let fix = tracker(concat!(
+            // Wrong, missing, one digit and a non-hex checksum
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48\r\n",
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,\r\n",
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*7\r\n",
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*zz\r\n",
+        ))
+        .fix()
+        .clone();
+        assert_eq!((fix.sentences, fix.bad_checksums), (0, 4));
+        assert_eq!(fix.quality, None);
+
+        // The checksum is 09, which from_str_radix would also read from +9
+        let fix = tracker("$GPTXT,01,01,02,GPS*+9\r\n$GPTXT,01,01,02,GPS*09\r\n")
+            .fix()
+            .clone();
+        assert_eq!((fix.sentences, fix.bad_checksums), (1, 1));
+    }
+
+    #[test]
+    fn ignores_noise_and_cut_off_sentences() {
+        // Noise before the $, a sentence cut off by the start of the next and an overlong line
+        let data = format!(
+            "garbage$GPGGA,1235{GGA}{}\r\n{RMC}",
+            "x".repeat(MAX_SENTENCE_LEN * 2)
+        );
+        let fix = tracker(&data).fix().clone();
+        assert_eq!((fix.sentences, fix.bad_checksums), (2, 0));
+        assert_eq!(fix.active, Some(true));
+    }
+
+    #[test]
+    fn malformed_fields_leave_the_fix_alone() {
+        // A sentence with a valid checksum but missing and unparsable fields
+        let body = "GPGGA,12:35,48x7,N,01131.000,Q,one";
+        let checksum = body.bytes().fold(0, |sum, b| sum ^ b);
+        let fix = tracker(&format!("{GGA}${body}*{checksum:02X}\r\n"))
+            .fix()
+            .clone();
+        assert_eq!(fix.sentences, 2);
+        assert_eq!(fix.time.as_deref(), Some("12:35:19"));
+        assert!(fix.latitude.is_some());
+        assert_eq!(fix.quality, None);
+        assert_eq!(fix.altitude, None);
+    }
+}
@@ -0,0 +1,21 @@
+//! Desktop notifications, for problems noticed while the terminal is in the background
+//!
+//! Shown through `notify-rust`: on Linux by the notification daemon of the desktop over the
+//! D-Bus session bus, on macOS by the Notification Center and on Windows as a toast.
+
+use anyhow::{Context, Result};
+use notify_rust::Notification;
+
+/// Name shown as the sender of the notifications
+const APP_NAME: &str = "nus-terminal";
+
+/// Shows a notification with the title `summary`
+pub async fn send(summary: &str, body: &str) -> Result<()> {
+    let mut notification = Notification::new();
+    notification.appname(APP_NAME).summary(summary).body(body);
+    // Showing blocks until the notification service answers
+    tokio::task::spawn_blocking(move || notification.show().map(drop))
+        .await
+        .context("Notification task failed")??;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Nordic UART Service definitions, and those of the UART services of other BLE modules
+
+use crate::link::WriteMode;
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{CharPropFlags, Characteristic};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+pub const NUS_SERVICE_UUID: Uuid = Uuid::from_u128(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e);
+pub const NUS_RX_CHAR_UUID: Uuid = Uuid::from_u128(0x6e400002_b5a3_f393_e0a9_e50e24dcca9e); // Write
+pub const NUS_TX_CHAR_UUID: Uuid = Uuid::from_u128(0x6e400003_b5a3_f393_e0a9_e50e24dcca9e); // Notify
+
+/// UUIDs of the UART service and its characteristics, overridable for NUS-like services
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NusUuids {
+    pub service: Uuid,
+    pub rx: Uuid,
+    pub tx: Uuid,
+}
+
+impl Default for NusUuids {
+    fn default() -> Self {
+        Protocol::Nus.uuids()
+    }
+}
+
+/// Serial-over-BLE services of common modules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Protocol {
+    /// Nordic UART Service
+    #[default]
+    Nus,
+    /// HM-10 and other modules with a CC2540/CC2541, one characteristic for both directions
+    #[value(name = "hm10")]
+    Hm10,
+    /// TI SimpleLink Serial Port over BLE
+    Ti,
+    /// Microchip Transparent UART of the RN4870/71 and BM70/71
+    Microchip,
+}
+
+impl Protocol {
+    pub fn uuids(self) -> NusUuids {
+        match self {
+            Protocol::Nus => NusUuids {
+                service: NUS_SERVICE_UUID,
+                rx: NUS_RX_CHAR_UUID,
+                tx: NUS_TX_CHAR_UUID,
+            },
+            Protocol::Hm10 => NusUuids {
+                service: uuid_from_u16(0xffe0),
+                rx: uuid_from_u16(0xffe1),
+                tx: uuid_from_u16(0xffe1),
+            },
+            Protocol::Ti => NusUuids {
+                service: Uuid::from_u128(0xf000c0e0_0451_4000_b000_000000000000),
+                rx: Uuid::from_u128(0xf000c0e1_0451_4000_b000_000000000000),
+                tx: Uuid::from_u128(0xf000c0e1_0451_4000_b000_000000000000),
+            },
+            Protocol::Microchip => NusUuids {
+                service: Uuid::from_u128(0x49535343_fe7d_4ae5_8fa9_9fafd205e455),
+                rx: Uuid::from_u128(0x49535343_8841_43f4_a8d4_ecbe34729bb3),
+                tx: Uuid::from_u128(0x49535343_1e4d_4bd9_ba61_23c647249616),
+            },
+        }
+    }
+
+    /// How data is written to the device unless `--write-mode` says otherwise
+    pub fn write_mode(self) -> WriteMode {
+        match self {
+            Protocol::Hm10 | Protocol::Ti => WriteMode::WithoutResponse,
+            Protocol::Nus | Protocol::Microchip => WriteMode::Auto,
+        }
+    }
+}
+
+/// Services with a notify and a write characteristic that are not a UART: MCUmgr SMP and
+/// the Nordic DFU services
+const NOT_UART: [Uuid; 3] = [
+    Uuid::from_u128(0x8d53dc1d_1db7_4cd3_868b_8a527460aa84),
+    uuid_from_u16(0xfe59),
+    Uuid::from_u128(0x00001530_1212_efde_1523_785feabcd123),
+];
+
+/// Finds a service that looks like a UART, with a characteristic notifying (or indicating)
+/// data and one to write to, which may be the same
+///
+/// Services assigned by the Bluetooth SIG, like the Battery Service, are left out.
+pub fn detect(chars: &BTreeSet<Characteristic>) -> Option<NusUuids> {
+    let notifies = |c: &Characteristic| {
+        c.properties
+            .intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE)
+    };
+    let writable = |c: &Characteristic| {
+        c.properties
+            .intersects(CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE)
+    };
+    let services: BTreeSet<Uuid> = chars
+        .iter()
+        .map(|c| c.service_uuid)
+        .filter(|service| !is_assigned(*service) && !NOT_UART.contains(service))
+        .collect();
+    services.into_iter().find_map(|service| {
+        let chars: Vec<&Characteristic> =
+            chars.iter().filter(|c| c.service_uuid == service).collect();
+        // Separate characteristics for both directions are the more likely the UART
+        let (rx, tx) = chars
+            .iter()
+            .filter(|c| writable(c))
+            .flat_map(|rx| chars.iter().filter(|c| notifies(c)).map(move |tx| (rx, tx)))
+            .min_by_key(|(rx, tx)| rx.uuid == tx.uuid)?;
+        Some(NusUuids {
+            service,
+            rx: rx.uuid,
+            tx: tx.uuid,
+        })
+    })
+}
+
+/// Whether `uuid` is a 16-bit UUID in the range of services assigned by the Bluetooth SIG
+fn is_assigned(uuid: Uuid) -> bool {
+    let (short, base) = (uuid.as_u128() >> 96, uuid.as_u128() & ((1 << 96) - 1));
+    base == uuid_from_u16(0).as_u128() && (0x1800..0x1900).contains(&short)
+}
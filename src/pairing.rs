@@ -0,0 +1,142 @@
+//! Pairing with (bonding to) devices that require an encrypted link
+//!
+//! On Linux a BlueZ agent, registered through `bluer`, takes part in the pairing, passing the
+//! passkeys to show or enter on to an [`Agent`]. Other platforms run their own pairing dialog
+//! when a device asks for encryption, so there is nothing to do there.
+
+use anyhow::Result;
+use btleplug::platform::Peripheral;
+use tokio::sync::{mpsc, oneshot};
+
+/// Receives the requests of a pairing on the way, for the user to answer
+pub type Agent = mpsc::UnboundedSender<Request>;
+
+/// Something the user has to do for the pairing to go ahead
+///
+/// Dropping the reply of a request rejects it, which makes the pairing fail.
+#[derive(Debug)]
+pub enum Request {
+    /// The passkey shown by the device has to be entered
+    Passkey(oneshot::Sender<u32>),
+    /// The PIN code of the device has to be entered, for devices using legacy pairing
+    PinCode(oneshot::Sender<String>),
+    /// Whether the device shows the same passkey
+    Confirm(u32, oneshot::Sender<bool>),
+    /// The passkey has to be entered on the device
+    DisplayPasskey(u32),
+    /// The PIN code has to be entered on the device
+    DisplayPinCode(String),
+}
+
+/// Time the user has to complete the pairing
+#[cfg(target_os = "linux")]
+const PAIRING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(90);
+
+/// Pairs with the connected `peripheral` unless it is bonded already, returns whether a
+/// pairing took place
+#[cfg(target_os = "linux")]
+pub async fn pair(peripheral: &Peripheral, agent: &Agent) -> Result<bool> {
+    use anyhow::{Context, anyhow, bail};
+    use btleplug::api::Peripheral as _;
+    use log::debug;
+
+    let session = bluer::Session::new()
+        .await
+        .context("BlueZ is not reachable")?;
+    let address = bluer::Address(peripheral.address().into_inner());
+    let mut device = None;
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        if adapter.device_addresses().await?.contains(&address) {
+            device = Some(adapter.device(address)?);
+            break;
+        }
+    }
+    let device = device.ok_or(anyhow!("BlueZ does not know the device {address}"))?;
+    if device.is_paired().await? {
+        return Ok(false);
+    }
+
+    // Unregistered again when the handle is dropped
+    let _handle = session.register_agent(agent::new(agent)).await?;
+    debug!("Pairing with {address}");
+    let paired = tokio::time::timeout(PAIRING_TIMEOUT, device.pair())
+        .await
+        .context("Pairing timed out")?;
+    match paired {
+        Ok(()) => Ok(true),
+        // Bonded meanwhile, e.g. by the desktop's own agent
+        Err(e) if e.kind == bluer::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => bail!("Pairing failed: {}", e.message),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn pair(_peripheral: &Peripheral, _agent: &Agent) -> Result<bool> {
+    Ok(false)
+}
+
+/// The BlueZ agent taking part in the pairing
+#[cfg(target_os = "linux")]
+mod agent {
+    use super::{Agent, Request};
+    use bluer::agent::{self, ReqError, ReqResult};
+    use futures::FutureExt;
+    use futures::future::{self, BoxFuture};
+    use tokio::sync::oneshot;
+
+    /// An agent passing the requests on to `agent`, a dropped reply rejects the request
+    pub fn new(agent: &Agent) -> agent::Agent {
+        agent::Agent {
+            request_passkey: Some(Box::new({
+                let agent = agent.clone();
+                move |_| ask(&agent, Request::Passkey)
+            })),
+            request_pin_code: Some(Box::new({
+                let agent = agent.clone();
+                move |_| ask(&agent, Request::PinCode)
+            })),
+            request_confirmation: Some(Box::new({
+                let agent = agent.clone();
+                move |request| {
+                    let confirmed = ask(&agent, |reply| Request::Confirm(request.passkey, reply));
+                    confirmed
+                        .map(|c| c.and_then(|c| if c { Ok(()) } else { Err(ReqError::Rejected) }))
+                        .boxed()
+                }
+            })),
+            display_passkey: Some(Box::new({
+                let agent = agent.clone();
+                move |request| {
+                    // Called again for every digit typed on the device
+                    if request.entered == 0 {
+                        let _ = agent.send(Request::DisplayPasskey(request.passkey));
+                    }
+                    future::ok(()).boxed()
+                }
+            })),
+            display_pin_code: Some(Box::new({
+                let agent = agent.clone();
+                move |request| {
+                    let _ = agent.send(Request::DisplayPinCode(request.pincode));
+                    future::ok(()).boxed()
+                }
+            })),
+            // Only the pairing started by this process reaches the agent, so what it asks to
+            // authorize is wanted
+            request_authorization: Some(Box::new(|_| future::ok(()).boxed())),
+            authorize_service: Some(Box::new(|_| future::ok(()).boxed())),
+            ..Default::default()
+        }
+    }
+
+    /// Sends the request made by `request` to `agent`, the future gives the user's reply
+    fn ask<T: Send + 'static>(
+        agent: &Agent,
+        request: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> BoxFuture<'static, ReqResult<T>> {
+        let (sender, reply) = oneshot::channel();
+        let _ = agent.send(request(sender));
+        reply.map(|r| r.map_err(|_| ReqError::Rejected)).boxed()
+    }
+}
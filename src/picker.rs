@@ -0,0 +1,94 @@
+use crate::device::DeviceInfo;
+use anyhow::Result;
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::{ExecutableCommand, QueueableCommand, cursor, event, terminal};
+use std::io::{self, Write};
+
+/// Lets the user choose one of `devices` with the arrow keys.
+///
+/// Returns the index of the selected device, or `None` if the selection was cancelled.
+pub fn pick(devices: &[DeviceInfo]) -> Result<Option<usize>> {
+    let mut stdout = io::stdout();
+    let _terminal = PickerTerminal::enter(&mut stdout)?;
+    run(&mut stdout, devices)
+}
+
+/// Raw mode and the alternate screen of the picker, left again when dropped, also on errors
+struct PickerTerminal;
+
+impl PickerTerminal {
+    fn enter(stdout: &mut io::Stdout) -> Result<PickerTerminal> {
+        terminal::enable_raw_mode()?;
+        // Restores what was changed so far if the rest fails
+        let entered = PickerTerminal;
+        stdout.execute(terminal::EnterAlternateScreen)?;
+        stdout.execute(cursor::Hide)?;
+        Ok(entered)
+    }
+}
+
+impl Drop for PickerTerminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.execute(cursor::Show);
+        let _ = stdout.execute(terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn run(stdout: &mut io::Stdout, devices: &[DeviceInfo]) -> Result<Option<usize>> {
+    let mut selected = 0;
+    loop {
+        draw(stdout, devices, selected)?;
+
+        if let event::Event::Key(key_event) = event::read()? {
+            if key_event.kind != event::KeyEventKind::Press {
+                continue;
+            }
+            match key_event.code {
+                event::KeyCode::Up => selected = selected.saturating_sub(1),
+                event::KeyCode::Down => selected = (selected + 1).min(devices.len() - 1),
+                event::KeyCode::Enter => return Ok(Some(selected)),
+                event::KeyCode::Esc | event::KeyCode::Char('q') => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn draw(stdout: &mut io::Stdout, devices: &[DeviceInfo], selected: usize) -> Result<()> {
+    stdout.queue(terminal::Clear(terminal::ClearType::All))?;
+    stdout.queue(cursor::MoveTo(0, 0))?;
+    stdout.queue(Print(
+        "Multiple devices found. Use Up/Down to select, Enter to connect, Esc to cancel.\r\n\r\n",
+    ))?;
+
+    let name_width = devices
+        .iter()
+        .map(|d| d.display_name().len())
+        .max()
+        .unwrap_or(0);
+    for (i, d) in devices.iter().enumerate() {
+        let rssi = d
+            .rssi
+            .map(|r| format!("{r} dBm"))
+            .unwrap_or_else(|| "-".to_string());
+        let line = format!(
+            "{:name_width$}  {}  {:>8}",
+            d.display_name(),
+            d.address,
+            rssi
+        );
+        if i == selected {
+            stdout.queue(SetAttribute(Attribute::Reverse))?;
+            stdout.queue(Print(format!("> {line}")))?;
+            stdout.queue(SetAttribute(Attribute::Reset))?;
+        } else {
+            stdout.queue(Print(format!("  {line}")))?;
+        }
+        stdout.queue(Print("\r\n"))?;
+    }
+
+    stdout.flush()?;
+    Ok(())
+}
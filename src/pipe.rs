@@ -0,0 +1,163 @@
+//! Non-interactive sessions copying stdin to the device and its output to stdout
+
+use crate::cli::ConnectArgs;
+use crate::connect;
+use crate::decode;
+use crate::device;
+use crate::error::Error;
+use crate::flow::FlowControl;
+use crate::link::{Link, LinkOptions, Trace};
+use crate::plugin;
+use crate::stats::{self, Stats};
+use crate::transfer::Pacing;
+use anyhow::{Result, bail};
+use btleplug::platform::Adapter;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Runs until stdin ends and the device has been quiet for `--pipe-timeout`
+pub async fn run(central: &Adapter, args: &ConnectArgs) -> Result<()> {
+    if args.flow_control != FlowControl::None || args.reliable {
+        bail!("Flow control and --reliable are not supported in pipe mode");
+    }
+    let mut log = connect::open_log(args, None)?;
+    let mut capture = connect::open_capture(args, None)?;
+    let mut csv = connect::open_csv(args, None)?;
+    let mut plugin = match &args.plugin {
+        Some(path) => Some(plugin::Module::load(path)?.instantiate()?),
+        None => None,
+    };
+    let options = LinkOptions {
+        trace: connect::open_pcap(args, None)?.map(|pcap| pcap as Arc<dyn Trace>),
+        ..LinkOptions::from(&args.device)
+    };
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, mut notifications) = Link::open(device.peripheral.clone(), &options).await?;
+    info!("Connected to {}", device.display_name());
+    let env = connect::device_env(
+        device.display_name(),
+        &device.address,
+        device.rssi,
+        link.mtu,
+    );
+    if let Some(command) = &args.on_connect {
+        spawn_command(command, &env);
+    }
+
+    // A blocking read of stdin cannot be cancelled, so it gets a thread that does not hold
+    // up exiting
+    let (input_sender, mut input) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0; 1024];
+        while let Ok(n @ 1..) = stdin.read(&mut buf) {
+            if input_sender.blocking_send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let pacing = Pacing::from(args);
+    let mut hex = args.hex.then(|| decode::Decoder::new(decode::Mode::Hex));
+    let idle = Duration::from_millis(args.pipe_timeout);
+    let mut input_ended = false;
+    let mut stdout = tokio::io::stdout();
+    let terminated = connect::terminated();
+    tokio::pin!(terminated);
+    let started = Instant::now();
+    let mut stats = Stats {
+        name: device.display_name().to_string(),
+        address: device.address.clone(),
+        duration: Duration::ZERO,
+        tx_bytes: 0,
+        tx_packets: 0,
+        rx_bytes: 0,
+        rx_notifications: 0,
+        reconnects: 0,
+    };
+    let result = loop {
+        tokio::select! {
+            data = input.recv(), if !input_ended => match data {
+                Some(data) => {
+                    let mut data = args.send_newline.translate(&data);
+                    if let Some(plugin) = &mut plugin {
+                        data = match plugin.send(&data) {
+                            Ok(data) => data,
+                            Err(e) => break Err(e),
+                        };
+                    }
+                    if let Err(e) = pacing.write(&link, &data).await {
+                        break Err(e);
+                    }
+                    stats.tx_bytes += data.len() as u64;
+                    stats.tx_packets += data.len().div_ceil(link.max_payload()) as u64;
+                }
+                None => input_ended = true,
+            },
+            notification = notifications.next() => {
+                let Some(notification) = notification else {
+                    break Err(Error::ConnectionLost.into());
+                };
+                let mut data = notification.value;
+                stats.rx_bytes += data.len() as u64;
+                stats.rx_notifications += 1;
+                if let Some(capture) = &mut capture
+                    && let Err(e) = capture.write(&data)
+                {
+                    warn!("Writing to the capture failed: {e}");
+                }
+                // The capture has the data as received, everything else as the plugin turns it
+                if let Some(plugin) = &mut plugin {
+                    data = match plugin.receive(&data) {
+                        Ok(data) => data,
+                        Err(e) => break Err(e),
+                    };
+                }
+                if let Some(log) = &mut log
+                    && let Err(e) = log.write(&data)
+                {
+                    warn!("Writing to log failed: {e}");
+                }
+                if let Some(csv) = &mut csv
+                    && let Err(e) = csv.write(&data)
+                {
+                    warn!("Writing to the CSV file failed: {e}");
+                }
+                let output = match &mut hex {
+                    Some(decoder) => decoder.decode(&data).into_bytes(),
+                    None => data,
+                };
+                // Output piped into a program that exits early, e.g. head
+                if stdout.write_all(&output).await.is_err() || stdout.flush().await.is_err() {
+                    break Ok(());
+                }
+            },
+            // Restarted by every event, so it only fires once the device has gone quiet
+            _ = tokio::time::sleep(idle), if input_ended => break Ok(()),
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+            _ = &mut terminated => break Ok(()),
+        }
+    };
+    let _ = link.close().await;
+    if let Some(command) = &args.on_disconnect {
+        spawn_command(command, &env);
+    }
+    stats.duration = started.elapsed();
+    info!("{}", stats.summary());
+    if let Some(path) = &args.stats_json {
+        stats::write_json(path, std::slice::from_ref(&stats))?;
+    }
+    result
+}
+
+/// Starts a command given with `--on-connect` or `--on-disconnect`
+fn spawn_command(command: &str, env: &[(&str, String)]) {
+    if let Err(e) = connect::run_command(command, env) {
+        warn!("Could not run `{command}`: {e}");
+    }
+}
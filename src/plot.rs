@@ -0,0 +1,188 @@
+//! Numbers taken from the received lines with `--plot`, drawn as a chart above the output
+//!
+//! Every capture group of the pattern is a series, named after the group if it has a name.
+//! A line matching the pattern adds a point to every series whose group matched a number.
+//! With `--csv` the same values are written to a file as rows, with the time they arrived.
+
+use anyhow::Result;
+use jiff::fmt::strtime;
+use regex::Regex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Longest line matched, longer ones are cut off
+const MAX_LINE_LEN: usize = 4096;
+
+/// Most points kept of a series, however short the time between them
+const MAX_POINTS: usize = 10_000;
+
+/// Parses the pattern of `--plot`, which needs a capture group for every value
+pub fn pattern(pattern: &str) -> Result<Regex, String> {
+    let regex = Regex::new(pattern).map_err(|e| format!("invalid regex: {e}"))?;
+    if regex.captures_len() < 2 {
+        return Err("the pattern needs a capture group for every value plotted".to_string());
+    }
+    Ok(regex)
+}
+
+/// Names of the series of `pattern`, the number of a group without a name
+fn names(pattern: &Regex) -> impl Iterator<Item = String> + '_ {
+    pattern
+        .capture_names()
+        .enumerate()
+        .skip(1)
+        .map(|(i, name)| name.map_or_else(|| i.to_string(), str::to_string))
+}
+
+/// Splits received data into lines
+#[derive(Debug, Default)]
+struct Lines {
+    /// Start of a line whose ending was not received yet
+    line: Vec<u8>,
+}
+
+impl Lines {
+    /// Calls `f` with every non-empty line completed by `data`
+    fn push(&mut self, data: &[u8], mut f: impl FnMut(&str)) {
+        for &b in data {
+            match b {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if !line.is_empty() {
+                        f(&String::from_utf8_lossy(&line));
+                    }
+                }
+                _ if self.line.len() < MAX_LINE_LEN => self.line.push(b),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Series {
+    pub name: String,
+    /// Seconds since the plot started and the value
+    pub points: VecDeque<(f64, f64)>,
+}
+
+#[derive(Debug)]
+pub struct Plot {
+    pattern: Regex,
+    /// Time shown, older points are dropped
+    pub window: Duration,
+    started: Instant,
+    lines: Lines,
+    pub series: Vec<Series>,
+}
+
+impl Plot {
+    pub fn new(pattern: Regex, window: Duration) -> Plot {
+        let series = names(&pattern)
+            .map(|name| Series {
+                name,
+                points: VecDeque::new(),
+            })
+            .collect();
+        Plot {
+            pattern,
+            window,
+            started: Instant::now(),
+            lines: Lines::default(),
+            series,
+        }
+    }
+
+    /// Seconds since the plot started, where the time axis ends
+    pub fn now(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        let mut lines = std::mem::take(&mut self.lines);
+        lines.push(data, |line| self.line(line));
+        self.lines = lines;
+    }
+
+    fn line(&mut self, line: &str) {
+        let Some(captures) = self.pattern.captures(line) else {
+            return;
+        };
+        let now = self.now();
+        let oldest = now - self.window.as_secs_f64();
+        for (series, group) in self.series.iter_mut().zip(captures.iter().skip(1)) {
+            if let Some(value) = group.and_then(|group| number(group.as_str())) {
+                series.points.push_back((now, value));
+            }
+            while series
+                .points
+                .front()
+                .is_some_and(|&(time, _)| time < oldest)
+                || series.points.len() > MAX_POINTS
+            {
+                series.points.pop_front();
+            }
+        }
+    }
+}
+
+/// The number a group matched, `None` for anything else
+fn number(text: &str) -> Option<f64> {
+    text.trim().parse().ok()
+}
+
+/// Values captured by the pattern of `--plot`, written as CSV rows to the file of `--csv`
+///
+/// The first column is the local time the line arrived with milliseconds and the UTC offset,
+/// followed by a column per series. A group not matching a number leaves its cell empty.
+#[derive(Debug)]
+pub struct Csv {
+    pattern: Regex,
+    lines: Lines,
+    file: LineWriter<File>,
+}
+
+impl Csv {
+    /// Creates the file and writes the header naming the columns
+    pub fn create(path: &Path, pattern: Regex) -> Result<Csv> {
+        let mut file = LineWriter::new(File::create(path)?);
+        // Group names hold no commas or quotes, so none needs quoting
+        let header: Vec<String> = std::iter::once("time".to_string())
+            .chain(names(&pattern))
+            .collect();
+        writeln!(file, "{}", header.join(","))?;
+        Ok(Csv {
+            pattern,
+            lines: Lines::default(),
+            file,
+        })
+    }
+
+    /// Writes a row for every line completed by `data` that matches the pattern
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let mut rows = Vec::new();
+        self.lines.push(data, |line| {
+            let Some(captures) = self.pattern.captures(line) else {
+                return;
+            };
+            let time = strtime::format("%Y-%m-%dT%H:%M:%S%.3f%:z", &jiff::Zoned::now())
+                .unwrap_or_default();
+            let mut row = time;
+            for group in captures.iter().skip(1) {
+                row.push(',');
+                // The text matched rather than the number parsed, which keeps its precision
+                if let Some(group) = group.filter(|group| number(group.as_str()).is_some()) {
+                    row.push_str(group.as_str().trim());
+                }
+            }
+            rows.push(row);
+        });
+        for row in rows {
+            writeln!(self.file, "{row}")?;
+        }
+        Ok(())
+    }
+}
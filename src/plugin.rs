@@ -0,0 +1,210 @@
+//! WebAssembly plugins transforming the data with `--plugin`, e.g. to decrypt it
+//!
+//! A plugin is a module without imports exporting its `memory` and:
+//!
+//! - `nus_alloc(len: i32) -> i32`, the address of `len` bytes where the host puts the input
+//! - `nus_receive(ptr: i32, len: i32) -> i64`, transforming received data before it is shown
+//! - `nus_send(ptr: i32, len: i32) -> i64`, transforming data before it is written
+//!
+//! At least one of the transforms is needed, data goes through the other one unchanged. They
+//! return the address of the output in the upper 32 bits and its length in the lower ones,
+//! where the host copies it from before the next call. The output may be empty, e.g. while a
+//! transform waits for the rest of a frame.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::Path;
+use wasmi::{Config, Engine, Linker, Memory, Store, TypedFunc};
+
+/// Instructions a call may take, so a plugin that never returns fails instead of hanging the tab
+const FUEL_PER_CALL: u64 = 10_000_000;
+
+/// A compiled plugin, instantiated by every tab on its own
+#[derive(Debug, Clone)]
+pub struct Module {
+    engine: Engine,
+    module: wasmi::Module,
+}
+
+impl Module {
+    pub fn load(path: &Path) -> Result<Module> {
+        let wasm =
+            std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = wasmi::Module::new(&engine, wasm)
+            .with_context(|| format!("Invalid plugin {}", path.display()))?;
+        let module = Module { engine, module };
+        // Fail at startup rather than in every tab
+        module
+            .instantiate()
+            .with_context(|| format!("Invalid plugin {}", path.display()))?;
+        Ok(module)
+    }
+
+    /// A new instance of the plugin, with its own memory
+    pub fn instantiate(&self) -> Result<Plugin> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = Linker::new(&self.engine)
+            .instantiate_and_start(&mut store, &self.module)
+            .context("could not instantiate it, plugins cannot import anything")?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or(anyhow!("it does not export its memory"))?;
+        let alloc = instance
+            .get_typed_func(&store, "nus_alloc")
+            .context("it does not export nus_alloc(i32) -> i32")?;
+        let transform = |name| {
+            let func = instance.get_export(&store, name)?.into_func()?;
+            Some(
+                func.typed(&store)
+                    .with_context(|| format!("{name} is not (i32, i32) -> i64")),
+            )
+        };
+        let receive = transform("nus_receive").transpose()?;
+        let send = transform("nus_send").transpose()?;
+        if receive.is_none() && send.is_none() {
+            bail!("it exports neither nus_receive nor nus_send");
+        }
+        Ok(Plugin {
+            store,
+            memory,
+            alloc,
+            receive,
+            send,
+        })
+    }
+}
+
+/// An instance of a plugin, which keeps its state between calls
+#[derive(Debug)]
+pub struct Plugin {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    receive: Option<TypedFunc<(i32, i32), i64>>,
+    send: Option<TypedFunc<(i32, i32), i64>>,
+}
+
+impl Plugin {
+    /// Transforms received data
+    pub fn receive(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.receive {
+            Some(receive) => self.call(receive, data).context("nus_receive failed"),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    /// Transforms data to be written
+    pub fn send(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.send {
+            Some(send) => self.call(send, data).context("nus_send failed"),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    fn call(&mut self, transform: TypedFunc<(i32, i32), i64>, data: &[u8]) -> Result<Vec<u8>> {
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        let len = i32::try_from(data.len())?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, data)
+            .context("nus_alloc returned memory out of bounds")?;
+        let output = transform.call(&mut self.store, (ptr, len))? as u64;
+        let (ptr, len) = ((output >> 32) as usize, output as u32 as usize);
+        // Checked before allocating, a bogus length must not make the host allocate gigabytes
+        let size = self.memory.data_size(&self.store);
+        let Some(end) = ptr.checked_add(len).filter(|&end| end <= size) else {
+            bail!("the output is out of bounds");
+        };
+        Ok(self.memory.data(&self.store)[ptr..end].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads the plugin written in WAT as `module`
+    fn load(name: &str, module: &str) -> Plugin {
+        let path =
+            std::env::temp_dir().join(format!("nus-plugin-{name}-{}.wat", std::process::id()));
+        std::fs::write(&path, module).unwrap();
+        let module = Module::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        module.unwrap().instantiate().unwrap()
+    }
+
+    /// A plugin whose `nus_receive` returns `output` for any input, put at address 16
+    fn returning(name: &str, output: &str) -> Plugin {
+        load(
+            name,
+            &format!(
+                r#"(module
+                    (memory (export "memory") 1)
+                    (func (export "nus_alloc") (param i32) (result i32) i32.const 16)
+                    (func (export "nus_receive") (param i32 i32) (result i64) {output}))"#
+            ),
+        )
+    }
+
+    #[test]
+    fn transforms_in_place() {
+        // Upper-cases ASCII letters
+        let mut plugin = load(
+            "upper",
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "nus_alloc") (param i32) (result i32) i32.const 16)
+                (func (export "nus_receive") (param $ptr i32) (param $len i32) (result i64)
+                    (local $i i32) (local $c i32)
+                    (block $done
+                        (loop $next
+                            (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                            (local.set $c (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                            (if (i32.and (i32.ge_u (local.get $c) (i32.const 97))
+                                         (i32.le_u (local.get $c) (i32.const 122)))
+                                (then (i32.store8 (i32.add (local.get $ptr) (local.get $i))
+                                                  (i32.sub (local.get $c) (i32.const 32)))))
+                            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                            (br $next)))
+                    (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                            (i64.extend_i32_u (local.get $len)))))"#,
+        );
+        assert_eq!(plugin.receive(b"abc-Xyz").unwrap(), b"ABC-XYZ");
+        // Without nus_send, data to be written goes through unchanged
+        assert_eq!(plugin.send(b"abc").unwrap(), b"abc");
+    }
+
+    #[test]
+    fn empty_output() {
+        let mut plugin = returning("empty", "i64.const 0");
+        assert_eq!(plugin.receive(b"abc").unwrap(), b"");
+    }
+
+    #[test]
+    fn output_ending_at_the_end_of_memory() {
+        // The last 4 bytes of the single 64 KiB page
+        let mut plugin = returning("end", "i64.const 0xfffc00000004");
+        assert_eq!(plugin.receive(b"abc").unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn output_out_of_bounds() {
+        for (name, output) in [
+            // One byte past the end of memory
+            ("past", "i64.const 0xfffc00000005"),
+            // Starting beyond memory
+            ("beyond", "i64.const 0x1000000000001"),
+            // A length of 4 GiB - 1
+            ("huge", "i64.const 0xffffffff"),
+            // Address and length both at their maximum
+            ("wrap", "i64.const -1"),
+        ] {
+            let mut plugin = returning(name, output);
+            let e = plugin.receive(b"abc").unwrap_err();
+            assert!(format!("{e:#}").contains("out of bounds"), "{name}: {e:#}");
+        }
+    }
+}
@@ -0,0 +1,581 @@
+//! Decoding of protobuf messages using the types of a compiled descriptor set
+//!
+//! The descriptor set is the file `protoc --include_imports --descriptor_set_out=<file>`
+//! writes. Messages are shown in the text format on a single line, fields missing from the
+//! descriptors by their number.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Nesting allowed before a message is rejected, keeping the recursion bounded
+const MAX_DEPTH: usize = 32;
+
+/// Longest message a [`Delimited`] stream waits for, longer ones are dropped as invalid
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Longest encoding of a varint
+const MAX_VARINT_LEN: usize = 10;
+
+// Types of FieldDescriptorProto
+const TYPE_DOUBLE: u64 = 1;
+const TYPE_FLOAT: u64 = 2;
+const TYPE_INT64: u64 = 3;
+const TYPE_UINT64: u64 = 4;
+const TYPE_INT32: u64 = 5;
+const TYPE_FIXED64: u64 = 6;
+const TYPE_FIXED32: u64 = 7;
+const TYPE_BOOL: u64 = 8;
+const TYPE_STRING: u64 = 9;
+const TYPE_MESSAGE: u64 = 11;
+const TYPE_BYTES: u64 = 12;
+const TYPE_UINT32: u64 = 13;
+const TYPE_ENUM: u64 = 14;
+const TYPE_SFIXED32: u64 = 15;
+const TYPE_SFIXED64: u64 = 16;
+const TYPE_SINT32: u64 = 17;
+const TYPE_SINT64: u64 = 18;
+
+/// A field value as encoded on the wire
+#[derive(Debug, Clone, Copy)]
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..)
+        .and_then(|rest| rest.get(..len))
+        .ok_or_else(|| anyhow!("Message ends unexpectedly"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let b = take(data, pos, 1)?[0];
+        value |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint longer than {MAX_VARINT_LEN} bytes")
+}
+
+/// The fields of a message with their numbers, in the order they were encoded
+fn fields(data: &[u8]) -> Result<Vec<(u64, Wire<'_>)>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = varint(data, &mut pos)?;
+        let number = key >> 3;
+        let value = match key & 7 {
+            0 => Wire::Varint(varint(data, &mut pos)?),
+            1 => Wire::Fixed64(u64::from_le_bytes(take(data, &mut pos, 8)?.try_into()?)),
+            2 => {
+                let len = usize::try_from(varint(data, &mut pos)?)?;
+                Wire::Bytes(take(data, &mut pos, len)?)
+            }
+            5 => Wire::Fixed32(u32::from_le_bytes(take(data, &mut pos, 4)?.try_into()?)),
+            wire => bail!("Unsupported wire type {wire} of field {number}"),
+        };
+        fields.push((number, value));
+    }
+    Ok(fields)
+}
+
+fn string(fields: &[(u64, Wire)], number: u64) -> String {
+    fields
+        .iter()
+        .find_map(|(n, value)| match value {
+            Wire::Bytes(bytes) if *n == number => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+fn number(fields: &[(u64, Wire)], number: u64) -> u64 {
+    fields
+        .iter()
+        .find_map(|(n, value)| match value {
+            Wire::Varint(v) if *n == number => Some(*v),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+fn qualified(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_string()
+    } else {
+        format!("{scope}.{name}")
+    }
+}
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    number: u64,
+    kind: u64,
+    /// Fully qualified name of the message or enum type, without the leading dot
+    type_name: String,
+}
+
+/// The message and enum types of a descriptor set
+#[derive(Debug, Default)]
+struct Schema {
+    messages: HashMap<String, Vec<Field>>,
+    /// Names of the values of each enum by number
+    enums: HashMap<String, HashMap<i64, String>>,
+}
+
+impl Schema {
+    fn parse(data: &[u8]) -> Result<Schema> {
+        let mut schema = Schema::default();
+        for (number, value) in fields(data)? {
+            if let (1, Wire::Bytes(file)) = (number, value) {
+                schema.file(file)?;
+            }
+        }
+        Ok(schema)
+    }
+
+    fn file(&mut self, data: &[u8]) -> Result<()> {
+        let fields = fields(data)?;
+        let package = string(&fields, 2);
+        for (number, value) in fields {
+            match (number, value) {
+                (4, Wire::Bytes(message)) => self.message(&package, message)?,
+                (5, Wire::Bytes(enumeration)) => self.enumeration(&package, enumeration)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, scope: &str, data: &[u8]) -> Result<()> {
+        let fields = fields(data)?;
+        let name = qualified(scope, &string(&fields, 1));
+        let mut message = Vec::new();
+        for (number, value) in fields {
+            match (number, value) {
+                (2, Wire::Bytes(field)) => {
+                    let field = self::fields(field)?;
+                    message.push(Field {
+                        name: string(&field, 1),
+                        number: self::number(&field, 3),
+                        kind: self::number(&field, 5),
+                        type_name: string(&field, 6).trim_start_matches('.').to_string(),
+                    });
+                }
+                (3, Wire::Bytes(nested)) => self.message(&name, nested)?,
+                (4, Wire::Bytes(enumeration)) => self.enumeration(&name, enumeration)?,
+                _ => {}
+            }
+        }
+        self.messages.insert(name, message);
+        Ok(())
+    }
+
+    fn enumeration(&mut self, scope: &str, data: &[u8]) -> Result<()> {
+        let fields = fields(data)?;
+        let mut values = HashMap::new();
+        for (number, value) in &fields {
+            if let (2, Wire::Bytes(value)) = (number, value) {
+                let value = self::fields(value)?;
+                // Negative values are sign extended to 64 bits like int32 fields
+                values.insert(i64::from(self::number(&value, 2) as i32), string(&value, 1));
+            }
+        }
+        self.enums
+            .insert(qualified(scope, &string(&fields, 1)), values);
+        Ok(())
+    }
+
+    /// The fields of `data` in the text format, separated by spaces
+    fn format(&self, message: &str, data: &[u8], depth: usize) -> Result<String> {
+        if depth > MAX_DEPTH {
+            bail!("Message is nested too deeply");
+        }
+        let descriptors = self
+            .messages
+            .get(message)
+            .ok_or_else(|| anyhow!("Message type {message} is not in the descriptor set"))?;
+        let mut parts = Vec::new();
+        for (number, value) in fields(data)? {
+            let Some(field) = descriptors.iter().find(|field| field.number == number) else {
+                parts.push(format!("{number}: {}", unknown(value)));
+                continue;
+            };
+            match value {
+                Wire::Bytes(bytes) if field.kind == TYPE_MESSAGE => {
+                    let inner = self.format(&field.type_name, bytes, depth + 1)?;
+                    if inner.is_empty() {
+                        parts.push(format!("{} {{}}", field.name));
+                    } else {
+                        parts.push(format!("{} {{ {inner} }}", field.name));
+                    }
+                }
+                // Repeated scalars packed into one value
+                Wire::Bytes(bytes) if !matches!(field.kind, TYPE_STRING | TYPE_BYTES) => {
+                    for value in packed(field.kind, bytes)? {
+                        parts.push(format!("{}: {}", field.name, self.scalar(field, value)));
+                    }
+                }
+                value => parts.push(format!("{}: {}", field.name, self.scalar(field, value))),
+            }
+        }
+        Ok(parts.join(" "))
+    }
+
+    fn scalar(&self, field: &Field, value: Wire) -> String {
+        match (field.kind, value) {
+            (TYPE_DOUBLE, Wire::Fixed64(v)) => f64::from_bits(v).to_string(),
+            (TYPE_FLOAT, Wire::Fixed32(v)) => f32::from_bits(v).to_string(),
+            (TYPE_INT64 | TYPE_INT32, Wire::Varint(v)) => (v as i64).to_string(),
+            (TYPE_UINT64 | TYPE_UINT32, Wire::Varint(v)) => v.to_string(),
+            (TYPE_SINT64 | TYPE_SINT32, Wire::Varint(v)) => {
+                ((v >> 1) as i64 ^ -((v & 1) as i64)).to_string()
+            }
+            (TYPE_BOOL, Wire::Varint(v)) => (v != 0).to_string(),
+            (TYPE_ENUM, Wire::Varint(v)) => {
+                let v = i64::from(v as i32);
+                self.enums
+                    .get(&field.type_name)
+                    .and_then(|values| values.get(&v))
+                    .cloned()
+                    .unwrap_or_else(|| v.to_string())
+            }
+            (TYPE_FIXED64, Wire::Fixed64(v)) => v.to_string(),
+            (TYPE_SFIXED64, Wire::Fixed64(v)) => (v as i64).to_string(),
+            (TYPE_FIXED32, Wire::Fixed32(v)) => v.to_string(),
+            (TYPE_SFIXED32, Wire::Fixed32(v)) => (v as i32).to_string(),
+            (TYPE_STRING, Wire::Bytes(bytes)) => format!("{:?}", String::from_utf8_lossy(bytes)),
+            // Encoded differently than the descriptor says, shown as it is
+            (_, value) => unknown(value),
+        }
+    }
+}
+
+/// The values of a packed repeated field of scalar type `kind`
+fn packed(kind: u64, data: &[u8]) -> Result<Vec<Wire<'_>>> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        values.push(match kind {
+            TYPE_DOUBLE | TYPE_FIXED64 | TYPE_SFIXED64 => {
+                Wire::Fixed64(u64::from_le_bytes(take(data, &mut pos, 8)?.try_into()?))
+            }
+            TYPE_FLOAT | TYPE_FIXED32 | TYPE_SFIXED32 => {
+                Wire::Fixed32(u32::from_le_bytes(take(data, &mut pos, 4)?.try_into()?))
+            }
+            _ => Wire::Varint(varint(data, &mut pos)?),
+        });
+    }
+    Ok(values)
+}
+
+/// A value without a field in the descriptors
+fn unknown(value: Wire) -> String {
+    match value {
+        Wire::Varint(v) => v.to_string(),
+        Wire::Fixed64(v) => format!("0x{v:016x}"),
+        Wire::Fixed32(v) => format!("0x{v:08x}"),
+        Wire::Bytes(bytes) => bytes_literal(bytes),
+    }
+}
+
+/// Bytes as a string of the text format, escaping what is not printable ASCII
+fn bytes_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            0x20..=0x7e => literal.push(char::from(b)),
+            _ => literal.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Decodes messages of one type
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    schema: Arc<Schema>,
+    message: String,
+}
+
+impl Decoder {
+    /// Reads the descriptor set at `path`, which has to contain the type `message`
+    pub fn load(path: &Path, message: &str) -> Result<Decoder> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let schema = Schema::parse(&data)
+            .with_context(|| format!("{} is not a descriptor set", path.display()))?;
+        let message = message.trim_start_matches('.').to_string();
+        if !schema.messages.contains_key(&message) {
+            let mut names: Vec<_> = schema.messages.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            bail!(
+                "No message type {message} in {}, it has {}",
+                path.display(),
+                names.join(", ")
+            );
+        }
+        Ok(Decoder {
+            schema: Arc::new(schema),
+            message,
+        })
+    }
+
+    /// A message in the text format
+    pub fn format(&self, data: &[u8]) -> Result<String> {
+        self.schema.format(&self.message, data, 0)
+    }
+}
+
+/// Splits a stream of messages, each preceded by its length as a varint
+#[derive(Debug)]
+pub struct Delimited {
+    decoder: Decoder,
+    /// Start of a message that was not all received yet
+    buffer: Vec<u8>,
+}
+
+impl Delimited {
+    pub fn new(decoder: Decoder) -> Delimited {
+        Delimited {
+            decoder,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Returns the messages completed by `data` in the text format
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<String>> {
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+        loop {
+            let mut pos = 0;
+            let len = match varint(&self.buffer, &mut pos) {
+                Ok(len) => len,
+                Err(_) if self.buffer.len() < MAX_VARINT_LEN => break,
+                Err(e) => {
+                    messages.push(Err(e));
+                    self.buffer.clear();
+                    break;
+                }
+            };
+            let Some(len) = usize::try_from(len)
+                .ok()
+                .filter(|&len| len <= MAX_MESSAGE_LEN)
+            else {
+                // Where the next message starts cannot be told
+                messages.push(Err(anyhow!("Message of {len} bytes is too long")));
+                self.buffer.clear();
+                break;
+            };
+            let Some(message) = self.buffer.get(pos..pos + len) else {
+                break;
+            };
+            messages.push(self.decoder.format(message));
+            self.buffer.drain(..pos + len);
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_varint(mut v: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+        out
+    }
+
+    fn varint_field(number: u64, v: u64) -> Vec<u8> {
+        [encode_varint(number << 3), encode_varint(v)].concat()
+    }
+
+    fn bytes_field(number: u64, bytes: &[u8]) -> Vec<u8> {
+        [
+            encode_varint(number << 3 | 2),
+            encode_varint(bytes.len() as u64),
+            bytes.to_vec(),
+        ]
+        .concat()
+    }
+
+    /// A FieldDescriptorProto
+    fn field(name: &str, number: u64, kind: u64, type_name: &str) -> Vec<u8> {
+        let mut field = [
+            bytes_field(1, name.as_bytes()),
+            varint_field(3, number),
+            varint_field(5, kind),
+        ]
+        .concat();
+        if !type_name.is_empty() {
+            field.extend(bytes_field(6, type_name.as_bytes()));
+        }
+        bytes_field(2, &field)
+    }
+
+    /// A descriptor set of package `test` with the message `Reading`, an `Inner` message nested
+    /// in it and the enum `State`
+    fn descriptor_set() -> Vec<u8> {
+        let inner = [bytes_field(1, b"Inner"), field("ok", 1, TYPE_BOOL, "")].concat();
+        let reading = [
+            bytes_field(1, b"Reading"),
+            field("id", 1, TYPE_UINT32, ""),
+            field("temp", 2, TYPE_SINT32, ""),
+            field("name", 3, TYPE_STRING, ""),
+            field("state", 4, TYPE_ENUM, ".test.State"),
+            field("inner", 5, TYPE_MESSAGE, ".test.Reading.Inner"),
+            field("values", 6, TYPE_INT32, ""),
+            field("ratio", 7, TYPE_FLOAT, ""),
+            bytes_field(3, &inner),
+        ]
+        .concat();
+        let value = |name: &str, number: i32| {
+            bytes_field(
+                2,
+                &[
+                    bytes_field(1, name.as_bytes()),
+                    varint_field(2, i64::from(number) as u64),
+                ]
+                .concat(),
+            )
+        };
+        let state = [
+            bytes_field(1, b"State"),
+            value("IDLE", 0),
+            value("ERROR", -1),
+        ]
+        .concat();
+        let file = [
+            bytes_field(1, b"test.proto"),
+            bytes_field(2, b"test"),
+            bytes_field(4, &reading),
+            bytes_field(5, &state),
+        ]
+        .concat();
+        bytes_field(1, &file)
+    }
+
+    fn decoder() -> Decoder {
+        Decoder {
+            schema: Arc::new(Schema::parse(&descriptor_set()).unwrap()),
+            message: "test.Reading".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_nested_types_of_the_descriptor_set() {
+        let schema = Schema::parse(&descriptor_set()).unwrap();
+        let mut messages: Vec<_> = schema.messages.keys().cloned().collect();
+        messages.sort_unstable();
+        assert_eq!(messages, ["test.Reading", "test.Reading.Inner"]);
+        assert_eq!(schema.enums["test.State"][&-1], "ERROR");
+    }
+
+    #[test]
+    fn formats_messages_in_the_text_format() {
+        let message = [
+            varint_field(1, 300),
+            // Zigzag encoded -3
+            varint_field(2, 5),
+            bytes_field(3, b"a\"b"),
+            varint_field(4, u64::MAX),
+            bytes_field(5, &varint_field(1, 1)),
+            // Packed, with a negative int32 sign extended to ten bytes
+            bytes_field(6, &[encode_varint(7), encode_varint(u64::MAX)].concat()),
+            [encode_varint(7 << 3 | 5), 1.5f32.to_le_bytes().to_vec()].concat(),
+        ]
+        .concat();
+        assert_eq!(
+            decoder().format(&message).unwrap(),
+            r#"id: 300 temp: -3 name: "a\"b" state: ERROR inner { ok: true } values: 7 values: -1 ratio: 1.5"#
+        );
+        assert_eq!(decoder().format(&bytes_field(5, &[])).unwrap(), "inner {}");
+    }
+
+    #[test]
+    fn shows_unknown_fields_by_number() {
+        let message = [
+            varint_field(9, 42),
+            bytes_field(10, b"\x00A\\"),
+            [encode_varint(11 << 3 | 1), 1u64.to_le_bytes().to_vec()].concat(),
+            // A string field encoded as a varint
+            varint_field(3, 1),
+        ]
+        .concat();
+        assert_eq!(
+            decoder().format(&message).unwrap(),
+            r#"9: 42 10: "\x00A\\" 11: 0x0000000000000001 name: 1"#
+        );
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let decoder = decoder();
+        // A truncated varint, a length running past the end, an unsupported wire type and a
+        // varint longer than ten bytes
+        assert!(decoder.format(&[0x08, 0x80]).is_err());
+        assert!(decoder.format(&[0x1a, 0x05, b'a']).is_err());
+        assert!(decoder.format(&[0x0b]).is_err());
+        assert!(
+            decoder
+                .format(&[[0x08].as_slice(), &[0xff; 10], &[0x01]].concat())
+                .is_err()
+        );
+        // A packed fixed32 field of the wrong length
+        assert!(decoder.format(&bytes_field(7, &[0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn delimited_joins_messages_split_across_pushes() {
+        let messages = [varint_field(1, 1), vec![], varint_field(1, 300)];
+        let stream: Vec<u8> = messages
+            .iter()
+            .flat_map(|message| [encode_varint(message.len() as u64), message.clone()].concat())
+            .collect();
+        for split in [1, 2, stream.len()] {
+            let mut delimited = Delimited::new(decoder());
+            let formatted: Vec<_> = stream
+                .chunks(split)
+                .flat_map(|chunk| delimited.push(chunk))
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(
+                formatted,
+                ["id: 1", "", "id: 300"],
+                "split every {split} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn delimited_drops_an_overlong_message() {
+        let mut delimited = Delimited::new(decoder());
+        let len = encode_varint(MAX_MESSAGE_LEN as u64 + 1);
+        let results = delimited.push(&len);
+        assert!(matches!(results[..], [Err(_)]));
+        let results = delimited.push(&[0x02, 0x08, 0x01]);
+        assert_eq!(
+            results.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            ["id: 1"]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Pseudo-terminals standing in for a serial port
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// A pseudo-terminal pair, with the master side used by the bridge
+#[derive(Debug)]
+pub struct Pty {
+    pub master: File,
+    /// Kept open so the master does not see a hangup while no program uses the terminal
+    _slave: File,
+    /// Device path of the slave side, e.g. `/dev/pts/3`
+    pub path: PathBuf,
+    /// Symbolic link pointing at `path`, removed when the pty is dropped
+    link: Option<PathBuf>,
+}
+
+impl Pty {
+    /// Makes the terminal reachable under `link` as well, replacing an existing symbolic link
+    pub fn link(&mut self, link: &Path) -> Result<()> {
+        if link
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink())
+        {
+            std::fs::remove_file(link)
+                .with_context(|| format!("Could not remove {}", link.display()))?;
+        }
+        symlink(&self.path, link)
+            .with_context(|| format!("Could not create {}", link.display()))?;
+        self.link = Some(link.to_path_buf());
+        Ok(())
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_original: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Allocates a pseudo-terminal in raw mode
+#[cfg(unix)]
+pub fn open() -> Result<Pty> {
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let mut master = 0;
+    let mut slave = 0;
+    // SAFETY: openpty only writes the two descriptors, name, termios and size are optional
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: both descriptors were just opened and are owned by nothing else
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+    let mut name = [0 as libc::c_char; 128];
+    // SAFETY: the buffer length is passed along, termios is fully initialized by tcgetattr
+    unsafe {
+        if libc::ttyname_r(slave.as_raw_fd(), name.as_mut_ptr(), name.len()) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // Pass bytes through untouched, programs using the terminal set it up as they need
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    // SAFETY: ttyname_r wrote a NUL terminated string into the buffer
+    let path = unsafe { CStr::from_ptr(name.as_ptr()) };
+
+    Ok(Pty {
+        master: File::from(master),
+        _slave: File::from(slave),
+        path: PathBuf::from(path.to_string_lossy().into_owned()),
+        link: None,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn open() -> Result<Pty> {
+    anyhow::bail!("Pseudo-terminals are only available on Linux and macOS")
+}
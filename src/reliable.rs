@@ -0,0 +1,230 @@
+//! Reliable transfers for firmwares implementing this protocol, `--reliable`
+//!
+//! Notifications can be dropped on a congested link without anyone noticing. With this,
+//! the data in both directions travels in numbered frames that are acknowledged and
+//! retransmitted until they arrive. Every frame is COBS encoded and ends with a 0x00 byte:
+//!
+//! - `01 seq data… crc` carries data, `seq` counting up from 0 after every connection and
+//!   wrapping around after 255
+//! - `02 seq crc` acknowledges every data frame before `seq`, the one expected next
+//! - `03 seq crc` asks for `seq` and every frame after it again, sent when a frame was
+//!   corrupted or one is missing
+//!
+//! `crc` is the CRC-16/XMODEM of the bytes before it, big-endian. Up to [`WINDOW`] data
+//! frames are sent before waiting for acknowledgements; when the oldest is not acknowledged
+//! within [`RETRANSMIT_TIMEOUT`] all of them are sent again (go-back-N). A receiver passes
+//! the data on in order, drops frames it got already and acknowledges the ones it takes.
+
+use crate::cobs;
+use crate::xmodem::crc16;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+const DATA: u8 = 0x01;
+const ACK: u8 = 0x02;
+const NAK: u8 = 0x03;
+
+/// Most data frames sent and not acknowledged yet
+pub const WINDOW: usize = 8;
+
+/// Time after which unacknowledged frames are sent again
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Retransmissions of a frame after which the device is reported as not answering
+pub const MAX_RETRIES: u32 = 10;
+
+/// Bytes a frame adds to its data: kind, number, CRC and the COBS overhead of a single write
+pub const OVERHEAD: usize = 4 + 3;
+
+/// Both directions of the protocol for one connection, shared by the writer and the
+/// receiving side
+#[derive(Debug, Default)]
+pub struct Channel {
+    state: Mutex<State>,
+    /// Woken when acknowledgements make room in the window
+    acked: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Number of the next data frame sent
+    next: u8,
+    /// Data frames sent and not acknowledged yet, oldest first
+    unacked: VecDeque<Sent>,
+    /// Number of the data frame expected next
+    expected: u8,
+    /// Whether the expected frame was asked for, which is only done once until it arrives
+    nak_sent: bool,
+    decoder: cobs::Decoder,
+}
+
+#[derive(Debug)]
+struct Sent {
+    seq: u8,
+    data: Vec<u8>,
+    sent: Instant,
+    retries: u32,
+}
+
+/// What received data held
+#[derive(Debug, Default)]
+pub struct Received {
+    /// Data of the frames taken, in order
+    pub data: Vec<u8>,
+    /// Frames to write in reply, acknowledgements and retransmissions
+    pub replies: Vec<Vec<u8>>,
+}
+
+/// Frames sent again since the oldest was not acknowledged in time
+#[derive(Debug)]
+pub struct Retransmission {
+    pub frames: Vec<Vec<u8>>,
+    /// How often the oldest frame was sent again
+    pub retries: u32,
+}
+
+impl Channel {
+    /// Waits for room in the window, then returns the frame carrying `data` for writing
+    pub async fn send(&self, data: &[u8]) -> Vec<u8> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.unacked.len() < WINDOW {
+                    return state.push(data);
+                }
+            }
+            self.acked.notified().await;
+        }
+    }
+
+    /// Returns the frame carrying `data` right away, beyond the window, for writes that
+    /// cannot wait for acknowledgements
+    pub fn send_now(&self, data: &[u8]) -> Vec<u8> {
+        self.state.lock().unwrap().push(data)
+    }
+
+    /// Handles received data, returns the data of the frames it completed
+    pub fn receive(&self, data: &[u8]) -> Received {
+        let mut state = self.state.lock().unwrap();
+        let mut received = Received::default();
+        let mut ack = false;
+        for frame in state.decoder.push(data) {
+            let Some((kind, seq, data)) = frame.as_deref().and_then(parse) else {
+                state.ask_again(&mut received.replies);
+                continue;
+            };
+            match kind {
+                DATA if seq == state.expected => {
+                    received.data.extend_from_slice(data);
+                    state.expected = seq.wrapping_add(1);
+                    state.nak_sent = false;
+                    ack = true;
+                }
+                // Received already, the acknowledgement might have been lost
+                DATA if usize::from(state.expected.wrapping_sub(seq)) <= WINDOW => ack = true,
+                DATA => state.ask_again(&mut received.replies),
+                ACK => {
+                    state.acknowledge(seq);
+                    self.acked.notify_one();
+                }
+                NAK => {
+                    state.acknowledge(seq);
+                    self.acked.notify_one();
+                    received.replies.extend(state.resend());
+                }
+                _ => {}
+            }
+        }
+        if ack {
+            received.replies.push(frame(ACK, state.expected, &[]));
+        }
+        received
+    }
+
+    /// Frames to send again if the oldest was not acknowledged in time
+    pub fn due(&self) -> Option<Retransmission> {
+        let mut state = self.state.lock().unwrap();
+        let oldest = state.unacked.front()?;
+        if oldest.sent.elapsed() < RETRANSMIT_TIMEOUT {
+            return None;
+        }
+        let retries = oldest.retries + 1;
+        Some(Retransmission {
+            frames: state.resend(),
+            retries,
+        })
+    }
+
+    /// Starts over for a new connection, returns the frames not acknowledged on the last
+    /// one, numbered anew
+    pub fn reset(&self) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let unacked = std::mem::take(&mut state.unacked);
+        *state = State::default();
+        unacked.iter().map(|sent| state.push(&sent.data)).collect()
+    }
+}
+
+impl State {
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        let seq = self.next;
+        self.next = seq.wrapping_add(1);
+        self.unacked.push_back(Sent {
+            seq,
+            data: data.to_vec(),
+            sent: Instant::now(),
+            retries: 0,
+        });
+        frame(DATA, seq, data)
+    }
+
+    /// Drops the frames before `next`, if it is one of those sent
+    fn acknowledge(&mut self, next: u8) {
+        if let Some(oldest) = self.unacked.front() {
+            let count = usize::from(next.wrapping_sub(oldest.seq));
+            if count <= self.unacked.len() {
+                self.unacked.drain(..count);
+            }
+        }
+    }
+
+    /// Frames of all unacknowledged data, sent again
+    fn resend(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        self.unacked
+            .iter_mut()
+            .map(|sent| {
+                sent.sent = now;
+                sent.retries += 1;
+                frame(DATA, sent.seq, &sent.data)
+            })
+            .collect()
+    }
+
+    /// Asks for the expected frame after a corrupted or missing one
+    fn ask_again(&mut self, replies: &mut Vec<Vec<u8>>) {
+        if !self.nak_sent {
+            self.nak_sent = true;
+            replies.push(frame(NAK, self.expected, &[]));
+        }
+    }
+}
+
+fn frame(kind: u8, seq: u8, data: &[u8]) -> Vec<u8> {
+    let mut content = Vec::with_capacity(data.len() + 4);
+    content.extend_from_slice(&[kind, seq]);
+    content.extend_from_slice(data);
+    content.extend_from_slice(&crc16(&content).to_be_bytes());
+    cobs::encode(&content)
+}
+
+/// Splits a decoded frame into kind, number and data, `None` if it is corrupted
+fn parse(content: &[u8]) -> Option<(u8, u8, &[u8])> {
+    let (body, crc) = content.split_at_checked(content.len().checked_sub(2)?)?;
+    if body.len() < 2 || crc16(body).to_be_bytes() != crc {
+        return None;
+    }
+    Some((body[0], body[1], &body[2..]))
+}
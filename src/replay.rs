@@ -0,0 +1,422 @@
+//! Playback of recorded sessions with their original timing
+//!
+//! Recordings made with `--record` carry the time of every chunk of output. Logs written
+//! with `--log-timestamps` are played line by line at the times they were stamped with;
+//! lines without a stamp follow the one before them.
+
+use crate::cli::ReplayArgs;
+use crate::connect::RawTerminal;
+use crate::json;
+use crate::timestamp;
+use anyhow::{Context, Result, bail};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::{ExecutableCommand, terminal};
+use jiff::civil::{DateTime, Time};
+use jiff::fmt::strtime;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How far a Left or Right key press moves
+const SEEK_STEP: Duration = Duration::from_secs(10);
+/// How often the position shown in the title is updated
+const TITLE_REFRESH: Duration = Duration::from_millis(500);
+const SPEEDS: std::ops::RangeInclusive<f64> = 1.0 / 64.0..=64.0;
+
+/// Output written at a time after the start of the session
+#[derive(Debug)]
+struct Chunk {
+    time: Duration,
+    data: Vec<u8>,
+}
+
+pub fn run(args: &ReplayArgs) -> Result<()> {
+    if !SPEEDS.contains(&args.speed) {
+        bail!("--speed must be between 1/64 and 64");
+    }
+    let text = std::fs::read(&args.file)
+        .with_context(|| format!("Could not read {}", args.file.display()))?;
+    let mut chunks = load(&args.file, &text, &args.timestamp_format)?;
+    if let Some(max_idle) = args.max_idle {
+        compress(&mut chunks, Duration::from_secs_f64(max_idle.max(0.0)));
+    }
+    let end = chunks.last().map_or(Duration::ZERO, |chunk| chunk.time);
+
+    let _raw_terminal = RawTerminal::enter()?;
+    let mut out = io::stdout();
+    let mut clock = Clock::new(args.speed);
+    let mut next = 0;
+    let mut title = String::new();
+    loop {
+        let position = clock.position().min(end);
+        while let Some(chunk) = chunks.get(next).filter(|chunk| chunk.time <= position) {
+            out.write_all(&chunk.data)?;
+            next += 1;
+        }
+        out.flush()?;
+        let state = if next == chunks.len() {
+            "end"
+        } else if clock.paused() {
+            "paused"
+        } else {
+            "playing"
+        };
+        let current = format!(
+            "replay {} / {} at {}x, {state}",
+            clock_time(position),
+            clock_time(end),
+            clock.speed
+        );
+        if current != title {
+            out.execute(terminal::SetTitle(&current))?;
+            title = current;
+        }
+
+        let wait = match chunks.get(next) {
+            Some(chunk) if !clock.paused() => clock.until(chunk.time).min(TITLE_REFRESH),
+            _ => TITLE_REFRESH,
+        };
+        if !event::poll(wait)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind == KeyEventKind::Release {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Char(' ') => clock.toggle_pause(),
+            KeyCode::Char('+') | KeyCode::Up => clock.set_speed(clock.speed * 2.0),
+            KeyCode::Char('-') | KeyCode::Down => clock.set_speed(clock.speed / 2.0),
+            KeyCode::Right => clock.seek(position.saturating_add(SEEK_STEP).min(end)),
+            KeyCode::Left => {
+                // Output cannot be taken back, so the screen is rebuilt from the start
+                let target = position.saturating_sub(SEEK_STEP);
+                out.write_all(b"\x1b[0m\x1b[2J\x1b[H")?;
+                next = 0;
+                clock.seek(target);
+            }
+            KeyCode::Home => {
+                out.write_all(b"\x1b[0m\x1b[2J\x1b[H")?;
+                next = 0;
+                clock.seek(Duration::ZERO);
+            }
+            KeyCode::End => clock.seek(end),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Position in the session, moving at the playback speed unless paused
+struct Clock {
+    speed: f64,
+    /// Position when the clock last started or was moved
+    base: Duration,
+    /// When it started, `None` while paused
+    started: Option<Instant>,
+}
+
+impl Clock {
+    fn new(speed: f64) -> Clock {
+        Clock {
+            speed,
+            base: Duration::ZERO,
+            started: Some(Instant::now()),
+        }
+    }
+
+    fn position(&self) -> Duration {
+        match self.started {
+            Some(started) => self.base + started.elapsed().mul_f64(self.speed),
+            None => self.base,
+        }
+    }
+
+    fn paused(&self) -> bool {
+        self.started.is_none()
+    }
+
+    /// Real time until the playback reaches `time`
+    fn until(&self, time: Duration) -> Duration {
+        time.saturating_sub(self.position()).div_f64(self.speed)
+    }
+
+    fn toggle_pause(&mut self) {
+        self.base = self.position();
+        self.started = match self.started {
+            Some(_) => None,
+            None => Some(Instant::now()),
+        };
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.seek(self.position());
+        self.speed = speed.clamp(*SPEEDS.start(), *SPEEDS.end());
+    }
+
+    fn seek(&mut self, position: Duration) {
+        self.base = position;
+        if self.started.is_some() {
+            self.started = Some(Instant::now());
+        }
+    }
+}
+
+/// Reads a recording, or a log file if it does not start with an asciinema header
+fn load(path: &Path, text: &[u8], timestamp_format: &str) -> Result<Vec<Chunk>> {
+    let header = text.split(|&b| b == b'\n').next().unwrap_or_default();
+    if header.starts_with(b"{") && header.windows(9).any(|w| w == b"\"version\"") {
+        let text = std::str::from_utf8(text)
+            .with_context(|| format!("{} is not a valid recording", path.display()))?;
+        return load_recording(text)
+            .with_context(|| format!("Invalid recording {}", path.display()));
+    }
+    let chunks = load_log(text, timestamp_format);
+    if chunks.iter().all(|chunk| chunk.time.is_zero()) {
+        bail!(
+            "{} has no timestamps, write logs with --log-timestamps or record with --record",
+            path.display()
+        );
+    }
+    Ok(chunks)
+}
+
+/// The output events of an asciinema v2 recording, input events are skipped
+fn load_recording(text: &str) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    // The first line is the header
+    for (number, line) in text.lines().enumerate().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((time, strings)) = json::parse_event(line) else {
+            bail!("line {} is not an event", number + 1);
+        };
+        if let [kind, data] = strings.as_slice()
+            && kind == "o"
+        {
+            chunks.push(Chunk {
+                time: Duration::from_secs_f64(time.max(0.0)),
+                data: data.clone().into_bytes(),
+            });
+        }
+    }
+    Ok(chunks)
+}
+
+/// The lines of a log with timestamps, relative to the first stamp
+fn load_log(text: &[u8], timestamp_format: &str) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut first = None;
+    let mut previous = None;
+    // Times of day start over after midnight
+    let mut days = Duration::ZERO;
+    let mut time = Duration::ZERO;
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        let (stamp, rest) = split_stamp(line, timestamp_format);
+        if let Some(stamp) = stamp {
+            if let Some(previous) = previous
+                && stamp < previous
+            {
+                days += Duration::from_secs(24 * 60 * 60);
+            }
+            previous = Some(stamp);
+            let first = *first.get_or_insert(stamp);
+            time = (stamp + days).saturating_sub(first);
+        }
+        // The raw terminal needs a CR to return to the start of the line
+        let mut data = Vec::with_capacity(rest.len() + 1);
+        match rest.strip_suffix(b"\n") {
+            Some(content) if !content.ends_with(b"\r") => {
+                data.extend_from_slice(content);
+                data.extend_from_slice(b"\r\n");
+            }
+            _ => data.extend_from_slice(rest),
+        }
+        chunks.push(Chunk { time, data });
+    }
+    chunks
+}
+
+/// Splits a `[stamp] ` prefix off `line`, giving the time it shows as a duration
+fn split_stamp<'a>(line: &'a [u8], timestamp_format: &str) -> (Option<Duration>, &'a [u8]) {
+    let parsed = line.strip_prefix(b"[").and_then(|rest| {
+        let end = rest.iter().position(|&b| b == b']')?;
+        let stamp = std::str::from_utf8(&rest[..end]).ok()?;
+        let rest = rest[end + 1..]
+            .strip_prefix(b" ")
+            .unwrap_or(&rest[end + 1..]);
+        Some((parse_stamp(stamp, timestamp_format)?, rest))
+    });
+    match parsed {
+        Some((stamp, rest)) => (Some(stamp), rest),
+        None => (None, line),
+    }
+}
+
+fn parse_stamp(stamp: &str, timestamp_format: &str) -> Option<Duration> {
+    // Relative stamps are seconds since the start
+    if let Ok(seconds) = stamp.parse::<f64>() {
+        return Some(Duration::from_secs_f64(seconds.max(0.0)));
+    }
+    // Logs written with --log-timestamps alone carry the date as well
+    let parsed = [timestamp_format, timestamp::LOG_FORMAT]
+        .into_iter()
+        .find_map(|format| strtime::parse(format, stamp).ok())?;
+    let since = match parsed.to_datetime() {
+        Ok(datetime) => datetime.duration_since(DateTime::ZERO),
+        Err(_) => parsed.to_time().ok()?.duration_since(Time::midnight()),
+    };
+    Duration::try_from(since).ok()
+}
+
+/// Shortens every pause between chunks to at most `max_idle`
+fn compress(chunks: &mut [Chunk], max_idle: Duration) {
+    let mut removed = Duration::ZERO;
+    let mut previous = Duration::ZERO;
+    for chunk in chunks {
+        let gap = chunk.time.saturating_sub(previous);
+        previous = chunk.time;
+        removed += gap.saturating_sub(max_idle);
+        chunk.time -= removed;
+    }
+}
+
+/// `duration` as H:MM:SS
+fn clock_time(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Times in milliseconds and data of `chunks`
+    fn timeline(chunks: &[Chunk]) -> Vec<(u128, String)> {
+        chunks
+            .iter()
+            .map(|chunk| {
+                let data = String::from_utf8(chunk.data.clone()).unwrap();
+                (chunk.time.as_millis(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plays_the_output_events_of_recordings() {
+        let recording = concat!(
+            "{\"version\": 2, \"width\": 80, \"height\": 24, \"timestamp\": 1700000000}\n",
+            "[0.250000, \"o\", \"uart:~$ \"]\n",
+            "[1.000000, \"i\", \"help\\r\"]\n",
+            "\n",
+            "[1.500000, \"o\", \"help\\r\\n\\u001b[1mbold\\u001b[0m\"]\n",
+        );
+        let chunks = load(Path::new("session.cast"), recording.as_bytes(), "").unwrap();
+        assert_eq!(
+            timeline(&chunks),
+            [
+                (250, "uart:~$ ".to_string()),
+                (1500, "help\r\n\x1b[1mbold\x1b[0m".to_string())
+            ]
+        );
+
+        let broken = "{\"version\": 2}\n[0.5, \"o\"\n";
+        let e = load(Path::new("broken.cast"), broken.as_bytes(), "").unwrap_err();
+        assert_eq!(
+            format!("{e:#}"),
+            "Invalid recording broken.cast: line 2 is not an event"
+        );
+    }
+
+    #[test]
+    fn plays_logs_at_their_timestamps() {
+        let log = concat!(
+            "[23:59:59.500] booting\n",
+            "no stamp, follows the line before\r\n",
+            "[00:00:01.000] after midnight\n",
+            "[00:00:01.250]tight\n",
+        );
+        let chunks = load(
+            Path::new("device.log"),
+            log.as_bytes(),
+            timestamp::DEFAULT_FORMAT,
+        );
+        assert_eq!(
+            timeline(&chunks.unwrap()),
+            [
+                (0, "booting\r\n".to_string()),
+                (0, "no stamp, follows the line before\r\n".to_string()),
+                (1500, "after midnight\r\n".to_string()),
+                (1750, "tight\r\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn plays_logs_with_dates_or_relative_stamps() {
+        let log = "[2024-02-29 10:00:00.000] a\n[2024-02-29 10:00:02.500] b";
+        let chunks = load(
+            Path::new("device.log"),
+            log.as_bytes(),
+            timestamp::DEFAULT_FORMAT,
+        );
+        assert_eq!(
+            timeline(&chunks.unwrap()),
+            [(0, "a\r\n".to_string()), (2500, "b".to_string())]
+        );
+
+        let log = "[0.125] a\n[3.000] b\n";
+        let chunks = load(
+            Path::new("device.log"),
+            log.as_bytes(),
+            timestamp::DEFAULT_FORMAT,
+        );
+        assert_eq!(
+            timeline(&chunks.unwrap()),
+            [(0, "a\r\n".to_string()), (2875, "b\r\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn logs_without_timestamps_are_rejected() {
+        let e = load(
+            Path::new("plain.log"),
+            b"one\ntwo\n",
+            timestamp::DEFAULT_FORMAT,
+        );
+        assert!(e.unwrap_err().to_string().contains("has no timestamps"));
+    }
+
+    #[test]
+    fn compresses_pauses() {
+        let mut chunks: Vec<Chunk> = [0, 1, 10, 11, 41]
+            .into_iter()
+            .map(|seconds| Chunk {
+                time: Duration::from_secs(seconds),
+                data: Vec::new(),
+            })
+            .collect();
+        compress(&mut chunks, Duration::from_secs(2));
+        let times: Vec<u64> = chunks.iter().map(|chunk| chunk.time.as_secs()).collect();
+        assert_eq!(times, [0, 1, 3, 4, 6]);
+    }
+
+    #[test]
+    fn clock_times() {
+        assert_eq!(clock_time(Duration::from_millis(59_999)), "0:00:59");
+        assert_eq!(
+            clock_time(Duration::from_secs(3 * 3600 + 4 * 60 + 5)),
+            "3:04:05"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use crate::adapter;
+use crate::cli::ScanArgs;
+use crate::device::{self, DeviceInfo};
+use crate::json;
+use anyhow::Result;
+use btleplug::api::CentralState;
+use btleplug::platform::Adapter;
+use log::info;
+use std::time::Duration;
+
+/// Runs the `scan` subcommand
+pub async fn run(central: &Adapter, args: &ScanArgs) -> Result<()> {
+    info!("Scanning for {} seconds", args.duration);
+    let service = args.service_uuid.unwrap_or(args.protocol.uuids().service);
+    let services = if args.all { Vec::new() } else { vec![service] };
+    device::scan(central, Duration::from_secs(args.duration), &services).await?;
+
+    let mut devices = device::discover(central).await?;
+    if !args.all {
+        devices.retain(|d| d.advertises(service));
+    }
+
+    if args.json {
+        println!("{}", to_json(&devices));
+    } else {
+        print_table(&devices);
+    }
+    Ok(())
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Manufacturer data entries ordered by company ID, with hex-encoded payloads
+fn manufacturer_data(device: &DeviceInfo) -> Vec<(u16, String)> {
+    let mut entries = device
+        .manufacturer_data
+        .iter()
+        .map(|(id, data)| (*id, hex(data)))
+        .collect::<Vec<_>>();
+    entries.sort();
+    entries
+}
+
+fn print_table(devices: &[DeviceInfo]) {
+    let name_width = devices
+        .iter()
+        .map(|d| d.display_name().len())
+        .chain(["NAME".len()])
+        .max()
+        .unwrap_or(0);
+    let address_width = devices
+        .iter()
+        .map(|d| d.address.len())
+        .chain(["ADDRESS".len()])
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "{:name_width$}  {:address_width$}  {:>5}  SERVICES / MANUFACTURER DATA",
+        "NAME", "ADDRESS", "RSSI"
+    );
+    for d in devices {
+        let rssi = d
+            .rssi
+            .map(|r| r.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let services = d
+            .services
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let manufacturer_data = manufacturer_data(d)
+            .iter()
+            .map(|(id, data)| format!("{id:04x}:{data}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        println!(
+            "{:name_width$}  {:address_width$}  {rssi:>5}  {services} {manufacturer_data}",
+            d.display_name(),
+            d.address,
+        );
+    }
+}
+
+fn to_json(devices: &[DeviceInfo]) -> String {
+    let entries = devices
+        .iter()
+        .map(|d| {
+            let services = d
+                .services
+                .iter()
+                .map(|s| json::string(&s.to_string()))
+                .collect::<Vec<_>>()
+                .join(",");
+            let manufacturer_data = manufacturer_data(d)
+                .iter()
+                .map(|(id, data)| format!("\"{id}\":{}", json::string(data)))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                "{{\"name\":{},\"address\":{},\"rssi\":{},\"services\":[{services}],\"manufacturer_data\":{{{manufacturer_data}}}}}",
+                json::opt_string(d.name.as_deref()),
+                json::string(&d.address),
+                json::opt_number(d.rssi),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("[{entries}]")
+}
+
+/// Runs the `list-adapters` subcommand
+pub async fn list_adapters() -> Result<()> {
+    let adapters = adapter::list().await?;
+    if adapters.is_empty() {
+        info!("No bluetooth adapter found");
+        return Ok(());
+    }
+
+    let name_width = adapters
+        .iter()
+        .map(|a| a.name().len())
+        .chain(["NAME".len()])
+        .max()
+        .unwrap_or(0);
+    println!("INDEX  {:name_width$}  {:17}  STATE", "NAME", "ADDRESS");
+    for a in &adapters {
+        let state = match a.state {
+            CentralState::PoweredOn => "on",
+            CentralState::PoweredOff => "off",
+            CentralState::Unknown => "unknown",
+        };
+        println!(
+            "{:5}  {:name_width$}  {:17}  {state}",
+            a.index,
+            a.name(),
+            a.address.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,787 @@
+use crate::ansi;
+use crate::cbor;
+use crate::cobs;
+use crate::decode::{self, Decoder};
+use crate::defmt;
+use crate::highlight::{self, Highlight};
+use crate::length_prefix;
+use crate::mouse;
+use crate::nmea;
+use crate::plot::Plot;
+use crate::protobuf;
+use crate::slip;
+use crate::timestamp::{Kind, Timestamps};
+use crate::vt::{self, Grid};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Received output with a scrollback buffer
+///
+/// Output keeps accumulating while the view is scrolled back; the view stays on the same
+/// content until scrolled to the bottom again.
+#[derive(Debug)]
+pub struct Screen {
+    lines: VecDeque<String>,
+    partial: String,
+    capacity: usize,
+    /// Number of lines the view is scrolled back from the bottom
+    offset: usize,
+    decoder: Decoder,
+    /// Line ending used by the device
+    newline: Newline,
+    /// Whether the last received byte was a CR, which an LF may follow in the next chunk
+    after_cr: bool,
+    /// Frames text mode output is made of, if any
+    framing: Option<Framing>,
+    /// How the frames of `framing` are shown
+    frame_format: FrameFormat,
+    timestamps: Option<Timestamps>,
+    /// Whether received lines are prefixed with `timestamps`
+    show_timestamps: bool,
+    /// Whether the next device output starts a new line
+    at_line_start: bool,
+    highlights: Vec<Highlight>,
+    /// Whether the terminal bell is to be rung
+    bell: bool,
+    /// View the device's lines are copied to, shared with other devices
+    merged: Option<MergedFeed>,
+    /// Woken whenever there is something new to show
+    redraw: Option<Arc<Notify>>,
+    /// Lines shown out of those received, all without it
+    filter: Option<Filter>,
+    /// Bytes of output added since the view was frozen, `None` unless it is
+    frozen: Option<u64>,
+    /// Pattern searched for, its matches are highlighted
+    search: Option<Highlight>,
+    /// Lines selected for copying, from the anchor to the cursor
+    selection: Option<Selection>,
+    /// Mouse events the device asked for
+    mouse: mouse::Reporting,
+    /// The screen of a full-screen program, while the device is on the alternate screen
+    grid: Option<Grid>,
+    /// Size a grid is created with, that of the output pane
+    grid_size: (u16, u16),
+    /// Start of an alternate screen sequence the chunk ended in
+    held: String,
+    /// Output not printed yet with `--inline`, where the terminal itself shows it
+    inline: Option<String>,
+    /// GPS fix followed in the NMEA sentences received, with `--nmea`
+    nmea: Option<nmea::Tracker>,
+    /// Values plotted from the lines received, with `--plot`
+    plot: Option<Plot>,
+}
+
+/// Lines selected for copying, as positions counted from the bottom like the scroll offset
+///
+/// Positions move up along with their lines as new ones arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Selection {
+    /// Where the selection was started
+    pub anchor: usize,
+    /// The end moved by the keys or the mouse
+    pub cursor: usize,
+}
+
+impl Selection {
+    pub fn contains(&self, position: usize) -> bool {
+        (self.anchor.min(self.cursor)..=self.anchor.max(self.cursor)).contains(&position)
+    }
+}
+
+/// Start of the messages of the terminal itself, which a filter never hides
+const STATUS_PREFIX: &str = "\x1b[7m[nus-terminal] ";
+
+/// Pattern picking the lines that are shown, everything is still kept and logged
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub pattern: Regex,
+    /// Whether matching lines are hidden instead of being the only ones shown
+    pub invert: bool,
+}
+
+impl Filter {
+    fn shows(&self, line: &str) -> bool {
+        if line.starts_with(STATUS_PREFIX) {
+            return true;
+        }
+        self.pattern.is_match(&plain(line)) != self.invert
+    }
+}
+
+/// The text of `line` as displayed, without escape sequences, for matching it
+fn plain(line: &str) -> String {
+    ansi::render(line).cells.iter().map(|&(c, _)| c).collect()
+}
+
+/// Copies the complete lines of a device into a view interleaving those of several devices
+#[derive(Debug)]
+struct MergedFeed {
+    view: Arc<Mutex<Screen>>,
+    /// Put in front of every line, naming the device
+    label: String,
+    /// Output of the current line, copied once it is complete
+    partial: String,
+}
+
+impl MergedFeed {
+    fn push(&mut self, text: &str) {
+        let mut parts = text.split('\n');
+        if let Some(first) = parts.next() {
+            self.partial.push_str(first);
+        }
+        for part in parts {
+            let line = std::mem::replace(&mut self.partial, part.to_string());
+            // Reset at the end, so colors of the device do not run into the next label
+            self.view
+                .lock()
+                .unwrap()
+                .output(&format!("{}{line}\x1b[0m\n", self.label));
+        }
+    }
+}
+
+/// Framing of packets sent by the device
+#[derive(Debug)]
+pub enum Framing {
+    /// defmt log frames
+    Defmt(defmt::Decoder),
+    /// COBS frames, shown as hex dumps
+    Cobs(cobs::Decoder),
+    /// SLIP frames, shown as hex dumps
+    Slip(slip::Decoder),
+    /// Frames starting with their length, shown as hex dumps
+    Prefixed(length_prefix::Decoder),
+    /// CBOR data items without framing, shown in diagnostic notation
+    Cbor(cbor::Sequence),
+    /// Protobuf messages preceded by their length, shown in the text format
+    Protobuf(protobuf::Delimited),
+}
+
+/// How the frames of COBS, SLIP or length-prefixed framing are shown
+#[derive(Debug, Clone)]
+pub enum FrameFormat {
+    Hex,
+    /// The CBOR data items of each frame in diagnostic notation
+    Cbor,
+    /// Each frame as a protobuf message in the text format
+    Protobuf(protobuf::Decoder),
+}
+
+impl FrameFormat {
+    fn show(&self, frame: &Option<Vec<u8>>) -> String {
+        match (self, frame) {
+            (FrameFormat::Hex, _) | (_, None) => frame_dump(frame),
+            (FrameFormat::Cbor, Some(frame)) => frame_cbor(frame),
+            (FrameFormat::Protobuf(decoder), Some(frame)) => match decoder.format(frame) {
+                Ok(text) => format!("{text}\r\n"),
+                Err(e) => invalid_frame("protobuf", &e, frame),
+            },
+        }
+    }
+}
+
+/// A line ending
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Newline {
+    Cr,
+    Lf,
+    Crlf,
+}
+
+impl Newline {
+    pub fn bytes(self) -> &'static [u8] {
+        match self {
+            Newline::Cr => b"\r",
+            Newline::Lf => b"\n",
+            Newline::Crlf => b"\r\n",
+        }
+    }
+
+    /// Replaces the LF or CRLF line endings of local input by this one
+    pub fn translate(self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            let line = &rest[..end];
+            out.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+            out.extend_from_slice(self.bytes());
+            rest = &rest[end + 1..];
+        }
+        out.extend_from_slice(rest);
+        out
+    }
+}
+
+impl Screen {
+    /// Creates a screen for a device ending its lines with `newline`
+    ///
+    /// LF always starts a new line, a CR only does with [`Newline::Cr`], where an LF following
+    /// it is dropped. Otherwise a CR moves back to the start of the line.
+    pub fn new(capacity: usize, mode: decode::Mode, newline: Newline) -> Screen {
+        Screen {
+            lines: VecDeque::new(),
+            partial: String::new(),
+            capacity: capacity.max(1),
+            offset: 0,
+            decoder: Decoder::new(mode),
+            newline,
+            after_cr: false,
+            framing: None,
+            frame_format: FrameFormat::Hex,
+            timestamps: None,
+            show_timestamps: false,
+            at_line_start: true,
+            highlights: Vec::new(),
+            bell: false,
+            merged: None,
+            redraw: None,
+            filter: None,
+            frozen: None,
+            search: None,
+            selection: None,
+            mouse: mouse::Reporting::default(),
+            grid: None,
+            grid_size: (80, 24),
+            held: String::new(),
+            inline: None,
+            nmea: None,
+            plot: None,
+        }
+    }
+
+    /// Wakes `redraw` whenever output is added or the bell rung
+    pub fn set_redraw(&mut self, redraw: Arc<Notify>) {
+        self.redraw = Some(redraw);
+    }
+
+    fn changed(&self) {
+        if let Some(redraw) = &self.redraw {
+            redraw.notify_one();
+        }
+    }
+
+    /// Copies every complete line of device output to `view` as well, after `label`
+    pub fn set_merged(&mut self, view: Arc<Mutex<Screen>>, label: String) {
+        self.merged = Some(MergedFeed {
+            view,
+            label,
+            partial: String::new(),
+        });
+    }
+
+    pub fn ring_bell(&mut self) {
+        self.bell = true;
+        self.changed();
+    }
+
+    /// Whether the bell was rung since the last call
+    pub fn take_bell(&mut self) -> bool {
+        std::mem::take(&mut self.bell)
+    }
+
+    /// Shows only the lines `filter` picks, or all of them again
+    pub fn set_filter(&mut self, filter: Option<Filter>) {
+        self.filter = filter;
+        self.offset = 0;
+        self.changed();
+    }
+
+    pub fn filter(&self) -> Option<&Filter> {
+        self.filter.as_ref()
+    }
+
+    fn shows(&self, line: &str) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.shows(line))
+    }
+
+    /// Keeps the view on what it shows now while output goes on collecting below it
+    pub fn freeze(&mut self) {
+        self.frozen.get_or_insert(0);
+    }
+
+    /// Follows the output again from the bottom
+    pub fn resume(&mut self) {
+        self.frozen = None;
+        self.offset = 0;
+        self.changed();
+    }
+
+    /// Bytes of output collected since the view was frozen, if it is
+    pub fn frozen(&self) -> Option<u64> {
+        self.frozen
+    }
+
+    /// Highlights the matches of `pattern`, or none
+    pub fn set_search(&mut self, pattern: Option<Regex>) {
+        self.search = pattern.map(highlight::search);
+        self.changed();
+    }
+
+    pub fn search(&self) -> Option<&Highlight> {
+        self.search.as_ref()
+    }
+
+    /// Scrolls to the nearest line matching the search, older than the line `offset` lines
+    /// from the bottom or newer, or that line itself with `inclusive`
+    ///
+    /// The matching line ends up at the bottom of the view. Returns whether there was one.
+    pub fn find(&mut self, offset: usize, older: bool, inclusive: bool) -> bool {
+        let Some(search) = &self.search else {
+            return false;
+        };
+        let matches = |(_, line): &(usize, &str)| search.matches(&plain(line));
+        let mut lines = self.shown_rev().enumerate();
+        let found = if older {
+            let skip = if inclusive { offset } else { offset + 1 };
+            lines.nth(skip).into_iter().chain(lines).find(matches)
+        } else {
+            let end = if inclusive { offset + 1 } else { offset };
+            let newer: Vec<_> = lines.take(end).collect();
+            newer.into_iter().rev().find(matches)
+        };
+        match found {
+            Some((position, _)) => {
+                self.offset = position;
+                self.changed();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Selects the line at `position`, counted from the bottom, or extends the selection to it
+    pub fn select(&mut self, position: usize, extend: bool) {
+        let position = position.min(self.total_lines() - 1);
+        self.selection = match self.selection {
+            Some(selection) if extend => Some(Selection {
+                cursor: position,
+                ..selection
+            }),
+            _ => Some(Selection {
+                anchor: position,
+                cursor: position,
+            }),
+        };
+        self.changed();
+    }
+
+    /// Scrolls just enough for the line at `position` to be among the bottom `rows` lines
+    pub fn reveal(&mut self, position: usize, rows: usize) {
+        if position < self.offset {
+            self.offset = position;
+        } else if position >= self.offset + rows {
+            self.offset = position + 1 - rows;
+        }
+    }
+
+    pub fn selection(&self) -> Option<Selection> {
+        self.selection
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection = None;
+        self.changed();
+    }
+
+    /// The text of the selected lines, oldest first, without escape sequences
+    pub fn selected_text(&self) -> Option<String> {
+        let selection = self.selection?;
+        let mut lines: Vec<String> = self
+            .shown_rev()
+            .enumerate()
+            .filter(|&(position, _)| selection.contains(position))
+            .map(|(_, line)| plain(line))
+            .collect();
+        lines.reverse();
+        Some(lines.join("\n"))
+    }
+
+    /// The mouse reporting switched on by the device
+    pub fn mouse(&self) -> &mouse::Reporting {
+        &self.mouse
+    }
+
+    /// The screen of the full-screen program running on the device, if one is
+    pub fn grid(&self) -> Option<&Grid> {
+        self.grid.as_ref()
+    }
+
+    /// Keeps what is shown for printing it to the terminal, which takes care of the
+    /// alternate screen itself
+    pub fn set_inline(&mut self) {
+        self.inline = Some(String::new());
+    }
+
+    /// Output added since the last call, with `--inline`
+    pub fn take_inline(&mut self) -> String {
+        self.inline.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// The last line, which is not complete yet
+    pub fn partial(&self) -> &str {
+        &self.partial
+    }
+
+    /// Sets the size of the grid to that of the output pane
+    pub fn resize_grid(&mut self, width: u16, height: u16) {
+        if self.grid_size == (width, height) {
+            return;
+        }
+        self.grid_size = (width, height);
+        if let Some(grid) = &mut self.grid {
+            grid.resize(width, height);
+            self.changed();
+        }
+    }
+
+    pub fn set_highlights(&mut self, highlights: Vec<Highlight>) {
+        self.highlights = highlights;
+    }
+
+    /// Rules highlighting parts of the output when it is drawn
+    pub fn highlights(&self) -> &[Highlight] {
+        &self.highlights
+    }
+
+    /// Sets how received lines are timestamped and whether they are right away
+    pub fn set_timestamps(&mut self, timestamps: Timestamps, show: bool) {
+        self.timestamps = Some(timestamps);
+        self.show_timestamps = show;
+    }
+
+    /// The kind of timestamps received lines are prefixed with, if any
+    pub fn timestamps_shown(&self) -> Option<Kind> {
+        let timestamps = self.timestamps.as_ref()?;
+        self.show_timestamps.then(|| timestamps.kind())
+    }
+
+    /// Switches to the next kind of timestamps, from none through all kinds, returns the
+    /// new kind
+    pub fn cycle_timestamps(&mut self) -> Option<Kind> {
+        let timestamps = self.timestamps.as_mut()?;
+        let kind = Kind::next(self.show_timestamps.then(|| timestamps.kind()));
+        if let Some(kind) = kind {
+            timestamps.set_kind(kind);
+        }
+        self.show_timestamps = kind.is_some();
+        kind
+    }
+
+    /// Decodes output received in text mode as frames
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = Some(framing);
+    }
+
+    /// Sets how the frames of COBS, SLIP or length-prefixed framing are shown
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.frame_format = format;
+    }
+
+    /// Follows the GPS fix in the NMEA sentences received from now on
+    pub fn follow_nmea(&mut self) {
+        self.nmea = Some(nmea::Tracker::default());
+    }
+
+    /// The GPS fix, if NMEA sentences are followed
+    pub fn nmea(&self) -> Option<&nmea::Fix> {
+        self.nmea.as_ref().map(nmea::Tracker::fix)
+    }
+
+    /// Plots the values `plot` takes from the lines received from now on
+    pub fn set_plot(&mut self, plot: Plot) {
+        self.plot = Some(plot);
+    }
+
+    pub fn plot(&self) -> Option<&Plot> {
+        self.plot.as_ref()
+    }
+
+    /// Appends data received from the device, decoded according to the current mode
+    pub fn receive(&mut self, data: &[u8]) {
+        self.mouse.scan(data);
+        if let Some(nmea) = &mut self.nmea {
+            nmea.push(data);
+        }
+        if let Some(plot) = &mut self.plot {
+            plot.push(data);
+        }
+        if let Some(framing) = &mut self.framing
+            && self.decoder.mode() == decode::Mode::Text
+        {
+            let format = &self.frame_format;
+            let show = |frames: Vec<Option<Vec<u8>>>| -> String {
+                frames.iter().map(|frame| format.show(frame)).collect()
+            };
+            let text = match framing {
+                Framing::Defmt(decoder) => decoder.decode(data),
+                Framing::Cobs(decoder) => show(decoder.push(data)),
+                Framing::Slip(decoder) => show(decoder.push(data)),
+                Framing::Prefixed(decoder) => show(decoder.push(data)),
+                Framing::Cbor(sequence) => sequence.push(data).iter().map(cbor_line).collect(),
+                Framing::Protobuf(stream) => stream.push(data).iter().map(protobuf_line).collect(),
+            };
+            self.device_output(&text);
+        } else if self.newline == Newline::Cr && self.mode() == decode::Mode::Text {
+            let data = self.translate_cr(data);
+            let text = self.decoder.decode(&data);
+            self.device_output(&text);
+        } else {
+            let text = self.decoder.decode(data);
+            self.device_output(&text);
+        }
+    }
+
+    /// Appends decoded device output, drawing it on the grid while on the alternate screen
+    fn device_output(&mut self, text: &str) {
+        if self.inline.is_some() {
+            self.line_output(text);
+            return;
+        }
+        let mut text = std::mem::take(&mut self.held) + text;
+        loop {
+            if let Some(grid) = &mut self.grid {
+                let Some(rest) = grid.feed(&text) else {
+                    self.changed();
+                    return;
+                };
+                self.grid = None;
+                text.drain(..rest);
+            } else if let Some((start, len)) = alternate_screen(&text) {
+                self.line_output(&text[..start]);
+                self.grid = Some(Grid::new(self.grid_size.0, self.grid_size.1));
+                text.drain(..start + len);
+            } else {
+                // A sequence split between notifications is completed by the next one
+                if let Some(start) = text.rfind('\x1b')
+                    && vt::ENTER_SEQUENCES
+                        .iter()
+                        .any(|sequence| sequence.starts_with(&text[start..]))
+                {
+                    self.held = text.split_off(start);
+                }
+                self.line_output(&text);
+                return;
+            }
+        }
+    }
+
+    /// Appends device output to the lines, prefixing new lines with timestamps when shown
+    fn line_output(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let mut stamped = String::with_capacity(text.len());
+        for piece in text.split_inclusive('\n') {
+            if self.at_line_start
+                && self.show_timestamps
+                && let Some(timestamps) = &mut self.timestamps
+            {
+                stamped.push_str(&format!("\x1b[2m{}\x1b[0m ", timestamps.stamp()));
+            }
+            stamped.push_str(piece);
+            self.at_line_start = piece.ends_with('\n');
+        }
+        if let Some(merged) = &mut self.merged {
+            merged.push(&stamped);
+        }
+        self.output(&stamped);
+    }
+
+    /// Shows input sent to the device, for firmware that does not echo it
+    pub fn echo(&mut self, data: &[u8]) {
+        let mut text = Vec::with_capacity(data.len());
+        let mut bytes = data.iter().copied().peekable();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'\r' => {
+                    bytes.next_if_eq(&b'\n');
+                    text.push(b'\n');
+                }
+                // Erases the character, as a shell echoing backspace does
+                0x08 | 0x7f => text.extend_from_slice(b"\x08 \x08"),
+                // The sequences of keys like the arrows move nothing in the output
+                0x1b => {
+                    if bytes.next_if(|&b| b == b'[' || b == b'O').is_some() {
+                        for b in bytes.by_ref() {
+                            if (0x40..=0x7e).contains(&b) {
+                                break;
+                            }
+                        }
+                    }
+                }
+                b'\n' | b'\t' => text.push(b),
+                0..0x20 => {}
+                b => text.push(b),
+            }
+        }
+        self.device_output(&String::from_utf8_lossy(&text));
+    }
+
+    /// Turns CR and CR LF into LF
+    fn translate_cr(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            match b {
+                b'\r' => out.push(b'\n'),
+                b'\n' if self.after_cr => {}
+                _ => out.push(b),
+            }
+            self.after_cr = b == b'\r';
+        }
+        out
+    }
+
+    pub fn newline(&self) -> Newline {
+        self.newline
+    }
+
+    pub fn mode(&self) -> decode::Mode {
+        self.decoder.mode()
+    }
+
+    pub fn toggle_hex(&mut self) {
+        let text = self.decoder.toggle_hex();
+        self.grid = None;
+        self.device_output(&text);
+    }
+
+    /// Appends device output
+    pub fn output(&mut self, text: &str) {
+        self.changed();
+        if let Some(inline) = &mut self.inline {
+            inline.push_str(text);
+        }
+        if let Some(frozen) = &mut self.frozen {
+            *frozen += text.len() as u64;
+        }
+        let mut parts = text.split('\n');
+        if let Some(first) = parts.next() {
+            self.partial.push_str(first);
+        }
+        for part in parts {
+            let line = std::mem::replace(&mut self.partial, part.to_string());
+            let shown = self.shows(&line);
+            self.lines.push_back(line);
+            if self.lines.len() > self.capacity {
+                self.lines.pop_front();
+            }
+            if let Some(selection) = &mut self.selection
+                && shown
+            {
+                selection.anchor += 1;
+                selection.cursor += 1;
+            }
+            if (self.offset > 0 || self.frozen.is_some()) && shown {
+                // Keep the scrolled view on the same content, until it drops out at the top
+                self.offset = (self.offset + 1).min(self.lines.len());
+            }
+        }
+    }
+
+    /// Shows a message from the terminal itself, set apart from the device output
+    pub fn status(&mut self, msg: &str) {
+        if !self.partial.is_empty() {
+            self.output("\n");
+        }
+        self.output(&format!("{STATUS_PREFIX}{msg}\x1b[0m\n"));
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Scrolls the view back by `lines`, or forward if negative
+    pub fn scroll(&mut self, lines: isize) {
+        let max_offset = self.total_lines() - 1;
+        self.offset = self.offset.saturating_add_signed(lines).min(max_offset);
+    }
+
+    /// Lines from the bottom of the view up to the top of the buffer, including the
+    /// unterminated last line when not scrolled back
+    ///
+    /// With a filter only the lines it shows are included, besides the line still being
+    /// received, which is shown until it is complete.
+    pub fn view_rev(&self) -> impl Iterator<Item = &str> {
+        self.shown_rev().skip(self.offset)
+    }
+
+    /// The lines shown with the filter from the bottom up, as if not scrolled back
+    fn shown_rev(&self) -> impl Iterator<Item = &str> {
+        let lines = self
+            .lines
+            .iter()
+            .rev()
+            .map(String::as_str)
+            .filter(|line| self.shows(line));
+        std::iter::once(self.partial.as_str()).chain(lines)
+    }
+
+    fn total_lines(&self) -> usize {
+        match &self.filter {
+            Some(filter) => 1 + self.lines.iter().filter(|line| filter.shows(line)).count(),
+            None => self.lines.len() + 1,
+        }
+    }
+}
+
+/// Formats a received frame as a hex dump below a line with its length
+fn frame_dump(frame: &Option<Vec<u8>>) -> String {
+    match frame {
+        Some(frame) => format!(
+            "\x1b[2mframe, {} bytes\x1b[0m\r\n{}",
+            frame.len(),
+            decode::hex_dump(0, frame)
+        ),
+        None => "\x1b[31minvalid frame\x1b[0m\r\n".to_string(),
+    }
+}
+
+/// Formats a frame that could not be decoded, with the error and a hex dump
+fn invalid_frame(what: &str, e: &anyhow::Error, frame: &[u8]) -> String {
+    format!(
+        "\x1b[31minvalid {what}: {e}\x1b[0m\r\n{}",
+        decode::hex_dump(0, frame)
+    )
+}
+
+/// Formats the CBOR data items of a received frame in diagnostic notation, a line each
+fn frame_cbor(frame: &[u8]) -> String {
+    let mut text = String::new();
+    let mut rest = frame;
+    while !rest.is_empty() {
+        match cbor::decode(rest) {
+            Ok((value, len)) => {
+                text.push_str(&format!("{value}\r\n"));
+                rest = &rest[len..];
+            }
+            Err(e) => return invalid_frame("CBOR", &e, frame),
+        }
+    }
+    text
+}
+
+/// Formats a CBOR data item received without framing
+fn cbor_line(item: &anyhow::Result<cbor::Value>) -> String {
+    match item {
+        Ok(value) => format!("{value}\r\n"),
+        Err(e) => format!("\x1b[31minvalid CBOR: {e}\x1b[0m\r\n"),
+    }
+}
+
+/// Formats a protobuf message received with its length
+fn protobuf_line(message: &anyhow::Result<String>) -> String {
+    match message {
+        Ok(text) => format!("{text}\r\n"),
+        Err(e) => format!("\x1b[31minvalid protobuf: {e}\x1b[0m\r\n"),
+    }
+}
+
+/// Where the earliest sequence switching to the alternate screen starts in `text`, and its length
+fn alternate_screen(text: &str) -> Option<(usize, usize)> {
+    vt::ENTER_SEQUENCES
+        .iter()
+        .filter_map(|sequence| text.find(sequence).map(|start| (start, sequence.len())))
+        .min()
+}
@@ -0,0 +1,342 @@
+//! Expect-style scripts driving the device shell without a terminal
+//!
+//! A script is a list of commands, one per line:
+//!
+//! ```text
+//! # Wait for the prompt, then check the version
+//! timeout 5s
+//! send "\r"
+//! expect "uart:~$ "
+//! sendline "kernel version"
+//! expect /Zephyr version \d+\.\d+/ 2s
+//! log "version ok"
+//! ```
+
+use crate::cli::ScriptRunArgs;
+use crate::device;
+use crate::error::Error;
+use crate::link::{Link, LinkOptions};
+use crate::transport::{self, ByteStream, Transport};
+use anyhow::{Context, Result, anyhow, bail};
+use btleplug::platform::Adapter;
+use futures::stream::StreamExt;
+use log::{error, info};
+use regex::bytes::Regex;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Most received bytes kept for matching, older output is discarded
+const MAX_BUFFER_LEN: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum Step {
+    /// Writes the bytes to the device
+    Send(Vec<u8>),
+    /// Waits until the output matches, failing the script after the timeout
+    Expect {
+        pattern: Regex,
+        timeout: Option<Duration>,
+    },
+    /// Sets the timeout of the following expects
+    Timeout(Duration),
+    Sleep(Duration),
+    Log(String),
+    /// Fails the script with the given message
+    Fail(String),
+}
+
+/// A step along with the line it was read from
+#[derive(Debug)]
+pub struct Line {
+    pub number: usize,
+    pub step: Step,
+}
+
+pub fn load(path: &Path) -> Result<Vec<Line>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read script {}", path.display()))?;
+    parse(&content).with_context(|| format!("Invalid script {}", path.display()))
+}
+
+pub fn parse(content: &str) -> Result<Vec<Line>> {
+    let mut lines = Vec::new();
+    for (i, text) in content.lines().enumerate() {
+        let number = i + 1;
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+        let (command, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mut args = Args {
+            rest: rest.trim_start(),
+        };
+        let step = args
+            .step(command)
+            .and_then(|step| args.end().map(|()| step))
+            .map_err(|e| anyhow!("line {number}: {e}"))?;
+        lines.push(Line { number, step });
+    }
+    Ok(lines)
+}
+
+/// Arguments following the command on a line
+struct Args<'a> {
+    rest: &'a str,
+}
+
+impl Args<'_> {
+    /// Parses the arguments of `command`
+    fn step(&mut self, command: &str) -> Result<Step> {
+        Ok(match command {
+            "send" => Step::Send(self.string()?),
+            "sendline" => {
+                let mut line = self.string()?;
+                line.push(b'\r');
+                Step::Send(line)
+            }
+            "expect" => Step::Expect {
+                pattern: self.pattern()?,
+                timeout: self.optional_duration()?,
+            },
+            "timeout" => Step::Timeout(self.duration()?),
+            "sleep" => Step::Sleep(self.duration()?),
+            "log" => Step::Log(self.text()?),
+            "fail" => Step::Fail(self.text()?),
+            _ => bail!("unknown command '{command}'"),
+        })
+    }
+
+    /// Parses a double quoted string with C-like escapes, `\xNN` giving a raw byte
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let mut chars = self
+            .rest
+            .strip_prefix('"')
+            .ok_or(anyhow!("expected a quoted string"))?
+            .char_indices();
+        let mut s = Vec::new();
+        while let Some((i, c)) = chars.next() {
+            let c = match c {
+                '"' => {
+                    self.rest = self.rest[i + 2..].trim_start();
+                    return Ok(s);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('e') => '\x1b',
+                    Some('0') => '\0',
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                        let byte = u8::from_str_radix(&hex, 16)
+                            .map_err(|_| anyhow!("invalid escape sequence \\x{hex}"))?;
+                        s.push(byte);
+                        continue;
+                    }
+                    Some(c @ ('\\' | '"')) => c,
+                    Some(c) => bail!("invalid escape sequence \\{c}"),
+                    None => bail!("unterminated string"),
+                },
+                c => c,
+            };
+            s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        bail!("unterminated string")
+    }
+
+    /// Parses a quoted string meant for humans
+    fn text(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.string()?).into_owned())
+    }
+
+    /// Parses a `/regex/` or a quoted string matched literally
+    fn pattern(&mut self) -> Result<Regex> {
+        let source = if self.rest.starts_with('"') {
+            regex::escape(&self.text()?)
+        } else {
+            let body = self
+                .rest
+                .strip_prefix('/')
+                .ok_or(anyhow!("expected a /regex/ or a quoted string"))?;
+            // A slash ends the pattern unless it is escaped
+            let mut end = None;
+            let mut escaped = false;
+            for (i, c) in body.char_indices() {
+                match c {
+                    '\\' => escaped = !escaped,
+                    '/' if !escaped => {
+                        end = Some(i);
+                        break;
+                    }
+                    _ => escaped = false,
+                }
+            }
+            let end = end.ok_or(anyhow!("unterminated regex"))?;
+            self.rest = body[end + 1..].trim_start();
+            body[..end].replace("\\/", "/")
+        };
+        Regex::new(&source).map_err(|e| anyhow!("invalid regex: {e}"))
+    }
+
+    fn optional_duration(&mut self) -> Result<Option<Duration>> {
+        if self.rest.is_empty() || self.rest.starts_with('#') {
+            Ok(None)
+        } else {
+            self.duration().map(Some)
+        }
+    }
+
+    /// Parses a duration like `500ms`, `2s` or `2` (seconds)
+    fn duration(&mut self) -> Result<Duration> {
+        let (word, rest) = self
+            .rest
+            .split_once(char::is_whitespace)
+            .unwrap_or((self.rest, ""));
+        self.rest = rest.trim_start();
+        let duration = if let Some(ms) = word.strip_suffix("ms") {
+            ms.parse().map(Duration::from_millis)
+        } else {
+            word.strip_suffix('s')
+                .unwrap_or(word)
+                .parse()
+                .map(Duration::from_secs)
+        };
+        duration.map_err(|_| anyhow!("invalid duration '{word}'"))
+    }
+
+    fn end(&self) -> Result<()> {
+        match self.rest {
+            "" => Ok(()),
+            rest if rest.starts_with('#') => Ok(()),
+            rest => bail!("unexpected '{rest}'"),
+        }
+    }
+}
+
+/// Sends to the device and waits for its output
+pub struct Expecter {
+    transport: Box<dyn Transport>,
+    received: ByteStream,
+    /// Received output not consumed by a match yet
+    buffer: Vec<u8>,
+    /// Whether received output is copied to stdout
+    echo: bool,
+}
+
+impl Expecter {
+    pub fn new(transport: Box<dyn Transport>, received: ByteStream, echo: bool) -> Expecter {
+        Expecter {
+            transport,
+            received,
+            buffer: Vec::new(),
+            echo,
+        }
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.transport.write(data).await
+    }
+
+    /// Waits for `pattern` to show up in the output, false if it did not within `timeout`
+    ///
+    /// Output up to the end of the match is consumed.
+    pub async fn expect(&mut self, pattern: &Regex, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(m) = pattern.find(&self.buffer) {
+                self.buffer.drain(..m.end());
+                return Ok(true);
+            }
+            if !self.receive_until(deadline).await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Output received since the last match
+    pub fn unmatched(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Forgets the output received so far
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Keeps collecting output for the given time
+    pub async fn sleep(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        while self.receive_until(deadline).await? {}
+        Ok(())
+    }
+
+    /// Adds the next notification to the buffer, false if none arrived before `deadline`
+    async fn receive_until(&mut self, deadline: Instant) -> Result<bool> {
+        tokio::select! {
+            value = self.received.next() => {
+                let value = value.ok_or(Error::ConnectionLost)?;
+                if self.echo {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&value);
+                    let _ = stdout.flush();
+                }
+                self.buffer.extend_from_slice(&value);
+                if self.buffer.len() > MAX_BUFFER_LEN {
+                    self.buffer.drain(..self.buffer.len() - MAX_BUFFER_LEN);
+                }
+                Ok(true)
+            }
+            _ = tokio::time::sleep_until(deadline) => Ok(false),
+        }
+    }
+
+    pub async fn disconnect(&self) {
+        let _ = self.transport.disconnect().await;
+    }
+}
+
+/// Runs the script selected by `args`, returns whether it passed
+pub async fn run(central: &Adapter, args: &ScriptRunArgs) -> Result<bool> {
+    let script = load(&args.file)?;
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, notifications) =
+        Link::open(device.peripheral, &LinkOptions::from(&args.device)).await?;
+
+    let mut expecter = Expecter::new(Box::new(link), transport::bytes(notifications), !args.quiet);
+    let result = execute(&mut expecter, &script, Duration::from_secs(args.timeout)).await;
+    expecter.disconnect().await;
+    result
+}
+
+async fn execute(expecter: &mut Expecter, script: &[Line], timeout: Duration) -> Result<bool> {
+    let mut timeout = timeout;
+    for line in script {
+        match &line.step {
+            Step::Send(data) => expecter.send(data).await?,
+            Step::Expect {
+                pattern,
+                timeout: line_timeout,
+            } => {
+                let timeout = line_timeout.unwrap_or(timeout);
+                if !expecter.expect(pattern, timeout).await? {
+                    error!(
+                        "line {}: /{pattern}/ not received within {:.1}s",
+                        line.number,
+                        timeout.as_secs_f32()
+                    );
+                    return Ok(false);
+                }
+            }
+            Step::Timeout(duration) => timeout = *duration,
+            Step::Sleep(duration) => expecter.sleep(*duration).await?,
+            Step::Log(msg) => info!("{msg}"),
+            Step::Fail(msg) => {
+                error!("line {}: {msg}", line.number);
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,174 @@
+//! Checking that data written to an echoing device comes back intact and in order
+//!
+//! With `--emulate` the device is the built-in emulator, advertised on a second adapter of
+//! this machine, which tests the adapters and the Bluetooth stack on their own.
+
+use crate::adapter;
+use crate::bench::Counts;
+use crate::cli::SelfTestArgs;
+use crate::device;
+use crate::emulate::{self, Behavior, Emulation};
+use crate::link::{Link, LinkOptions};
+use crate::nus::NusUuids;
+use anyhow::{Result, bail};
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Adapter;
+use futures::stream::StreamExt;
+use log::{debug, info, warn};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+/// Name the emulator advertises with `--emulate`
+pub const EMULATOR_NAME: &str = "nus-self-test";
+
+/// Failed writes in a row after which the test is given up
+const MAX_FAILED_WRITES: usize = 10;
+
+/// Runs the test, returns whether all data came back intact
+pub async fn run(central: &Adapter, args: &SelfTestArgs) -> Result<bool> {
+    let Some(selector) = &args.emulate else {
+        return test(central, args).await;
+    };
+    let emulator = adapter::select(Some(selector)).await?;
+    let emulation = Emulation {
+        name: EMULATOR_NAME.to_string(),
+        behavior: Behavior::Echo,
+        interval: Duration::ZERO,
+        uuids: NusUuids::from(&args.device.uuids),
+    };
+    let (stop, stopped) = oneshot::channel();
+    let (served, passed) = tokio::join!(
+        emulate::serve(&emulator, &emulation, async {
+            let _ = stopped.await;
+        }),
+        async {
+            let passed = test(central, args).await;
+            let _ = stop.send(());
+            passed
+        },
+    );
+    // A failure to serve explains why the device could not be found
+    served?;
+    passed
+}
+
+/// Pseudo-random test data, reproducible from its seed
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u8
+    }
+}
+
+async fn test(central: &Adapter, args: &SelfTestArgs) -> Result<bool> {
+    if args.window == 0 {
+        bail!("--window must be at least 1 byte");
+    }
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, mut notifications) =
+        Link::open(device.peripheral.clone(), &LinkOptions::from(&args.device)).await?;
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos() as u64)
+            .unwrap_or_default()
+    });
+    // Zero would stay zero
+    let mut random = XorShift(seed.max(1));
+    let data: Vec<u8> = (0..args.bytes).map(|_| random.next()).collect();
+    let payload = link.max_payload();
+    info!(
+        "Connected to {}, MTU {}, sending {} bytes (seed {seed})",
+        device.display_name(),
+        link.mtu,
+        data.len()
+    );
+
+    let idle = Duration::from_millis(args.timeout);
+    let started = Instant::now();
+    let mut sent = Counts::default();
+    let mut received = Counts::default();
+    let mut failed = 0;
+    let mut failed_in_row = 0;
+    let mut corrupted = 0;
+    let mut first_difference = None;
+    let mut extra = 0;
+    let mut lost = false;
+    while received.bytes < data.len() {
+        // Only as much in flight as the device is expected to buffer
+        loop {
+            let in_flight = sent.bytes.saturating_sub(received.bytes);
+            if sent.bytes == data.len() || in_flight >= args.window {
+                break;
+            }
+            let room = args.window - in_flight;
+            let end = data.len().min(sent.bytes + payload.min(room));
+            match link.write(&data[sent.bytes..end]).await {
+                Ok(()) => {
+                    sent.packets += 1;
+                    sent.bytes = end;
+                    sent.elapsed = started.elapsed();
+                    failed_in_row = 0;
+                }
+                Err(e) => {
+                    debug!("Write failed: {e}");
+                    failed += 1;
+                    failed_in_row += 1;
+                    if failed_in_row == MAX_FAILED_WRITES {
+                        warn!("Giving up after {MAX_FAILED_WRITES} failed writes in a row");
+                        lost = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if lost {
+            break;
+        }
+        let value = match tokio::time::timeout(idle, notifications.next()).await {
+            Ok(Some(notification)) => notification.value,
+            Ok(None) => {
+                warn!("Connection lost");
+                break;
+            }
+            Err(_) => break,
+        };
+        for (i, &byte) in value.iter().enumerate() {
+            let offset = received.bytes + i;
+            match data.get(offset) {
+                Some(&expected) if expected == byte => {}
+                Some(_) => {
+                    corrupted += 1;
+                    first_difference.get_or_insert(offset);
+                }
+                None => extra += 1,
+            }
+        }
+        received.bytes += value.len();
+        received.packets += 1;
+        received.elapsed = started.elapsed();
+    }
+    let _ = link.peripheral.disconnect().await;
+
+    let missing = data.len().saturating_sub(received.bytes);
+    println!("Sent:      {}", sent.summary(None));
+    println!("Received:  {}", received.summary(None));
+    let passed = corrupted == 0 && missing == 0 && extra == 0 && failed == 0;
+    if passed {
+        println!("Integrity: OK, all {} bytes came back in order", data.len());
+    } else {
+        let mut problems = vec![format!("{corrupted} corrupted bytes")];
+        if let Some(offset) = first_difference {
+            problems[0].push_str(&format!(" (first at byte {offset})"));
+        }
+        problems.push(format!("{missing} missing bytes"));
+        problems.push(format!("{extra} extra bytes"));
+        problems.push(format!("{failed} failed writes"));
+        println!("Integrity: FAILED, {} (seed {seed})", problems.join(", "));
+    }
+    Ok(passed)
+}
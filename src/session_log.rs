@@ -0,0 +1,65 @@
+use crate::timestamp::Timestamps;
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Appends the data received from the device to a file
+#[derive(Debug)]
+pub struct SessionLog {
+    file: File,
+    path: PathBuf,
+    timestamps: Option<Timestamps>,
+    enabled: bool,
+    at_line_start: bool,
+}
+
+impl SessionLog {
+    pub fn open(path: &Path, timestamps: Option<Timestamps>) -> Result<SessionLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SessionLog {
+            file,
+            path: path.to_path_buf(),
+            timestamps,
+            enabled: true,
+            at_line_start: true,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn pause(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Pauses or resumes capturing, returns the new state
+    pub fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        self.enabled
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let Some(timestamps) = &mut self.timestamps else {
+            self.file.write_all(data)?;
+            return Ok(());
+        };
+
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.at_line_start {
+                write!(self.file, "[{}] ", timestamps.stamp())?;
+            }
+            self.file.write_all(line)?;
+            self.at_line_start = line.ends_with(b"\n");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Serial Line IP (RFC 1055), framing packets with a 0xC0 delimiter
+
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+
+/// Encodes `data` as a frame, between delimiters
+///
+/// The leading delimiter ends any noise the device received before the frame.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 64 + 2);
+    out.push(END);
+    for &b in data {
+        match b {
+            END => out.extend_from_slice(&[ESC, ESC_END]),
+            ESC => out.extend_from_slice(&[ESC, ESC_ESC]),
+            b => out.push(b),
+        }
+    }
+    out.push(END);
+    out
+}
+
+/// Splits received data into frames
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// Start of a frame whose delimiter was not received yet
+    buffer: Vec<u8>,
+    /// Whether the last byte received was an escape
+    escaped: bool,
+    /// Whether the frame in `buffer` contains an invalid escape
+    malformed: bool,
+}
+
+impl Decoder {
+    /// Returns the frames completed by `data`, `None` for the malformed ones
+    pub fn push(&mut self, data: &[u8]) -> Vec<Option<Vec<u8>>> {
+        let mut frames = Vec::new();
+        for &b in data {
+            if b == END {
+                // Empty frames are the delimiters of both ends
+                if !self.buffer.is_empty() || self.malformed || self.escaped {
+                    let malformed = self.malformed || self.escaped;
+                    let frame = std::mem::take(&mut self.buffer);
+                    frames.push((!malformed).then_some(frame));
+                }
+                self.escaped = false;
+                self.malformed = false;
+            } else if self.escaped {
+                self.escaped = false;
+                match b {
+                    ESC_END => self.buffer.push(END),
+                    ESC_ESC => self.buffer.push(ESC),
+                    _ => self.malformed = true,
+                }
+            } else if b == ESC {
+                self.escaped = true;
+            } else {
+                self.buffer.push(b);
+            }
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_escapes_the_delimiter_and_the_escape() {
+        assert_eq!(encode(&[]), [END, END]);
+        assert_eq!(
+            encode(&[0x01, END, 0x02, ESC, 0x03]),
+            [END, 0x01, ESC, ESC_END, 0x02, ESC, ESC_ESC, 0x03, END]
+        );
+        // The escaped values on their own are ordinary bytes
+        assert_eq!(encode(&[ESC_END, ESC_ESC]), [END, ESC_END, ESC_ESC, END]);
+    }
+
+    #[test]
+    fn decoder_joins_frames_split_across_pushes() {
+        let data: Vec<Vec<u8>> = vec![
+            vec![0x01],
+            vec![END, ESC, END, ESC],
+            (0..=0xff).collect(),
+            vec![ESC_END, ESC_ESC],
+        ];
+        let stream: Vec<u8> = data.iter().flat_map(|data| encode(data)).collect();
+        for split in [1, 2, 3, 7, 64, stream.len()] {
+            let mut decoder = Decoder::default();
+            let frames: Vec<_> = stream
+                .chunks(split)
+                .flat_map(|chunk| decoder.push(chunk))
+                .collect();
+            let expected: Vec<_> = data.iter().cloned().map(Some).collect();
+            assert_eq!(frames, expected, "split every {split} bytes");
+        }
+    }
+
+    #[test]
+    fn decoder_skips_empty_frames() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.push(&[END, END, END]),
+            Vec::<Option<Vec<u8>>>::new()
+        );
+        // A frame without a leading delimiter, as sent by RFC 1055's own code
+        assert_eq!(decoder.push(&[0x01, 0x02, END]), [Some(vec![0x01, 0x02])]);
+    }
+
+    #[test]
+    fn decoder_reports_malformed_frames() {
+        let mut decoder = Decoder::default();
+        // An invalid escape, and an escape right before the delimiter
+        assert_eq!(
+            decoder.push(&[END, 0x01, ESC, 0x02, 0x03, END, ESC, END]),
+            [None, None]
+        );
+        // The next frame is unaffected
+        assert_eq!(decoder.push(&[0x04, END]), [Some(vec![0x04])]);
+    }
+}
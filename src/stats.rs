@@ -0,0 +1,82 @@
+//! Statistics of a session, summarized when it ends and written with `--stats-json`
+
+use crate::json;
+use crate::link::LinkStatus;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// What went over the link of one device during the session
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub name: String,
+    pub address: String,
+    pub duration: Duration,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_notifications: u64,
+    pub reconnects: u32,
+}
+
+impl Stats {
+    /// The counts of `status` for a session that lasted `duration`
+    pub fn new(status: &LinkStatus, duration: Duration) -> Stats {
+        Stats {
+            name: status.name.clone(),
+            address: status.address.clone(),
+            duration,
+            tx_bytes: status.tx_bytes,
+            tx_packets: status.tx_packets,
+            rx_bytes: status.rx_bytes,
+            rx_notifications: status.rx_notifications,
+            reconnects: status.reconnects,
+        }
+    }
+
+    /// Notifications received per second on average
+    fn notification_rate(&self) -> f64 {
+        self.rx_notifications as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// A line like `sent 1234 bytes in 12 packets, received …`
+    pub fn summary(&self) -> String {
+        let seconds = self.duration.as_secs();
+        format!(
+            "{} ({}): {}:{:02}:{:02}, sent {} bytes in {} packets, received {} bytes in {} notifications ({:.1}/s), {} reconnects",
+            self.name,
+            self.address,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.tx_bytes,
+            self.tx_packets,
+            self.rx_bytes,
+            self.rx_notifications,
+            self.notification_rate(),
+            self.reconnects
+        )
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"address\":{},\"duration_s\":{:.3},\"tx_bytes\":{},\"tx_packets\":{},\"rx_bytes\":{},\"rx_notifications\":{},\"notifications_per_s\":{:.3},\"reconnects\":{}}}",
+            json::string(&self.name),
+            json::string(&self.address),
+            self.duration.as_secs_f64(),
+            self.tx_bytes,
+            self.tx_packets,
+            self.rx_bytes,
+            self.rx_notifications,
+            self.notification_rate(),
+            self.reconnects
+        )
+    }
+}
+
+/// Writes the statistics of every device as a JSON array, replacing a file at `path`
+pub fn write_json(path: &Path, stats: &[Stats]) -> Result<()> {
+    let entries: Vec<String> = stats.iter().map(Stats::to_json).collect();
+    std::fs::write(path, format!("[{}]\n", entries.join(",")))
+        .with_context(|| format!("Could not write {}", path.display()))
+}
@@ -0,0 +1,122 @@
+//! Integration with systemd for bridges run as services
+//!
+//! Readiness and status go to the socket in `$NOTIFY_SOCKET` (`Type=notify`), and with
+//! `--daemon` log records go to the journal with their priority and source location as
+//! fields. Without systemd both do nothing.
+
+use log::{Log, Metadata, Record};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+/// Socket of the journal's native protocol
+#[cfg(unix)]
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Set once log records go to the journal
+#[cfg(unix)]
+static JOURNAL: OnceLock<UnixDatagram> = OnceLock::new();
+
+/// Tells the service manager about the state of the bridge, e.g. `READY=1`
+///
+/// Does nothing unless started by systemd with `NotifyAccess` for the process.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Some(path) = std::env::var_os("NOTIFY_SOCKET")
+        && let Ok(socket) = UnixDatagram::unbound()
+    {
+        let path = path.to_string_lossy();
+        let sent = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return,
+            None => socket.send_to(state.as_bytes(), &*path),
+        };
+        if let Err(e) = sent {
+            log::debug!("Could not notify systemd: {e}");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Sends log records to the journal from now on, if the standard error goes there
+///
+/// systemd sets `$JOURNAL_STREAM` for services whose output is connected to the journal;
+/// run from a terminal, records keep going to the standard error.
+pub fn log_to_journal() {
+    #[cfg(unix)]
+    if std::env::var_os("JOURNAL_STREAM").is_some()
+        && let Ok(socket) = UnixDatagram::unbound()
+        && socket.connect(JOURNAL_SOCKET).is_ok()
+    {
+        let _ = JOURNAL.set(socket);
+    }
+}
+
+/// Logs to the standard error like `env_logger`, or to the journal after [`log_to_journal`]
+pub struct Logger(pub env_logger::Logger);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        #[cfg(unix)]
+        if let Some(journal) = JOURNAL.get()
+            && journal.send(&entry(record)).is_ok()
+        {
+            return;
+        }
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// A record in the journal's native protocol, one `NAME=value` field per line
+#[cfg(unix)]
+fn entry(record: &Record) -> Vec<u8> {
+    let priority = match record.level() {
+        log::Level::Error => "3",
+        log::Level::Warn => "4",
+        log::Level::Info => "6",
+        log::Level::Debug | log::Level::Trace => "7",
+    };
+    let mut entry = Vec::new();
+    let mut field = |name: &str, value: &str| {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // Values spanning lines are sent with their length instead
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    };
+    field("MESSAGE", &record.args().to_string());
+    field("PRIORITY", priority);
+    field("SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+    field("CODE_MODULE", record.target());
+    if let Some(file) = record.file() {
+        field("CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        field("CODE_LINE", &line.to_string());
+    }
+    entry
+}
@@ -0,0 +1,237 @@
+//! Headless test runs against the device shell, reported in JUnit format
+//!
+//! ```toml
+//! timeout = 5              # seconds each expectation may take, default 10
+//!
+//! [[test]]
+//! name = "version"
+//! send = "kernel version\r"
+//! expect = ["Zephyr version \\d+\\.\\d+", "uart:~\\$ "]
+//! timeout = 2
+//! ```
+
+use crate::cli::TestArgs;
+use crate::config;
+use crate::device;
+use crate::link::{Link, LinkOptions};
+use crate::script::Expecter;
+use crate::toml::{self, Table, Value};
+use crate::transport;
+use anyhow::{Context, Result, anyhow, bail};
+use btleplug::platform::Adapter;
+use log::{error, info};
+use regex::bytes::Regex;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of unmatched output bytes included in a failure report
+const FAILURE_OUTPUT_LEN: usize = 1024;
+
+#[derive(Debug)]
+struct TestCase {
+    name: String,
+    send: Option<Vec<u8>>,
+    /// Patterns that have to match in this order
+    expect: Vec<Regex>,
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct TestResult {
+    name: String,
+    time: Duration,
+    /// Why the test failed, with the output that did not match
+    failure: Option<(String, String)>,
+}
+
+fn load(path: &Path) -> Result<Vec<TestCase>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read test file {}", path.display()))?;
+    let table = toml::parse(&content)
+        .with_context(|| format!("Could not parse test file {}", path.display()))?;
+    parse(&table).with_context(|| format!("Invalid test file {}", path.display()))
+}
+
+fn parse(table: &Table) -> Result<Vec<TestCase>> {
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut tests = Vec::new();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("timeout", _) => timeout = Duration::from_secs(config::integer(key, value)?),
+            ("test", Value::Array(items)) => tests = items.iter().collect(),
+            ("test", _) => bail!("'test' must be an array of tables ([[test]])"),
+            _ => bail!("Unknown setting '{key}'"),
+        }
+    }
+
+    tests
+        .into_iter()
+        .enumerate()
+        .map(|(i, test)| match test {
+            Value::Table(test) => {
+                test_case(test, timeout).with_context(|| format!("Invalid test {}", i + 1))
+            }
+            _ => bail!("Test {} must be a table", i + 1),
+        })
+        .collect()
+}
+
+fn test_case(table: &Table, default_timeout: Duration) -> Result<TestCase> {
+    let mut test = TestCase {
+        name: String::new(),
+        send: None,
+        expect: Vec::new(),
+        timeout: default_timeout,
+    };
+    for (key, value) in table {
+        match key.as_str() {
+            "name" => test.name = config::string(key, value)?,
+            "send" => test.send = Some(config::string(key, value)?.into_bytes()),
+            "expect" => {
+                let patterns = match value {
+                    Value::Array(items) => items
+                        .iter()
+                        .map(|item| config::string(key, item))
+                        .collect::<Result<Vec<_>>>()?,
+                    _ => vec![config::string(key, value)?],
+                };
+                test.expect = patterns
+                    .iter()
+                    .map(|p| Regex::new(p).map_err(|e| anyhow!("invalid regex: {e}")))
+                    .collect::<Result<_>>()?;
+            }
+            "timeout" => test.timeout = Duration::from_secs(config::integer(key, value)?),
+            _ => bail!("Unknown setting '{key}'"),
+        }
+    }
+    if test.name.is_empty() {
+        bail!("'name' is required");
+    }
+    Ok(test)
+}
+
+/// Runs all tests, returns whether they passed
+pub async fn run(central: &Adapter, args: &TestArgs) -> Result<bool> {
+    let tests = load(&args.file)?;
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, notifications) =
+        Link::open(device.peripheral, &LinkOptions::from(&args.device)).await?;
+
+    let mut expecter = Expecter::new(Box::new(link), transport::bytes(notifications), false);
+    let started = Instant::now();
+    let mut results = Vec::with_capacity(tests.len());
+    for test in &tests {
+        let result = match run_test(&mut expecter, test).await {
+            Ok(result) => result,
+            Err(e) => {
+                expecter.disconnect().await;
+                return Err(e.context(format!("Test {} could not be run", test.name)));
+            }
+        };
+        match &result.failure {
+            None => info!("PASS {}", test.name),
+            Some((msg, _)) => error!("FAIL {}: {msg}", test.name),
+        }
+        results.push(result);
+    }
+    expecter.disconnect().await;
+
+    let failures = results.iter().filter(|r| r.failure.is_some()).count();
+    info!(
+        "{} of {} tests passed",
+        results.len() - failures,
+        results.len()
+    );
+
+    let report = junit_report(&args.file, &results, started.elapsed());
+    match &args.report {
+        Some(path) => std::fs::write(path, report)
+            .with_context(|| format!("Could not write report {}", path.display()))?,
+        None => print!("{report}"),
+    }
+    Ok(failures == 0)
+}
+
+async fn run_test(expecter: &mut Expecter, test: &TestCase) -> Result<TestResult> {
+    let started = Instant::now();
+    // Output left over from earlier tests must not satisfy this one
+    expecter.clear();
+    if let Some(data) = &test.send {
+        expecter.send(data).await?;
+    }
+
+    let mut failure = None;
+    for pattern in &test.expect {
+        if !expecter.expect(pattern, test.timeout).await? {
+            let output = expecter.unmatched();
+            let output = &output[output.len().saturating_sub(FAILURE_OUTPUT_LEN)..];
+            failure = Some((
+                format!(
+                    "/{pattern}/ not received within {:.1}s",
+                    test.timeout.as_secs_f32()
+                ),
+                String::from_utf8_lossy(output).into_owned(),
+            ));
+            break;
+        }
+    }
+    Ok(TestResult {
+        name: test.name.clone(),
+        time: started.elapsed(),
+        failure,
+    })
+}
+
+fn junit_report(path: &Path, results: &[TestResult], time: Duration) -> String {
+    let failures = results.iter().filter(|r| r.failure.is_some()).count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" errors=\"0\" time=\"{:.3}\">",
+        xml_escape(&path.display().to_string()),
+        results.len(),
+        time.as_secs_f64()
+    );
+    for result in results {
+        let _ = write!(
+            xml,
+            "  <testcase name=\"{}\" classname=\"nus-terminal\" time=\"{:.3}\"",
+            xml_escape(&result.name),
+            result.time.as_secs_f64()
+        );
+        match &result.failure {
+            Some((msg, output)) => {
+                let _ = writeln!(
+                    xml,
+                    ">\n    <failure message=\"{}\">{}</failure>\n  </testcase>",
+                    xml_escape(msg),
+                    xml_escape(output)
+                );
+            }
+            None => xml.push_str("/>\n"),
+        }
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+/// Escapes text for XML content and attributes, dropping characters XML cannot represent
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,82 @@
+//! Timestamps prefixed to received lines
+
+use anyhow::{Result, anyhow};
+use jiff::fmt::strtime;
+use std::time::{Duration, Instant};
+
+/// Format of absolute timestamps unless given with `--timestamp-format`
+pub const DEFAULT_FORMAT: &str = "%H:%M:%S%.3f";
+/// Format of `--log-timestamps` without `--timestamps`
+pub const LOG_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// What a timestamp shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Kind {
+    /// Wall clock time, formatted with the timestamp format
+    Absolute,
+    /// Time since the session started
+    Relative,
+    /// Time since the previous line
+    Delta,
+}
+
+impl Kind {
+    /// The kind following this one when cycling through them at runtime, `None` for off
+    pub fn next(kind: Option<Kind>) -> Option<Kind> {
+        match kind {
+            None => Some(Kind::Absolute),
+            Some(Kind::Absolute) => Some(Kind::Relative),
+            Some(Kind::Relative) => Some(Kind::Delta),
+            Some(Kind::Delta) => None,
+        }
+    }
+}
+
+/// Stamps lines with the time they started arriving
+#[derive(Debug, Clone)]
+pub struct Timestamps {
+    kind: Kind,
+    /// strftime format of absolute timestamps
+    format: String,
+    start: Instant,
+    previous: Option<Instant>,
+}
+
+impl Timestamps {
+    pub fn new(kind: Kind, format: &str) -> Result<Timestamps> {
+        strtime::format(format, &jiff::Zoned::now())
+            .map_err(|e| anyhow!("Invalid timestamp format '{format}': {e}"))?;
+        Ok(Timestamps {
+            kind,
+            format: format.to_string(),
+            start: Instant::now(),
+            previous: None,
+        })
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Switches to `kind`, keeping the start of the session
+    pub fn set_kind(&mut self, kind: Kind) {
+        self.kind = kind;
+    }
+
+    /// Returns the timestamp of a line starting now
+    pub fn stamp(&mut self) -> String {
+        let now = Instant::now();
+        let previous = self.previous.replace(now);
+        match self.kind {
+            Kind::Absolute => {
+                strtime::format(&self.format, &jiff::Zoned::now()).unwrap_or_default()
+            }
+            Kind::Relative => seconds(now - self.start),
+            Kind::Delta => format!("+{}", seconds(now - previous.unwrap_or(now))),
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{}.{:03}", duration.as_secs(), duration.subsec_millis())
+}
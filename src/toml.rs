@@ -0,0 +1,464 @@
+//! Parser for the subset of TOML used by the configuration file
+//!
+//! Supported are tables (`[a.b]`), arrays of tables (`[[a]]`), bare, quoted and dotted keys,
+//! basic and literal strings, integers, booleans, arrays and inline tables. Floats, dates and
+//! multi-line strings are not.
+
+use anyhow::{Result, anyhow, bail};
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+    parser
+        .document()
+        .map_err(|e| anyhow!("line {}: {e}", parser.line))
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn document(&mut self) -> Result<Table> {
+        let mut root = Table::new();
+        // Path of the table the following key/value pairs belong to
+        let mut current: Vec<String> = Vec::new();
+
+        loop {
+            self.skip_whitespace_and_newlines();
+            match self.chars.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.chars.next();
+                    let array = self.chars.next_if_eq(&'[').is_some();
+                    self.skip_whitespace();
+                    current = self.key()?;
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                        push_array_table(&mut root, &current)?;
+                    } else {
+                        define_table(&mut root, &current)?;
+                    }
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.expect('=')?;
+                    self.skip_whitespace();
+                    let value = self.value()?;
+                    let table = table_at(&mut root, &current)?;
+                    insert(table, &key, value)?;
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    /// Parses a possibly dotted key
+    fn key(&mut self) -> Result<Vec<String>> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let part = match self.chars.peek() {
+                Some('"') => {
+                    self.chars.next();
+                    self.basic_string()?
+                }
+                Some('\'') => {
+                    self.chars.next();
+                    self.literal_string()?
+                }
+                _ => {
+                    let mut part = String::new();
+                    while let Some(c) = self
+                        .chars
+                        .next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+                    {
+                        part.push(c);
+                    }
+                    if part.is_empty() {
+                        bail!("expected a key");
+                    }
+                    part
+                }
+            };
+            parts.push(part);
+            self.skip_whitespace();
+            if self.chars.next_if_eq(&'.').is_none() {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.chars.peek() {
+            Some('"') => {
+                self.chars.next();
+                Ok(Value::String(self.basic_string()?))
+            }
+            Some('\'') => {
+                self.chars.next();
+                Ok(Value::String(self.literal_string()?))
+            }
+            Some('[') => {
+                self.chars.next();
+                self.array()
+            }
+            Some('{') => {
+                self.chars.next();
+                self.inline_table()
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || "+-_".contains(*c))
+                {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "" => bail!("expected a value"),
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => integer(&word).map(Value::Integer),
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace_and_newlines();
+            if self.chars.next_if_eq(&']').is_some() {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_whitespace_and_newlines();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => bail!("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value> {
+        let mut table = Table::new();
+        self.skip_whitespace();
+        if self.chars.next_if_eq(&'}').is_some() {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            let key = self.key()?;
+            self.expect('=')?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            insert(&mut table, &key, value)?;
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Table(table)),
+                _ => bail!("expected ',' or '}}' in inline table"),
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                None | Some('\n') => bail!("unterminated string"),
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.escape()?),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char> {
+        Ok(match self.chars.next() {
+            Some('b') => '\x08',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('f') => '\x0c',
+            Some('r') => '\r',
+            Some('e') => '\x1b',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(kind @ ('x' | 'u' | 'U')) => {
+                let len = match kind {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                let hex: String = self.chars.by_ref().take(len).collect();
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(anyhow!("invalid escape sequence \\{kind}{hex}"))?
+            }
+            Some(c) => bail!("invalid escape sequence \\{c}"),
+            None => bail!("unterminated string"),
+        })
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                None | Some('\n') => bail!("unterminated string"),
+                Some('\'') => return Ok(s),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => bail!("expected '{expected}', found '{c}'"),
+            None => bail!("expected '{expected}'"),
+        }
+    }
+
+    /// Consumes the rest of the line, which may only hold a comment
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            None => Ok(()),
+            Some('\n') => {
+                self.line += 1;
+                Ok(())
+            }
+            Some(c) => bail!("unexpected '{c}' after value"),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .next_if(|c| *c == ' ' || *c == '\t' || *c == '\r')
+            .is_some()
+        {}
+        if self.chars.peek() == Some(&'#') {
+            while self.chars.next_if(|c| *c != '\n').is_some() {}
+        }
+    }
+
+    fn skip_whitespace_and_newlines(&mut self) {
+        loop {
+            self.skip_whitespace();
+            if self.chars.next_if_eq(&'\n').is_none() {
+                return;
+            }
+            self.line += 1;
+        }
+    }
+}
+
+fn integer(word: &str) -> Result<i64> {
+    let digits = word.replace('_', "");
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+    };
+    let magnitude = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(octal) = digits.strip_prefix("0o") {
+        i64::from_str_radix(octal, 8)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        digits.parse()
+    }
+    .map_err(|_| anyhow!("invalid value '{word}'"))?;
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+/// Returns the table at `path`, creating missing tables on the way
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table> {
+    let mut table = root;
+    for key in path {
+        let value = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match value {
+            Value::Table(t) => t,
+            // Keys below an array of tables refer to its last element
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(t)) => t,
+                _ => bail!("'{key}' is not a table"),
+            },
+            _ => bail!("'{key}' is not a table"),
+        };
+    }
+    Ok(table)
+}
+
+fn define_table(root: &mut Table, path: &[String]) -> Result<()> {
+    table_at(root, path).map(|_| ())
+}
+
+fn push_array_table(root: &mut Table, path: &[String]) -> Result<()> {
+    let (last, parent) = path.split_last().ok_or(anyhow!("empty table name"))?;
+    let parent = table_at(root, parent)?;
+    match parent
+        .entry(last.clone())
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        Value::Array(items) => {
+            items.push(Value::Table(Table::new()));
+            Ok(())
+        }
+        _ => bail!("'{last}' is not an array of tables"),
+    }
+}
+
+fn insert(table: &mut Table, key: &[String], value: Value) -> Result<()> {
+    let (last, parent) = key.split_last().ok_or(anyhow!("empty key"))?;
+    let table = table_at(table, parent)?;
+    if table.contains_key(last) {
+        bail!("duplicate key '{last}'");
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn keys_and_values() {
+        let table = parse(
+            "# comment\n\
+             name = \"Nordic_UART\" # trailing comment\n\
+             'quoted key' = 'C:\\path'\n\
+             \"basic\".dotted = true\n\
+             mtu = 0x_f7\n\
+             delay = -1_000\n\
+             mask = 0b101\n\
+             mode = 0o17\n",
+        )
+        .unwrap();
+        assert_eq!(table["name"], string("Nordic_UART"));
+        assert_eq!(table["quoted key"], string("C:\\path"));
+        let Value::Table(basic) = &table["basic"] else {
+            panic!("not a table");
+        };
+        assert_eq!(basic["dotted"], Value::Boolean(true));
+        assert_eq!(table["mtu"], Value::Integer(0xf7));
+        assert_eq!(table["delay"], Value::Integer(-1000));
+        assert_eq!(table["mask"], Value::Integer(5));
+        assert_eq!(table["mode"], Value::Integer(0o17));
+    }
+
+    #[test]
+    fn escapes() {
+        let table = parse(r#"s = "a\tb\n\"\\\e\x41\u00e9\U0001F600""#).unwrap();
+        assert_eq!(table["s"], string("a\tb\n\"\\\x1bA\u{e9}\u{1f600}"));
+    }
+
+    #[test]
+    fn arrays_and_inline_tables() {
+        let table =
+            parse("init = [\n  \"a\", # first\n  { send = \"b\", delay = 5 },\n]\nempty = []\n")
+                .unwrap();
+        let mut inline = Table::new();
+        inline.insert("send".to_string(), string("b"));
+        inline.insert("delay".to_string(), Value::Integer(5));
+        assert_eq!(
+            table["init"],
+            Value::Array(vec![string("a"), Value::Table(inline)])
+        );
+        assert_eq!(table["empty"], Value::Array(Vec::new()));
+    }
+
+    #[test]
+    fn tables_and_arrays_of_tables() {
+        let table = parse(
+            "[profile.lab]\nmtu = 100\n\n[[trigger]]\nmatch = \"a\"\n[[trigger]]\nmatch = \"b\"\n[trigger.action]\nrun = \"c\"\n",
+        )
+        .unwrap();
+        let Value::Table(profile) = &table["profile"] else {
+            panic!("not a table");
+        };
+        let Value::Table(lab) = &profile["lab"] else {
+            panic!("not a table");
+        };
+        assert_eq!(lab["mtu"], Value::Integer(100));
+        let Value::Array(triggers) = &table["trigger"] else {
+            panic!("not an array");
+        };
+        assert_eq!(triggers.len(), 2);
+        let Value::Table(last) = &triggers[1] else {
+            panic!("not a table");
+        };
+        assert_eq!(last["match"], string("b"));
+        assert!(matches!(&last["action"], Value::Table(action) if action["run"] == string("c")));
+    }
+
+    #[test]
+    fn malformed_input() {
+        for (input, error) in [
+            ("a = \"open\n", "line 1: unterminated string"),
+            ("a = 'open", "line 1: unterminated string"),
+            ("\n\na = 1\na = 2\n", "line 4: duplicate key 'a'"),
+            ("a = 1 2\n", "line 1: unexpected '2' after value"),
+            ("a = 1.5\n", "line 1: unexpected '.' after value"),
+            ("a = \"\\q\"\n", "line 1: invalid escape sequence \\q"),
+            ("a = [1 2]\n", "line 1: expected ',' or ']' in array"),
+            (
+                "a = { b = 1\n",
+                "line 1: expected ',' or '}' in inline table",
+            ),
+            ("= 1\n", "line 1: expected a key"),
+            ("a =\n", "line 1: expected a value"),
+            ("a = 1\n[a]\n", "line 2: 'a' is not a table"),
+            ("[a]\n[[a]]\n", "line 2: 'a' is not an array of tables"),
+            ("[a\n", "line 1: expected ']', found '\n'"),
+            (
+                "a = 99999999999999999999\n",
+                "line 1: invalid value '99999999999999999999'",
+            ),
+        ] {
+            let e = parse(input).unwrap_err();
+            assert_eq!(e.to_string(), error, "{input:?}");
+        }
+    }
+}
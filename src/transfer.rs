@@ -0,0 +1,217 @@
+//! Streaming local files to the device
+
+use crate::cli::{ConnectArgs, SendFileArgs};
+use crate::device;
+use crate::link::{Link, LinkOptions};
+use crate::screen::Screen;
+use crate::transport::Transport;
+use anyhow::{Context, Result};
+use btleplug::api::Peripheral as _;
+use btleplug::platform::Adapter;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc};
+
+/// Width of the progress bar printed by the `send-file` subcommand
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+/// Receiver of notifications diverted from the terminal while a transfer protocol runs
+pub type Tap = Arc<Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>;
+
+/// Pauses after every character and line written, for devices that drop bytes written
+/// back-to-back
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pacing {
+    pub char_delay: Duration,
+    pub line_delay: Duration,
+}
+
+impl From<&ConnectArgs> for Pacing {
+    fn from(args: &ConnectArgs) -> Self {
+        Pacing {
+            char_delay: Duration::from_millis(args.char_delay),
+            line_delay: Duration::from_millis(args.line_delay),
+        }
+    }
+}
+
+impl Pacing {
+    /// Writes `data`, pausing after each character and after each line on top of that
+    pub async fn write(&self, link: &dyn Transport, data: &[u8]) -> Result<()> {
+        if self.char_delay.is_zero() && self.line_delay.is_zero() {
+            return link.write(data).await;
+        }
+        let mut start = 0;
+        for (i, &byte) in data.iter().enumerate() {
+            // A CR followed by an LF ends the line only together with it
+            let line_end = byte == b'\n' || (byte == b'\r' && data.get(i + 1) != Some(&b'\n'));
+            if !line_end && self.char_delay.is_zero() {
+                continue;
+            }
+            link.write(&data[start..=i]).await?;
+            start = i + 1;
+            let delay = if line_end {
+                self.char_delay + self.line_delay
+            } else {
+                self.char_delay
+            };
+            tokio::time::sleep(delay).await;
+        }
+        if start < data.len() {
+            link.write(&data[start..]).await?;
+        }
+        Ok(())
+    }
+}
+
+/// State of a running file transfer
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Kind of transfer shown in the status bar, e.g. `SEND`
+    pub label: &'static str,
+    /// File name without the directory
+    pub name: String,
+    pub transferred: usize,
+    /// Size of the file, unknown while receiving
+    pub total: Option<usize>,
+    abort: Arc<Notify>,
+}
+
+impl Progress {
+    /// Starts tracking a transfer, which is expected to stop when `abort` is notified
+    pub fn new(
+        label: &'static str,
+        path: &Path,
+        total: Option<usize>,
+        abort: Arc<Notify>,
+    ) -> Progress {
+        Progress {
+            label,
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string()),
+            transferred: 0,
+            total,
+            abort,
+        }
+    }
+
+    pub fn percent(&self) -> Option<usize> {
+        match self.total? {
+            0 => Some(100),
+            total => Some(self.transferred * 100 / total),
+        }
+    }
+
+    /// Asks the transfer to stop
+    pub fn abort(&self) {
+        self.abort.notify_one();
+    }
+
+    /// Renders a bar of `width` characters like `[=====>    ]`
+    pub fn bar(&self, width: usize) -> String {
+        let inner = width.saturating_sub(2);
+        let filled = inner * self.percent().unwrap_or(0) / 100;
+        let mut bar = String::with_capacity(width);
+        bar.push('[');
+        bar.push_str(&"=".repeat(filled));
+        if filled < inner {
+            bar.push('>');
+            bar.push_str(&" ".repeat(inner - filled - 1));
+        }
+        bar.push(']');
+        bar
+    }
+}
+
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+}
+
+/// Connects to the device selected by `args`, sends the file and disconnects
+pub async fn run(central: &Adapter, args: &SendFileArgs) -> Result<()> {
+    let data = read(&args.file)?;
+    let device = device::select(central, &args.device.search()).await?;
+    let (link, _notifications) =
+        Link::open(device.peripheral, &LinkOptions::from(&args.device)).await?;
+
+    let delay = Duration::from_millis(args.chunk_delay);
+    let mut progress = Progress::new("SEND", &args.file, Some(data.len()), Arc::default());
+    let mut stderr = std::io::stderr();
+    for chunk in data.chunks(link.max_payload()) {
+        link.write(chunk).await?;
+        progress.transferred += chunk.len();
+        let _ = write!(
+            stderr,
+            "\r{} {} {:3}% ({}/{} bytes)",
+            progress.name,
+            progress.bar(PROGRESS_BAR_WIDTH),
+            progress.percent().unwrap_or(0),
+            progress.transferred,
+            data.len()
+        );
+        let _ = stderr.flush();
+        tokio::time::sleep(delay).await;
+    }
+    let _ = writeln!(stderr);
+
+    link.peripheral.disconnect().await?;
+    Ok(())
+}
+
+/// Sends a file through the write queue of an interactive session
+///
+/// Returns immediately, the transfer runs in the background and reports its progress in
+/// `transfer` until it is done.
+pub fn spawn(
+    path: &Path,
+    chunk_size: usize,
+    delay: Duration,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    screen: Arc<Mutex<Screen>>,
+) {
+    let data = match read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            screen.lock().unwrap().status(&format!("{e:#}"));
+            return;
+        }
+    };
+    let abort = Arc::new(Notify::new());
+    let progress = Progress::new("SEND", path, Some(data.len()), abort.clone());
+    screen
+        .lock()
+        .unwrap()
+        .status(&format!("Sending {} ({} bytes)", progress.name, data.len()));
+    *transfer.lock().unwrap() = Some(progress);
+
+    tokio::spawn(async move {
+        let mut aborted = false;
+        for chunk in data.chunks(chunk_size.max(1)) {
+            if write_queue.send(chunk.to_vec()).await.is_err() {
+                break;
+            }
+            if let Some(progress) = transfer.lock().unwrap().as_mut() {
+                progress.transferred += chunk.len();
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = abort.notified() => {
+                    aborted = true;
+                    break;
+                }
+            }
+        }
+        if let Some(progress) = transfer.lock().unwrap().take() {
+            let msg = if aborted { "Aborted sending" } else { "Sent" };
+            screen.lock().unwrap().status(&format!(
+                "{msg} {} ({} bytes)",
+                progress.name, progress.transferred
+            ));
+        }
+    });
+}
@@ -0,0 +1,132 @@
+//! Byte links to a device, abstracting over the Bluetooth stack
+//!
+//! Opening a transport is specific to each implementation: [`Link::open`] connects over BLE,
+//! [`mock`] creates an in-memory pair for exercising code without hardware.
+
+use crate::link::{Link, Notifications};
+use anyhow::{Result, bail};
+use futures::channel::mpsc as stream_channel;
+use futures::future::BoxFuture;
+use futures::stream::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+
+/// Data received from the device, ending when it disconnects
+pub type ByteStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+/// The sending half of a connection, data is received through the accompanying [`ByteStream`]
+pub trait Transport: Send + Sync {
+    /// Writes `data`, split into as many writes as the transport requires
+    fn write<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// Largest number of bytes that fit in a single write
+    fn max_payload(&self) -> usize;
+
+    fn disconnect(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+impl Transport for Link {
+    fn write<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(Link::write(self, data))
+    }
+
+    fn max_payload(&self) -> usize {
+        Link::max_payload(self)
+    }
+
+    fn disconnect(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.close())
+    }
+}
+
+/// Turns the notifications of a [`Link`] into the data they carry
+pub fn bytes(notifications: Notifications) -> ByteStream {
+    Box::pin(notifications.map(|notification| notification.value))
+}
+
+/// In-memory transport, the device side is played by the returned [`MockDevice`]
+pub fn mock(max_payload: usize) -> (MockTransport, ByteStream, MockDevice) {
+    let (written_tx, written_rx) = mpsc::unbounded_channel();
+    let (output_tx, output_rx) = stream_channel::unbounded();
+    let connected = Arc::new(AtomicBool::new(true));
+    let transport = MockTransport {
+        written: written_tx,
+        max_payload,
+        connected: connected.clone(),
+    };
+    let device = MockDevice {
+        written: written_rx,
+        output: output_tx,
+        connected,
+    };
+    let received = Box::pin(output_rx);
+    (transport, received, device)
+}
+
+#[derive(Debug, Clone)]
+pub struct MockTransport {
+    written: mpsc::UnboundedSender<Vec<u8>>,
+    max_payload: usize,
+    connected: Arc<AtomicBool>,
+}
+
+impl Transport for MockTransport {
+    /// Hands the data to the device in chunks of at most `max_payload` bytes
+    fn write<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for chunk in data.chunks(self.max_payload.max(1)) {
+                if !self.connected.load(Ordering::Relaxed)
+                    || self.written.send(chunk.to_vec()).is_err()
+                {
+                    bail!("Not connected");
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    fn disconnect(&self) -> BoxFuture<'_, Result<()>> {
+        self.connected.store(false, Ordering::Relaxed);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Device side of a [`mock`] transport, dropping it ends the received stream
+#[derive(Debug)]
+pub struct MockDevice {
+    written: mpsc::UnboundedReceiver<Vec<u8>>,
+    output: stream_channel::UnboundedSender<Vec<u8>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl MockDevice {
+    /// Makes the device send `data` to the other side
+    pub fn send(&self, data: &[u8]) {
+        let _ = self.output.unbounded_send(data.to_vec());
+    }
+
+    /// Waits for the next write made through the transport, `None` once it was dropped
+    pub async fn receive(&mut self) -> Option<Vec<u8>> {
+        self.written.recv().await
+    }
+
+    /// Returns the writes made so far without waiting, concatenated
+    pub fn written(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Ok(chunk) = self.written.try_recv() {
+            data.extend_from_slice(&chunk);
+        }
+        data
+    }
+
+    /// Whether the transport has not been disconnected
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
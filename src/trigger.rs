@@ -0,0 +1,172 @@
+//! Actions triggered by patterns in the received data
+//!
+//! ```toml
+//! [[trigger]]
+//! pattern = "Press any key to continue"
+//! send = "\r"
+//!
+//! [[trigger]]
+//! pattern = "(?i)kernel panic"
+//! notify = "Device panicked"
+//! exit = 3
+//! ```
+
+use crate::config;
+use crate::toml::{Table, Value};
+use anyhow::{Context, Result, anyhow, bail};
+use regex::bytes::Regex;
+
+/// Most received bytes kept for matching, older data is discarded
+const MAX_BUFFER_LEN: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Writes the bytes to the device
+    Send(Vec<u8>),
+    /// Runs a shell command
+    Run(String),
+    /// Shows a desktop notification with the message
+    Notify(String),
+    /// Rings the terminal bell
+    Bell,
+    /// Pauses the session log
+    StopLog,
+    /// Ends the session, exiting with the code
+    Exit(i32),
+}
+
+/// Actions run when the received data matches a pattern
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pattern: Regex,
+    actions: Vec<Action>,
+    /// Whether the trigger is disabled after matching once
+    once: bool,
+}
+
+impl Trigger {
+    pub fn pattern(&self) -> &Regex {
+        &self.pattern
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+}
+
+/// Reads the triggers of an array of `[[trigger]]` tables
+pub fn parse(value: &Value) -> Result<Vec<Trigger>> {
+    let Value::Array(items) = value else {
+        bail!("'trigger' must be an array of tables ([[trigger]])");
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| match item {
+            Value::Table(table) => {
+                trigger(table).with_context(|| format!("Invalid trigger {}", i + 1))
+            }
+            _ => bail!("Trigger {} must be a table", i + 1),
+        })
+        .collect()
+}
+
+fn trigger(table: &Table) -> Result<Trigger> {
+    let mut pattern = None;
+    let mut actions = Vec::new();
+    let mut once = false;
+    for (key, value) in table {
+        match key.as_str() {
+            "pattern" => {
+                let source = config::string(key, value)?;
+                pattern = Some(Regex::new(&source).map_err(|e| anyhow!("invalid regex: {e}"))?);
+            }
+            "send" => actions.push(Action::Send(config::string(key, value)?.into_bytes())),
+            "run" => actions.push(Action::Run(config::string(key, value)?)),
+            "notify" => actions.push(Action::Notify(config::string(key, value)?)),
+            "bell" if config::boolean(key, value)? => actions.push(Action::Bell),
+            "stop_log" if config::boolean(key, value)? => actions.push(Action::StopLog),
+            "bell" | "stop_log" => {}
+            "exit" => actions.push(Action::Exit(config::integer(key, value)?)),
+            "once" => once = config::boolean(key, value)?,
+            _ => bail!("Unknown setting '{key}'"),
+        }
+    }
+    if actions.is_empty() {
+        bail!("no action given (send, run, notify, bell, stop_log or exit)");
+    }
+    // Exiting last lets the other actions run first
+    actions.sort_by_key(|action| matches!(action, Action::Exit(_)));
+    Ok(Trigger {
+        pattern: pattern.ok_or(anyhow!("'pattern' is required"))?,
+        actions,
+        once,
+    })
+}
+
+/// Parses a pattern given with `--notify-on`, a trigger showing a desktop notification
+pub fn notify_on(pattern: &str) -> Result<Trigger, String> {
+    Ok(Trigger {
+        pattern: Regex::new(pattern).map_err(|e| format!("invalid regex: {e}"))?,
+        actions: vec![Action::Notify(format!("Output matched /{pattern}/"))],
+        once: false,
+    })
+}
+
+/// Watches the received data for the patterns of the triggers
+#[derive(Debug, Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    /// Whether each trigger is still active
+    active: Vec<bool>,
+    /// Received data not consumed by a match yet
+    buffer: Vec<u8>,
+}
+
+impl Triggers {
+    pub fn new(triggers: Vec<Trigger>) -> Triggers {
+        Triggers {
+            active: vec![true; triggers.len()],
+            triggers,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Adds received data, returns the triggers it set off in the order they matched
+    ///
+    /// Data up to the end of a match is consumed, so every occurrence fires once.
+    pub fn receive(&mut self, data: &[u8]) -> Vec<&Trigger> {
+        if self.triggers.is_empty() {
+            return Vec::new();
+        }
+        self.buffer.extend_from_slice(data);
+
+        let mut fired = Vec::new();
+        while !self.buffer.is_empty() {
+            // Of the matches, the one starting first wins
+            let earliest = self
+                .triggers
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| self.active[i])
+                .filter_map(|(i, trigger)| {
+                    let m = trigger.pattern.find(&self.buffer)?;
+                    Some((i, m.start(), m.end()))
+                })
+                .min_by_key(|&(_, start, _)| start);
+            let Some((i, _, end)) = earliest else {
+                break;
+            };
+            // An empty match still consumes a byte, so the loop ends
+            self.buffer.drain(..end.max(1));
+            if self.triggers[i].once {
+                self.active[i] = false;
+            }
+            fired.push(i);
+        }
+        if self.buffer.len() > MAX_BUFFER_LEN {
+            self.buffer.drain(..self.buffer.len() - MAX_BUFFER_LEN);
+        }
+        fired.into_iter().map(|i| &self.triggers[i]).collect()
+    }
+}
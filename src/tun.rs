@@ -0,0 +1,57 @@
+//! TUN interfaces passing IP packets between the kernel and the bridge
+
+use anyhow::Result;
+use std::fs::File;
+
+/// A TUN interface, removed again when the file is closed
+#[derive(Debug)]
+pub struct Tun {
+    /// Every read returns one packet, every write sends one
+    pub file: File,
+    /// Name the kernel gave the interface, e.g. `nus0`
+    pub name: String,
+}
+
+/// Creates the interface `name`, which may contain `%d` for the kernel to number it
+///
+/// Needs `CAP_NET_ADMIN`. The interface is left down and without an address, for the user to
+/// configure with `ip`.
+#[cfg(target_os = "linux")]
+pub fn open(name: &str) -> Result<Tun> {
+    use anyhow::{Context, bail};
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+        bail!("Interface names are 1 to {} bytes long", libc::IFNAMSIZ - 1);
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+        .context("Could not open /dev/net/tun")?;
+    // SAFETY: ifreq is plain data, for which all zeroes is valid
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, &src) in request.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    // Packets without the extra header carrying their protocol
+    request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    // SAFETY: TUNSETIFF reads and updates the ifreq passed, which outlives the call
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut request) } != 0 {
+        let e = io::Error::last_os_error();
+        return Err(e).with_context(|| format!("Could not create the interface {name}"));
+    }
+    // SAFETY: the kernel returns the name of the interface null-terminated
+    let name = unsafe { CStr::from_ptr(request.ifr_name.as_ptr()) };
+    Ok(Tun {
+        file,
+        name: name.to_string_lossy().into_owned(),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open(_name: &str) -> Result<Tun> {
+    anyhow::bail!("TUN interfaces are only available on Linux")
+}
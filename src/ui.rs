@@ -0,0 +1,705 @@
+use crate::ansi;
+use crate::decode::Mode;
+use crate::highlight;
+use crate::line_editor::LineEditor;
+use crate::link::{ConnectionState, LinkStatus};
+use crate::menu::{self, EscapeKey};
+use crate::nmea::Fix;
+use crate::plot::Plot;
+use crate::screen::Screen;
+use crate::transfer::Progress;
+use crate::vt::Grid;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Axis, Block, Borders, Chart, Clear, Dataset, GraphType, Paragraph};
+
+/// Everything shown in the status bar besides the link itself
+#[derive(Debug, Clone, Copy)]
+pub struct Indicators<'a> {
+    /// Whether logging is active, `None` without a log file
+    pub logging: Option<bool>,
+    /// File transfer in progress
+    pub transfer: Option<&'a Progress>,
+    /// Input line replacing the status bar while it is open
+    pub prompt: Option<&'a Prompt>,
+    /// Line being edited in line mode, shown above the status bar
+    pub line: Option<&'a LineEditor>,
+    pub escape: EscapeKey,
+    /// Whether the command menu is open
+    pub menu: bool,
+    /// The connected devices, a tab bar is shown for more than one
+    pub tabs: &'a [TabLabel],
+    pub active_tab: usize,
+    /// Whether input goes to all devices
+    pub broadcast: bool,
+    /// Whether the merged view of all devices is shown
+    pub merged: bool,
+    /// Settings listed in the help overlay while it is open
+    pub help: Option<&'a [(&'static str, String)]>,
+    /// Battery level in percent shown as low
+    pub battery_alert: Option<u8>,
+    /// Set by the `--hooks` script
+    pub script_status: Option<&'a str>,
+}
+
+/// A device as shown in the tab bar
+#[derive(Debug, Clone)]
+pub struct TabLabel {
+    pub name: String,
+    pub state: ConnectionState,
+    /// Whether output arrived since the tab was last shown
+    pub unseen: bool,
+}
+
+/// A line of local input, e.g. the path of a file to send
+#[derive(Debug)]
+pub struct Prompt {
+    pub label: &'static str,
+    pub input: String,
+}
+
+impl Prompt {
+    pub fn new(label: &'static str) -> Prompt {
+        Prompt {
+            label,
+            input: String::new(),
+        }
+    }
+}
+
+/// Where the lines of the output pane were drawn, for telling what the mouse points at
+#[derive(Debug, Default)]
+pub struct OutputRows {
+    /// The area of the output pane
+    pub pane: Rect,
+    /// Terminal row of the first line drawn
+    pub top: u16,
+    /// Position of the line drawn in every row from the top, counted from the bottom of
+    /// the scrollback like the scroll offset
+    pub positions: Vec<usize>,
+}
+
+impl OutputRows {
+    /// Position of the line drawn in terminal row `row`
+    pub fn position(&self, row: u16) -> Option<usize> {
+        let index = row.checked_sub(self.top)?;
+        self.positions.get(index as usize).copied()
+    }
+}
+
+/// Rows of the GPS panel with `--nmea`, two lines inside a border
+const NMEA_PANEL_HEIGHT: u16 = 4;
+
+/// Fewest rows of the chart with `--plot`, which otherwise takes a third of the terminal
+const PLOT_MIN_HEIGHT: u16 = 8;
+
+/// Colors of the plotted series, in the order of their capture groups
+const PLOT_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Green,
+    Color::Red,
+    Color::Blue,
+];
+
+/// Draws the output pane and the status bar below it
+pub fn draw(
+    f: &mut Frame,
+    screen: &Screen,
+    status: &LinkStatus,
+    indicators: Indicators,
+) -> OutputRows {
+    let tabs_height = if indicators.tabs.len() > 1 { 1 } else { 0 };
+    let nmea_height = if screen.nmea().is_some() {
+        NMEA_PANEL_HEIGHT
+    } else {
+        0
+    };
+    let plot_height = if screen.plot().is_some() {
+        (f.area().height / 3).max(PLOT_MIN_HEIGHT)
+    } else {
+        0
+    };
+    let input_height = if indicators.line.is_some() { 1 } else { 0 };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(tabs_height),
+            Constraint::Length(nmea_height),
+            Constraint::Length(plot_height),
+            Constraint::Min(1),
+            Constraint::Length(input_height),
+            Constraint::Length(1),
+        ])
+        .split(f.area());
+
+    if tabs_height > 0 {
+        draw_tabs(f, chunks[0], indicators);
+    }
+    if let Some(fix) = screen.nmea() {
+        draw_nmea(f, chunks[1], fix);
+    }
+    if let Some(plot) = screen.plot() {
+        draw_plot(f, chunks[2], plot);
+    }
+    let rows = draw_output(f, chunks[3], screen);
+    if let Some(editor) = indicators.line {
+        draw_line(f, chunks[4], editor, indicators.prompt.is_none());
+    }
+    match indicators.prompt {
+        Some(prompt) => draw_prompt(f, chunks[5], prompt),
+        None => draw_status_bar(f, chunks[5], screen, status, indicators),
+    }
+    if indicators.menu {
+        draw_menu(f, indicators.escape);
+    }
+    if let Some(settings) = indicators.help {
+        draw_help(f, settings, indicators.escape);
+    }
+    rows
+}
+
+/// Draws a numbered label for each device, marking the ones with new output with `*`
+fn draw_tabs(f: &mut Frame, area: Rect, indicators: Indicators) {
+    let bar = Style::default().fg(Color::Black).bg(Color::Gray);
+    let selected = bar.add_modifier(Modifier::REVERSED | Modifier::BOLD);
+    let mut spans = vec![
+        Span::styled(" all ", if indicators.merged { selected } else { bar }),
+        Span::styled("|", bar),
+    ];
+    for (i, tab) in indicators.tabs.iter().enumerate() {
+        let active = i == indicators.active_tab;
+        let style = match tab.state {
+            ConnectionState::Connected => bar,
+            ConnectionState::Stalled | ConnectionState::Reconnecting => bar.fg(Color::Yellow),
+            ConnectionState::Lost => bar.fg(Color::Red),
+        };
+        let style = match (active, indicators.merged) {
+            (true, false) => style.add_modifier(Modifier::REVERSED | Modifier::BOLD),
+            // Input still goes to the active tab in the merged view
+            (true, true) => style.add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+            (false, _) => style,
+        };
+        let marker = if tab.unseen { "*" } else { " " };
+        spans.push(Span::styled(
+            format!(" {} {}{marker}", i + 1, tab.name),
+            style,
+        ));
+        spans.push(Span::styled("|", bar));
+    }
+    spans.push(Span::styled(
+        format!(" {} n/p: switch, m: all ", indicators.escape),
+        bar,
+    ));
+    f.render_widget(Paragraph::new(Line::from(spans)).style(bar), area);
+}
+
+/// Draws the GPS fix reported in NMEA sentences
+fn draw_nmea(f: &mut Frame, area: Rect, fix: &Fix) {
+    let unknown = || "-".to_string();
+    let label = Style::default().add_modifier(Modifier::BOLD);
+    let status = match (fix.active, fix.quality_name()) {
+        (Some(false), _) | (_, Some("no fix")) => {
+            Span::styled("no fix", Style::default().fg(Color::Red))
+        }
+        (_, Some(quality)) => Span::styled(quality, Style::default().fg(Color::Green)),
+        (Some(true), None) => Span::styled("active", Style::default().fg(Color::Green)),
+        (None, None) => Span::raw("waiting"),
+    };
+    let position = match (fix.latitude, fix.longitude) {
+        (Some(latitude), Some(longitude)) => format!(
+            "{:.6}° {} {:.6}° {}",
+            latitude.abs(),
+            if latitude < 0.0 { 'S' } else { 'N' },
+            longitude.abs(),
+            if longitude < 0.0 { 'W' } else { 'E' }
+        ),
+        _ => unknown(),
+    };
+    let altitude = fix.altitude.map_or_else(unknown, |m| format!("{m:.1} m"));
+    let time = fix
+        .time
+        .as_ref()
+        .map_or_else(unknown, |time| format!("{time} UTC"));
+    let used = fix.satellites_used.map_or_else(unknown, |n| n.to_string());
+    let in_view = if fix.in_view.is_empty() {
+        unknown()
+    } else {
+        let total: u32 = fix.in_view.values().sum();
+        let talkers: Vec<String> = fix
+            .in_view
+            .iter()
+            .map(|(talker, n)| format!("{talker} {n}"))
+            .collect();
+        format!("{total} ({})", talkers.join(", "))
+    };
+    let hdop = fix.hdop.map_or_else(unknown, |hdop| format!("{hdop:.1}"));
+    let speed = fix.speed_knots.map_or_else(unknown, |knots| {
+        format!("{knots:.1} kn ({:.1} km/h)", knots * 1.852)
+    });
+    let course = fix
+        .course
+        .map_or_else(unknown, |course| format!("{course:.1}°"));
+    let checksums = if fix.bad_checksums > 0 {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    };
+    let lines = vec![
+        Line::from(vec![
+            Span::styled("Fix ", label),
+            status,
+            Span::styled("  Position ", label),
+            Span::raw(position),
+            Span::styled("  Altitude ", label),
+            Span::raw(altitude),
+            Span::styled("  Time ", label),
+            Span::raw(time),
+        ]),
+        Line::from(vec![
+            Span::styled("Satellites ", label),
+            Span::raw(format!("{used} used, {in_view} in view")),
+            Span::styled("  HDOP ", label),
+            Span::raw(hdop),
+            Span::styled("  Speed ", label),
+            Span::raw(speed),
+            Span::styled("  Course ", label),
+            Span::raw(course),
+            Span::styled(
+                format!(
+                    "  {} sentences, {} bad checksums",
+                    fix.sentences, fix.bad_checksums
+                ),
+                checksums,
+            ),
+        ]),
+    ];
+    let block = Block::default().borders(Borders::ALL).title(" GPS ");
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Draws the series of `--plot` over the time window, with their latest values in the title
+fn draw_plot(f: &mut Frame, area: Rect, plot: &Plot) {
+    let now = plot.now();
+    let window = plot.window.as_secs_f64();
+    let start = (now - window).max(0.0);
+    let points: Vec<Vec<(f64, f64)>> = plot
+        .series
+        .iter()
+        .map(|series| {
+            series
+                .points
+                .iter()
+                .filter(|&&(time, _)| time >= start)
+                .copied()
+                .collect()
+        })
+        .collect();
+
+    let (mut low, mut high) = points.iter().flatten().fold(
+        (f64::INFINITY, f64::NEG_INFINITY),
+        |(low, high), &(_, value)| (low.min(value), high.max(value)),
+    );
+    if low > high {
+        (low, high) = (0.0, 1.0);
+    } else if low == high {
+        (low, high) = (low - 1.0, high + 1.0);
+    }
+    // Some room so the extremes are not drawn on the border
+    let margin = (high - low) * 0.05;
+    let (low, high) = (low - margin, high + margin);
+
+    let mut title = vec![Span::raw(" Plot ")];
+    let mut datasets = Vec::new();
+    for (i, (series, points)) in plot.series.iter().zip(&points).enumerate() {
+        let style = Style::default().fg(PLOT_COLORS[i % PLOT_COLORS.len()]);
+        let latest = series
+            .points
+            .back()
+            .map_or_else(|| "-".to_string(), |&(_, value)| value.to_string());
+        title.push(Span::styled(format!(" {} {latest} ", series.name), style));
+        datasets.push(
+            Dataset::default()
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(style)
+                .data(points),
+        );
+    }
+    let label = |value: f64| Span::raw(format!("{value:.1}"));
+    let chart = Chart::new(datasets)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Line::from(title)),
+        )
+        .x_axis(
+            Axis::default()
+                .bounds([start, start + window])
+                .labels(vec![Span::raw(format!("-{window:.0}s")), Span::raw("now")]),
+        )
+        .y_axis(Axis::default().bounds([low, high]).labels(vec![
+            label(low),
+            label((low + high) / 2.0),
+            label(high),
+        ]));
+    f.render_widget(chart, area);
+}
+
+fn draw_output(f: &mut Frame, area: Rect, screen: &Screen) -> OutputRows {
+    let width = area.width.max(1) as usize;
+    let height = area.height as usize;
+    if height == 0 {
+        return OutputRows::default();
+    }
+    if let Some(grid) = screen.grid()
+        && screen.offset() == 0
+        && screen.selection().is_none()
+    {
+        return draw_grid(f, area, grid);
+    }
+
+    // Wrap lines from the bottom of the view upwards until the pane is full
+    let mut rows = Vec::with_capacity(height);
+    let mut positions = Vec::with_capacity(height);
+    let mut cursor = None;
+    for (i, line) in screen.view_rev().enumerate() {
+        let position = screen.offset() + i;
+        let mut rendered = ansi::render(line);
+        highlight::apply(screen.highlights(), &mut rendered);
+        if let Some(search) = screen.search() {
+            highlight::apply(std::slice::from_ref(search), &mut rendered);
+        }
+        if screen
+            .selection()
+            .is_some_and(|selection| selection.contains(position))
+        {
+            for (_, style) in &mut rendered.cells {
+                *style = style.add_modifier(Modifier::REVERSED);
+            }
+        }
+        let mut line_rows = wrap(&rendered.cells, width);
+        if i == 0 && screen.offset() == 0 {
+            // The cursor may sit past the end of the line, possibly on a row of its own
+            let cursor_row = rendered.cursor / width;
+            while line_rows.len() <= cursor_row {
+                line_rows.push(Line::default());
+            }
+            cursor = Some((rendered.cursor % width, line_rows.len() - 1 - cursor_row));
+        }
+        positions.extend(std::iter::repeat_n(position, line_rows.len()));
+        rows.extend(line_rows.into_iter().rev());
+        if rows.len() >= height {
+            break;
+        }
+    }
+    rows.truncate(height);
+    rows.reverse();
+    positions.truncate(height);
+    positions.reverse();
+
+    // Rows are bottom-aligned, so output starts at the top only once the pane is full
+    let top = area.y + (height - rows.len()) as u16;
+    if let Some((x, rows_below)) = cursor {
+        let y = top as usize + rows.len() - 1 - rows_below.min(rows.len() - 1);
+        f.set_cursor_position((area.x + x as u16, y as u16));
+    }
+
+    let text_area = Rect {
+        y: top,
+        height: rows.len() as u16,
+        ..area
+    };
+    f.render_widget(Paragraph::new(rows), text_area);
+    OutputRows {
+        pane: area,
+        top,
+        positions,
+    }
+}
+
+/// Draws the screen of a full-screen program, which is not scrolled back
+fn draw_grid(f: &mut Frame, area: Rect, grid: &Grid) -> OutputRows {
+    let rows: Vec<Line> = grid
+        .rows()
+        .iter()
+        .take(area.height as usize)
+        .map(|row| {
+            let cells = &row[..row.len().min(area.width as usize)];
+            wrap(cells, cells.len().max(1)).swap_remove(0)
+        })
+        .collect();
+    f.render_widget(Paragraph::new(rows), area);
+    if let Some((x, y)) = grid.cursor()
+        && x < area.width as usize
+        && y < area.height as usize
+    {
+        f.set_cursor_position((area.x + x as u16, area.y + y as u16));
+    }
+    OutputRows {
+        pane: area,
+        top: area.y,
+        positions: Vec::new(),
+    }
+}
+
+/// Splits rendered cells into rows of at most `width` cells, merging equally styled cells
+fn wrap(cells: &[(char, Style)], width: usize) -> Vec<Line<'static>> {
+    if cells.is_empty() {
+        return vec![Line::default()];
+    }
+
+    cells
+        .chunks(width)
+        .map(|row| {
+            let mut spans: Vec<Span> = Vec::new();
+            let mut text = String::new();
+            let mut style = row[0].1;
+            for &(c, cell_style) in row {
+                if cell_style != style {
+                    spans.push(Span::styled(std::mem::take(&mut text), style));
+                    style = cell_style;
+                }
+                text.push(c);
+            }
+            spans.push(Span::styled(text, style));
+            Line::from(spans)
+        })
+        .collect()
+}
+
+/// Draws the list of commands in a box in the middle of the screen
+fn draw_menu(f: &mut Frame, escape: EscapeKey) {
+    let lines: Vec<Line> = menu::COMMANDS
+        .iter()
+        .map(|(key, _, description)| {
+            Line::from(vec![
+                Span::styled(
+                    format!(" {key} "),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(format!(" {description} ")),
+            ])
+        })
+        .collect();
+    let width = menu::COMMANDS
+        .iter()
+        .map(|(_, _, description)| description.len() as u16 + 6)
+        .max()
+        .unwrap_or(0)
+        .max(20);
+    let area = f.area();
+    let width = width.min(area.width);
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(format!(" {escape} "));
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(lines).block(block), popup);
+}
+
+/// Keys working without the escape key, for the help overlay
+const DIRECT_KEYS: &[(&str, &str)] = &[
+    ("[Shift+]PageUp / PageDown", "Scroll back / forward a page"),
+    ("Shift+Up / Shift+Down", "Scroll back / forward a line"),
+];
+
+/// Draws the current settings and all keys in the middle of the screen
+fn draw_help(f: &mut Frame, settings: &[(&str, String)], escape: EscapeKey) {
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let row = |key: String, description: &str| {
+        Line::from(vec![
+            Span::styled(format!(" {key:<22}"), bold),
+            Span::raw(format!(" {description} ")),
+        ])
+    };
+    let mut lines: Vec<Line> = settings
+        .iter()
+        .map(|(name, value)| row(name.to_string(), value))
+        .collect();
+    lines.push(Line::default());
+    lines.extend(
+        menu::COMMANDS
+            .iter()
+            .map(|(key, _, description)| row(format!("{escape} {key}"), description)),
+    );
+    lines.extend(
+        DIRECT_KEYS
+            .iter()
+            .map(|(key, description)| row(key.to_string(), description)),
+    );
+
+    let width = lines
+        .iter()
+        .map(|line| line.width() as u16 + 2)
+        .max()
+        .unwrap_or(0);
+    let area = f.area();
+    let width = width.min(area.width);
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(" Help, any key closes ");
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(lines).block(block), popup);
+}
+
+fn draw_prompt(f: &mut Frame, area: Rect, prompt: &Prompt) {
+    let label = format!("{}: ", prompt.label);
+    let cursor = (label.chars().count() + prompt.input.chars().count()) as u16;
+    let line = Line::from(vec![
+        Span::styled(label, Style::default().add_modifier(Modifier::BOLD)),
+        Span::raw(prompt.input.as_str()),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+    f.set_cursor_position((area.x + cursor.min(area.width.saturating_sub(1)), area.y));
+}
+
+/// Draws the line edited in line mode, scrolled horizontally to keep the cursor visible
+fn draw_line(f: &mut Frame, area: Rect, editor: &LineEditor, focused: bool) {
+    const MARKER: &str = "> ";
+    let width = (area.width as usize).saturating_sub(MARKER.len()).max(1);
+    let cursor = editor.cursor();
+    let skip = (cursor + 1).saturating_sub(width);
+    let visible: String = editor.line().chars().skip(skip).take(width).collect();
+    let line = Line::from(vec![
+        Span::styled(MARKER, Style::default().add_modifier(Modifier::BOLD)),
+        Span::raw(visible),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+    if focused {
+        f.set_cursor_position((area.x + (MARKER.len() + cursor - skip) as u16, area.y));
+    }
+}
+
+fn draw_status_bar(
+    f: &mut Frame,
+    area: Rect,
+    screen: &Screen,
+    status: &LinkStatus,
+    indicators: Indicators,
+) {
+    let bar = Style::default().fg(Color::Black).bg(Color::Gray);
+    let state_style = match status.state {
+        ConnectionState::Connected => bar.fg(Color::Green),
+        ConnectionState::Stalled | ConnectionState::Reconnecting => bar.fg(Color::Yellow),
+        ConnectionState::Lost => bar.fg(Color::Red),
+    }
+    .add_modifier(Modifier::BOLD);
+
+    let rssi = status
+        .rssi
+        .map(|r| format!("{r} dBm"))
+        .unwrap_or_else(|| "-".to_string());
+    let mut spans = vec![
+        Span::styled(
+            format!(" {} ", status.name),
+            bar.add_modifier(Modifier::BOLD),
+        ),
+        Span::styled(format!("{} ", status.address), bar),
+        Span::styled(format!("| {} ", status.state), state_style),
+        Span::styled(
+            format!(
+                "| RSSI {rssi} | MTU {} | TX {} | RX {} ",
+                status.mtu,
+                bytes(status.tx_bytes),
+                bytes(status.rx_bytes)
+            ),
+            bar,
+        ),
+    ];
+    if let Some(battery) = status.battery {
+        let style = match indicators.battery_alert {
+            Some(alert) if battery <= alert => bar.bg(Color::Red),
+            _ => bar,
+        };
+        spans.push(Span::styled(format!("| BAT {battery}% "), style));
+    }
+    if status.pending > 0 {
+        spans.push(Span::styled(
+            format!("| PENDING {} ", bytes(status.pending as u64)),
+            bar.bg(Color::Yellow),
+        ));
+    }
+    match indicators.logging {
+        Some(true) => spans.push(Span::styled("| LOG ", bar)),
+        Some(false) => spans.push(Span::styled("| LOG PAUSED ", bar)),
+        None => {}
+    }
+    if let Some(text) = indicators.script_status {
+        spans.push(Span::styled(format!("| {text} "), bar));
+    }
+    if let Some(progress) = indicators.transfer {
+        let amount = match progress.percent() {
+            Some(percent) => format!("{} {percent}%", progress.bar(12)),
+            None => bytes(progress.transferred as u64),
+        };
+        spans.push(Span::styled(
+            format!("| {} {} {amount} ", progress.label, progress.name),
+            bar,
+        ));
+    }
+    if screen.mode() == Mode::Hex {
+        spans.push(Span::styled("| HEX ", bar));
+    }
+    if indicators.line.is_some() {
+        spans.push(Span::styled("| LINE ", bar));
+    }
+    if let Some(filter) = screen.filter() {
+        let label = if filter.invert { "HIDING" } else { "FILTER" };
+        spans.push(Span::styled(
+            format!("| {label} /{}/ ", filter.pattern),
+            bar.bg(Color::Yellow),
+        ));
+    }
+    if indicators.broadcast {
+        spans.push(Span::styled("| BROADCAST ", bar.bg(Color::Yellow)));
+    }
+    if let Some(selection) = screen.selection() {
+        let lines = selection.anchor.abs_diff(selection.cursor) + 1;
+        spans.push(Span::styled(
+            format!("| SELECT {lines} lines, Enter copies, Esc cancels "),
+            bar.bg(Color::Yellow),
+        ));
+    }
+    if let Some(buffered) = screen.frozen() {
+        spans.push(Span::styled(
+            format!("| FROZEN {} buffered ", bytes(buffered)),
+            bar.bg(Color::Yellow),
+        ));
+    } else if screen.offset() > 0 {
+        spans.push(Span::styled(
+            format!("| SCROLLED -{} ", screen.offset()),
+            bar.bg(Color::Yellow),
+        ));
+    }
+
+    spans.push(Span::styled(format!("| {}: menu ", indicators.escape), bar));
+
+    f.render_widget(Paragraph::new(Line::from(spans)).style(bar), area);
+}
+
+/// Formats a byte count in human readable units
+fn bytes(count: u64) -> String {
+    match count {
+        0..1024 => format!("{count} B"),
+        1024..1048576 => format!("{:.1} KiB", count as f64 / 1024.0),
+        _ => format!("{:.1} MiB", count as f64 / 1048576.0),
+    }
+}
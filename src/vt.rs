@@ -0,0 +1,563 @@
+//! A cell grid for full-screen programs running on the device
+//!
+//! Programs like editors and `htop` switch to the alternate screen (`ESC [ ? 1049 h`) and
+//! then draw with cursor addressing, scroll regions and erasing, which do not fit the lines
+//! of the scrollback. Their output goes to a grid the size of the output pane instead,
+//! until they switch back. What VT100 and xterm do within a screen is supported, modes like
+//! origin mode and character sets are ignored.
+
+use crate::ansi;
+use ratatui::style::Style;
+
+/// Sequences switching to the alternate screen, which starts the grid
+pub const ENTER_SEQUENCES: [&str; 3] = ["\x1b[?1049h", "\x1b[?1047h", "\x1b[?47h"];
+
+type Cell = (char, Style);
+
+/// Where an escape sequence being received stands, as it may span notifications
+#[derive(Debug, Clone, Default)]
+enum Parser {
+    #[default]
+    Ground,
+    Escape,
+    /// A control sequence, with its parameters so far
+    Csi(String),
+    /// An operating system command like a window title, ignored up to BEL or ST
+    Osc {
+        escape: bool,
+    },
+    /// The character set designation `ESC ( B`, which takes one more character
+    Charset,
+}
+
+#[derive(Debug, Clone)]
+pub struct Grid {
+    cells: Vec<Vec<Cell>>,
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    style: Style,
+    /// Cursor saved with `ESC 7`
+    saved: (usize, usize, Style),
+    /// First and last row of the scroll region
+    top: usize,
+    bottom: usize,
+    /// Whether the last column was written, so the next character wraps first
+    wrap_pending: bool,
+    autowrap: bool,
+    cursor_visible: bool,
+    parser: Parser,
+}
+
+impl Grid {
+    pub fn new(width: u16, height: u16) -> Grid {
+        let (width, height) = (usize::from(width.max(1)), usize::from(height.max(1)));
+        Grid {
+            cells: vec![vec![(' ', Style::default()); width]; height],
+            width,
+            height,
+            x: 0,
+            y: 0,
+            style: Style::default(),
+            saved: (0, 0, Style::default()),
+            top: 0,
+            bottom: height - 1,
+            wrap_pending: false,
+            autowrap: true,
+            cursor_visible: true,
+            parser: Parser::Ground,
+        }
+    }
+
+    pub fn rows(&self) -> &[Vec<Cell>] {
+        &self.cells
+    }
+
+    /// Column and row of the cursor, unless the program hid it
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        self.cursor_visible.then_some((self.x, self.y))
+    }
+
+    /// Changes the size, keeping the rows around the cursor
+    pub fn resize(&mut self, width: u16, height: u16) {
+        let (width, height) = (usize::from(width.max(1)), usize::from(height.max(1)));
+        for row in &mut self.cells {
+            row.resize(width, (' ', Style::default()));
+        }
+        // Rows are taken from the top as long as the cursor stays on the screen
+        if self.y >= height {
+            self.cells.drain(..self.y + 1 - height);
+            self.y = height - 1;
+        }
+        self.cells
+            .resize(height, vec![(' ', Style::default()); width]);
+        self.width = width;
+        self.height = height;
+        self.x = self.x.min(width - 1);
+        self.top = 0;
+        self.bottom = height - 1;
+        self.wrap_pending = false;
+    }
+
+    /// Feeds output of the program, returns where the rest of `text` starts if the program
+    /// left the alternate screen
+    pub fn feed(&mut self, text: &str) -> Option<usize> {
+        for (i, c) in text.char_indices() {
+            self.parser = match std::mem::take(&mut self.parser) {
+                Parser::Ground => self.ground(c),
+                Parser::Escape => self.escape(c),
+                Parser::Csi(mut params) => {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        if self.csi(&params, c) {
+                            return Some(i + c.len_utf8());
+                        }
+                        Parser::Ground
+                    } else if c == '\x1b' {
+                        Parser::Escape
+                    } else {
+                        params.push(c);
+                        Parser::Csi(params)
+                    }
+                }
+                Parser::Osc { escape } => match c {
+                    '\x07' => Parser::Ground,
+                    '\\' if escape => Parser::Ground,
+                    c => Parser::Osc {
+                        escape: c == '\x1b',
+                    },
+                },
+                Parser::Charset => Parser::Ground,
+            };
+        }
+        None
+    }
+
+    fn ground(&mut self, c: char) -> Parser {
+        match c {
+            '\x1b' => return Parser::Escape,
+            '\r' => self.move_to(0, self.y),
+            '\n' | '\x0b' | '\x0c' => self.index(),
+            '\x08' => self.move_to(self.x.saturating_sub(1), self.y),
+            '\t' => self.move_to(((self.x / 8 + 1) * 8).min(self.width - 1), self.y),
+            c if c.is_control() => {}
+            c => self.put(c),
+        }
+        Parser::Ground
+    }
+
+    fn escape(&mut self, c: char) -> Parser {
+        match c {
+            '[' => return Parser::Csi(String::new()),
+            ']' => return Parser::Osc { escape: false },
+            '(' | ')' | '*' | '+' => return Parser::Charset,
+            '7' => self.saved = (self.x, self.y, self.style),
+            '8' => {
+                let (x, y, style) = self.saved;
+                self.move_to(x, y);
+                self.style = style;
+            }
+            'D' => self.index(),
+            'E' => {
+                self.move_to(0, self.y);
+                self.index();
+            }
+            'M' => self.reverse_index(),
+            'c' => *self = Grid::new(self.width as u16, self.height as u16),
+            _ => {}
+        }
+        Parser::Ground
+    }
+
+    /// Runs a control sequence, returns whether it leaves the alternate screen
+    fn csi(&mut self, params: &str, command: char) -> bool {
+        if let Some(modes) = params.strip_prefix('?') {
+            let on = command == 'h';
+            for mode in modes.split(';') {
+                match (mode, command) {
+                    ("1049" | "1047" | "47", 'l') => return true,
+                    // Entered again, e.g. by a program started from the one running
+                    ("1049" | "1047" | "47", 'h') => self.erase_display(2),
+                    ("25", 'h' | 'l') => self.cursor_visible = on,
+                    ("7", 'h' | 'l') => self.autowrap = on,
+                    _ => {}
+                }
+            }
+            return false;
+        }
+        if command == 'm' {
+            self.style = ansi::sgr(self.style, params);
+            return false;
+        }
+        let mut numbers = params.split(';').map(|p| p.parse::<usize>().unwrap_or(0));
+        let first = numbers.next().unwrap_or(0);
+        let second = numbers.next().unwrap_or(0);
+        let count = first.max(1);
+        match command {
+            'A' => self.move_to(self.x, self.y.saturating_sub(count).max(self.top_limit())),
+            'B' => self.move_to(self.x, (self.y + count).min(self.bottom_limit())),
+            'C' => self.move_to(self.x + count, self.y),
+            'D' => self.move_to(self.x.saturating_sub(count), self.y),
+            'E' => self.move_to(0, (self.y + count).min(self.bottom_limit())),
+            'F' => self.move_to(0, self.y.saturating_sub(count).max(self.top_limit())),
+            'G' | '`' => self.move_to(count - 1, self.y),
+            'd' => self.move_to(self.x, count - 1),
+            'H' | 'f' => self.move_to(second.max(1) - 1, count - 1),
+            'J' => self.erase_display(first),
+            'K' => self.erase_line(first),
+            'L' => self.insert_lines(count),
+            'M' => self.delete_lines(count),
+            '@' => {
+                let blank = self.blank();
+                let row = &mut self.cells[self.y];
+                let count = count.min(self.width - self.x);
+                row.splice(self.x..self.x, std::iter::repeat_n(blank, count));
+                row.truncate(self.width);
+            }
+            'P' => {
+                let blank = self.blank();
+                let row = &mut self.cells[self.y];
+                let end = (self.x + count).min(self.width);
+                row.drain(self.x..end);
+                row.resize(self.width, blank);
+            }
+            'X' => {
+                let blank = self.blank();
+                let end = (self.x + count).min(self.width);
+                self.cells[self.y][self.x..end].fill(blank);
+            }
+            'S' => self.scroll_up(count),
+            'T' => self.scroll_down(count),
+            'r' => {
+                let top = count - 1;
+                let bottom = if second == 0 { self.height } else { second }.min(self.height) - 1;
+                if top < bottom {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.move_to(0, 0);
+                }
+            }
+            's' => self.saved = (self.x, self.y, self.style),
+            'u' => {
+                let (x, y, style) = self.saved;
+                self.move_to(x, y);
+                self.style = style;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Rows the cursor moves up to, the top of the scroll region when inside it
+    fn top_limit(&self) -> usize {
+        if self.y >= self.top { self.top } else { 0 }
+    }
+
+    fn bottom_limit(&self) -> usize {
+        if self.y <= self.bottom {
+            self.bottom
+        } else {
+            self.height - 1
+        }
+    }
+
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.x = x.min(self.width - 1);
+        self.y = y.min(self.height - 1);
+        self.wrap_pending = false;
+    }
+
+    fn put(&mut self, c: char) {
+        if self.wrap_pending {
+            self.move_to(0, self.y);
+            self.index();
+        }
+        self.cells[self.y][self.x] = (c, self.style);
+        if self.x + 1 < self.width {
+            self.x += 1;
+        } else {
+            self.wrap_pending = self.autowrap;
+        }
+    }
+
+    /// Moves down a row, scrolling the region at its bottom
+    fn index(&mut self) {
+        self.wrap_pending = false;
+        if self.y == self.bottom {
+            self.scroll_up(1);
+        } else if self.y + 1 < self.height {
+            self.y += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.y == self.top {
+            self.scroll_down(1);
+        } else {
+            self.y = self.y.saturating_sub(1);
+        }
+    }
+
+    /// Erased cells keep the background color, like in xterm
+    fn blank(&self) -> Cell {
+        let style = match self.style.bg {
+            Some(bg) => Style::default().bg(bg),
+            None => Style::default(),
+        };
+        (' ', style)
+    }
+
+    fn blank_row(&self) -> Vec<Cell> {
+        vec![self.blank(); self.width]
+    }
+
+    fn scroll_up(&mut self, count: usize) {
+        for _ in 0..count.min(self.bottom + 1 - self.top) {
+            self.cells.remove(self.top);
+            self.cells.insert(self.bottom, self.blank_row());
+        }
+    }
+
+    fn scroll_down(&mut self, count: usize) {
+        for _ in 0..count.min(self.bottom + 1 - self.top) {
+            self.cells.remove(self.bottom);
+            self.cells.insert(self.top, self.blank_row());
+        }
+    }
+
+    fn insert_lines(&mut self, count: usize) {
+        if !(self.top..=self.bottom).contains(&self.y) {
+            return;
+        }
+        for _ in 0..count.min(self.bottom + 1 - self.y) {
+            self.cells.remove(self.bottom);
+            self.cells.insert(self.y, self.blank_row());
+        }
+        self.move_to(0, self.y);
+    }
+
+    fn delete_lines(&mut self, count: usize) {
+        if !(self.top..=self.bottom).contains(&self.y) {
+            return;
+        }
+        for _ in 0..count.min(self.bottom + 1 - self.y) {
+            self.cells.remove(self.y);
+            self.cells.insert(self.bottom, self.blank_row());
+        }
+        self.move_to(0, self.y);
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        let blank = self.blank();
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for row in &mut self.cells[self.y + 1..] {
+                    row.fill(blank);
+                }
+            }
+            1 => {
+                self.erase_line(1);
+                for row in &mut self.cells[..self.y] {
+                    row.fill(blank);
+                }
+            }
+            _ => {
+                for row in &mut self.cells {
+                    row.fill(blank);
+                }
+            }
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let blank = self.blank();
+        let row = &mut self.cells[self.y];
+        match mode {
+            0 => row[self.x..].fill(blank),
+            1 => row[..=self.x].fill(blank),
+            _ => row.fill(blank),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::style::{Color, Modifier};
+
+    /// A grid fed `text`
+    fn fed(width: u16, height: u16, text: &str) -> Grid {
+        let mut grid = Grid::new(width, height);
+        assert_eq!(grid.feed(text), None);
+        grid
+    }
+
+    /// The rows without trailing blanks
+    fn text(grid: &Grid) -> Vec<String> {
+        grid.rows()
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|(c, _)| c)
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn feed(grid: &mut Grid, text: &str) -> Option<(usize, usize)> {
+        assert_eq!(grid.feed(text), None);
+        grid.cursor()
+    }
+
+    #[test]
+    fn cursor_movement_is_clamped_to_the_screen() {
+        let mut grid = Grid::new(10, 5);
+        assert_eq!(feed(&mut grid, "\x1b[3;4H"), Some((3, 2)));
+        assert_eq!(feed(&mut grid, "\x1b[2A"), Some((3, 0)));
+        assert_eq!(feed(&mut grid, "\x1b[A"), Some((3, 0)));
+        assert_eq!(feed(&mut grid, "\x1b[99B"), Some((3, 4)));
+        assert_eq!(feed(&mut grid, "\x1b[99C"), Some((9, 4)));
+        assert_eq!(feed(&mut grid, "\x1b[99D"), Some((0, 4)));
+        assert_eq!(feed(&mut grid, "\x1b[20;40H"), Some((9, 4)));
+        assert_eq!(feed(&mut grid, "\x1b[H"), Some((0, 0)));
+        assert_eq!(feed(&mut grid, "\x1b[2;2H\x1b[0;0H"), Some((0, 0)));
+        assert_eq!(feed(&mut grid, "\x1b[5G\x1b[3d"), Some((4, 2)));
+        assert_eq!(feed(&mut grid, "\x08\x08\x08\x08\x08"), Some((0, 2)));
+        assert_eq!(feed(&mut grid, "\t"), Some((8, 2)));
+        assert_eq!(feed(&mut grid, "\t"), Some((9, 2)));
+        assert_eq!(feed(&mut grid, "\x1b7\x1b[H\x1b8"), Some((9, 2)));
+        assert_eq!(feed(&mut grid, "\x1b[?25l"), None);
+        assert_eq!(feed(&mut grid, "\x1b[?25h"), Some((9, 2)));
+    }
+
+    #[test]
+    fn erase_in_line() {
+        let grid = fed(8, 1, "abcdef\x1b[3G\x1b[K");
+        assert_eq!(text(&grid), ["ab"]);
+        assert_eq!(grid.cursor(), Some((2, 0)));
+        let mut grid = fed(8, 1, "abcdef\x1b[3G\x1b[1K");
+        assert_eq!(text(&grid), ["   def"]);
+        feed(&mut grid, "\x1b[2K");
+        assert_eq!(text(&grid), [""]);
+    }
+
+    #[test]
+    fn erase_in_display() {
+        let rows = "aaaa\r\nbbbb\r\ncccc";
+        let grid = fed(4, 3, &format!("{rows}\x1b[2;2H\x1b[J"));
+        assert_eq!(text(&grid), ["aaaa", "b", ""]);
+        let grid = fed(4, 3, &format!("{rows}\x1b[2;3H\x1b[1J"));
+        assert_eq!(text(&grid), ["", "   b", "cccc"]);
+        let grid = fed(4, 3, &format!("{rows}\x1b[2;3H\x1b[2J"));
+        assert_eq!(text(&grid), ["", "", ""]);
+        // The cursor stays where it was
+        assert_eq!(grid.cursor(), Some((2, 1)));
+    }
+
+    #[test]
+    fn erased_cells_keep_the_background() {
+        let grid = fed(2, 1, "ab\x1b[1;41m\x1b[2J");
+        assert_eq!(grid.rows()[0][1], (' ', Style::default().bg(Color::Red)));
+    }
+
+    #[test]
+    fn scroll_regions() {
+        let mut grid = fed(2, 5, "1\r\n2\r\n3\r\n4\r\n5\x1b[2;4r");
+        // Setting the region homes the cursor
+        assert_eq!(grid.cursor(), Some((0, 0)));
+        feed(&mut grid, "\x1b[4H\n");
+        assert_eq!(text(&grid), ["1", "3", "4", "", "5"]);
+        feed(&mut grid, "\x1b[2H\x1bM");
+        assert_eq!(text(&grid), ["1", "", "3", "4", "5"]);
+        // Moving down stops at the bottom of the region
+        assert_eq!(feed(&mut grid, "\x1b[99B"), Some((0, 3)));
+        feed(&mut grid, "\x1b[S");
+        assert_eq!(text(&grid), ["1", "3", "4", "", "5"]);
+        feed(&mut grid, "\x1b[2T");
+        assert_eq!(text(&grid), ["1", "", "", "3", "5"]);
+        // Below the region the cursor is at the bottom of the screen, which does not scroll
+        assert_eq!(feed(&mut grid, "\x1b[5H\n"), Some((0, 4)));
+        assert_eq!(text(&grid), ["1", "", "", "3", "5"]);
+
+        // An empty region is ignored
+        assert_eq!(feed(&mut grid, "\x1b[3;3r"), Some((0, 4)));
+        // Resetting it scrolls the whole screen again
+        feed(&mut grid, "\x1b[r\x1b[5H\n");
+        assert_eq!(text(&grid), ["", "", "3", "5", ""]);
+    }
+
+    #[test]
+    fn inserting_and_deleting_lines_stays_in_the_region() {
+        let mut grid = fed(2, 5, "1\r\n2\r\n3\r\n4\r\n5\x1b[2;4r\x1b[3H\x1b[L");
+        assert_eq!(text(&grid), ["1", "2", "", "3", "5"]);
+        feed(&mut grid, "\x1b[2M");
+        assert_eq!(text(&grid), ["1", "2", "", "", "5"]);
+        // Outside the region nothing happens
+        feed(&mut grid, "\x1b[5H\x1b[L");
+        assert_eq!(text(&grid), ["1", "2", "", "", "5"]);
+    }
+
+    #[test]
+    fn sgr_colors() {
+        let grid = fed(
+            8,
+            1,
+            "\x1b[1;31mA\x1b[38;5;208mB\x1b[48;2;1;2;3mC\x1b[0mD\x1b[38;5;208;4mE",
+        );
+        let styles: Vec<Style> = grid.rows()[0].iter().map(|(_, style)| *style).collect();
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        assert_eq!(styles[0], bold.fg(Color::Red));
+        assert_eq!(styles[1], bold.fg(Color::Indexed(208)));
+        assert_eq!(
+            styles[2],
+            bold.fg(Color::Indexed(208)).bg(Color::Rgb(1, 2, 3))
+        );
+        assert_eq!(styles[3], Style::default());
+        // Parameters after an extended color still apply
+        let underlined = Style::default().add_modifier(Modifier::UNDERLINED);
+        assert_eq!(styles[4], underlined.fg(Color::Indexed(208)));
+    }
+
+    #[test]
+    fn truncated_extended_colors_are_ignored() {
+        let grid = fed(4, 1, "\x1b[32mA\x1b[38;5mB\x1b[38;2;1;2mC\x1b[38mD");
+        for (c, style) in &grid.rows()[0] {
+            assert_eq!(style.fg, Some(Color::Green), "{c}");
+        }
+    }
+
+    #[test]
+    fn sequences_split_across_notifications() {
+        let mut grid = fed(4, 1, "\x1b[3");
+        feed(&mut grid, "4mA");
+        assert_eq!(grid.rows()[0][0], ('A', Style::default().fg(Color::Blue)));
+    }
+
+    #[test]
+    fn wraps_after_the_last_column() {
+        let mut grid = fed(4, 2, "abcd");
+        // Not before the next character is written
+        assert_eq!(grid.cursor(), Some((3, 0)));
+        assert_eq!(feed(&mut grid, "e"), Some((1, 1)));
+        assert_eq!(text(&grid), ["abcd", "e"]);
+        feed(&mut grid, "fghi");
+        assert_eq!(text(&grid), ["efgh", "i"]);
+
+        let grid = fed(4, 1, "abcd\rX");
+        assert_eq!(text(&grid), ["Xbcd"]);
+        let grid = fed(4, 1, "\x1b[?7labcdef");
+        assert_eq!(text(&grid), ["abcf"]);
+        assert_eq!(grid.cursor(), Some((3, 0)));
+    }
+
+    #[test]
+    fn leaving_the_alternate_screen() {
+        let mut grid = Grid::new(4, 1);
+        assert_eq!(grid.feed("ab\x1b[?1049lrest"), Some(10));
+        assert_eq!(text(&grid), ["ab"]);
+    }
+}
@@ -0,0 +1,472 @@
+//! Server side of the WebSocket protocol (RFC 6455) for the WebSocket bridge
+
+use anyhow::{Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Appended to the client's key to compute the accept header of the handshake
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake request accepted
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Largest message accepted from a client
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// A message received from a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A ping, to be answered with a pong carrying the same data
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Answers the HTTP upgrade request of a client
+///
+/// Browsers send the page's origin, which has to be one of `origins` (`*` allowing any): any
+/// web page could connect to the bridge otherwise. Clients other than browsers send none.
+/// Returns data the client sent after the request, which belongs to the first frames.
+pub async fn accept(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    origins: &[String],
+) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let end = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if request.len() > MAX_REQUEST_LEN {
+            bail!("Handshake request too long");
+        }
+        let mut buf = [0; 1024];
+        match stream.read(&mut buf).await? {
+            0 => bail!("Client closed the connection during the handshake"),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    };
+    let rest = request.split_off(end + 4);
+    let request = String::from_utf8_lossy(&request);
+
+    let mut lines = request.split("\r\n");
+    let is_get = lines.next().is_some_and(|line| line.starts_with("GET "));
+    let mut key = None;
+    let mut upgrade = false;
+    let mut origin = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = Some(value.to_string());
+        }
+    }
+    let (true, true, Some(key)) = (is_get, upgrade, key) else {
+        let response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        stream.write_all(response.as_bytes()).await?;
+        bail!("Not a WebSocket handshake");
+    };
+    if let Some(origin) = origin.filter(|origin| !allowed(origin, origins)) {
+        let response = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        stream.write_all(response.as_bytes()).await?;
+        bail!("Origin {origin} not allowed, see --origin");
+    }
+
+    let accept = accept_key(&key);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(rest)
+}
+
+/// Whether a browser page from `origin` may connect
+fn allowed(origin: &str, origins: &[String]) -> bool {
+    origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// The accept header answering the client's key
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{HANDSHAKE_GUID}").as_bytes()))
+}
+
+/// Encodes a frame sent by the server, which is never masked
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xffff => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn text(text: &str) -> Vec<u8> {
+    frame(OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn binary(data: &[u8]) -> Vec<u8> {
+    frame(OPCODE_BINARY, data)
+}
+
+pub fn pong(data: &[u8]) -> Vec<u8> {
+    frame(OPCODE_PONG, data)
+}
+
+pub fn close() -> Vec<u8> {
+    frame(OPCODE_CLOSE, &[])
+}
+
+/// Reassembles the messages of a client from the received data
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// Opcode and data of a fragmented message received so far
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl Decoder {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Takes the next complete message out of the received data
+    pub fn next(&mut self) -> Result<Option<Message>> {
+        loop {
+            let Some((fin, opcode, payload)) = self.frame()? else {
+                return Ok(None);
+            };
+            let (opcode, payload) = match (opcode, self.fragments.take()) {
+                (OPCODE_CONTINUATION, Some((opcode, mut data))) => {
+                    data.extend_from_slice(&payload);
+                    if data.len() > MAX_MESSAGE_LEN {
+                        bail!("Message too large");
+                    }
+                    (opcode, data)
+                }
+                (OPCODE_CONTINUATION, None) => bail!("Continuation without a message"),
+                // Control frames can arrive between the fragments of a message
+                (OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG, fragments) => {
+                    self.fragments = fragments;
+                    (opcode, payload)
+                }
+                (_, Some(_)) => bail!("New message before the previous one ended"),
+                (_, None) => (opcode, payload),
+            };
+            if !fin {
+                self.fragments = Some((opcode, payload));
+                continue;
+            }
+            return Ok(Some(match opcode {
+                OPCODE_TEXT => Message::Text(String::from_utf8(payload)?),
+                OPCODE_BINARY => Message::Binary(payload),
+                OPCODE_PING => Message::Ping(payload),
+                OPCODE_PONG => continue,
+                OPCODE_CLOSE => Message::Close,
+                _ => bail!("Unknown opcode {opcode}"),
+            }));
+        }
+    }
+
+    /// Takes the next complete frame out of the buffer, unmasked
+    fn frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>> {
+        let [first, second, ..] = self.buffer[..] else {
+            return Ok(None);
+        };
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0f;
+        if second & 0x80 == 0 {
+            bail!("Client frame is not masked");
+        }
+        let (len, mut start) = match second & 0x7f {
+            126 => match self.buffer.get(2..4) {
+                Some(len) => (u64::from(u16::from_be_bytes([len[0], len[1]])), 4),
+                None => return Ok(None),
+            },
+            127 => match self.buffer.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into()?), 10),
+                None => return Ok(None),
+            },
+            len => (u64::from(len), 2),
+        };
+        if len > MAX_MESSAGE_LEN as u64 {
+            bail!("Message too large");
+        }
+        let len = len as usize;
+        if self.buffer.len() < start + 4 + len {
+            return Ok(None);
+        }
+        let mask: [u8; 4] = self.buffer[start..start + 4].try_into()?;
+        start += 4;
+        let payload = self.buffer[start..start + len]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        self.buffer.drain(..start + len);
+        Ok(Some((fin, opcode, payload)))
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..20 => ((b & c) | (!b & d), 0x5a827999),
+                20..40 => (b ^ c ^ d, 0x6ed9eba1),
+                40..60 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (out, word) in digest.chunks_exact_mut(4).zip(h) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Encodes `data` in standard base64 with padding
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client frame carrying `payload`, masked with the key of RFC 6455 §5.7
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut out = vec![first];
+        match payload.len() {
+            len @ 0..=125 => out.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(0x80 | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    fn decode(data: &[u8]) -> Result<Option<Message>> {
+        let mut decoder = Decoder::default();
+        decoder.push(data);
+        decoder.next()
+    }
+
+    /// Answer of the bridge to a handshake with the extra `headers`
+    async fn handshake(headers: &str, origins: &[&str]) -> (Result<Vec<u8>>, String) {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let request = format!(
+            "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n{headers}\r\nfirst"
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let origins: Vec<_> = origins.iter().map(|o| o.to_string()).collect();
+        let result = accept(&mut server, &origins).await;
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        (result, response)
+    }
+
+    #[test]
+    fn accept_key_of_the_rfc() {
+        // RFC 6455 §1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+    }
+
+    #[tokio::test]
+    async fn handshake_without_origin() {
+        let (rest, response) = handshake("", &[]).await;
+        assert_eq!(rest.unwrap(), b"first");
+        assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+        assert!(response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn handshake_from_browsers() {
+        let origin = "Origin: http://localhost:3000\r\n";
+        let (rest, response) = handshake(origin, &[]).await;
+        assert!(rest.is_err());
+        assert!(response.starts_with("HTTP/1.1 403 "), "{response}");
+        let (rest, response) = handshake(origin, &["http://example.com"]).await;
+        assert!(rest.is_err());
+        assert!(response.starts_with("HTTP/1.1 403 "), "{response}");
+
+        for allowed in ["http://localhost:3000", "HTTP://LOCALHOST:3000/", "*"] {
+            let (rest, response) = handshake(origin, &["http://example.com", allowed]).await;
+            assert!(rest.is_ok(), "{allowed}");
+            assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn not_a_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(accept(&mut server, &[]).await.is_err());
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+    }
+
+    #[test]
+    fn frame_lengths() {
+        assert_eq!(text("Hello"), b"\x81\x05Hello");
+        assert_eq!(close(), [0x88, 0]);
+        for (len, header) in [
+            (125, vec![0x82, 125]),
+            (126, vec![0x82, 126, 0, 126]),
+            (0xffff, vec![0x82, 126, 0xff, 0xff]),
+            (0x10000, vec![0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ] {
+            let frame = binary(&vec![0x55; len]);
+            assert_eq!(frame[..header.len()], header, "{len}");
+            assert_eq!(frame.len(), header.len() + len, "{len}");
+        }
+    }
+
+    #[test]
+    fn masked_client_frames() {
+        // RFC 6455 §5.7
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(decode(&hello).unwrap(), Some(Message::Text("Hello".into())));
+        assert_eq!(masked(0x81, b"Hello"), hello);
+        assert!(decode(b"\x81\x05Hello").is_err(), "unmasked");
+    }
+
+    #[test]
+    fn client_frame_lengths() {
+        for len in [0, 125, 126, 0xffff, 0x10000] {
+            let data = vec![0xaa; len];
+            assert_eq!(
+                decode(&masked(0x82, &data)).unwrap(),
+                Some(Message::Binary(data)),
+                "{len}"
+            );
+        }
+        let mut too_large = vec![0x82, 0x80 | 127];
+        too_large.extend_from_slice(&(MAX_MESSAGE_LEN as u64 + 1).to_be_bytes());
+        assert!(decode(&too_large).is_err());
+    }
+
+    #[test]
+    fn partial_and_fragmented_frames() {
+        let mut decoder = Decoder::default();
+        let mut data = masked(0x01, b"Hel");
+        // A ping between the fragments
+        data.extend(masked(0x89, b"p"));
+        let ping_end = data.len();
+        data.extend(masked(0x80, b"lo"));
+        for (i, byte) in data.iter().enumerate() {
+            decoder.push(&[*byte]);
+            let expected = match i + 1 {
+                end if end == ping_end => Some(Message::Ping(b"p".to_vec())),
+                end if end == data.len() => Some(Message::Text("Hello".into())),
+                _ => None,
+            };
+            assert_eq!(decoder.next().unwrap(), expected, "{i}");
+        }
+        assert!(decode(&masked(0x80, b"lo")).is_err(), "continuation first");
+    }
+}
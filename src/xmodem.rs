@@ -0,0 +1,631 @@
+//! XMODEM file transfers over the UART link
+//!
+//! Sending supports 128 byte blocks with CRC-16 or checksum and 1K blocks with CRC-16,
+//! depending on what the receiver asks for. Receiving requests CRC-16, falls back to
+//! checksums for senders that do not answer, and accepts both block sizes.
+
+use crate::screen::Screen;
+use crate::transfer::{self, Progress, Tap};
+use anyhow::{Result, anyhow, bail};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc};
+use tokio::time::Instant;
+
+pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+pub const CRC_MODE: u8 = b'C';
+/// Padding of the last block
+pub const SUB: u8 = 0x1a;
+
+pub const MAX_RETRIES: u32 = 10;
+/// How long the sender waits for the receiver to start the transfer
+pub const START_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the receiver waits between requests to start
+pub const START_INTERVAL: Duration = Duration::from_secs(3);
+/// Requests for CRC-16 made before asking for checksums, which every sender supports
+const CRC_ATTEMPTS: u32 = 3;
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest gap between the bytes of one packet
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Block sizes used when sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockSize {
+    /// 128 byte blocks, the original XMODEM
+    Standard,
+    /// 1024 byte blocks (XMODEM-1K), which need the receiver to ask for CRC-16
+    OneK,
+}
+
+/// Integrity check of each block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Checksum,
+    Crc,
+}
+
+/// Byte stream over the link, fed with notifications diverted from the terminal
+pub struct Port {
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    buffer: VecDeque<u8>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    abort: Arc<Notify>,
+}
+
+impl Port {
+    /// Reads one byte, `None` if nothing arrived within `timeout`
+    pub async fn read(&mut self, timeout: Duration) -> Result<Option<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(b) = self.buffer.pop_front() {
+                return Ok(Some(b));
+            }
+            tokio::select! {
+                data = self.incoming.recv() => match data {
+                    Some(data) => self.buffer.extend(data),
+                    None => bail!("link closed"),
+                },
+                _ = self.abort.notified() => bail!("aborted"),
+                _ = tokio::time::sleep_until(deadline) => return Ok(None),
+            }
+        }
+    }
+
+    pub async fn write(&self, data: Vec<u8>) -> Result<()> {
+        self.outgoing
+            .send(data)
+            .await
+            .map_err(|_| anyhow!("link closed"))
+    }
+
+    /// Discards input until the line has been quiet for a moment, e.g. after a corrupted packet
+    pub async fn purge(&mut self) -> Result<()> {
+        while self.read(BYTE_TIMEOUT).await?.is_some() {}
+        Ok(())
+    }
+
+    /// Tells the other side to give up the transfer
+    async fn cancel(&self) {
+        let _ = self.write(vec![CAN; 3]).await;
+    }
+
+    /// Waits for an ACK, `Ok(false)` if the packet has to be sent again
+    pub async fn acknowledged(&mut self) -> Result<bool> {
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        loop {
+            match self
+                .read(deadline.saturating_duration_since(Instant::now()))
+                .await?
+            {
+                Some(ACK) => return Ok(true),
+                Some(CAN) => bail!("cancelled by the receiver"),
+                // A late request to start the transfer, which already started
+                Some(CRC_MODE) => {}
+                _ => return Ok(false),
+            }
+        }
+    }
+}
+
+/// Starts sending `path` in the background, reporting progress in `transfer`
+pub fn spawn_send(
+    path: &Path,
+    block_size: BlockSize,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    screen: Arc<Mutex<Screen>>,
+) {
+    let data = match transfer::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            screen.lock().unwrap().status(&format!("{e:#}"));
+            return;
+        }
+    };
+    let label = match block_size {
+        BlockSize::Standard => "XMODEM SEND",
+        BlockSize::OneK => "XMODEM-1K SEND",
+    };
+    let mut port = start(label, path, Some(data.len()), write_queue, &tap, &transfer);
+    screen.lock().unwrap().status(&format!(
+        "XMODEM: sending {} ({} bytes), start the receiver on the device",
+        path.display(),
+        data.len()
+    ));
+
+    tokio::spawn(async move {
+        let result = send(&mut port, &data, block_size, &transfer).await;
+        finish(&port, result.is_err(), &tap, &transfer).await;
+        let msg = match result {
+            Ok(()) => format!("XMODEM: sent {} bytes", data.len()),
+            Err(e) => format!("XMODEM: sending failed: {e}"),
+        };
+        screen.lock().unwrap().status(&msg);
+    });
+}
+
+/// Starts receiving into `path` in the background, reporting progress in `transfer`
+pub fn spawn_receive(
+    path: PathBuf,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    screen: Arc<Mutex<Screen>>,
+) {
+    let mut port = start("XMODEM RECV", &path, None, write_queue, &tap, &transfer);
+    screen.lock().unwrap().status(&format!(
+        "XMODEM: receiving into {}, start the sender on the device",
+        path.display()
+    ));
+
+    tokio::spawn(async move {
+        let result = receive(&mut port, &transfer).await;
+        finish(&port, result.is_err(), &tap, &transfer).await;
+        let result = result.and_then(|data| {
+            std::fs::write(&path, &data)
+                .map_err(|e| anyhow!("could not write {}: {e}", path.display()))?;
+            Ok(data.len())
+        });
+        let msg = match result {
+            Ok(len) => format!("XMODEM: received {len} bytes into {}", path.display()),
+            Err(e) => format!("XMODEM: receiving failed: {e}"),
+        };
+        screen.lock().unwrap().status(&msg);
+    });
+}
+
+/// Diverts received data into a new port and registers the transfer
+pub fn start(
+    label: &'static str,
+    path: &Path,
+    total: Option<usize>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: &Tap,
+    transfer: &Mutex<Option<Progress>>,
+) -> Port {
+    let (tx, incoming) = mpsc::unbounded_channel();
+    *tap.lock().unwrap() = Some(tx);
+    let abort = Arc::new(Notify::new());
+    *transfer.lock().unwrap() = Some(Progress::new(label, path, total, abort.clone()));
+    Port {
+        incoming,
+        buffer: VecDeque::new(),
+        outgoing: write_queue,
+        abort,
+    }
+}
+
+/// Cancels a failed transfer and gives the link back to the terminal
+pub async fn finish(port: &Port, failed: bool, tap: &Tap, transfer: &Mutex<Option<Progress>>) {
+    if failed {
+        port.cancel().await;
+    }
+    *tap.lock().unwrap() = None;
+    *transfer.lock().unwrap() = None;
+}
+
+/// Sends `data` once the receiver starts the transfer, ending it with EOT
+pub async fn send(
+    port: &mut Port,
+    data: &[u8],
+    block_size: BlockSize,
+    transfer: &Mutex<Option<Progress>>,
+) -> Result<()> {
+    // The receiver starts the transfer by asking for the integrity check it supports
+    let deadline = Instant::now() + START_TIMEOUT;
+    let check = loop {
+        match port
+            .read(deadline.saturating_duration_since(Instant::now()))
+            .await?
+        {
+            Some(CRC_MODE) => break Check::Crc,
+            Some(NAK) => break Check::Checksum,
+            Some(CAN) => bail!("cancelled by the receiver"),
+            Some(_) => {}
+            None => bail!("receiver did not start"),
+        }
+    };
+    let block_len = match (block_size, check) {
+        (BlockSize::OneK, Check::Crc) => 1024,
+        _ => 128,
+    };
+
+    let mut sequence: u8 = 1;
+    let mut offset = 0;
+    while offset < data.len() {
+        // Short remainders go into a small block to save padding
+        let len = if data.len() - offset <= 128 {
+            128
+        } else {
+            block_len
+        };
+        let end = (offset + len).min(data.len());
+        let packet = packet(sequence, &data[offset..end], len, check, SUB);
+
+        let mut retries = 0;
+        loop {
+            port.write(packet.clone()).await?;
+            if port.acknowledged().await? {
+                break;
+            }
+            retries += 1;
+            if retries == MAX_RETRIES {
+                bail!("block {sequence} not acknowledged");
+            }
+        }
+
+        offset = end;
+        sequence = sequence.wrapping_add(1);
+        if let Some(progress) = transfer.lock().unwrap().as_mut() {
+            progress.transferred = offset;
+        }
+    }
+
+    for _ in 0..MAX_RETRIES {
+        port.write(vec![EOT]).await?;
+        if port.acknowledged().await? {
+            return Ok(());
+        }
+    }
+    bail!("end of transfer not acknowledged")
+}
+
+/// Builds a packet holding `data` padded to `len` bytes with `pad`
+pub fn packet(sequence: u8, data: &[u8], len: usize, check: Check, pad: u8) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(len + 5);
+    packet.push(if len == 1024 { STX } else { SOH });
+    packet.push(sequence);
+    packet.push(!sequence);
+    packet.extend_from_slice(data);
+    packet.resize(3 + len, pad);
+    match check {
+        Check::Checksum => packet.push(checksum(&packet[3..])),
+        Check::Crc => packet.extend_from_slice(&crc16(&packet[3..]).to_be_bytes()),
+    }
+    packet
+}
+
+async fn receive(port: &mut Port, transfer: &Mutex<Option<Progress>>) -> Result<Vec<u8>> {
+    let mut data = receive_blocks(port, transfer).await?;
+    // XMODEM does not transmit the file size, strip the padding of the last block
+    while data.last() == Some(&SUB) {
+        data.pop();
+    }
+    Ok(data)
+}
+
+/// Starts the transfer asking for CRC-16, or checksums if the sender does not answer, and
+/// receives the blocks up to EOT, with the padding of the last one
+pub async fn receive_blocks(
+    port: &mut Port,
+    transfer: &Mutex<Option<Progress>>,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut expected: u8 = 1;
+    let mut errors = 0;
+    let mut started = false;
+    let mut check = Check::Crc;
+
+    loop {
+        if !started {
+            if errors == MAX_RETRIES {
+                bail!("sender did not start");
+            }
+            if errors == CRC_ATTEMPTS {
+                check = Check::Checksum;
+            }
+            let request = match check {
+                Check::Crc => CRC_MODE,
+                Check::Checksum => NAK,
+            };
+            port.write(vec![request]).await?;
+        }
+        let timeout = if started { ACK_TIMEOUT } else { START_INTERVAL };
+        let len = match port.read(timeout).await? {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            Some(EOT) => {
+                port.write(vec![ACK]).await?;
+                break;
+            }
+            Some(CAN) if started => bail!("cancelled by the sender"),
+            _ if !started => {
+                errors += 1;
+                port.purge().await?;
+                continue;
+            }
+            _ => {
+                errors += 1;
+                if errors == MAX_RETRIES {
+                    bail!("too many errors");
+                }
+                port.purge().await?;
+                port.write(vec![NAK]).await?;
+                continue;
+            }
+        };
+        started = true;
+
+        let Some((sequence, block)) = read_packet(port, len, check).await? else {
+            errors += 1;
+            if errors == MAX_RETRIES {
+                bail!("too many errors");
+            }
+            port.purge().await?;
+            port.write(vec![NAK]).await?;
+            continue;
+        };
+        if sequence == expected {
+            data.extend_from_slice(&block);
+            expected = expected.wrapping_add(1);
+            if let Some(progress) = transfer.lock().unwrap().as_mut() {
+                progress.transferred = data.len();
+            }
+        } else if sequence != expected.wrapping_sub(1) {
+            // Neither the next block nor a repetition of the last one
+            bail!("block {sequence} out of sequence, expected {expected}");
+        }
+        errors = 0;
+        port.write(vec![ACK]).await?;
+    }
+    Ok(data)
+}
+
+/// Reads the rest of a packet of `len` data bytes after its SOH or STX, returns its sequence
+/// number and data, `None` if it is cut short or corrupted
+pub async fn read_packet(
+    port: &mut Port,
+    len: usize,
+    check: Check,
+) -> Result<Option<(u8, Vec<u8>)>> {
+    let total = match check {
+        Check::Checksum => len + 3,
+        Check::Crc => len + 4,
+    };
+    let mut packet = Vec::with_capacity(total);
+    while packet.len() < total {
+        match port.read(BYTE_TIMEOUT).await? {
+            Some(b) => packet.push(b),
+            None => break,
+        }
+    }
+    let valid = packet.len() == total
+        && packet[0] == !packet[1]
+        && match check {
+            Check::Checksum => checksum(&packet[2..2 + len]) == packet[2 + len],
+            Check::Crc => crc16(&packet[2..2 + len]).to_be_bytes() == packet[2 + len..],
+        };
+    Ok(valid.then(|| (packet[0], packet[2..2 + len].to_vec())))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, initial value 0)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The other end of a port, seeing every write as one chunk
+    pub struct Peer {
+        pub tx: mpsc::UnboundedSender<Vec<u8>>,
+        pub rx: mpsc::Receiver<Vec<u8>>,
+    }
+
+    impl Peer {
+        pub fn send(&self, data: &[u8]) {
+            self.tx.send(data.to_vec()).unwrap();
+        }
+
+        pub async fn recv(&mut self) -> Vec<u8> {
+            self.rx.recv().await.unwrap()
+        }
+    }
+
+    pub fn port() -> (Port, Peer) {
+        let (write_queue, rx) = mpsc::channel(64);
+        let tap = Tap::default();
+        let port = start(
+            "TEST",
+            Path::new("test"),
+            None,
+            write_queue,
+            &tap,
+            &Mutex::new(None),
+        );
+        let tx = tap.lock().unwrap().take().unwrap();
+        (port, Peer { tx, rx })
+    }
+
+    /// Two ports connected to each other
+    pub fn pair() -> (Port, Port) {
+        let (a, mut a_peer) = port();
+        let (b, mut b_peer) = port();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(data) = a_peer.rx.recv() => { let _ = b_peer.tx.send(data); }
+                    Some(data) = b_peer.rx.recv() => { let _ = a_peer.tx.send(data); }
+                    else => return,
+                }
+            }
+        });
+        (a, b)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn packet_framing() {
+        let packet = packet(1, b"hi", 128, Check::Checksum, SUB);
+        assert_eq!(packet.len(), 3 + 128 + 1);
+        assert_eq!(&packet[..5], &[SOH, 1, 0xfe, b'h', b'i']);
+        assert!(packet[5..131].iter().all(|&b| b == SUB));
+        assert_eq!(packet[131], checksum(&packet[3..131]));
+
+        let packet = super::packet(0xff, &[0; 1024], 1024, Check::Crc, SUB);
+        assert_eq!(packet.len(), 3 + 1024 + 2);
+        assert_eq!(&packet[..3], &[STX, 0xff, 0x00]);
+        assert_eq!(&packet[1027..], &crc16(&[0; 1024]).to_be_bytes());
+    }
+
+    async fn loopback(len: usize, block_size: BlockSize) {
+        let (mut sender, mut receiver) = pair();
+        let sent = data(len);
+        let transfer = Mutex::new(None);
+        let (sent_result, received) = tokio::join!(
+            send(&mut sender, &sent, block_size, &transfer),
+            receive(&mut receiver, &transfer)
+        );
+        sent_result.unwrap();
+        assert_eq!(received.unwrap(), sent);
+    }
+
+    #[tokio::test]
+    async fn loopback_standard_blocks() {
+        loopback(1000, BlockSize::Standard).await;
+    }
+
+    #[tokio::test]
+    async fn loopback_1k_blocks() {
+        // Two 1K blocks and a 128 byte one for the rest
+        loopback(2100, BlockSize::OneK).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receiver_falls_back_to_checksums() {
+        let (mut port, mut peer) = port();
+        let sender = async {
+            // A sender without CRC-16 only answers a NAK
+            let mut requests = Vec::new();
+            loop {
+                let request = peer.recv().await;
+                requests.extend_from_slice(&request);
+                if request == [NAK] {
+                    break;
+                }
+            }
+            assert_eq!(
+                requests,
+                [[CRC_MODE; CRC_ATTEMPTS as usize].as_slice(), &[NAK]].concat()
+            );
+            peer.send(&packet(1, b"hello", 128, Check::Checksum, SUB));
+            assert_eq!(peer.recv().await, [ACK]);
+            peer.send(&[EOT]);
+            assert_eq!(peer.recv().await, [ACK]);
+        };
+        let transfer = Mutex::new(None);
+        let (received, ()) = tokio::join!(receive(&mut port, &transfer), sender);
+        assert_eq!(received.unwrap(), b"hello");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receiver_naks_corrupted_blocks() {
+        let (mut port, mut peer) = port();
+        let sender = async {
+            assert_eq!(peer.recv().await, [CRC_MODE]);
+            let good = packet(1, b"data", 128, Check::Crc, SUB);
+            let mut bad = good.clone();
+            bad[10] ^= 0xff;
+            peer.send(&bad);
+            assert_eq!(peer.recv().await, [NAK]);
+            peer.send(&good);
+            assert_eq!(peer.recv().await, [ACK]);
+            // A repeated block whose ACK got lost is acknowledged again, not added twice
+            peer.send(&good);
+            assert_eq!(peer.recv().await, [ACK]);
+            peer.send(&[EOT]);
+            assert_eq!(peer.recv().await, [ACK]);
+        };
+        let transfer = Mutex::new(None);
+        let (received, ()) = tokio::join!(receive(&mut port, &transfer), sender);
+        assert_eq!(received.unwrap(), b"data");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_retries_and_ignores_late_start_requests() {
+        let (mut port, mut peer) = port();
+        let sent = data(200);
+        let receiver = async {
+            peer.send(&[CRC_MODE]);
+            let first = peer.recv().await;
+            assert_eq!(first, packet(1, &sent[..128], 128, Check::Crc, SUB));
+            peer.send(&[NAK]);
+            assert_eq!(peer.recv().await, first);
+            // A 'C' sent before the first block arrived is not a NAK
+            peer.send(&[CRC_MODE, ACK]);
+            assert_eq!(
+                peer.recv().await,
+                packet(2, &sent[128..], 128, Check::Crc, SUB)
+            );
+            peer.send(&[ACK]);
+            assert_eq!(peer.recv().await, [EOT]);
+            peer.send(&[NAK]);
+            assert_eq!(peer.recv().await, [EOT]);
+            peer.send(&[ACK]);
+        };
+        let transfer = Mutex::new(None);
+        let (result, ()) = tokio::join!(
+            send(&mut port, &sent, BlockSize::Standard, &transfer),
+            receiver
+        );
+        result.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_uses_checksums_when_asked() {
+        let (mut port, mut peer) = port();
+        let receiver = async {
+            peer.send(&[NAK]);
+            // No 1K blocks without CRC-16
+            assert_eq!(
+                peer.recv().await,
+                packet(1, &[7; 200][..128], 128, Check::Checksum, SUB)
+            );
+            peer.send(&[ACK]);
+            assert_eq!(
+                peer.recv().await,
+                packet(2, &[7; 72], 128, Check::Checksum, SUB)
+            );
+            peer.send(&[CAN]);
+        };
+        let transfer = Mutex::new(None);
+        let (result, ()) = tokio::join!(
+            send(&mut port, &[7; 200], BlockSize::OneK, &transfer),
+            receiver
+        );
+        assert_eq!(result.unwrap_err().to_string(), "cancelled by the receiver");
+    }
+}
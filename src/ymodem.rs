@@ -0,0 +1,443 @@
+//! YMODEM batch file transfers over the UART link
+//!
+//! Every file starts with block 0 holding its name and size, followed by its data as in
+//! XMODEM-1K with CRC-16. A block 0 with an empty name ends the batch.
+
+use crate::screen::Screen;
+use crate::transfer::{self, Progress, Tap};
+use crate::xmodem::{
+    self, ACK, BlockSize, CAN, CRC_MODE, Check, MAX_RETRIES, NAK, Port, SOH, START_INTERVAL,
+    START_TIMEOUT, STX,
+};
+use anyhow::{Result, anyhow, bail};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// A file as described by its block 0, which ZMODEM uses for its ZFILE frames too
+#[derive(Debug)]
+pub struct Header {
+    pub name: String,
+    /// Unknown if the sender left it out
+    pub size: Option<usize>,
+}
+
+impl Header {
+    /// Parses block 0, `None` for the empty one ending the batch
+    pub fn parse(block: &[u8]) -> Option<Header> {
+        let mut fields = block.split(|&b| b == 0);
+        let name = fields.next().filter(|name| !name.is_empty())?;
+        // The size may be followed by the modification time and mode, separated by spaces
+        let size = fields.next().and_then(|rest| {
+            let size = rest.split(|&b| b == b' ').next()?;
+            std::str::from_utf8(size).ok()?.parse().ok()
+        });
+        Some(Header {
+            name: String::from_utf8_lossy(name).into_owned(),
+            size,
+        })
+    }
+
+    /// Only the name, a sender cannot put files outside the directory they are received into
+    pub fn file_name(&self) -> Result<&OsStr> {
+        Path::new(&self.name)
+            .file_name()
+            .ok_or(anyhow!("invalid file name '{}'", self.name))
+    }
+
+    /// Block 0 for the file, the name followed by NUL and its size in decimal
+    fn encode(&self) -> Vec<u8> {
+        let mut block = self.name.as_bytes().to_vec();
+        block.push(0);
+        if let Some(size) = self.size {
+            block.extend_from_slice(size.to_string().as_bytes());
+        }
+        block
+    }
+}
+
+/// The files sent for `path`, every file in it for a directory
+fn files(path: &Path) -> Result<Vec<(Header, Vec<u8>)>> {
+    let paths = if path.is_dir() {
+        let mut paths = std::fs::read_dir(path)
+            .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    } else {
+        vec![path.to_path_buf()]
+    };
+    paths
+        .iter()
+        .map(|path| {
+            let data = transfer::read(path)?;
+            let name = path
+                .file_name()
+                .ok_or(anyhow!("{} has no file name", path.display()))?
+                .to_string_lossy()
+                .into_owned();
+            let header = Header {
+                name,
+                size: Some(data.len()),
+            };
+            if header.encode().len() > 1024 {
+                bail!("The name of {} is too long for YMODEM", path.display());
+            }
+            Ok((header, data))
+        })
+        .collect()
+}
+
+/// Starts sending `path` in the background, or every file in it for a directory
+pub fn spawn_send(
+    path: &Path,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    screen: Arc<Mutex<Screen>>,
+) {
+    let files = match files(path) {
+        Ok(files) if files.is_empty() => {
+            let msg = format!("YMODEM: no files in {}", path.display());
+            screen.lock().unwrap().status(&msg);
+            return;
+        }
+        Ok(files) => files,
+        Err(e) => {
+            screen.lock().unwrap().status(&format!("{e:#}"));
+            return;
+        }
+    };
+    let total: usize = files.iter().map(|(_, data)| data.len()).sum();
+    let mut port = xmodem::start("YMODEM SEND", path, None, write_queue, &tap, &transfer);
+    screen.lock().unwrap().status(&format!(
+        "YMODEM: sending {} files ({total} bytes), start the receiver on the device",
+        files.len()
+    ));
+
+    tokio::spawn(async move {
+        let result = send(&mut port, &files, &transfer).await;
+        xmodem::finish(&port, result.is_err(), &tap, &transfer).await;
+        let msg = match result {
+            Ok(()) => format!("YMODEM: sent {} files ({total} bytes)", files.len()),
+            Err(e) => format!("YMODEM: sending failed: {e}"),
+        };
+        screen.lock().unwrap().status(&msg);
+    });
+}
+
+/// Starts receiving files into the directory `dir` in the background
+pub fn spawn_receive(
+    dir: PathBuf,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    screen: Arc<Mutex<Screen>>,
+) {
+    let dir = if dir.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        dir
+    };
+    if !dir.is_dir() {
+        let msg = format!("YMODEM: {} is not a directory", dir.display());
+        screen.lock().unwrap().status(&msg);
+        return;
+    }
+    let mut port = xmodem::start("YMODEM RECV", &dir, None, write_queue, &tap, &transfer);
+    screen.lock().unwrap().status(&format!(
+        "YMODEM: receiving into {}, start the sender on the device",
+        dir.display()
+    ));
+
+    tokio::spawn(async move {
+        let result = receive(&mut port, &dir, &transfer, &screen).await;
+        xmodem::finish(&port, result.is_err(), &tap, &transfer).await;
+        let msg = match result {
+            Ok(count) => format!("YMODEM: received {count} files into {}", dir.display()),
+            Err(e) => format!("YMODEM: receiving failed: {e}"),
+        };
+        screen.lock().unwrap().status(&msg);
+    });
+}
+
+async fn send(
+    port: &mut Port,
+    files: &[(Header, Vec<u8>)],
+    transfer: &Mutex<Option<Progress>>,
+) -> Result<()> {
+    for (header, data) in files {
+        if let Some(progress) = transfer.lock().unwrap().as_mut() {
+            progress.name = header.name.clone();
+            progress.total = Some(data.len());
+            progress.transferred = 0;
+        }
+        send_header(port, &header.encode()).await?;
+        xmodem::send(port, data, BlockSize::OneK, transfer).await?;
+    }
+    send_header(port, &[]).await
+}
+
+/// Sends block 0 once the receiver asks for it
+async fn send_header(port: &mut Port, header: &[u8]) -> Result<()> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        match port
+            .read(deadline.saturating_duration_since(Instant::now()))
+            .await?
+        {
+            Some(CRC_MODE) => break,
+            Some(NAK) => bail!("the receiver asked for XMODEM with checksums"),
+            Some(CAN) => bail!("cancelled by the receiver"),
+            Some(_) => {}
+            None => bail!("receiver did not start"),
+        }
+    }
+    let len = if header.len() > 128 { 1024 } else { 128 };
+    let packet = xmodem::packet(0, header, len, Check::Crc, 0);
+    for _ in 0..MAX_RETRIES {
+        port.write(packet.clone()).await?;
+        if port.acknowledged().await? {
+            return Ok(());
+        }
+    }
+    bail!("file header not acknowledged")
+}
+
+/// Receives files into `dir` until the batch ends, returns how many there were
+async fn receive(
+    port: &mut Port,
+    dir: &Path,
+    transfer: &Mutex<Option<Progress>>,
+    screen: &Mutex<Screen>,
+) -> Result<usize> {
+    let mut count = 0;
+    while let Some(header) = receive_header(port).await? {
+        let name = header.file_name()?;
+        let path = dir.join(name);
+        if let Some(progress) = transfer.lock().unwrap().as_mut() {
+            progress.name = name.to_string_lossy().into_owned();
+            progress.total = header.size;
+            progress.transferred = 0;
+        }
+
+        let mut data = xmodem::receive_blocks(port, transfer).await?;
+        match header.size {
+            Some(size) => data.truncate(size),
+            None => {
+                while data.last() == Some(&xmodem::SUB) {
+                    data.pop();
+                }
+            }
+        }
+        std::fs::write(&path, &data)
+            .map_err(|e| anyhow!("could not write {}: {e}", path.display()))?;
+        let msg = format!("YMODEM: received {} ({} bytes)", path.display(), data.len());
+        screen.lock().unwrap().status(&msg);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Asks for block 0 until it arrives, `None` if it ends the batch
+async fn receive_header(port: &mut Port) -> Result<Option<Header>> {
+    for _ in 0..MAX_RETRIES {
+        port.write(vec![CRC_MODE]).await?;
+        let len = match port.read(START_INTERVAL).await? {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            _ => {
+                port.purge().await?;
+                continue;
+            }
+        };
+        match xmodem::read_packet(port, len, Check::Crc).await? {
+            Some((0, block)) => {
+                port.write(vec![ACK]).await?;
+                return Ok(Header::parse(&block));
+            }
+            _ => port.purge().await?,
+        }
+    }
+    bail!("sender did not start")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode;
+    use crate::screen::Newline;
+    use crate::xmodem::tests::{pair, port};
+
+    fn screen() -> Mutex<Screen> {
+        Mutex::new(Screen::new(100, decode::Mode::Text, Newline::Lf))
+    }
+
+    /// An empty directory of its own for every test
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nus-ymodem-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn header_holds_name_and_size() {
+        let header = Header {
+            name: "log.txt".into(),
+            size: Some(1234),
+        };
+        assert_eq!(header.encode(), b"log.txt\x001234");
+        let header = Header {
+            name: "log.txt".into(),
+            size: None,
+        };
+        assert_eq!(header.encode(), b"log.txt\x00");
+    }
+
+    #[test]
+    fn header_parse_skips_padding_and_extra_fields() {
+        let mut block = b"fw.bin\x0065536 14404212431 100644".to_vec();
+        block.resize(128, 0);
+        let header = Header::parse(&block).unwrap();
+        assert_eq!((header.name.as_str(), header.size), ("fw.bin", Some(65536)));
+
+        let header = Header::parse(&[b"fw.bin\0".as_slice(), &[0; 121]].concat()).unwrap();
+        assert_eq!(header.size, None);
+        let header = Header::parse(b"fw.bin\0big\0").unwrap();
+        assert_eq!(header.size, None);
+        // The null header ending the batch
+        assert!(Header::parse(&[0; 128]).is_none());
+    }
+
+    #[test]
+    fn header_file_name_drops_directories() {
+        let header = |name: &str| Header {
+            name: name.into(),
+            size: None,
+        };
+        assert_eq!(header("../../etc/passwd").file_name().unwrap(), "passwd");
+        assert_eq!(header("/tmp/a.bin").file_name().unwrap(), "a.bin");
+        assert!(header("..").file_name().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn block_0_is_padded_with_zeros() {
+        let (mut port, mut peer) = port();
+        let files = [(
+            Header {
+                name: "a.txt".into(),
+                size: Some(3),
+            },
+            b"abc".to_vec(),
+        )];
+        let receiver = async {
+            peer.send(&[CRC_MODE]);
+            let block = peer.recv().await;
+            assert_eq!(block, xmodem::packet(0, b"a.txt\x003", 128, Check::Crc, 0));
+            assert_eq!(&block[..3], [SOH, 0, 0xff]);
+            assert!(block[3 + 7..3 + 128].iter().all(|&b| b == 0));
+            peer.send(&[ACK]);
+            // The data, as in XMODEM
+            peer.send(&[CRC_MODE]);
+            assert_eq!(
+                peer.recv().await,
+                xmodem::packet(1, b"abc", 128, Check::Crc, xmodem::SUB)
+            );
+            peer.send(&[ACK]);
+            assert_eq!(peer.recv().await, [0x04]);
+            peer.send(&[ACK]);
+            // The null header, block 0 of nothing but zeros
+            peer.send(&[CRC_MODE]);
+            let block = peer.recv().await;
+            assert_eq!(block, xmodem::packet(0, &[], 128, Check::Crc, 0));
+            assert!(block[3..3 + 128].iter().all(|&b| b == 0));
+            peer.send(&[ACK]);
+        };
+        let transfer = Mutex::new(None);
+        let (result, ()) = tokio::join!(send(&mut port, &files, &transfer), receiver);
+        result.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_header_takes_a_1k_block() {
+        let (mut port, mut peer) = port();
+        let name = "n".repeat(200);
+        let receiver = async {
+            peer.send(&[CRC_MODE]);
+            let block = peer.recv().await;
+            assert_eq!(block[0], STX);
+            assert_eq!(block.len(), 3 + 1024 + 2);
+            peer.send(&[ACK]);
+        };
+        let (result, ()) = tokio::join!(send_header(&mut port, name.as_bytes()), receiver);
+        result.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_refuses_checksum_mode() {
+        let (mut port, peer) = port();
+        peer.send(&[NAK]);
+        let error = send_header(&mut port, b"a\0").await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the receiver asked for XMODEM with checksums"
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_of_several_files() {
+        let dir = dir("batch");
+        let files: Vec<_> = [("one.txt", 10), ("two.bin", 1500), ("empty", 0)]
+            .into_iter()
+            .map(|(name, len)| {
+                let header = Header {
+                    name: name.into(),
+                    size: Some(len),
+                };
+                // Ending in SUB, which the size keeps from being taken for padding
+                (header, vec![xmodem::SUB; len])
+            })
+            .collect();
+        let (mut sender, mut receiver) = pair();
+        let (transfer, screen) = (Mutex::new(None), screen());
+        let (sent, received) = tokio::join!(
+            send(&mut sender, &files, &transfer),
+            super::receive(&mut receiver, &dir, &transfer, &screen)
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), 3);
+        for (header, data) in &files {
+            assert_eq!(&std::fs::read(dir.join(&header.name)).unwrap(), data);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn receiver_strips_padding_without_size_and_directories() {
+        let dir = dir("nosize");
+        let (mut sender, mut receiver) = pair();
+        let (transfer, screen) = (Mutex::new(None), screen());
+        let header = Header {
+            name: "sub/raw.bin".into(),
+            size: None,
+        };
+        let sending = async {
+            send_header(&mut sender, &header.encode()).await?;
+            xmodem::send(&mut sender, b"raw", BlockSize::OneK, &transfer).await?;
+            send_header(&mut sender, &[]).await
+        };
+        let (sent, received) = tokio::join!(
+            sending,
+            super::receive(&mut receiver, &dir, &transfer, &screen)
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), 1);
+        assert_eq!(std::fs::read(dir.join("raw.bin")).unwrap(), b"raw");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
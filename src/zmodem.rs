@@ -0,0 +1,669 @@
+//! ZMODEM downloads started by the device, received into the directory of `--zmodem-dir`
+//!
+//! A sender like `sz` starts with a ZRQINIT header, which is spotted in the received data like
+//! minicom does with lrzsz. Frames with CRC-16 and CRC-32 are accepted. Resuming, compression
+//! and commands sent with ZCOMMAND are not supported.
+
+use crate::screen::Screen;
+use crate::transfer::{Progress, Tap};
+use crate::xmodem::{self, CAN, MAX_RETRIES, Port};
+use crate::ymodem::Header as FileInfo;
+use anyhow::{Result, anyhow, bail};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const ZPAD: u8 = b'*';
+/// Escapes control characters, the same byte as CAN
+const ZDLE: u8 = 0x18;
+const XON: u8 = 0x11;
+
+// Header formats following ZPAD ZDLE
+const ZBIN: u8 = b'A';
+const ZHEX: u8 = b'B';
+const ZBIN32: u8 = b'C';
+
+// Frame types
+const ZRQINIT: u8 = 0;
+const ZRINIT: u8 = 1;
+const ZSINIT: u8 = 2;
+const ZACK: u8 = 3;
+const ZFILE: u8 = 4;
+const ZABORT: u8 = 7;
+const ZFIN: u8 = 8;
+const ZRPOS: u8 = 9;
+const ZDATA: u8 = 10;
+const ZEOF: u8 = 11;
+const ZFERR: u8 = 12;
+const ZCOMMAND: u8 = 18;
+
+// Ends of data subpackets: frame ends, frame goes on, with and without an ACK
+const ZCRCE: u8 = b'h';
+const ZCRCG: u8 = b'i';
+const ZCRCQ: u8 = b'j';
+const ZCRCW: u8 = b'k';
+// Escaped DEL and DEL with the high bit set
+const ZRUB0: u8 = b'l';
+const ZRUB1: u8 = b'm';
+
+/// What ZRINIT announces: full duplex, receiving while writing the file and CRC-32
+const RECEIVER_FLAGS: u8 = 0x01 | 0x02 | 0x20;
+
+/// The start of ZRQINIT as a hex header
+const START: &[u8] = b"**\x18B00";
+
+/// How long the receiver waits for a header before repeating its last one
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest gap between the bytes of one header or subpacket
+const BYTE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Data skipped while looking for a header before repeating the last one, e.g. the rest of a
+/// stream that went on after an error
+const MAX_GARBAGE: usize = 64 * 1024;
+const MAX_SUBPACKET_LEN: usize = 8192;
+
+/// Spots the start of a transfer in the received data
+#[derive(Debug, Default)]
+pub struct Detector {
+    /// Bytes of [`START`] at the end of the data so far
+    matched: usize,
+}
+
+impl Detector {
+    /// Where the start sequence begins in `data`, 0 if it began in earlier data
+    pub fn find(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &b) in data.iter().enumerate() {
+            self.matched = if b == START[self.matched] {
+                self.matched + 1
+            } else if b == ZPAD && self.matched == 2 {
+                // A third pad, the last two still start the sequence
+                2
+            } else {
+                usize::from(b == ZPAD)
+            };
+            if self.matched == START.len() {
+                self.matched = 0;
+                return Some((i + 1).saturating_sub(START.len()));
+            }
+        }
+        None
+    }
+}
+
+/// Starts receiving the files the device sends into `dir` in the background
+pub fn spawn_receive(
+    dir: PathBuf,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    tap: Tap,
+    transfer: Arc<Mutex<Option<Progress>>>,
+    screen: Arc<Mutex<Screen>>,
+) {
+    if !dir.is_dir() {
+        let msg = format!("ZMODEM: {} is not a directory", dir.display());
+        screen.lock().unwrap().status(&msg);
+        return;
+    }
+    let mut port = xmodem::start("ZMODEM RECV", &dir, None, write_queue, &tap, &transfer);
+    screen
+        .lock()
+        .unwrap()
+        .status(&format!("ZMODEM: receiving into {}", dir.display()));
+
+    tokio::spawn(async move {
+        let result = receive(&mut port, &dir, &transfer, &screen).await;
+        if result.is_err() {
+            // Eight CANs abort a ZMODEM transfer, the backspaces erase them from a command line
+            let _ = port.write([[CAN; 8], [0x08; 8]].concat()).await;
+        }
+        xmodem::finish(&port, false, &tap, &transfer).await;
+        let msg = match result {
+            Ok(count) => format!("ZMODEM: received {count} files into {}", dir.display()),
+            Err(e) => format!("ZMODEM: receiving failed: {e}"),
+        };
+        screen.lock().unwrap().status(&msg);
+    });
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: u8,
+    /// Position in the file or flags, depending on the kind
+    data: [u8; 4],
+    /// Whether the subpackets following it end with CRC-32
+    crc32: bool,
+}
+
+impl Header {
+    fn position(&self) -> usize {
+        u32::from_le_bytes(self.data) as usize
+    }
+}
+
+/// A file being received
+struct Download {
+    path: PathBuf,
+    file: File,
+    /// Bytes written so far
+    offset: usize,
+}
+
+/// Receives files into `dir` until the sender is done, returns how many there were
+async fn receive(
+    port: &mut Port,
+    dir: &Path,
+    transfer: &Mutex<Option<Progress>>,
+    screen: &Mutex<Screen>,
+) -> Result<usize> {
+    let zrinit = hex_header(ZRINIT, [0, 0, 0, RECEIVER_FLAGS]);
+    let mut count = 0;
+    let mut download: Option<Download> = None;
+    // Repeated when the sender goes quiet
+    let mut reply = zrinit.clone();
+    port.write(reply.clone()).await?;
+    let mut retries = 0;
+    loop {
+        let Some(header) = read_header(port).await? else {
+            retries += 1;
+            if retries == MAX_RETRIES {
+                bail!("sender stopped answering");
+            }
+            port.write(reply.clone()).await?;
+            continue;
+        };
+        retries = 0;
+        match header.kind {
+            ZRQINIT => port.write(reply.clone()).await?,
+            ZSINIT => {
+                // The attention string is only needed for interrupting the sender
+                let attention = read_subpacket(port, header.crc32).await?;
+                if attention.is_some() {
+                    port.write(hex_header(ZACK, [0; 4])).await?;
+                }
+            }
+            ZFILE => {
+                let Some((info, _)) = read_subpacket(port, header.crc32).await? else {
+                    // Asks for the file again
+                    port.write(reply.clone()).await?;
+                    continue;
+                };
+                let info = FileInfo::parse(&info).ok_or(anyhow!("file without a name"))?;
+                let name = info.file_name()?;
+                let path = dir.join(name);
+                let file = File::create(&path)
+                    .map_err(|e| anyhow!("could not create {}: {e}", path.display()))?;
+                if let Some(progress) = transfer.lock().unwrap().as_mut() {
+                    progress.name = name.to_string_lossy().into_owned();
+                    progress.total = info.size;
+                    progress.transferred = 0;
+                }
+                download = Some(Download {
+                    path,
+                    file,
+                    offset: 0,
+                });
+                reply = position_header(ZRPOS, 0);
+                port.write(reply.clone()).await?;
+            }
+            ZDATA => {
+                let download = download.as_mut().ok_or(anyhow!("data before a file"))?;
+                if header.position() != download.offset {
+                    reply = position_header(ZRPOS, download.offset);
+                    port.write(reply.clone()).await?;
+                    continue;
+                }
+                receive_data(port, header.crc32, download, transfer).await?;
+                reply = position_header(ZRPOS, download.offset);
+            }
+            ZEOF => {
+                // An end at another position is for data not received yet, ZRPOS asks for it
+                let Some(done) = download.take_if(|d| d.offset == header.position()) else {
+                    continue;
+                };
+                done.file
+                    .sync_all()
+                    .map_err(|e| anyhow!("could not write {}: {e}", done.path.display()))?;
+                let msg = format!(
+                    "ZMODEM: received {} ({} bytes)",
+                    done.path.display(),
+                    done.offset
+                );
+                screen.lock().unwrap().status(&msg);
+                count += 1;
+                reply = zrinit.clone();
+                port.write(reply.clone()).await?;
+            }
+            ZFIN => {
+                port.write(hex_header(ZFIN, [0; 4])).await?;
+                // The sender ends with "OO", over and out
+                for _ in 0..2 {
+                    if port.read(Duration::from_secs(1)).await?.is_none() {
+                        break;
+                    }
+                }
+                return Ok(count);
+            }
+            ZCOMMAND => bail!("the sender asked to run a command"),
+            ZABORT | ZFERR => bail!("aborted by the sender"),
+            _ => {}
+        }
+    }
+}
+
+/// Writes the subpackets of a ZDATA frame to the file until the frame ends or is corrupted
+async fn receive_data(
+    port: &mut Port,
+    crc32: bool,
+    download: &mut Download,
+    transfer: &Mutex<Option<Progress>>,
+) -> Result<()> {
+    loop {
+        let Some((data, end)) = read_subpacket(port, crc32).await? else {
+            // The sender goes back to where the data was still intact
+            return port.write(position_header(ZRPOS, download.offset)).await;
+        };
+        download
+            .file
+            .write_all(&data)
+            .map_err(|e| anyhow!("could not write {}: {e}", download.path.display()))?;
+        download.offset += data.len();
+        if let Some(progress) = transfer.lock().unwrap().as_mut() {
+            progress.transferred = download.offset;
+        }
+        match end {
+            ZCRCG => {}
+            ZCRCQ => port.write(position_header(ZACK, download.offset)).await?,
+            ZCRCW => return port.write(position_header(ZACK, download.offset)).await,
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Reads the next header, `None` if none arrived intact in time
+async fn read_header(port: &mut Port) -> Result<Option<Header>> {
+    let mut skipped = 0;
+    let mut padded = false;
+    let mut cans = 0;
+    loop {
+        let Some(b) = port.read(HEADER_TIMEOUT).await? else {
+            return Ok(None);
+        };
+        if b == ZDLE && padded {
+            break;
+        }
+        padded = b == ZPAD;
+        cans = if b == CAN { cans + 1 } else { 0 };
+        if cans == 5 {
+            bail!("cancelled by the sender");
+        }
+        skipped += 1;
+        if skipped > MAX_GARBAGE {
+            return Ok(None);
+        }
+    }
+    let mut bytes = Vec::with_capacity(9);
+    let crc32 = match port.read(BYTE_TIMEOUT).await? {
+        Some(ZHEX) => {
+            for _ in 0..7 {
+                let (Some(high), Some(low)) = (
+                    port.read(BYTE_TIMEOUT).await?,
+                    port.read(BYTE_TIMEOUT).await?,
+                ) else {
+                    return Ok(None);
+                };
+                let Ok(b) = u8::from_str_radix(&String::from_utf8_lossy(&[high, low]), 16) else {
+                    return Ok(None);
+                };
+                bytes.push(b);
+            }
+            false
+        }
+        Some(format @ (ZBIN | ZBIN32)) => {
+            let len = if format == ZBIN32 { 9 } else { 7 };
+            for _ in 0..len {
+                match read_escaped(port).await? {
+                    Some(Escaped::Byte(b)) => bytes.push(b),
+                    _ => return Ok(None),
+                }
+            }
+            format == ZBIN32
+        }
+        _ => return Ok(None),
+    };
+    let (header, crc) = bytes.split_at(5);
+    if !crc_matches(header, crc, crc32) {
+        return Ok(None);
+    }
+    Ok(Some(Header {
+        kind: header[0],
+        data: [header[1], header[2], header[3], header[4]],
+        crc32,
+    }))
+}
+
+/// Reads a data subpacket, `None` if it did not arrive intact in time
+async fn read_subpacket(port: &mut Port, crc32: bool) -> Result<Option<(Vec<u8>, u8)>> {
+    let mut data = Vec::new();
+    let end = loop {
+        match read_escaped(port).await? {
+            Some(Escaped::Byte(_)) if data.len() == MAX_SUBPACKET_LEN => return Ok(None),
+            Some(Escaped::Byte(b)) => data.push(b),
+            Some(Escaped::End(end)) => break end,
+            None => return Ok(None),
+        }
+    };
+    let mut crc = Vec::with_capacity(4);
+    for _ in 0..if crc32 { 4 } else { 2 } {
+        match read_escaped(port).await? {
+            Some(Escaped::Byte(b)) => crc.push(b),
+            _ => return Ok(None),
+        }
+    }
+    // The check covers the end too
+    data.push(end);
+    let intact = crc_matches(&data, &crc, crc32);
+    data.pop();
+    Ok(intact.then_some((data, end)))
+}
+
+/// A byte of a binary header or subpacket after undoing the escaping
+enum Escaped {
+    Byte(u8),
+    /// The end of a subpacket, ZCRCE to ZCRCW
+    End(u8),
+}
+
+/// Reads an escaped byte, `None` for a timeout or an invalid escape
+async fn read_escaped(port: &mut Port) -> Result<Option<Escaped>> {
+    // Flow control characters are not data, senders escape those they mean
+    let is_flow_control = |b: u8| matches!(b & 0x7f, 0x11 | 0x13);
+    loop {
+        match port.read(BYTE_TIMEOUT).await? {
+            Some(ZDLE) => break,
+            Some(b) if is_flow_control(b) => {}
+            Some(b) => return Ok(Some(Escaped::Byte(b))),
+            None => return Ok(None),
+        }
+    }
+    let escaped = loop {
+        match port.read(BYTE_TIMEOUT).await? {
+            Some(b) if is_flow_control(b) => {}
+            escaped => break escaped,
+        }
+    };
+    Ok(Some(match escaped {
+        Some(end @ ZCRCE..=ZCRCW) => Escaped::End(end),
+        Some(ZRUB0) => Escaped::Byte(0x7f),
+        Some(ZRUB1) => Escaped::Byte(0xff),
+        Some(CAN) => {
+            // Five CANs in a row cancel the transfer, ZDLE and this one are the first two
+            for _ in 0..3 {
+                if port.read(BYTE_TIMEOUT).await? != Some(CAN) {
+                    return Ok(None);
+                }
+            }
+            bail!("cancelled by the sender");
+        }
+        Some(b) if b & 0x60 == 0x40 => Escaped::Byte(b ^ 0x40),
+        _ => return Ok(None),
+    }))
+}
+
+/// A header sent as hex digits, as receivers send all of theirs
+fn hex_header(kind: u8, data: [u8; 4]) -> Vec<u8> {
+    let mut bytes = vec![kind];
+    bytes.extend(data);
+    bytes.extend(xmodem::crc16(&bytes).to_be_bytes());
+    let mut header = vec![ZPAD, ZPAD, ZDLE, ZHEX];
+    for b in bytes {
+        header.extend(format!("{b:02x}").bytes());
+    }
+    // CR and LF with the high bit set, as lrzsz ends them
+    header.extend([b'\r', 0x8a]);
+    // Lets a sender go on that saw XOFF in line noise, except after the last headers
+    if kind != ZACK && kind != ZFIN {
+        header.push(XON);
+    }
+    header
+}
+
+/// A header carrying a position in the file
+fn position_header(kind: u8, position: usize) -> Vec<u8> {
+    hex_header(kind, (position as u32).to_le_bytes())
+}
+
+/// Whether `check` is the CRC-16 of `data` sent big endian, or its CRC-32 sent little endian
+fn crc_matches(data: &[u8], check: &[u8], crc32: bool) -> bool {
+    if crc32 {
+        check == self::crc32(data).to_le_bytes()
+    } else {
+        check == xmodem::crc16(data).to_be_bytes()
+    }
+}
+
+/// CRC-32 as in zlib (reflected polynomial 0xedb88320)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode;
+    use crate::screen::Newline;
+    use crate::xmodem::tests::port;
+
+    /// Escapes `data` as a sender does, ZDLE and the flow control characters with and without
+    /// the high bit
+    fn escape(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &b in data {
+            match b {
+                0x18 | 0x10 | 0x11 | 0x13 | 0x90 | 0x91 | 0x93 => out.extend([ZDLE, b ^ 0x40]),
+                b => out.push(b),
+            }
+        }
+        out
+    }
+
+    /// A header in binary, with CRC-32 if `crc32`
+    fn binary_header(kind: u8, data: [u8; 4], crc32: bool) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend(data);
+        let mut header = vec![ZPAD, ZDLE];
+        if crc32 {
+            bytes.extend(self::crc32(&bytes).to_le_bytes());
+            header.push(ZBIN32);
+        } else {
+            bytes.extend(xmodem::crc16(&bytes).to_be_bytes());
+            header.push(ZBIN);
+        }
+        header.extend(escape(&bytes));
+        header
+    }
+
+    /// A data subpacket ending with `end`
+    fn subpacket(data: &[u8], end: u8, crc32: bool) -> Vec<u8> {
+        let mut packet = escape(data);
+        packet.extend([ZDLE, end]);
+        let checked = [data, &[end]].concat();
+        let crc = if crc32 {
+            self::crc32(&checked).to_le_bytes().to_vec()
+        } else {
+            xmodem::crc16(&checked).to_be_bytes().to_vec()
+        };
+        packet.extend(escape(&crc));
+        packet
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn hex_headers_match_lrzsz() {
+        assert_eq!(RECEIVER_FLAGS, 0x23);
+        assert_eq!(
+            hex_header(ZRINIT, [0, 0, 0, RECEIVER_FLAGS]),
+            b"**\x18B0100000023be50\r\x8a\x11"
+        );
+        assert_eq!(
+            position_header(ZRPOS, 0x10),
+            b"**\x18B0910000000b3db\r\x8a\x11"
+        );
+        // No XON after the last header
+        assert_eq!(hex_header(ZFIN, [0; 4]), b"**\x18B0800000000022d\r\x8a");
+    }
+
+    #[test]
+    fn escaping_covers_zdle_and_flow_control() {
+        assert_eq!(
+            escape(&[0x18, 0x10, 0x11, 0x13, 0x90, 0x91, 0x93, 0x12, 0x7f]),
+            [
+                ZDLE, 0x58, ZDLE, 0x50, ZDLE, 0x51, ZDLE, 0x53, ZDLE, 0xd0, ZDLE, 0xd1, ZDLE, 0xd3,
+                0x12, 0x7f
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn parses_hex_and_binary_headers() {
+        let (mut port, peer) = port();
+        // A position with every byte in need of escaping
+        let data = [0x18, 0x10, 0x11, 0x93];
+        peer.send(b"noise**");
+        peer.send(&hex_header(ZDATA, data));
+        peer.send(&binary_header(ZDATA, data, false));
+        peer.send(&binary_header(ZDATA, data, true));
+        for crc32 in [false, false, true] {
+            let header = read_header(&mut port).await.unwrap().unwrap();
+            assert_eq!(
+                (header.kind, header.data, header.crc32),
+                (ZDATA, data, crc32)
+            );
+            assert_eq!(header.position(), 0x9311_1018);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_corrupted_headers() {
+        let (mut port, peer) = port();
+        let mut header = binary_header(ZFILE, [0; 4], true);
+        *header.last_mut().unwrap() ^= 1;
+        peer.send(&header);
+        assert!(read_header(&mut port).await.unwrap().is_none());
+        let mut header = hex_header(ZFIN, [0; 4]);
+        header[5] = b'9';
+        peer.send(&header);
+        assert!(read_header(&mut port).await.unwrap().is_none());
+        // Five CANs cancel
+        peer.send(&[CAN; 5]);
+        assert!(read_header(&mut port).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn data_subpackets_round_trip() {
+        let data: Vec<u8> = (0..=0xff).collect();
+        for crc32 in [false, true] {
+            let (mut port, peer) = port();
+            peer.send(&subpacket(&data, ZCRCG, crc32));
+            peer.send(&subpacket(b"", ZCRCE, crc32));
+            assert_eq!(
+                read_subpacket(&mut port, crc32).await.unwrap(),
+                Some((data.clone(), ZCRCG))
+            );
+            assert_eq!(
+                read_subpacket(&mut port, crc32).await.unwrap(),
+                Some((Vec::new(), ZCRCE))
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subpackets_undo_escapes_and_skip_flow_control() {
+        let (mut port, peer) = port();
+        let data = [0x7f, 0xff, 0x18];
+        let mut packet = vec![ZDLE, ZRUB0, XON, ZDLE, ZRUB1, 0x93, ZDLE, 0x58];
+        packet.extend([ZDLE, ZCRCW]);
+        packet.extend(escape(
+            &crc32(&[&data[..], &[ZCRCW]].concat()).to_le_bytes(),
+        ));
+        peer.send(&packet);
+        assert_eq!(
+            read_subpacket(&mut port, true).await.unwrap(),
+            Some((data.to_vec(), ZCRCW))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_corrupted_subpackets() {
+        let (mut port, peer) = port();
+        let mut packet = subpacket(b"data", ZCRCW, false);
+        packet[0] ^= 1;
+        peer.send(&packet);
+        assert_eq!(read_subpacket(&mut port, false).await.unwrap(), None);
+        // An invalid escape
+        peer.send(&[ZDLE, b'z']);
+        assert_eq!(read_subpacket(&mut port, false).await.unwrap(), None);
+        // Timing out in the middle
+        peer.send(b"da");
+        assert_eq!(read_subpacket(&mut port, false).await.unwrap(), None);
+    }
+
+    #[test]
+    fn detector_spots_zrqinit_across_chunks() {
+        let mut detector = Detector::default();
+        assert_eq!(detector.find(b"rz\r**"), None);
+        assert_eq!(detector.find(b"\x18B0000"), Some(0));
+        assert_eq!(detector.find(b"$ sz a\r\n***\x18B00"), Some(9));
+        assert_eq!(detector.find(b"**\x18A00"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receives_a_file() {
+        let dir = std::env::temp_dir().join(format!("nus-zmodem-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let (mut port, mut peer) = port();
+        let sender = async {
+            assert_eq!(
+                peer.recv().await,
+                hex_header(ZRINIT, [0, 0, 0, RECEIVER_FLAGS])
+            );
+            peer.send(&binary_header(ZFILE, [0; 4], true));
+            peer.send(&subpacket(b"sub/a.bin\x003 0 0\0", ZCRCW, true));
+            assert_eq!(peer.recv().await, position_header(ZRPOS, 0));
+            peer.send(&binary_header(ZDATA, [0; 4], true));
+            peer.send(&subpacket(b"a\x18", ZCRCG, true));
+            peer.send(&subpacket(b"\x91", ZCRCE, true));
+            peer.send(&binary_header(ZEOF, 3u32.to_le_bytes(), true));
+            assert_eq!(
+                peer.recv().await,
+                hex_header(ZRINIT, [0, 0, 0, RECEIVER_FLAGS])
+            );
+            peer.send(&hex_header(ZFIN, [0; 4]));
+            assert_eq!(peer.recv().await, hex_header(ZFIN, [0; 4]));
+            peer.send(b"OO");
+        };
+        let transfer = Mutex::new(None);
+        let screen = Mutex::new(Screen::new(100, decode::Mode::Text, Newline::Lf));
+        let (received, ()) = tokio::join!(receive(&mut port, &dir, &transfer, &screen), sender);
+        assert_eq!(received.unwrap(), 1);
+        assert_eq!(std::fs::read(dir.join("a.bin")).unwrap(), b"a\x18\x91");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}